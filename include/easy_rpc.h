@@ -0,0 +1,78 @@
+/*
+ * C API of easy-rpc, built with the `ffi` feature. Link the crate into a `staticlib` or `cdylib` crate to get the symbols.
+ * The arguments and results are msgpack values encoded by the application, an empty one is sent as nil.
+ */
+#ifndef EASY_RPC_H
+#define EASY_RPC_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define EASY_RPC_OK 0
+/* The peer answered an error, the result holds its code and its message in UTF-8 */
+#define EASY_RPC_REMOTE_ERROR 1
+#define EASY_RPC_DISCONNECTED (-1)
+/* The method isn't a UTF-8 string */
+#define EASY_RPC_INVALID (-2)
+/* The send queue of the session is full */
+#define EASY_RPC_WOULD_BLOCK (-3)
+
+typedef struct EasyRpcSession EasyRpcSession;
+typedef struct EasyRpcRet EasyRpcRet;
+
+/* Transport implemented by the application, its callbacks may be called from any thread */
+typedef struct EasyRpcAdaptor {
+    void *user;
+    /* Send a whole packet, false if the connection is closed */
+    bool (*send)(void *user, const uint8_t *data, size_t len);
+    /* Wait for the next packet and point `data` to it, which stays valid until the next call. False if the connection is closed */
+    bool (*recv)(void *user, const uint8_t **data, size_t *len);
+    /* Close the connection, so `recv` returns false. May be NULL */
+    void (*close)(void *user);
+} EasyRpcAdaptor;
+
+/* Bytes allocated by the library, freed with easy_rpc_buffer_free */
+typedef struct EasyRpcBuffer {
+    uint8_t *data;
+    size_t len;
+} EasyRpcBuffer;
+
+/* The outcome of easy_rpc_request, its buffer is freed with easy_rpc_buffer_free */
+typedef struct EasyRpcResult {
+    /* The msgpack value answered, or the message of the error */
+    EasyRpcBuffer data;
+    /* The code of the error answered, 0 for a value */
+    int64_t code;
+} EasyRpcResult;
+
+/* Handle a request of the peer, or a notify if `ret` is NULL. The integer methods are in decimal.
+ * A request is answered with easy_rpc_ret_ok or easy_rpc_ret_error before returning */
+typedef void (*EasyRpcHandler)(void *user, const char *method, const uint8_t *args, size_t len, EasyRpcRet *ret);
+
+/* `handler` may be NULL, it gets `user` */
+EasyRpcSession *easy_rpc_session_new(EasyRpcAdaptor adaptor, EasyRpcHandler handler, void *user);
+/* Handle the packets of the peer until the connection is closed */
+void easy_rpc_session_loop(const EasyRpcSession *ss);
+void easy_rpc_session_close(const EasyRpcSession *ss);
+/* Free a session, after its loop returned */
+void easy_rpc_session_free(EasyRpcSession *ss);
+
+/* `result` gets the msgpack value answered or the error */
+int easy_rpc_request(const EasyRpcSession *ss, const char *method, const uint8_t *args, size_t len, EasyRpcResult *result);
+bool easy_rpc_notify(const EasyRpcSession *ss, const char *method, const uint8_t *args, size_t len);
+
+void easy_rpc_ret_ok(EasyRpcRet *ret, const uint8_t *data, size_t len);
+void easy_rpc_ret_error(EasyRpcRet *ret, const char *message);
+
+void easy_rpc_buffer_free(EasyRpcBuffer buffer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -6,11 +6,13 @@ const RESPONSE = 1
 const NOTIFY = 2
 
 class EasySession {
-    constructor(url, service) {
+    // options.json sends the packets as JSON text, the server wraps its adaptor in a JsonAdaptor
+    constructor(url, service, options = {}) {
         let socket = new WebSocket(url)
         socket.session = this 
         this.socket = socket
         this.service = service
+        this.json = !!options.json
         this._id = 0
         this._callback = {}
         let self = this 
@@ -44,7 +46,7 @@ class EasySession {
     async _handle(blob) {
         // let data = await blob.arrayBuffer();
         let data = await new Response(blob).arrayBuffer()
-        let pack = msgpack.decode(data)
+        let pack = this.json ? JSON.parse(new TextDecoder().decode(data)) : msgpack.decode(data)
         switch (pack[0]) {
             case REQUEST: {
                 let req_id = pack[1]
@@ -53,7 +55,9 @@ class EasySession {
                     let ret = this.service[method](pack[3], this)
                     this._send_pack([RESPONSE, req_id, null, ret])
                 } catch (err) {
-                    this._send_pack([RESPONSE, req_id, err.toString(), null]);
+                    // Errors with a code are answered as [CODE, MESSAGE, DATA]
+                    let error = err.code != null ? [err.code, err.message, err.data == null ? null : err.data] : err.toString()
+                    this._send_pack([RESPONSE, req_id, error, null]);
                 }
                 break
             }
@@ -70,7 +74,9 @@ class EasySession {
                 let req_id = pack[1]
                 let err = pack[2]
                 let callback = this._callback[req_id]
-                if (err != null)
+                if (Array.isArray(err))
+                    callback.reject(Object.assign(new Error(err[1]), {code: err[0], data: err[2]}))
+                else if (err != null)
                     callback.reject(err)
                 else
                     callback.resolve(pack[3])
@@ -80,5 +86,7 @@ class EasySession {
         }
     }
 
-    _send_pack(pack) { this.socket.send(msgpack.encode(pack)) }
+    _send_pack(pack) {
+        this.socket.send(this.json ? new TextEncoder().encode(JSON.stringify(pack)) : msgpack.encode(pack))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,214 @@
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro_hack::proc_macro_hack;
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2};
+use quote::{quote, format_ident};
+use syn::{parse_macro_input, Error, FnArg, GenericArgument, ImplItem, ImplItemMethod, ItemImpl, LitStr, Pat, PathArguments, ReturnType, Type};
+
+/// Implement `easy_rpc::Service` for the type of an impl block, each method taking `&self` is handled
+/// as the string method of its name and as its `method_id!`, other methods are answered `MethodNotFound`.
+/// Two names of the same id don't compile.
+///
+/// The arguments are decoded like `easy_service!` does, so a single one is sent bare and several ones as a tuple.
+/// A method can take the `&Session` before its arguments. The value returned is the response,
+/// a `Result` is unwrapped with `?` so its error must convert into `HandleError`.
+///
+/// ```ignore
+/// struct Calculator;
+///
+/// #[rpc_service]
+/// impl Calculator {
+///     fn add(&self, a: u32, b: u32) -> u32 { a + b }
+///
+///     fn div(&self, a: u32, b: u32) -> Result<u32, HandleError> {
+///         a.checked_div(b).ok_or_else(|| HandleError::new(ErrorKind::InvalidArgs, "division by zero"))
+///     }
+///
+///     fn hello(&self, ss: &Session, name: String) { ss.notify("print", format!("hello {}", name)); }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn rpc_service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(Span::call_site(), "rpc_service takes no arguments").to_compile_error().into();
+    }
+    let item = parse_macro_input!(item as ItemImpl);
+    let service = expand(&item).unwrap_or_else(|e| e.to_compile_error());
+    quote!(#item #service).into()
+}
+
+/// The `easy_rpc::method_id` of a method name, as a `u32` literal usable in constants and patterns
+///
+/// ```ignore
+/// const ADD: u32 = method_id!("calc.add");
+/// let sum: u32 = session.request(ADD, (1, 2)).into()?;
+/// ```
+#[proc_macro_hack]
+pub fn method_id(input: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(input as LitStr);
+    let id = Literal::u32_suffixed(id_of(&name.value()));
+    quote!(#id).into()
+}
+
+/// Same as `easy_rpc::method_id`, the ids must match
+fn id_of(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5u32, |hash, b| (hash ^ b as u32).wrapping_mul(0x0100_0193)) & 0x7fff_ffff
+}
+
+fn expand(item: &ItemImpl) -> Result<TokenStream2, Error> {
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(Error::new_spanned(path, "rpc_service must be put on an inherent impl block"));
+    }
+    let mut arms = Vec::new();
+    let mut names = Vec::new();
+    let mut ids = Vec::new();
+    let mut infos = Vec::new();
+    for method in item.items.iter().filter_map(|i| if let ImplItem::Method(m) = i { Some(m) } else { None }) {
+        if let Some((arm, info)) = expand_method(method)? {
+            let name = method.sig.ident.to_string();
+            let id = id_of(&name);
+            if let Some(i) = ids.iter().position(|&other| other == id) {
+                let message = format!("`{}` has the same method id as `{}`", name, names[i]);
+                return Err(Error::new_spanned(&method.sig.ident, message));
+            }
+            arms.push(arm);
+            names.push(name);
+            ids.push(id);
+            infos.push(info);
+        }
+    }
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::easy_rpc::Service for #self_ty #where_clause {
+            #[allow(unused_variables)]
+            fn handle(&self, ss: &::easy_rpc::Session, arg: ::easy_rpc::Arg, ret: ::easy_rpc::Ret) -> ::std::result::Result<(), ::easy_rpc::HandleError> {
+                let not_found = || ::easy_rpc::HandleError::new(::easy_rpc::ErrorKind::MethodNotFound, "No this method");
+                let method = match arg.method {
+                    ::easy_rpc::Method::Str(method) => method,
+                    ::easy_rpc::Method::Int(id) => match id {
+                        #(#ids => #names,)*
+                        _ => return Err(not_found()),
+                    },
+                };
+                match method {
+                    #(#arms)*
+                    _ => return Err(not_found()),
+                }
+                Ok(())
+            }
+
+            fn supports(&self, method: ::easy_rpc::Method) -> Option<bool> {
+                Some(match method {
+                    ::easy_rpc::Method::Str(method) => [#(#names),*].contains(&method),
+                    ::easy_rpc::Method::Int(id) => [#(#ids),*].contains(&id),
+                })
+            }
+
+            fn methods(&self) -> Vec<::easy_rpc::introspect::MethodInfo> { vec![#(#infos),*] }
+        }
+    })
+}
+
+/// The match arm calling `method` and its `MethodInfo`, `None` for the associated functions
+fn expand_method(method: &ImplItemMethod) -> Result<Option<(TokenStream2, TokenStream2)>, Error> {
+    let sig = &method.sig;
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(r)) if r.reference.is_some() && r.mutability.is_none() => {}
+        Some(FnArg::Receiver(r)) => return Err(Error::new_spanned(r, "rpc methods must take &self")),
+        _ => return Ok(None),
+    }
+
+    // The session can be taken before the arguments
+    let mut inputs = inputs.peekable();
+    let session = match inputs.peek() {
+        Some(FnArg::Typed(t)) if is_session(&t.ty) => { inputs.next(); quote!(ss,) }
+        _ => quote!(),
+    };
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    for (i, input) in inputs.enumerate() {
+        if let FnArg::Typed(t) = input {
+            names.push(match &*t.pat {
+                Pat::Ident(p) => p.ident.clone(),
+                _ => format_ident!("arg{}", i),
+            });
+            types.push(&t.ty);
+        }
+    }
+
+    // Arguments are decoded like easy_service! does: none, a single one bare, several ones as a tuple
+    let decode = match names.len() {
+        0 => quote!(),
+        1 => quote!(let #(#names: #types)* = arg.into()?;),
+        _ => quote!(let (#(#names),*): (#(#types),*) = arg.into()?;),
+    };
+    let ident = &sig.ident;
+    let call = quote!(self.#ident(#session #(#names),*));
+    let call = if returns_result(&sig.output) { quote!(#call?) } else { call };
+    let name = ident.to_string();
+    let params = match types.as_slice() {
+        [] => "()".to_string(),
+        [ty] => type_string(ty),
+        types => format!("({})", types.iter().map(|ty| type_string(ty)).collect::<Vec<_>>().join(", ")),
+    };
+    let returns = response_type(&sig.output);
+    let arm = quote! {
+        #name => {
+            #decode
+            ret.ok(#call);
+        }
+    };
+    let info = quote!(::easy_rpc::introspect::MethodInfo::new(#name, #params, Some(#returns)));
+    Ok(Some((arm, info)))
+}
+
+/// The type answered, the `Ok` one of a `Result`
+fn response_type(output: &ReturnType) -> String {
+    let ty = match output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => return "()".into(),
+    };
+    if let Type::Path(p) = &**ty {
+        let last = p.path.segments.last();
+        if let Some(PathArguments::AngleBracketed(args)) = last.filter(|s| s.ident == "Result").map(|s| &s.arguments) {
+            if let Some(GenericArgument::Type(ok)) = args.args.first() { return type_string(ok); }
+        }
+    }
+    type_string(ty)
+}
+
+/// The type as written, without the spaces between its tokens
+fn type_string(ty: &Type) -> String {
+    let mut s = quote!(#ty).to_string();
+    for (spaced, tight) in &[(" < ", "<"), ("< ", "<"), (" >", ">"), (" :: ", "::"), (":: ", "::"), ("& ", "&"), (" ,", ","), ("( ", "("), (" )", ")"), ("[ ", "["), (" ]", "]"), (" ;", ";")] {
+        s = s.replace(spaced, tight);
+    }
+    s
+}
+
+fn is_session(ty: &Type) -> bool {
+    match ty {
+        Type::Reference(r) => last_segment_is(&r.elem, "Session"),
+        _ => false,
+    }
+}
+
+/// `Result`s are unwrapped with `?`, so the error is answered to the peer
+fn returns_result(output: &ReturnType) -> bool {
+    match output {
+        ReturnType::Type(_, ty) => last_segment_is(ty, "Result"),
+        ReturnType::Default => false,
+    }
+}
+
+fn last_segment_is(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(p) => p.path.segments.last().is_some_and(|s| s.ident == name),
+        _ => false,
+    }
+}
@@ -0,0 +1,172 @@
+
+use std::collections::HashMap;
+use std::mem::take;
+use std::sync::{Arc, Weak, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+
+use crate::*;
+
+/// When a batch is flushed
+#[derive(Debug, Clone, Copy)]
+pub struct BatchPolicy {
+    /// Flush as soon as the batch holds so many items
+    pub max_len: usize,
+    /// Flush when the oldest item has waited so long
+    pub max_delay: Duration,
+}
+
+trait Slot: Send + Sync {
+    fn push(&self, bytes: &[u8], now: Instant) -> Result<(), DecodeError>;
+    /// Flush if it's time to, or unconditionally if `now` is `None`
+    fn flush(&self, now: Option<Instant>);
+    fn max_delay(&self) -> Duration;
+}
+
+struct Batch<T> {
+    // Pending items and the arriving time of the first one
+    items: Mutex<(Vec<T>, Option<Instant>)>,
+    policy: BatchPolicy,
+    on_flush: Box<dyn Fn(Vec<T>) + Send + Sync>,
+}
+
+impl<T: DeserializeOwned + Send> Slot for Batch<T> {
+    fn push(&self, bytes: &[u8], now: Instant) -> Result<(), DecodeError> {
+        let item = rmps::from_read_ref(bytes)?;
+        let full = {
+            let mut items = self.items.lock().unwrap();
+            items.1.get_or_insert(now);
+            items.0.push(item);
+            if items.0.len() >= self.policy.max_len {
+                Some(take(&mut *items).0)
+            } else { None }
+        };
+        // Flush on the receiving thread, which slows the peers down if the callback can't keep up
+        if let Some(items) = full { (self.on_flush)(items); }
+        Ok(())
+    }
+
+    fn flush(&self, now: Option<Instant>) {
+        let items = {
+            let mut items = self.items.lock().unwrap();
+            let due = match (items.1, now) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(first), Some(now)) => now.saturating_duration_since(first) >= self.policy.max_delay,
+            };
+            if !due { return; }
+            take(&mut *items).0
+        };
+        (self.on_flush)(items);
+    }
+
+    fn max_delay(&self) -> Duration { self.policy.max_delay }
+}
+
+/// A [`Service`] for servers receiving high-rate notifies from many clients (e.g. telemetry):
+/// the arguments of each registered method are decoded and collected into batches,
+/// which are passed to a callback when they are full or too old. A single `Aggregator` is shared by all the sessions
+/// ```no_run
+/// # use std::{sync::Arc, time::Duration};
+/// # use easy_rpc::*;
+/// # use easy_rpc::aggregate::*;
+/// let service = Aggregator::new()
+///     .batch("sample", BatchPolicy { max_len: 1000, max_delay: Duration::from_millis(100) }, |samples: Vec<(u32, f64)>| {
+///         println!("{} samples", samples.len());
+///     })
+///     .start();
+/// # let adaptor = ws::connect("ws://127.0.0.1:3333").unwrap();
+/// Session::new(adaptor, service).loop_handle();
+/// ```
+/// A request to a batched method is answered with `nil` once its argument is queued,
+/// the other methods are handled by the [`Aggregator::fallback`] service
+pub struct Aggregator {
+    strs: HashMap<String, Box<dyn Slot>>,
+    ints: HashMap<u32, Box<dyn Slot>>,
+    fallback: Option<ServiceType>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self { Self::new() }
+}
+
+impl Aggregator {
+    pub fn new() -> Self {
+        Aggregator { strs: HashMap::new(), ints: HashMap::new(), fallback: None, clock: Arc::new(SystemClock) }
+    }
+
+    /// Collect the arguments of `method` into batches of `T`
+    pub fn batch<'a, T, F>(mut self, method: impl ToMethod<'a>, policy: BatchPolicy, on_flush: F) -> Self
+    where T: DeserializeOwned + Send + 'static, F: Fn(Vec<T>) + Send + Sync + 'static {
+        let slot = Box::new(Batch {
+            items: Mutex::new((Vec::new(), None)),
+            policy: BatchPolicy { max_len: policy.max_len.max(1), ..policy },
+            on_flush: Box::new(on_flush),
+        });
+        match method.to_method() {
+            Method::Int(i) => { self.ints.insert(i, slot); }
+            Method::Str(s) => { self.strs.insert(s.into(), slot); }
+        }
+        self
+    }
+
+    /// Service handling the methods which are not batched
+    pub fn fallback(mut self, service: ServiceType) -> Self {
+        self.fallback = Some(service); self
+    }
+
+    /// Measure the ages of the batches with `clock` rather than the system's
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock; self
+    }
+
+    /// Spawn the thread flushing the batches in time, it exits once the aggregator is dropped
+    pub fn start(self) -> Arc<Aggregator> {
+        let this = Arc::new(self);
+        let tick = this.slots().map(|s| s.max_delay() / 2).min()
+                       .unwrap_or_default().max(Duration::from_millis(1));
+        let weak: Weak<Aggregator> = Arc::downgrade(&this);
+        let clock = this.clock.clone();
+        std::thread::spawn(move || {
+            while let Some(this) = weak.upgrade() {
+                let now = clock.now();
+                this.slots().for_each(|s| s.flush(Some(now)));
+                drop(this);
+                clock.sleep(tick);
+            }
+        });
+        this
+    }
+
+    /// Flush all the pending items, e.g. before shutdown
+    pub fn flush_all(&self) {
+        self.slots().for_each(|s| s.flush(None));
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &Box<dyn Slot>> {
+        self.strs.values().chain(self.ints.values())
+    }
+
+    fn slot(&self, method: Method) -> Option<&dyn Slot> {
+        match method {
+            Method::Int(i) => self.ints.get(&i),
+            Method::Str(s) => self.strs.get(s),
+        }.map(|s| &**s)
+    }
+}
+
+impl Service for Aggregator {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        match (self.slot(arg.method), &self.fallback) {
+            (Some(slot), _) => {
+                slot.push(arg.bytes, self.clock.now())?;
+                ret.ok(());
+                Ok(())
+            }
+            (None, Some(fallback)) => fallback.handle(ss, arg, ret),
+            (None, None) => Err(HandleError::new(ErrorKind::MethodNotFound, "No this method")),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rmpv::Value;
+
+use crate::Method;
+
+/// Control request aliasing the methods named `[NAMES]` and enabling the aliasing of the others on their first use,
+/// answered their ids, `nil` for the ones which can't be
+pub(crate) const ALIAS_METHOD: &str = "$alias";
+/// Notify of the alias the peer gave to a method on its first use, `[NAME, ID]`
+pub(crate) const ALIASED_METHOD: &str = "$aliased";
+
+/// The ids of the aliases, out of the way of the integer methods of the services
+const BASE: u32 = 0xF000_0000;
+/// Count of methods a session aliases for its peer
+const MAX_ALIASES: usize = 4096;
+
+/// The method aliases of a session, in both ways
+#[derive(Default)]
+pub(crate) struct Aliases {
+    // The peer gave some, only looked up once it did
+    active: AtomicBool,
+    // Given by the peer to the methods the session calls
+    peer: RwLock<HashMap<String, u32>>,
+    // The peer aliases the methods it calls on their first use
+    lazy: AtomicBool,
+    // Given to the methods the peer calls
+    own: RwLock<Own>,
+}
+
+#[derive(Default)]
+struct Own {
+    // By id less `BASE`
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl Aliases {
+    /// The method to write in a packet, its alias if it has one
+    pub fn alias<'a>(&self, method: Method<'a>) -> Method<'a> {
+        if !self.active.load(Ordering::Relaxed) { return method; }
+        match method {
+            Method::Str(name) => self.peer.read().unwrap().get(name).map_or(method, |&id| Method::Int(id)),
+            Method::Int(_) => method,
+        }
+    }
+
+    /// Whether the session writes the methods with its own aliases, so its packets can't be shared with other sessions
+    pub fn is_active(&self) -> bool { self.active.load(Ordering::Relaxed) }
+
+    pub fn learn(&self, name: &str, id: u32) {
+        self.peer.write().unwrap().insert(name.into(), id);
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    pub fn handle_aliased(&self, args: &[u8]) -> Option<()> {
+        let (name, id): (String, u32) = rmps::from_read_ref(args).ok()?;
+        self.learn(&name, id);
+        Some(())
+    }
+
+    /// The name of an aliased method, the other methods as they are
+    pub fn resolve(&self, method: Value) -> Value {
+        let index = match method.as_u64() {
+            Some(id) if id >= BASE as u64 => (id - BASE as u64) as usize,
+            _ => return method,
+        };
+        self.own.read().unwrap().names.get(index).map_or(method, |name| Value::from(name.as_str()))
+    }
+
+    /// Alias the method `name` of the service, `None` for the session's own methods or once there are too many
+    pub fn assign(&self, name: &str) -> Option<u32> {
+        if name.starts_with('$') { return None; }
+        let mut own = self.own.write().unwrap();
+        if let Some(&id) = own.ids.get(name) { return Some(id); }
+        if own.names.len() >= MAX_ALIASES { return None; }
+        let id = BASE + own.names.len() as u32;
+        own.names.push(name.into());
+        own.ids.insert(name.into(), id);
+        Some(id)
+    }
+
+    pub fn handle_alias(&self, args: &[u8]) -> Option<Vec<Option<u32>>> {
+        let (names, lazy): (Vec<String>, bool) = rmps::from_read_ref(args).ok()?;
+        if lazy { self.lazy.store(true, Ordering::Relaxed); }
+        Some(names.iter().map(|name| self.assign(name)).collect())
+    }
+
+    /// The alias to give to a method the peer called by its name, if it's new and the peer wants it
+    pub fn first_use(&self, name: &str) -> Option<u32> {
+        if !self.lazy.load(Ordering::Relaxed) || name.starts_with('$') { return None; }
+        if self.own.read().unwrap().ids.contains_key(name) { return None; }
+        self.assign(name)
+    }
+
+    pub fn clear(&self) {
+        self.active.store(false, Ordering::Relaxed);
+        self.peer.write().unwrap().clear();
+        self.lazy.store(false, Ordering::Relaxed);
+        *self.own.write().unwrap() = Own::default();
+    }
+}
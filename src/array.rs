@@ -0,0 +1,137 @@
+
+use std::fmt;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::ser::SerializeMap;
+use serde::de::{self, Visitor, MapAccess, IgnoredAny};
+
+use crate::{Bytes, ByteBuf};
+
+/// Dense numeric array, encoded like `msgpack-numpy` does so Python peers get a `numpy.ndarray` with
+/// `msgpack.unpackb(data, object_hook=msgpack_numpy.decode)`:
+/// a map `{b"nd": true, b"type": DTYPE, b"kind": b"", b"shape": [..], b"data": BUFFER}`.
+/// The buffer holds the elements in row-major order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NdArray {
+    /// The numpy array-protocol type string, e.g. `<f4`
+    pub dtype: String,
+    pub shape: Vec<usize>,
+    pub data: Vec<u8>,
+}
+
+/// Type of the elements of an [`NdArray`]
+pub trait Element: Copy {
+    /// The numpy type string, little-endian
+    const DTYPE: &'static str;
+    const SIZE: usize;
+
+    fn write(self, buf: &mut Vec<u8>);
+    fn read(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_element {
+    ($($t:ty => $dtype:expr),*) => {$(
+        impl Element for $t {
+            const DTYPE: &'static str = $dtype;
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn write(self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.to_le_bytes()); }
+
+            fn read(bytes: &[u8]) -> Self {
+                let mut le = [0; std::mem::size_of::<$t>()];
+                le.copy_from_slice(bytes);
+                <$t>::from_le_bytes(le)
+            }
+        }
+    )*};
+}
+
+impl_element!(u8 => "|u1", i8 => "|i1", u16 => "<u2", i16 => "<i2", u32 => "<u4", i32 => "<i4",
+              u64 => "<u8", i64 => "<i8", f32 => "<f4", f64 => "<f8");
+
+impl NdArray {
+    /// Return `None` if the count of elements doesn't match `shape`
+    pub fn new<T: Element>(shape: Vec<usize>, elements: &[T]) -> Option<NdArray> {
+        if shape.iter().product::<usize>() != elements.len() { return None; }
+        let mut data = Vec::with_capacity(elements.len() * T::SIZE);
+        for &e in elements { e.write(&mut data); }
+        Some(NdArray { dtype: T::DTYPE.into(), shape, data })
+    }
+
+    pub fn len(&self) -> usize { self.shape.iter().product() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// The elements in row-major order, `None` if they are not of type `T` or don't match the shape
+    pub fn to_vec<T: Element>(&self) -> Option<Vec<T>> {
+        // Single byte types are written `|u1` by numpy, but accept `<u1` too
+        let dtype_matches = self.dtype == T::DTYPE || (T::SIZE == 1 && self.dtype.get(1..) == T::DTYPE.get(1..));
+        if !dtype_matches || self.data.len() != self.len() * T::SIZE { return None; }
+        Some(self.data.chunks(T::SIZE).map(T::read).collect())
+    }
+}
+
+impl Serialize for NdArray {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(5))?;
+        map.serialize_entry(Bytes::new(b"nd"), &true)?;
+        map.serialize_entry(Bytes::new(b"type"), &self.dtype)?;
+        map.serialize_entry(Bytes::new(b"kind"), Bytes::new(b""))?;
+        map.serialize_entry(Bytes::new(b"shape"), &self.shape)?;
+        map.serialize_entry(Bytes::new(b"data"), Bytes::new(&self.data))?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for NdArray {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(NdArrayVisitor)
+    }
+}
+
+struct NdArrayVisitor;
+
+impl<'de> Visitor<'de> for NdArrayVisitor {
+    type Value = NdArray;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("a msgpack-numpy array") }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let (mut dtype, mut shape, mut data) = (None, None, None);
+        // The keys are binaries from numpy, strings are accepted too
+        while let Some(key) = map.next_key::<ByteBuf>()? {
+            match &key[..] {
+                b"type" => dtype = Some(map.next_value::<String>()?),
+                b"shape" => shape = Some(map.next_value::<Vec<usize>>()?),
+                b"data" => data = Some(map.next_value::<ByteBuf>()?.into_vec()),
+                b"kind" => if !map.next_value::<ByteBuf>()?.is_empty() {
+                    return Err(de::Error::custom("structured dtypes are not supported"));
+                },
+                _ => { map.next_value::<IgnoredAny>()?; }
+            }
+        }
+        Ok(NdArray {
+            dtype: dtype.ok_or_else(|| de::Error::missing_field("type"))?,
+            shape: shape.ok_or_else(|| de::Error::missing_field("shape"))?,
+            data: data.ok_or_else(|| de::Error::missing_field("data"))?,
+        })
+    }
+}
+
+#[cfg(feature = "ndarray")]
+impl<T: Element, D: ndarray::Dimension> From<&ndarray::Array<T, D>> for NdArray {
+    fn from(array: &ndarray::Array<T, D>) -> NdArray {
+        let mut data = Vec::with_capacity(array.len() * T::SIZE);
+        // Iterated in logical order whatever the memory layout
+        for &e in array.iter() { e.write(&mut data); }
+        NdArray { dtype: T::DTYPE.into(), shape: array.shape().to_vec(), data }
+    }
+}
+
+#[cfg(feature = "ndarray")]
+impl NdArray {
+    /// Convert to an `ndarray` array, `None` if the elements are not of type `T`
+    pub fn to_ndarray<T: Element>(&self) -> Option<ndarray::ArrayD<T>> {
+        ndarray::ArrayD::from_shape_vec(self.shape.clone(), self.to_vec()?).ok()
+    }
+}
@@ -0,0 +1,81 @@
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rmp::encode;
+
+use crate::{ATTACHMENT, ProtocolError};
+
+/// Count of requests and responses whose attachments are being received at the same time
+const MAX_PENDING: usize = 64;
+
+/// The attachments to send ahead of a request, and where to put the ones of its response
+pub(crate) type Exchange<'a> = (&'a [&'a [u8]], &'a mut Vec<Vec<u8>>);
+
+/// Header of an attachment of the request `id` of the sender, or of the response to the request `id` of the
+/// receiver, followed by the `len` bytes of the blob
+pub(crate) fn header(id: u64, response: bool, len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(0x20);
+    encode::write_array_len(&mut header, 4);
+    encode::write_uint(&mut header, ATTACHMENT as u64);
+    encode::write_uint(&mut header, id);
+    encode::write_bool(&mut header, response);
+    encode::write_bin_len(&mut header, len as u32);
+    header
+}
+
+/// Whether the packet is a response, and its id
+type Key = (bool, u64);
+
+#[derive(Default)]
+struct Pending {
+    // The blobs of each packet, after the count of packets whose attachments were received before its first one
+    packets: HashMap<Key, (u64, Vec<Vec<u8>>)>,
+    started: u64,
+}
+
+/// Attachments received before the packet they belong to
+#[derive(Default)]
+pub(crate) struct Attachments(Mutex<Pending>);
+
+impl Attachments {
+    /// Keep the attachments of the response to the request `id`, the ones of the other responses are dropped
+    pub fn expect(&self, id: u64) {
+        let mut pending = self.0.lock().unwrap();
+        let started = pending.started;
+        pending.started += 1;
+        pending.packets.insert((true, id), (started, Vec::new()));
+    }
+
+    /// Keep an attachment until its packet is received. Once the attachments of [`MAX_PENDING`] packets are kept, the
+    /// oldest request's ones are dropped for it: they're sent right before their request, so it won't come anymore
+    pub fn push(&self, id: u64, response: bool, data: &[u8], max_len: usize) -> Result<(), ProtocolError> {
+        let mut pending = self.0.lock().unwrap();
+        let Pending { packets, started } = &mut *pending;
+        if response && !packets.contains_key(&(true, id)) { return Ok(()); }
+        if !packets.contains_key(&(response, id)) && packets.len() >= MAX_PENDING {
+            let oldest = packets.iter().filter(|((response, _), _)| !response).min_by_key(|(_, (started, _))| *started).map(|(&key, _)| key);
+            match oldest {
+                Some(key) => { packets.remove(&key); }
+                None => return Err(ProtocolError::LimitExceeded("pending attachments")),
+            }
+        }
+        let (_, blobs) = packets.entry((response, id)).or_insert_with(|| {
+            *started += 1;
+            (*started - 1, Vec::new())
+        });
+        if blobs.iter().map(Vec::len).sum::<usize>() + data.len() > max_len {
+            packets.remove(&(response, id));
+            return Err(ProtocolError::LimitExceeded("attachments size"));
+        }
+        blobs.push(data.into());
+        Ok(())
+    }
+
+    /// The attachments of a packet, in the order they were sent
+    pub fn take(&self, id: u64, response: bool) -> Vec<Vec<u8>> {
+        self.0.lock().unwrap().packets.remove(&(response, id)).map(|(_, blobs)| blobs).unwrap_or_default()
+    }
+
+    pub fn clear(&self) { self.0.lock().unwrap().packets.clear(); }
+}
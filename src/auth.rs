@@ -0,0 +1,68 @@
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use rmpv::Value;
+
+use crate::Session;
+
+/// Method of the request returning a fresh challenge to sign
+pub(crate) const CHALLENGE_METHOD: &str = "$challenge";
+/// Method of the request presenting the credentials
+pub(crate) const AUTH_METHOD: &str = "$auth";
+
+const CHALLENGE_LEN: usize = 32;
+
+/// What a client presents to authenticate, see [`Session::authenticate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// A bearer token
+    Token(String),
+    /// A signature of the challenge returned by [`Session::challenge`], made with the private key of `public_key`
+    Signature { public_key: Vec<u8>, signature: Vec<u8> },
+}
+
+/// Who the peer is, attached to the session once authenticated, see [`Session::identity`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Identity {
+    pub name: String,
+    pub roles: Vec<String>,
+}
+
+/// Validate the credentials presented by the peers, see [`Session::set_authenticator`]
+pub trait Authenticator: Send + Sync {
+    /// `challenge` is the last one sent to the peer of `ss` (empty if it didn't ask for one), it's valid for a single attempt.
+    /// The error is returned to the peer
+    fn authenticate(&self, ss: &Session, credentials: &Credentials, challenge: &[u8]) -> Result<Identity, String>;
+}
+
+/// Returned by [`Session::harden`] when no authenticator is set: the settings are applied, but the peers aren't
+/// authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoAuthenticator;
+
+impl Display for NoAuthenticator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult { write!(f, "no authenticator set, the peers aren't authenticated") }
+}
+
+impl std::error::Error for NoAuthenticator {}
+
+// Last challenge sent, stored in the session extensions
+pub(crate) struct Challenge(pub Vec<u8>);
+
+pub(crate) fn new_challenge() -> Vec<u8> {
+    (0..CHALLENGE_LEN).map(|_| rand::random::<u8>()).collect()
+}
+
+impl Credentials {
+    pub(crate) fn decode(val: &Value) -> Option<Credentials> {
+        let items = val.as_array()?;
+        match (items.first()?.as_str()?, items.len()) {
+            ("token", 2) => Some(Credentials::Token(items[1].as_str()?.into())),
+            ("signature", 3) => Some(Credentials::Signature {
+                public_key: items[1].as_slice()?.into(),
+                signature: items[2].as_slice()?.into(),
+            }),
+            _ => None,
+        }
+    }
+}
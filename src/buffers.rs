@@ -0,0 +1,33 @@
+
+use std::sync::Mutex;
+
+/// Buffers of the packets sent and received, given back once the adaptor is done with them
+/// (see [`Adaptor::send_buf`](crate::Adaptor::send_buf)) so a busy session doesn't allocate for each packet
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Count of buffers kept, the others are dropped
+    const MAX_BUFFERS: usize = 32;
+    /// The larger buffers are dropped, so one big packet doesn't keep its memory for the session
+    const MAX_CAPACITY: usize = 0x10000;
+
+    pub(crate) fn new() -> Self {
+        BufferPool { buffers: Mutex::new(Vec::new()) }
+    }
+
+    /// An empty buffer, of at least `capacity`
+    pub(crate) fn take(&self, capacity: usize) -> Vec<u8> {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buf.reserve(capacity);
+        buf
+    }
+
+    pub(crate) fn give(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > Self::MAX_CAPACITY { return; }
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < Self::MAX_BUFFERS { buffers.push(buf); }
+    }
+}
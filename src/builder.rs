@@ -0,0 +1,122 @@
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ext::ExtHandler;
+use crate::{
+    Adaptor, Authenticator, Clock, Compression, DecodeLimits, EmptyService, MetricsSink, Overflow, PacketTap,
+    ResponseCache, ServiceType, Session, SessionObserver, SizeLimits, SpanSink, Throttle, Unanswered,
+};
+
+type Setup = Box<dyn FnOnce(&Session)>;
+
+/// Configuration of a new [`Session`], applied before it handles any packet so nothing races with its receiving
+/// thread. See [`Session::builder`]. Each option does what the setter it names does
+pub struct SessionBuilder {
+    adaptor: Arc<dyn Adaptor>,
+    service: ServiceType,
+    setup: Vec<Setup>,
+}
+
+impl SessionBuilder {
+    pub(crate) fn new(adaptor: Arc<dyn Adaptor>) -> Self {
+        SessionBuilder { adaptor, service: Arc::new(EmptyService), setup: Vec::new() }
+    }
+
+    /// The service handling the requests and notifies of the peer, an [`EmptyService`] by default
+    pub fn service(mut self, service: ServiceType) -> Self {
+        self.service = service;
+        self
+    }
+
+    fn with(mut self, setup: impl FnOnce(&Session) + 'static) -> Self {
+        self.setup.push(Box::new(setup));
+        self
+    }
+
+    /// See [`Session::set_default_timeout`]
+    pub fn default_timeout(self, timeout: Duration) -> Self { self.with(move |ss| ss.set_default_timeout(Some(timeout))) }
+
+    /// Limit both the received and the sent packets to `max` bytes, see [`Session::set_size_limits`]
+    pub fn max_payload(self, max: usize) -> Self {
+        self.size_limits(SizeLimits { max_incoming: max, max_outgoing: max, ..SizeLimits::default() })
+    }
+
+    /// See [`Session::set_size_limits`]
+    pub fn size_limits(self, limits: SizeLimits) -> Self { self.with(move |ss| ss.set_size_limits(Some(limits))) }
+
+    /// See [`Session::set_decode_limits`]
+    pub fn decode_limits(self, limits: DecodeLimits) -> Self { self.with(move |ss| ss.set_decode_limits(Some(limits))) }
+
+    /// See [`Session::set_throttle`]
+    pub fn throttle(self, throttle: Throttle) -> Self { self.with(move |ss| ss.set_throttle(Some(throttle))) }
+
+    /// See [`Session::set_send_queue`]
+    pub fn send_queue(self, capacity: usize, overflow: Overflow) -> Self { self.with(move |ss| ss.set_send_queue(capacity, overflow)) }
+
+    /// See [`Session::set_compression`]
+    pub fn compression(self, compression: Compression) -> Self { self.with(move |ss| ss.set_compression(Some(compression))) }
+
+    /// See [`Session::set_max_frame`]
+    pub fn max_frame(self, size: usize) -> Self { self.with(move |ss| ss.set_max_frame(Some(size))) }
+
+    /// See [`Session::set_canonical`]
+    pub fn canonical(self) -> Self { self.with(|ss| ss.set_canonical(true)) }
+
+    /// See [`Session::set_ext_handler`]
+    pub fn ext_handler(self, tag: i8, handler: ExtHandler) -> Self { self.with(move |ss| ss.set_ext_handler(tag, Some(handler))) }
+
+    /// Let the panics of the service unwind through the loop, see [`Session::set_catch_panics`]
+    pub fn propagate_panics(self) -> Self { self.with(|ss| ss.set_catch_panics(false)) }
+
+    /// See [`Session::set_ordered_responses`]
+    pub fn unordered_responses(self) -> Self { self.with(|ss| ss.set_ordered_responses(false)) }
+
+    /// See [`Session::set_authenticator`]
+    pub fn authenticator(self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.with(move |ss| ss.set_authenticator(Some(authenticator)))
+    }
+
+    /// See [`Session::set_metrics`]
+    pub fn metrics(self, sink: Arc<dyn MetricsSink>) -> Self { self.with(move |ss| ss.set_metrics(Some(sink))) }
+
+    /// See [`Session::set_span_sink`]
+    pub fn span_sink(self, sink: Arc<dyn SpanSink>) -> Self { self.with(move |ss| ss.set_span_sink(Some(sink))) }
+
+    /// See [`Session::set_response_cache`]
+    pub fn response_cache(self, cache: Arc<ResponseCache>) -> Self { self.with(move |ss| ss.set_response_cache(Some(cache))) }
+
+    /// See [`Session::set_observer`]
+    pub fn observer(self, observer: Arc<dyn SessionObserver>) -> Self { self.with(move |ss| ss.set_observer(Some(observer))) }
+
+    /// See [`Session::set_packet_tap`]
+    pub fn packet_tap(self, tap: PacketTap) -> Self { self.with(move |ss| ss.set_packet_tap(Some(tap))) }
+
+    /// See [`Session::set_unanswered`]
+    pub fn unanswered(self, unanswered: Unanswered) -> Self { self.with(move |ss| ss.set_unanswered(unanswered)) }
+
+    /// See [`Session::set_housekeeping`]
+    pub fn housekeeping(self, interval: Duration, task: impl Fn(&Session) + Send + Sync + 'static) -> Self {
+        let task = Arc::new(task);
+        self.with(move |ss| ss.set_housekeeping(Some((interval, task))))
+    }
+
+    /// See [`Session::set_idle_timeout`]
+    pub fn idle_timeout(self, timeout: Duration) -> Self { self.with(move |ss| ss.set_idle_timeout(Some(timeout))) }
+
+    /// See [`Session::set_clock`]
+    pub fn clock(self, clock: Arc<dyn Clock>) -> Self { self.with(move |ss| ss.set_clock(clock)) }
+
+    /// See [`Session::harden`], the options given after override its settings. An authenticator may be set later, so a
+    /// missing one isn't an error here: check [`Session::has_authenticator`] before serving untrusted peers
+    pub fn harden(self) -> Self { self.with(|ss| { let _ = ss.harden(); }) }
+
+    /// See [`Session::set_introspection`]
+    pub fn introspection(self, enabled: bool) -> Self { self.with(move |ss| ss.set_introspection(enabled)) }
+
+    pub fn build(self) -> Session {
+        let ss = Session::new(self.adaptor, self.service);
+        for setup in self.setup { setup(&ss); }
+        ss
+    }
+}
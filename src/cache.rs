@@ -0,0 +1,104 @@
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::{Method, ToMethod};
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+struct Entry {
+    result: Vec<u8>,
+    expires: Instant,
+}
+
+/// The results of a method, by the serialized arguments
+type Results = HashMap<Vec<u8>, Entry>;
+
+/// Results of the idempotent methods of the peer kept by a session for a while, so requesting them again with the
+/// same arguments doesn't go to the peer, see [`Session::set_response_cache`](crate::Session::set_response_cache).
+/// Only the results are kept, not the errors. The methods are keyed by their serialized form
+pub struct ResponseCache {
+    ttls: HashMap<Vec<u8>, Duration>,
+    // By the method the notifies invalidate
+    invalidated_by: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    // By the method
+    entries: RwLock<HashMap<Vec<u8>, Results>>,
+    capacity: usize,
+}
+
+fn key<'a>(method: impl ToMethod<'a>) -> Vec<u8> {
+    let mut key = Vec::new();
+    method.to_method().serialize(&mut key);
+    key
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self { ResponseCache::new() }
+}
+
+impl ResponseCache {
+    /// A cache keeping at most 1024 results, of the methods marked with [`ResponseCache::cache`]
+    pub fn new() -> Self {
+        ResponseCache { ttls: HashMap::new(), invalidated_by: HashMap::new(), entries: Default::default(), capacity: DEFAULT_CAPACITY }
+    }
+
+    /// Keep at most `capacity` results, the ones expiring first are dropped to make room
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Keep the results of `method` for `ttl`, it must be idempotent
+    pub fn cache<'a>(mut self, method: impl ToMethod<'a>, ttl: Duration) -> Self {
+        self.ttls.insert(key(method), ttl);
+        self
+    }
+
+    /// Drop the results of `method` when the peer notifies `notify`, e.g. that the configuration it returns changed
+    pub fn invalidate_on<'a, 'b>(mut self, notify: impl ToMethod<'a>, method: impl ToMethod<'b>) -> Self {
+        self.invalidated_by.entry(key(notify)).or_default().push(key(method));
+        self
+    }
+
+    /// Drop the results of `method`
+    pub fn invalidate<'a>(&self, method: impl ToMethod<'a>) { self.entries.write().unwrap().remove(&key(method)); }
+
+    pub fn clear(&self) { self.entries.write().unwrap().clear(); }
+
+    /// Count of results kept, including the expired ones not dropped yet
+    pub fn len(&self) -> usize { self.entries.read().unwrap().values().map(Results::len).sum() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub(crate) fn ttl(&self, method: Method) -> Option<Duration> { self.ttls.get(&key(method)).copied() }
+
+    pub(crate) fn get(&self, method: Method, args: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let entries = self.entries.read().unwrap();
+        entries.get(&key(method))?.get(args).filter(|e| e.expires > now).map(|e| e.result.clone())
+    }
+
+    pub(crate) fn insert(&self, method: Method, args: &[u8], result: &[u8], expires: Instant, now: Instant) {
+        if self.capacity == 0 { return; }
+        let mut entries = self.entries.write().unwrap();
+        if entries.values().map(Results::len).sum::<usize>() >= self.capacity {
+            for results in entries.values_mut() { results.retain(|_, e| e.expires > now); }
+            entries.retain(|_, results| !results.is_empty());
+        }
+        if entries.values().map(Results::len).sum::<usize>() >= self.capacity {
+            let first = entries.iter().flat_map(|(method, results)| results.iter().map(move |(args, e)| (e.expires, method, args)))
+                .min().map(|(_, method, args)| (method.clone(), args.clone()));
+            if let Some((method, args)) = first {
+                if let Some(results) = entries.get_mut(&method) { results.remove(&args); }
+            }
+        }
+        entries.entry(key(method)).or_default().insert(args.into(), Entry { result: result.into(), expires });
+    }
+
+    pub(crate) fn notified(&self, method: Method) {
+        if let Some(methods) = self.invalidated_by.get(&key(method)) {
+            let mut entries = self.entries.write().unwrap();
+            for method in methods { entries.remove(method); }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+/// Notify calling back a closure of the peer, `[ID, ARG]`
+pub(crate) const CALLBACK_METHOD: &str = "$callback";
+
+/// Opaque id of a closure registered with [`Session::callback`](crate::Session::callback), to put in the arguments
+/// of a request so the peer can call it with [`Session::call_back`](crate::Session::call_back). Encoded as an integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Callback(pub(crate) u64);
+
+impl Serialize for Callback {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { serializer.serialize_u64(self.0) }
+}
+
+impl<'de> Deserialize<'de> for Callback {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> { u64::deserialize(deserializer).map(Callback) }
+}
+
+type Closure = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// The closures a session registered for its peer, they're revoked when it disconnects
+#[derive(Default)]
+pub(crate) struct Callbacks {
+    next_id: AtomicU64,
+    closures: RwLock<HashMap<u64, Closure>>,
+}
+
+impl Callbacks {
+    pub fn register(&self, closure: Closure) -> Callback {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.closures.write().unwrap().insert(id, closure);
+        Callback(id)
+    }
+
+    pub fn revoke(&self, callback: Callback) -> bool { self.closures.write().unwrap().remove(&callback.0).is_some() }
+
+    /// Call the closure of the notify, `None` if it's malformed. The revoked ones are ignored
+    pub fn handle(&self, mut args: &[u8]) -> Option<()> {
+        if rmp::decode::read_array_len(&mut args).ok()? != 2 { return None; }
+        let id: u64 = rmp::decode::read_int(&mut args).ok()?;
+        // Not holding the lock, so the closure can revoke itself
+        let closure = self.closures.read().unwrap().get(&id).cloned();
+        if let Some(closure) = closure { closure(args); }
+        Some(())
+    }
+
+    pub fn clear(&self) { self.closures.write().unwrap().clear(); }
+}
@@ -0,0 +1,50 @@
+
+use serde::Serialize;
+use rmpv::Value;
+use rmpv::decode::read_value;
+use crate::ext::write_value;
+use rmps::encode::Error as EncodeError;
+
+/// Serialize `val` to canonical msgpack, see [`canonicalize`]
+pub fn to_vec<T: Serialize + ?Sized>(val: &T) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::new();
+    append(val, &mut buf)?;
+    Ok(buf)
+}
+
+/// Re-encode msgpack data to its canonical form, in which equal values always have the same bytes:
+/// * integers (and all lengths) use their shortest encoding
+/// * map entries are sorted by the bytes of their encoded keys (so shorter strings come first)
+pub fn canonicalize(msgpack: &[u8]) -> Result<Vec<u8>, rmpv::decode::Error> {
+    let mut val = read_value(&mut &msgpack[..])?;
+    sort_maps(&mut val);
+    let mut buf = Vec::with_capacity(msgpack.len());
+    write_value(&mut buf, &val);
+    Ok(buf)
+}
+
+/// Serialize `val` to canonical msgpack at the end of `buf`
+pub(crate) fn append<T: Serialize + ?Sized>(val: &T, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+    let mut raw = Vec::new();
+    crate::ext::append(val, &mut raw)?;
+    let canonical = canonicalize(&raw).map_err(|e| EncodeError::Syntax(e.to_string()))?;
+    buf.extend_from_slice(&canonical);
+    Ok(())
+}
+
+fn sort_maps(val: &mut Value) {
+    match val {
+        Value::Array(items) => items.iter_mut().for_each(sort_maps),
+        Value::Map(entries) => {
+            let mut keyed = entries.drain(..).map(|(mut k, mut v)| {
+                sort_maps(&mut k); sort_maps(&mut v);
+                let mut key = Vec::new();
+                write_value(&mut key, &k);
+                (key, (k, v))
+            }).collect::<Vec<_>>();
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            entries.extend(keyed.into_iter().map(|(_, entry)| entry));
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,107 @@
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmpv::Value;
+use serde_cbor::Value as Cbor;
+
+use crate::{Adaptor, RecvError, TransportError};
+
+/// Convert a msgpack value to CBOR (RFC 8949). Binaries stay byte strings and the entries of maps are sorted by key,
+/// the duplicated keys keep their last value. The strings which aren't UTF-8 and the extensions have no CBOR form
+pub fn to_cbor(val: &Value) -> Result<Cbor, String> {
+    Ok(match val {
+        Value::Nil => Cbor::Null,
+        Value::Boolean(b) => Cbor::Bool(*b),
+        Value::Integer(i) => Cbor::Integer(i.as_u64().map(i128::from).or_else(|| i.as_i64().map(i128::from)).unwrap_or_default()),
+        Value::F32(f) => Cbor::Float(*f as f64),
+        Value::F64(f) => Cbor::Float(*f),
+        Value::String(s) => Cbor::Text(s.as_str().ok_or("The string is not UTF-8")?.to_string()),
+        Value::Binary(b) => Cbor::Bytes(b.clone()),
+        Value::Array(items) => Cbor::Array(items.iter().map(to_cbor).collect::<Result<_, _>>()?),
+        Value::Map(entries) => Cbor::Map(entries.iter().map(|(k, v)| Ok((to_cbor(k)?, to_cbor(v)?))).collect::<Result<_, String>>()?),
+        Value::Ext(ty, _) => return Err(format!("The extension {} has no CBOR form", ty)),
+    })
+}
+
+/// Convert a CBOR value to msgpack, the integers beyond 64 bits have no msgpack form
+pub fn from_cbor(val: &Cbor) -> Result<Value, String> {
+    Ok(match val {
+        Cbor::Null => Value::Nil,
+        Cbor::Bool(b) => Value::Boolean(*b),
+        Cbor::Integer(i) => if *i >= 0 && *i <= u64::MAX as i128 { Value::from(*i as u64) }
+            else if *i >= i64::MIN as i128 { Value::from(*i as i64) }
+            else { return Err(format!("The integer {} is beyond 64 bits", i)) },
+        Cbor::Float(f) => Value::F64(*f),
+        Cbor::Bytes(b) => Value::Binary(b.clone()),
+        Cbor::Text(s) => Value::from(s.as_str()),
+        Cbor::Array(items) => Value::Array(items.iter().map(from_cbor).collect::<Result<_, _>>()?),
+        Cbor::Map(entries) => Value::Map(entries.iter().map(|(k, v)| Ok((from_cbor(k)?, from_cbor(v)?))).collect::<Result<_, String>>()?),
+        _ => return Err("The CBOR value has no msgpack form".into()),
+    })
+}
+
+fn encode(msgpack: &[u8]) -> Result<Vec<u8>, String> {
+    let val = rmpv::decode::read_value(&mut &msgpack[..]).map_err(|e| e.to_string())?;
+    serde_cbor::to_vec(&to_cbor(&val)?).map_err(|e| e.to_string())
+}
+
+pub(crate) fn decode(data: &[u8]) -> Result<Vec<u8>, String> {
+    let cbor: Cbor = serde_cbor::from_slice(data).map_err(|e| e.to_string())?;
+    let mut msgpack = Vec::with_capacity(data.len());
+    rmpv::encode::write_value(&mut msgpack, &from_cbor(&cbor)?).map_err(|e| e.to_string())?;
+    Ok(msgpack)
+}
+
+/// Whole packets as CBOR over `inner`, for the peers speaking CBOR only: a request is sent `[0, ID, METHOD, ARGS]`
+/// like its msgpack form. Wrap the adaptor when making the session, `Session::new(CborAdaptor::new(adaptor), service)`.
+/// The conversions are the ones of [`to_cbor`] and [`from_cbor`]: a packet which can't be converted isn't sent (`send` returns
+/// false), and received data which can't be is passed as is and dropped as malformed. [`codec::Cbor`](crate::codec::Cbor)
+/// serializes the values straight to CBOR instead, in msgpack packets
+pub struct CborAdaptor {
+    inner: Arc<dyn Adaptor>,
+}
+
+impl CborAdaptor {
+    pub fn new(inner: Arc<dyn Adaptor>) -> Arc<CborAdaptor> {
+        Arc::new(CborAdaptor { inner })
+    }
+}
+
+impl Adaptor for CborAdaptor {
+    fn send(&self, data: Vec<u8>) -> bool {
+        match encode(&data) {
+            Ok(frame) => self.inner.send(frame),
+            Err(_) => false,
+        }
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        let data = self.inner.recv()?;
+        Ok(decode(&data).unwrap_or(data))
+    }
+
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> {
+        Some(self.inner.try_recv()?.map(|data| decode(&data).unwrap_or(data)))
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> { self.inner.raw_fd() }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> { self.inner.peer_addr() }
+
+    fn transport_kind(&self) -> Option<&'static str> { self.inner.transport_kind() }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> { self.inner.peer_certificate() }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> {
+        let data = self.inner.recv_timeout(timeout)?;
+        Ok(decode(&data).unwrap_or(data))
+    }
+
+    fn connected(&self) -> bool { self.inner.connected() }
+
+    fn close(&self) { self.inner.close() }
+
+    fn last_error(&self) -> Option<TransportError> { self.inner.last_error() }
+}
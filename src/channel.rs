@@ -0,0 +1,222 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use rmp::decode;
+
+use crate::{Session, RequestResult, DecodeError};
+
+/// Request opening a channel `[ID, NAME]`
+pub(crate) const OPEN_METHOD: &str = "$ch.open";
+/// Message of a channel `[ID, FROM_OPENER, MESSAGE]`
+pub(crate) const DATA_METHOD: &str = "$ch.data";
+/// The receiver consumed messages `[ID, FROM_OPENER, COUNT]`
+pub(crate) const CREDIT_METHOD: &str = "$ch.credit";
+/// `[ID, FROM_OPENER]`
+pub(crate) const CLOSE_METHOD: &str = "$ch.close";
+
+/// Count of messages a side can send before the receiver consumes them
+const WINDOW: usize = 64;
+/// Count of channels opened by the peer and not accepted yet
+const MAX_PENDING: usize = 16;
+
+#[derive(Debug)]
+pub enum ChannelError {
+    /// The channel is closed by the peer or the session disconnected
+    Closed,
+    Decode(DecodeError),
+}
+
+impl Display for ChannelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ChannelError::Closed => write!(f, "channel closed"),
+            ChannelError::Decode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+// A channel is identified by its id and by which side opened it, as both sides allocate ids
+type Key = (bool, u64);
+
+#[derive(Default)]
+struct State {
+    received: VecDeque<Vec<u8>>,
+    credits: usize,
+    // Received messages consumed and not reported to the peer yet
+    consumed: usize,
+    closed: bool,
+}
+
+struct Slot(Mutex<State>, Condvar);
+
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<Key, Arc<Slot>>,
+    // Channels opened by the peer, waiting to be accepted
+    pending: VecDeque<(String, Key, Arc<Slot>)>,
+    disconnected: bool,
+}
+
+/// Channels multiplexed over a session
+#[derive(Default)]
+pub(crate) struct Channels {
+    registry: Mutex<Registry>,
+    accepted: Condvar,
+    id_counter: AtomicU64,
+}
+
+impl Channels {
+    fn insert(&self, key: Key) -> Arc<Slot> {
+        let slot = Arc::new(Slot(Mutex::new(State { credits: WINDOW, ..Default::default() }), Condvar::new()));
+        self.registry.lock().unwrap().channels.insert(key, slot.clone());
+        slot
+    }
+
+    fn get(&self, key: Key) -> Option<Arc<Slot>> {
+        self.registry.lock().unwrap().channels.get(&key).cloned()
+    }
+
+    /// The peer opens a channel, return the error to answer
+    pub fn handle_open(&self, args: &[u8]) -> Result<(), &'static str> {
+        let (id, name): (u64, String) = rmps::from_read_ref(args).map_err(|_| "Malformed channel")?;
+        let key = (false, id);
+        if self.registry.lock().unwrap().pending.len() >= MAX_PENDING { return Err("Too many pending channels"); }
+        let slot = self.insert(key);
+        self.registry.lock().unwrap().pending.push_back((name, key, slot));
+        self.accepted.notify_all();
+        Ok(())
+    }
+
+    fn accept(&self, name: &str) -> Option<(Key, Arc<Slot>)> {
+        let mut registry = self.registry.lock().unwrap();
+        loop {
+            if let Some(i) = registry.pending.iter().position(|p| p.0 == name) {
+                return registry.pending.remove(i).map(|(_, key, slot)| (key, slot));
+            }
+            if registry.disconnected { return None; }
+            registry = self.accepted.wait(registry).unwrap();
+        }
+    }
+
+    /// Handle the notifies of the channels, return false if `method` is not one of them
+    pub fn handle_notify(&self, method: &str, mut args: &[u8]) -> bool {
+        if ![DATA_METHOD, CREDIT_METHOD, CLOSE_METHOD].contains(&method) { return false; }
+        let (key, slot) = match read_key(&mut args).and_then(|key| self.get(key).map(|slot| (key, slot))) {
+            Some(channel) => channel,
+            None => return true,
+        };
+        let mut state = slot.0.lock().unwrap();
+        match method {
+            // A peer ignoring the window gets its channel closed
+            DATA_METHOD if state.received.len() < WINDOW => state.received.push_back(args.to_vec()),
+            CREDIT_METHOD => state.credits += decode::read_int::<u32, _>(&mut args).unwrap_or(0) as usize,
+            _ => {
+                state.closed = true;
+                self.remove(key);
+            }
+        }
+        slot.1.notify_all();
+        true
+    }
+
+    fn remove(&self, key: Key) {
+        self.registry.lock().unwrap().channels.remove(&key);
+    }
+
+    /// The session disconnected
+    pub fn close_all(&self) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.disconnected = true;
+        for (_, slot) in registry.channels.drain() {
+            slot.0.lock().unwrap().closed = true;
+            slot.1.notify_all();
+        }
+        self.accepted.notify_all();
+    }
+}
+
+fn read_key(args: &mut &[u8]) -> Option<Key> {
+    decode::read_array_len(args).ok()?;
+    let id: u64 = decode::read_int(args).ok()?;
+    let from_opener = decode::read_bool(args).ok()?;
+    // The channel is ours if the peer didn't open it
+    Some((!from_opener, id))
+}
+
+/// Typed bidirectional message channel multiplexed over a session, see [`Session::open_channel`].
+/// A side can send 64 messages ahead of the receiver, then [`Channel::send`] blocks.
+/// The messages are received by the thread handling the session (e.g. [`Session::loop_handle`]),
+/// so don't wait for them on that thread
+pub struct Channel<Tx, Rx> {
+    ss: Arc<Session>,
+    key: Key,
+    slot: Arc<Slot>,
+    _types: PhantomData<fn(Tx) -> Rx>,
+}
+
+impl<Tx: Serialize, Rx: DeserializeOwned> Channel<Tx, Rx> {
+    pub(crate) fn open(ss: &Arc<Session>, name: &str) -> Result<Self, String> {
+        let id = ss.channels.id_counter.fetch_add(1, Ordering::SeqCst);
+        let key = (true, id);
+        // Registered first, the peer may send messages right after answering
+        let slot = ss.channels.insert(key);
+        match ss.request(OPEN_METHOD, (id, name)) {
+            RequestResult::Data(_) => Ok(Channel { ss: ss.clone(), key, slot, _types: PhantomData }),
+            result => {
+                ss.channels.remove(key);
+                Err(match result { RequestResult::Error(e) => e.message, r => format!("{:?}", r) })
+            }
+        }
+    }
+
+    pub(crate) fn accept(ss: &Arc<Session>, name: &str) -> Option<Self> {
+        let (key, slot) = ss.channels.accept(name)?;
+        Some(Channel { ss: ss.clone(), key, slot, _types: PhantomData })
+    }
+
+    /// Send a message, wait while the peer has too many to consume
+    pub fn send(&self, msg: &Tx) -> Result<(), ChannelError> {
+        let mut state = self.slot.0.lock().unwrap();
+        while state.credits == 0 && !state.closed { state = self.slot.1.wait(state).unwrap(); }
+        if state.closed { return Err(ChannelError::Closed); }
+        state.credits -= 1;
+        drop(state);
+        self.ss.notify(DATA_METHOD, (self.key.1, self.key.0, msg));
+        Ok(())
+    }
+
+    /// Receive a message, wait until there is one. The messages received before the peer closed the channel are still returned
+    pub fn recv(&self) -> Result<Rx, ChannelError> {
+        let mut state = self.slot.0.lock().unwrap();
+        let msg = loop {
+            if let Some(msg) = state.received.pop_front() { break msg; }
+            if state.closed { return Err(ChannelError::Closed); }
+            state = self.slot.1.wait(state).unwrap();
+        };
+        state.consumed += 1;
+        let credit = if state.consumed >= WINDOW / 2 && !state.closed {
+            std::mem::replace(&mut state.consumed, 0)
+        } else { 0 };
+        drop(state);
+        if credit > 0 { self.ss.notify(CREDIT_METHOD, (self.key.1, self.key.0, credit as u32)); }
+        rmps::from_read_ref(&msg).map_err(ChannelError::Decode)
+    }
+
+    pub fn is_closed(&self) -> bool { self.slot.0.lock().unwrap().closed }
+}
+
+impl<Tx, Rx> Drop for Channel<Tx, Rx> {
+    fn drop(&mut self) {
+        let closed = std::mem::replace(&mut self.slot.0.lock().unwrap().closed, true);
+        self.ss.channels.remove(self.key);
+        if !closed { self.ss.notify(CLOSE_METHOD, (self.key.1, self.key.0)); }
+    }
+}
@@ -0,0 +1,507 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{Adaptor, Clock, SystemClock, Method, RequestError, RetryPolicy, ServiceType, Session, ToMethod, encode_arg};
+use crate::protocol::MethodBuf;
+use crate::retry;
+
+type Connect = Box<dyn Fn() -> io::Result<Arc<dyn Adaptor>> + Send + Sync>;
+type ConnectTo = Box<dyn Fn(&str) -> io::Result<Arc<dyn Adaptor>> + Send + Sync>;
+
+#[derive(Default)]
+struct Slot {
+    session: Option<Arc<Session>>,
+    in_use: usize,
+    // A thread is connecting it, without the lock of the slots
+    connecting: bool,
+}
+
+// The slots of a pool and the next one to hand out
+type Slots = (Vec<Slot>, usize);
+
+impl Slot {
+    fn connected(&self) -> bool { self.session.as_ref().is_some_and(|ss| ss.adaptor.connected()) }
+}
+
+/// Sessions to the same endpoint, handed out in turn to spread the requests. Each one is received by its own thread,
+/// so they can be used from many threads at once. The dead ones are reconnected when they're next handed out
+pub struct Pool {
+    connect: Connect,
+    factory: Box<dyn Fn() -> ServiceType + Send + Sync>,
+    slots: Mutex<Slots>,
+    released: Condvar,
+    max_in_flight: usize,
+    // The last latencies of the hedged requests of each method
+    latencies: Mutex<HashMap<String, VecDeque<Duration>>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Count of latencies a hedged method keeps, and needs before it's hedged
+const HEDGE_WINDOW: usize = 100;
+const HEDGE_MIN_SAMPLES: usize = 10;
+
+impl Pool {
+    /// A pool of `size` sessions (at least one), connected by `connect` when first needed, e.g. `move || ws::connect(&url)`.
+    /// `factory` makes the service of each one
+    pub fn new<A, E>(size: usize, connect: impl Fn() -> Result<Arc<A>, E> + Send + Sync + 'static, factory: impl Fn() -> ServiceType + Send + Sync + 'static) -> Self
+        where A: Adaptor + 'static, E: std::error::Error + Send + Sync + 'static
+    {
+        Pool {
+            connect: Box::new(move || connect().map(|adaptor| adaptor as Arc<dyn Adaptor>).map_err(io_error)),
+            factory: Box::new(factory),
+            slots: Mutex::new(((0..size.max(1)).map(|_| Slot::default()).collect(), 0)),
+            released: Condvar::new(),
+            max_in_flight: usize::MAX,
+            latencies: Default::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Hand out a session for at most `max` requests at once, [`Pool::get`] waits when all of them have as many
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.max_in_flight = max.max(1);
+        self
+    }
+
+    /// Time the hedged requests with `clock` rather than the system's
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The next session able to take a request, connected if needed. It counts as in flight until the [`Pooled`] is dropped.
+    /// The error is the one of the connection, if none of the sessions could be connected
+    pub fn get(&self) -> io::Result<Pooled<'_>> {
+        let mut guard = self.slots.lock().unwrap();
+        loop {
+            let (next, pooled) = self.next(guard, None);
+            if let Some(pooled) = pooled? { return Ok(pooled); }
+            guard = self.released.wait(next).unwrap();
+        }
+    }
+
+    // The next session able to take a request, other than the one of `skip`. `None` if they're all busy. A slot to connect
+    // is reserved, and connected without the lock, so the other threads aren't held up meanwhile. The lock is given back
+    fn next<'a>(&'a self, mut guard: MutexGuard<'a, Slots>, skip: Option<usize>) -> (MutexGuard<'a, Slots>, io::Result<Option<Pooled<'a>>>) {
+        let (len, first) = (guard.0.len(), guard.1);
+        let mut error = None;
+        for i in (first..first + len).map(|i| i % len) {
+            let slot = &mut guard.0[i];
+            if slot.in_use >= self.max_in_flight || slot.connecting || skip == Some(i) { continue; }
+            slot.in_use += 1;
+            if !slot.connected() {
+                slot.connecting = true;
+                drop(guard);
+                let connected = self.connect();
+                guard = self.slots.lock().unwrap();
+                let slot = &mut guard.0[i];
+                slot.connecting = false;
+                // The threads waiting for a slot may take this one now, or will have to connect it
+                self.released.notify_all();
+                match connected {
+                    Ok(ss) => {
+                        if let Some(dead) = slot.session.replace(ss) { dead.adaptor.close(); }
+                    }
+                    Err(e) => {
+                        slot.in_use -= 1;
+                        error = Some(e);
+                        continue;
+                    }
+                }
+            }
+            guard.1 = i + 1;
+            let session = guard.0[i].session.clone().unwrap();
+            return (guard, Ok(Some(Pooled { pool: self, index: i, session })));
+        }
+        (guard, match error { Some(e) => Err(e), None => Ok(None) })
+    }
+
+    /// Do a request on the next session, see [`Session::call`]. It fails with [`RequestError::Connect`] if none could be
+    /// connected
+    pub fn request<'a, T: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> Result<T, RequestError> {
+        self.get().map_err(connect_error)?.call(method, arg)
+    }
+
+    /// Do a request like [`Pool::request`], and send it again on another session once it waits longer than most requests
+    /// of its method, the 95th percentile of their last latencies. The first successful response is taken, an error only
+    /// once both requests failed. The other request is cancelled (see [`Session::cancel_request`]), but the peer may
+    /// have handled both already, so it's for the idempotent methods. A method is hedged once 10 of its requests were timed,
+    /// and only if another session can take a request at once
+    pub fn hedged_request<'a, T: DeserializeOwned + Send + 'static>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> Result<T, RequestError> {
+        let (method, name) = match method.to_method() {
+            Method::Int(n) => (MethodBuf::Int(n), n.to_string()),
+            Method::Str(s) => (MethodBuf::Str(s.into()), s.into()),
+        };
+        // Encoded once, the same bytes are sent on both sessions
+        let mut args = Vec::new();
+        encode_arg(&arg, &mut args, false);
+        let args = Arc::new(args);
+        let delay = self.hedge_delay(&name);
+        let start = self.clock.now();
+        let (sender, receiver) = mpsc::channel();
+        let first = self.get().map_err(connect_error)?;
+        let mut sent = vec![spawn_tracked(&first, 0, method.clone(), args.clone(), sender.clone())];
+        let mut pooled = vec![first];
+        let early = match delay.map(|delay| receiver.recv_timeout(delay)) {
+            Some(Err(mpsc::RecvTimeoutError::Timeout)) => {
+                if let Some(second) = self.next(self.slots.lock().unwrap(), Some(pooled[0].index)).1.ok().flatten() {
+                    sent.push(spawn_tracked(&second, 1, method, args, sender.clone()));
+                    pooled.push(second);
+                }
+                None
+            }
+            Some(received) => received.ok(),
+            None => None,
+        };
+        // The results end once the requests sent are all done
+        drop(sender);
+        let (mut winner, mut result) = (None, Err(RequestError::Disconnected));
+        for (index, received) in early.into_iter().chain(receiver.iter()) {
+            let ok = received.is_ok();
+            result = received;
+            if ok {
+                winner = Some(index);
+                break;
+            }
+        }
+        for (i, (ss, id)) in sent.iter().enumerate() {
+            if let (true, Some(id)) = (Some(i) != winner, *id.lock().unwrap()) { ss.cancel_request(id); }
+        }
+        let mut latencies = self.latencies.lock().unwrap();
+        let latencies = latencies.entry(name).or_default();
+        if latencies.len() >= HEDGE_WINDOW { latencies.pop_front(); }
+        latencies.push_back(self.clock.now().saturating_duration_since(start));
+        result
+    }
+
+    // The 95th percentile of the latencies of `method`, once there are enough
+    fn hedge_delay(&self, method: &str) -> Option<Duration> {
+        let latencies = self.latencies.lock().unwrap();
+        let mut latencies: Vec<_> = latencies.get(method).filter(|l| l.len() >= HEDGE_MIN_SAMPLES)?.iter().copied().collect();
+        latencies.sort();
+        Some(latencies[(latencies.len() * 95 / 100).min(latencies.len() - 1)])
+    }
+
+    /// The count of sessions connected
+    pub fn connected(&self) -> usize {
+        self.slots.lock().unwrap().0.iter().filter(|slot| slot.connected()).count()
+    }
+
+    /// Close all the sessions, the pool connects again when used
+    pub fn close(&self) {
+        for slot in &mut self.slots.lock().unwrap().0 {
+            if let Some(ss) = slot.session.take() { ss.adaptor.close(); }
+        }
+    }
+
+    fn connect(&self) -> io::Result<Arc<Session>> {
+        Ok(start(Session::new((self.connect)()?, (self.factory)())))
+    }
+}
+
+fn io_error(e: impl std::error::Error + Send + Sync + 'static) -> io::Error { io::Error::other(e) }
+
+fn connect_error(e: io::Error) -> RequestError { RequestError::Connect(e.to_string()) }
+
+// Receive the packets of a client session on its own thread
+fn start(ss: Session) -> Arc<Session> {
+    let ss = Arc::new(ss);
+    let receiver = ss.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    ss
+}
+
+// Do the request `index` of a hedged request on its own thread, which sends the result with the index. Return the
+// session and where the id of the request is put once sent, to cancel it
+fn spawn_tracked<T: DeserializeOwned + Send + 'static>(pooled: &Pooled, index: usize, method: MethodBuf, args: Arc<Vec<u8>>,
+                                                        results: mpsc::Sender<(usize, Result<T, RequestError>)>) -> (Arc<Session>, Arc<Mutex<Option<u64>>>) {
+    let (ss, id) = (pooled.session.clone(), Arc::new(Mutex::new(None)));
+    let (session, sent) = (ss.clone(), id.clone());
+    std::thread::spawn(move || {
+        let result = session.call_tracked(method.as_method(), &args, |id| *sent.lock().unwrap() = Some(id));
+        let _ = results.send((index, result));
+    });
+    (ss, id)
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) { self.close(); }
+}
+
+/// A session handed out by a [`Pool`]
+pub struct Pooled<'a> {
+    pool: &'a Pool,
+    index: usize,
+    session: Arc<Session>,
+}
+
+impl Deref for Pooled<'_> {
+    type Target = Arc<Session>;
+
+    fn deref(&self) -> &Arc<Session> { &self.session }
+}
+
+impl Drop for Pooled<'_> {
+    fn drop(&mut self) {
+        self.pool.slots.lock().unwrap().0[self.index].in_use -= 1;
+        self.pool.released.notify_one();
+    }
+}
+
+struct Endpoint {
+    address: String,
+    session: Option<Arc<Session>>,
+    failed: Option<Instant>,
+}
+
+impl Endpoint {
+    fn new(address: String) -> Self { Endpoint { address, session: None, failed: None } }
+
+    fn connected(&self) -> Option<&Arc<Session>> { self.session.as_ref().filter(|ss| ss.adaptor.connected()) }
+
+    // Whether it failed to connect less than `delay` before `now`
+    fn skipped(&self, delay: Duration, now: Instant) -> bool {
+        self.connected().is_none() && self.failed.is_some_and(|failed| now.saturating_duration_since(failed) < delay)
+    }
+}
+
+/// The requests of a route of a [`Failover`], see [`CanaryReport`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RouteStats {
+    pub requests: u64,
+    /// The requests which failed, whatever the error
+    pub errors: u64,
+    /// Total time of the requests
+    pub latency: Duration,
+}
+
+impl RouteStats {
+    pub fn error_rate(&self) -> f64 { if self.requests == 0 { 0.0 } else { self.errors as f64 / self.requests as f64 } }
+
+    pub fn mean_latency(&self) -> Duration {
+        if self.requests == 0 { Duration::from_secs(0) } else { self.latency / self.requests.min(u32::MAX as u64) as u32 }
+    }
+
+    fn record<T>(&mut self, result: &Result<T, RequestError>, latency: Duration) {
+        self.requests += 1;
+        self.errors += result.is_err() as u64;
+        self.latency += latency;
+    }
+}
+
+/// How the canary endpoint of a [`Failover`] does compared with the others, see [`Failover::canary`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CanaryReport {
+    pub canary: RouteStats,
+    /// The requests of the other endpoints
+    pub stable: RouteStats,
+}
+
+impl CanaryReport {
+    /// The error rate of the canary minus the one of the other endpoints
+    pub fn error_delta(&self) -> f64 { self.canary.error_rate() - self.stable.error_rate() }
+
+    /// The mean latency of the canary minus the one of the other endpoints, in seconds
+    pub fn latency_delta(&self) -> f64 { self.canary.mean_latency().as_secs_f64() - self.stable.mean_latency().as_secs_f64() }
+}
+
+struct Canary {
+    endpoint: Mutex<Endpoint>,
+    // Part of the requests sent to it, from 0 to 1
+    share: f64,
+    report: Mutex<CanaryReport>,
+}
+
+/// Client of a server replicated on several endpoints. It uses the first one it can connect to and moves to the next
+/// one once it disconnects, or with [`Failover::balance`] it spreads the requests across all the connected ones.
+/// The requests failed by a disconnection aren't retried unless with a [`Failover::retry_policy`], they may have been handled
+pub struct Failover {
+    connect: ConnectTo,
+    factory: Box<dyn Fn() -> ServiceType + Send + Sync>,
+    endpoints: Vec<Mutex<Endpoint>>,
+    // The endpoint used last
+    current: AtomicUsize,
+    balance: bool,
+    retry_delay: Duration,
+    retry_policy: Option<RetryPolicy>,
+    canary: Option<Canary>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Failover {
+    /// `connect` opens a connection to an address, e.g. `|url| ws::connect(url)`. `factory` makes the service of each session
+    pub fn new<A, E>(addresses: impl IntoIterator<Item = impl Into<String>>, connect: impl Fn(&str) -> Result<Arc<A>, E> + Send + Sync + 'static,
+        factory: impl Fn() -> ServiceType + Send + Sync + 'static) -> Self
+        where A: Adaptor + 'static, E: std::error::Error + Send + Sync + 'static
+    {
+        let endpoints = addresses.into_iter().map(|address| Mutex::new(Endpoint::new(address.into()))).collect();
+        Failover {
+            connect: Box::new(move |address| connect(address).map(|adaptor| adaptor as Arc<dyn Adaptor>).map_err(io_error)),
+            factory: Box::new(factory),
+            endpoints,
+            current: AtomicUsize::new(0),
+            balance: false,
+            retry_delay: Duration::from_secs(5),
+            retry_policy: None,
+            canary: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Keep a session to each endpoint and use them in turn
+    pub fn balance(mut self) -> Self {
+        self.balance = true;
+        self
+    }
+
+    /// How long an endpoint which failed to connect is skipped, 5 seconds by default
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Send the failed requests again as `policy` tells, on the next session connected. The attempts carry the same
+    /// idempotency key, so servers keeping an [`IdempotencyCache`](crate::IdempotencyCache) handle them once
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Send `percent` of the requests of [`Failover::request`] to the endpoint `address`, e.g. a new version being rolled out,
+    /// and the others to the endpoints as usual. They go to the others too while it can't be connected.
+    /// [`Failover::canary_report`] compares their errors and latencies
+    pub fn canary(mut self, address: impl Into<String>, percent: f64) -> Self {
+        let share = (percent / 100.0).clamp(0.0, 1.0);
+        self.canary = Some(Canary { endpoint: Mutex::new(Endpoint::new(address.into())), share, report: Default::default() });
+        self
+    }
+
+    /// Measure the retry delays and the latencies of the requests with `clock` rather than the system's
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The requests of the canary endpoint and of the others so far, if there's a [`Failover::canary`]
+    pub fn canary_report(&self) -> Option<CanaryReport> { self.canary.as_ref().map(|canary| *canary.report.lock().unwrap()) }
+
+    /// The session for the next request, connected if needed. The error is the one of the last connection tried
+    pub fn session(&self) -> io::Result<Arc<Session>> {
+        let len = self.endpoints.len();
+        let current = self.current.load(Ordering::SeqCst);
+        let first = if self.balance { current + 1 } else { current };
+        let mut error = io::Error::new(io::ErrorKind::NotConnected, "No endpoint available");
+        for i in (first..first + len).map(|i| i % len) {
+            match self.connect_to(&self.endpoints[i]) {
+                Some(Ok(ss)) => {
+                    self.current.store(i, Ordering::SeqCst);
+                    return Ok(ss);
+                }
+                Some(Err(e)) => error = e,
+                None => {}
+            }
+        }
+        Err(error)
+    }
+
+    // The session of `endpoint`, connected if needed without holding its lock, so the other threads aren't held up
+    // meanwhile. `None` if it's skipped
+    fn connect_to(&self, endpoint: &Mutex<Endpoint>) -> Option<io::Result<Arc<Session>>> {
+        let address = {
+            let endpoint = endpoint.lock().unwrap();
+            if let Some(ss) = endpoint.connected() { return Some(Ok(ss.clone())); }
+            if endpoint.skipped(self.retry_delay, self.clock.now()) { return None; }
+            endpoint.address.clone()
+        };
+        let connected = (self.connect)(&address);
+        let mut endpoint = endpoint.lock().unwrap();
+        match connected {
+            Ok(adaptor) => {
+                // Another thread connected it meanwhile
+                if let Some(ss) = endpoint.connected() {
+                    adaptor.close();
+                    return Some(Ok(ss.clone()));
+                }
+                let ss = start(Session::new(adaptor, (self.factory)()));
+                // To send the idempotency keys
+                if self.retry_policy.is_some() { ss.negotiate_metadata(); }
+                endpoint.session = Some(ss.clone());
+                endpoint.failed = None;
+                Some(Ok(ss))
+            }
+            Err(e) => {
+                endpoint.failed = Some(self.clock.now());
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Do a request on the session of [`Failover::session`], or on the canary for its part of them, see [`Session::call`]
+    pub fn request<'a, T: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> Result<T, RequestError> {
+        let method = method.to_method();
+        let canary = self.canary.as_ref().is_some_and(|canary| rand::thread_rng().gen_bool(canary.share));
+        let policy = match &self.retry_policy {
+            Some(policy) => policy,
+            None => return self.attempt(method, &arg, None, canary),
+        };
+        let key = retry::new_key();
+        let mut attempt = 1;
+        loop {
+            let result = self.attempt(method, &arg, Some(&key), canary);
+            match &result {
+                Err(e) if attempt < policy.max_attempts && policy.retries_error(e) => {}
+                _ => return result,
+            }
+            self.clock.sleep(policy.delay(attempt));
+            attempt += 1;
+        }
+    }
+
+    // A request on the canary if `canary` and it can be connected, otherwise on the session of `Failover::session`
+    fn attempt<T: DeserializeOwned>(&self, method: Method, arg: &impl Serialize, key: Option<&str>, canary: bool) -> Result<T, RequestError> {
+        let on_canary = self.canary.as_ref().filter(|_| canary).and_then(|canary| self.connect_to(&canary.endpoint)?.ok());
+        let (ss, is_canary) = match on_canary {
+            Some(ss) => (ss, true),
+            None => (self.session().map_err(connect_error)?, false),
+        };
+        let start = self.clock.now();
+        let result = match key { Some(key) => ss.call_keyed(method, arg, key), None => ss.call(method, arg) };
+        if let Some(canary) = &self.canary {
+            let mut report = canary.report.lock().unwrap();
+            let route = if is_canary { &mut report.canary } else { &mut report.stable };
+            route.record(&result, self.clock.now().saturating_duration_since(start));
+        }
+        result
+    }
+
+    /// The address of the endpoint used last, if it's still connected
+    pub fn endpoint(&self) -> Option<String> {
+        let endpoint = self.endpoints.get(self.current.load(Ordering::SeqCst))?.lock().unwrap();
+        endpoint.connected().map(|_| endpoint.address.clone())
+    }
+
+    /// The addresses of the endpoints connected
+    pub fn connected(&self) -> Vec<String> {
+        self.endpoints.iter().map(|e| e.lock().unwrap()).filter(|e| e.connected().is_some()).map(|e| e.address.clone()).collect()
+    }
+
+    pub fn close(&self) {
+        for endpoint in &self.endpoints {
+            if let Some(ss) = endpoint.lock().unwrap().session.take() { ss.adaptor.close(); }
+        }
+        if let Some(ss) = self.canary.as_ref().and_then(|canary| canary.endpoint.lock().unwrap().session.take()) { ss.adaptor.close(); }
+    }
+}
+
+impl Drop for Failover {
+    fn drop(&mut self) { self.close(); }
+}
@@ -0,0 +1,54 @@
+
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+/// Source of the time measured by the sessions and utilities (timeouts, delays, ages of requests),
+/// see [`Session::set_clock`](crate::Session::set_clock)
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Block the current thread for `duration` of this clock
+    fn sleep(&self, duration: Duration);
+}
+
+/// The time of the system, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+
+    fn sleep(&self, duration: Duration) { std::thread::sleep(duration) }
+}
+
+/// Clock which only moves with [`MockClock::advance`], so the tests of time dependent behaviour are deterministic.
+/// The threads sleeping on it wake up when it's advanced past their deadline
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    advanced: Condvar,
+}
+
+impl MockClock {
+    pub fn new() -> Arc<MockClock> {
+        Arc::new(MockClock { start: Instant::now(), elapsed: Mutex::new(Duration::default()), advanced: Condvar::new() })
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+        self.advanced.notify_all();
+    }
+
+    /// The time advanced since its creation
+    pub fn elapsed(&self) -> Duration { *self.elapsed.lock().unwrap() }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant { self.start + self.elapsed() }
+
+    fn sleep(&self, duration: Duration) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        let deadline = *elapsed + duration;
+        while *elapsed < deadline { elapsed = self.advanced.wait(elapsed).unwrap(); }
+    }
+}
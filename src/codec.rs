@@ -0,0 +1,169 @@
+//! Serialization of the values of a session: the arguments, the results and the data of the errors,
+//! see [`Session::set_codec`](crate::Session::set_codec).
+//!
+//! A value of a codec other than msgpack is sent in a msgpack extension of the type of the codec, so the envelopes
+//! of the packets stay msgpack. A session only accepts msgpack and the values of its own codec, which it converts to
+//! msgpack before anything reads them, so the decode limits and the schemas apply to them like to msgpack values.
+//! The built-in codecs serialize the values once, straight to their format, the other ones convert them from msgpack
+
+use serde::Serialize;
+
+use crate::encode_arg;
+
+/// Type of the extension holding a CBOR value
+const CBOR: i8 = 0x43;
+/// Type of the extension holding a JSON text
+const JSON: i8 = 0x4a;
+
+/// A value to encode, of any serializable type, see [`Codec::encode`]
+pub trait Encode {
+    /// Append the value in msgpack, the way the session sends it (see [`Session::set_canonical`](crate::Session::set_canonical))
+    fn msgpack(&self, w: &mut Vec<u8>);
+
+    /// The value in CBOR
+    #[cfg(feature = "cbor")]
+    fn cbor(&self) -> Result<Vec<u8>, String>;
+
+    /// The value in JSON
+    #[cfg(feature = "json")]
+    fn json(&self) -> Result<Vec<u8>, String>;
+
+    /// The value in bincode
+    #[cfg(feature = "bincode")]
+    fn bincode(&self) -> Result<Vec<u8>, String>;
+}
+
+/// A serializable value to encode, e.g. with [`append`]
+pub struct Serde<'a, T> {
+    val: &'a T,
+    canonical: bool,
+}
+
+impl<'a, T> Serde<'a, T> {
+    pub fn new(val: &'a T) -> Self { Serde { val, canonical: false } }
+
+    pub(crate) fn canonical(val: &'a T, canonical: bool) -> Self { Serde { val, canonical } }
+}
+
+impl<T: Serialize> Encode for Serde<'_, T> {
+    fn msgpack(&self, w: &mut Vec<u8>) { encode_arg(self.val, w, self.canonical) }
+
+    #[cfg(feature = "cbor")]
+    fn cbor(&self) -> Result<Vec<u8>, String> { serde_cbor::to_vec(self.val).map_err(|e| e.to_string()) }
+
+    #[cfg(feature = "json")]
+    fn json(&self) -> Result<Vec<u8>, String> { serde_json::to_vec(self.val).map_err(|e| e.to_string()) }
+
+    #[cfg(feature = "bincode")]
+    fn bincode(&self) -> Result<Vec<u8>, String> { bincode::serialize(self.val).map_err(|e| e.to_string()) }
+}
+
+/// A serialization of the values: [`Msgpack`] (the default), `Cbor` (feature `cbor`), `Json` (feature `json`),
+/// `Bincode` (feature `bincode`) or one of your own converting the values from and to msgpack
+pub trait Codec: Send + Sync {
+    /// Type of the msgpack extension holding the values, `None` for msgpack itself
+    fn tag(&self) -> Option<i8>;
+
+    /// Append `val` in the format of the codec, the error tells why it can't be
+    fn encode(&self, val: &dyn Encode, w: &mut Vec<u8>) -> Result<(), String>;
+
+    /// Convert a value of the codec to msgpack, the error tells why it can't be
+    fn to_msgpack(&self, data: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// Msgpack, the values as they are in the packets
+pub struct Msgpack;
+
+impl Codec for Msgpack {
+    fn tag(&self) -> Option<i8> { None }
+
+    fn encode(&self, val: &dyn Encode, w: &mut Vec<u8>) -> Result<(), String> {
+        val.msgpack(w);
+        Ok(())
+    }
+
+    fn to_msgpack(&self, data: &[u8]) -> Result<Vec<u8>, String> { Ok(data.to_vec()) }
+}
+
+/// CBOR (RFC 8949) with `serde_cbor`
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn tag(&self) -> Option<i8> { Some(CBOR) }
+
+    fn encode(&self, val: &dyn Encode, w: &mut Vec<u8>) -> Result<(), String> {
+        w.extend_from_slice(&val.cbor()?);
+        Ok(())
+    }
+
+    fn to_msgpack(&self, data: &[u8]) -> Result<Vec<u8>, String> { crate::cbor::decode(data) }
+}
+
+/// JSON text with `serde_json`, so binaries are arrays of bytes
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn tag(&self) -> Option<i8> { Some(JSON) }
+
+    fn encode(&self, val: &dyn Encode, w: &mut Vec<u8>) -> Result<(), String> {
+        w.extend_from_slice(&val.json()?);
+        Ok(())
+    }
+
+    fn to_msgpack(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let json: serde_json::Value = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+        let mut msgpack = Vec::with_capacity(data.len());
+        rmpv::encode::write_value(&mut msgpack, &crate::json::from_json(&json)).map_err(|e| e.to_string())?;
+        Ok(msgpack)
+    }
+}
+
+/// Bincode, switched to by [`Session::negotiate_bincode`](crate::Session::negotiate_bincode). It's not
+/// self-describing: the values are decoded straight into their types, so they have no msgpack form, and the values
+/// the session reads itself stay in msgpack
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn tag(&self) -> Option<i8> { Some(crate::native::EXT_TYPE) }
+
+    fn encode(&self, val: &dyn Encode, w: &mut Vec<u8>) -> Result<(), String> {
+        w.extend_from_slice(&val.bincode()?);
+        Ok(())
+    }
+
+    fn to_msgpack(&self, _data: &[u8]) -> Result<Vec<u8>, String> { Err("Bincode values have no msgpack form".into()) }
+}
+
+/// Append `val` encoded with `codec`, in its extension unless it's msgpack
+pub fn append(codec: &dyn Codec, val: &dyn Encode, w: &mut Vec<u8>) -> Result<(), String> {
+    let tag = match codec.tag() {
+        Some(tag) => tag,
+        None => return codec.encode(val, w),
+    };
+    let mut data = Vec::new();
+    codec.encode(val, &mut data)?;
+    rmp::encode::write_ext_meta(w, data.len() as u32, tag).map_err(|e| e.to_string())?;
+    w.extend_from_slice(&data);
+    Ok(())
+}
+
+/// Whether the session can read the values of `codec` itself, only the ones of bincode it can't
+pub(crate) fn is_readable(codec: &dyn Codec) -> bool {
+    codec.tag() != Some(crate::native::EXT_TYPE)
+}
+
+/// Name of the built-in codec of the extension type `tag`, its values are refused by the sessions of another codec
+pub(crate) fn name(tag: i8) -> Option<&'static str> {
+    match tag {
+        CBOR => Some("CBOR"),
+        JSON => Some("JSON"),
+        crate::native::EXT_TYPE => Some("Bincode"),
+        _ => None,
+    }
+}
@@ -0,0 +1,86 @@
+
+use rmp::encode;
+
+use crate::COMPRESSED;
+
+/// Compression algorithm of packets, each one is available with the cargo feature of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionCodec {
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+/// Compression of the sent packets, see [`Session::set_compression`](crate::Session::set_compression)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: CompressionCodec,
+    /// Packets shorter than that are sent as is
+    pub threshold: usize,
+}
+
+/// Method of the request negotiating the compression, handled by the session itself
+pub(crate) const NEGOTIATE_METHOD: &str = "$compression";
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl CompressionCodec {
+    /// The codecs compiled in, in order of preference
+    pub fn supported() -> &'static [CompressionCodec] {
+        const SUPPORTED: &[CompressionCodec] = &[
+            #[cfg(feature = "lz4")] CompressionCodec::Lz4,
+            #[cfg(feature = "zstd")] CompressionCodec::Zstd,
+        ];
+        SUPPORTED
+    }
+
+    pub(crate) fn from_id(id: u64) -> Option<CompressionCodec> {
+        Self::supported().iter().copied().find(|&c| c as u64 == id)
+    }
+}
+
+/// Build the frame `[COMPRESSED, CODEC, DATA: bin]` holding `pack`, `None` if it doesn't get shorter
+pub(crate) fn compress(codec: CompressionCodec, pack: &[u8]) -> Option<Vec<u8>> {
+    let data: Vec<u8> = match codec {
+        #[cfg(feature = "lz4")]
+        CompressionCodec::Lz4 => lz4::block::compress(pack, None, true).ok(),
+        #[cfg(feature = "zstd")]
+        CompressionCodec::Zstd => zstd::stream::encode_all(pack, ZSTD_LEVEL).ok(),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }?;
+    if data.len() + 8 >= pack.len() { return None; }
+
+    let mut frame = Vec::with_capacity(data.len() + 8);
+    encode::write_array_len(&mut frame, 3);
+    encode::write_uint(&mut frame, COMPRESSED as u64);
+    encode::write_uint(&mut frame, codec as u64);
+    encode::write_bin(&mut frame, &data);
+    Some(frame)
+}
+
+/// Decompress the data of a frame, fail if the result would be longer than `max_len`
+#[allow(unused_variables)]
+pub(crate) fn decompress(codec: CompressionCodec, data: &[u8], max_len: usize) -> Result<Vec<u8>, &'static str> {
+    match codec {
+        #[cfg(feature = "lz4")]
+        CompressionCodec::Lz4 => {
+            // The decompressed size is prepended, check it before allocating anything
+            if data.len() < 4 { return Err("compressed data"); }
+            let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            if len > max_len { return Err("decompressed length"); }
+            lz4::block::decompress(data, None).map_err(|_| "compressed data")
+        }
+        #[cfg(feature = "zstd")]
+        CompressionCodec::Zstd => {
+            use std::io::Read;
+            let mut pack = Vec::new();
+            zstd::stream::read::Decoder::new(data).map_err(|_| "compressed data")?
+                .take(max_len as u64 + 1).read_to_end(&mut pack).map_err(|_| "compressed data")?;
+            if pack.len() > max_len { return Err("decompressed length"); }
+            Ok(pack)
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err("compressed data"),
+    }
+}
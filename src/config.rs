@@ -0,0 +1,198 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    Authenticator, Credentials, Identity, ProtocolError, Session, SessionBuilder, SessionObserver, Throttle,
+    TransportError,
+};
+
+/// What the sessions of a [`ServerConfig`] write to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogLevel {
+    #[default]
+    Off,
+    /// The packets rejected and the connections lost
+    Errors,
+    /// Also the sessions connecting and disconnecting
+    Sessions,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "off" => Ok(LogLevel::Off),
+            "errors" => Ok(LogLevel::Errors),
+            "sessions" => Ok(LogLevel::Sessions),
+            _ => Err(format!("unknown log level `{}`, expected off, errors or sessions", s)),
+        }
+    }
+}
+
+/// Deployment settings of a server, see [`Server::from_config`](crate::Server::from_config). A file has a `key = value`
+/// per line, the values may be quoted and the lines starting with `#` are comments:
+///
+/// ```text
+/// # Repeat the key, or separate the addresses with commas, to listen on several
+/// listen = 0.0.0.0:9000
+/// max_msg_size = 1048576
+/// max_in_flight = 256
+/// idle_timeout_ms = 300000
+/// handshake_timeout_ms = 10000
+/// max_handshakes_per_ip = 8
+/// harden = true
+/// # The peers must authenticate with one of the tokens, see Session::authenticate
+/// auth_token = "s3cret"
+/// log = errors
+/// ```
+///
+/// `tls_cert` and `tls_key` are the paths of the certificate and of the key of the listeners. The environment variables
+/// `EASYRPC_<KEY>` override the keys of the file, see [`ServerConfig::override_from_env`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub listen: Vec<String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// See [`SessionBuilder::max_payload`]
+    pub max_msg_size: Option<usize>,
+    /// See [`Throttle::max_in_flight`]
+    pub max_in_flight: Option<usize>,
+    /// See [`Server::idle_timeout`](crate::Server::idle_timeout)
+    pub idle_timeout: Option<Duration>,
+    /// See [`HandshakeLimits`](crate::ws::HandshakeLimits)
+    pub handshake_timeout: Option<Duration>,
+    pub max_handshakes_per_ip: Option<usize>,
+    /// See [`SessionBuilder::harden`], the other limits override its settings
+    pub harden: bool,
+    pub auth_tokens: Vec<String>,
+    pub log: LogLevel,
+}
+
+const ENV_PREFIX: &str = "EASYRPC_";
+
+fn invalid(message: String) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, message) }
+
+fn number<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("`{}` is not a number", value))
+}
+
+impl ServerConfig {
+    /// Read the settings of the file at `path`
+    pub fn load(path: impl AsRef<Path>) -> io::Result<ServerConfig> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Read the settings of the text of a file
+    pub fn parse(text: &str) -> io::Result<ServerConfig> {
+        let mut config = ServerConfig::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let eq = line.find('=').ok_or_else(|| invalid(format!("line {}: expected `key = value`", n + 1)))?;
+            let (key, value) = (line[..eq].trim(), line[eq + 1..].trim());
+            let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') { &value[1..value.len() - 1] } else { value };
+            config.set(key, value).map_err(|e| invalid(format!("line {}: {}", n + 1, e)))?;
+        }
+        Ok(config)
+    }
+
+    /// Override the settings with the environment variables `EASYRPC_<KEY>`, e.g. `EASYRPC_LISTEN` or
+    /// `EASYRPC_MAX_MSG_SIZE`. The variable of `listen` or `auth_token` replaces the values of the file rather than
+    /// adding to them
+    pub fn override_from_env(self) -> io::Result<ServerConfig> {
+        self.override_from(std::env::vars_os().filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?))))
+    }
+
+    /// Like [`ServerConfig::override_from_env`] with the variables given
+    pub fn override_from(mut self, vars: impl IntoIterator<Item = (String, String)>) -> io::Result<ServerConfig> {
+        for (name, value) in vars {
+            if !name.starts_with(ENV_PREFIX) { continue; }
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+            match key.as_str() {
+                "listen" => self.listen.clear(),
+                "auth_token" => self.auth_tokens.clear(),
+                _ => {}
+            }
+            self.set(&key, value.trim()).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+        }
+        Ok(self)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "listen" => self.listen.extend(value.split(',').map(str::trim).filter(|a| !a.is_empty()).map(String::from)),
+            "tls_cert" => self.tls_cert = Some(value.into()),
+            "tls_key" => self.tls_key = Some(value.into()),
+            "max_msg_size" => self.max_msg_size = Some(number(value)?),
+            "max_in_flight" => self.max_in_flight = Some(number(value)?),
+            "idle_timeout_ms" => self.idle_timeout = Some(Duration::from_millis(number(value)?)),
+            "handshake_timeout_ms" => self.handshake_timeout = Some(Duration::from_millis(number(value)?)),
+            "max_handshakes_per_ip" => self.max_handshakes_per_ip = Some(number(value)?),
+            "harden" => self.harden = value.parse().map_err(|_| format!("`{}` is not true or false", value))?,
+            "auth_token" => self.auth_tokens.push(value.to_string()),
+            "log" => self.log = value.parse()?,
+            _ => return Err(format!("unknown key `{}`", key)),
+        }
+        Ok(())
+    }
+
+    /// Give the options of the sessions to `builder`
+    pub fn configure(&self, mut builder: SessionBuilder) -> SessionBuilder {
+        if self.harden { builder = builder.harden(); }
+        if let Some(max) = self.max_msg_size { builder = builder.max_payload(max); }
+        if let Some(max) = self.max_in_flight { builder = builder.throttle(Throttle::default().max_in_flight(max)); }
+        if !self.auth_tokens.is_empty() { builder = builder.authenticator(Arc::new(Tokens(self.auth_tokens.clone()))); }
+        builder
+    }
+
+    /// The observer writing to stderr what `log` tells, `None` if it's off
+    pub fn observer(&self) -> Option<Arc<dyn SessionObserver>> {
+        match self.log {
+            LogLevel::Off => None,
+            level => Some(Arc::new(Log(level))),
+        }
+    }
+}
+
+/// Accept the peers presenting one of the tokens
+struct Tokens(Vec<String>);
+
+impl Authenticator for Tokens {
+    fn authenticate(&self, _ss: &Session, credentials: &Credentials, _challenge: &[u8]) -> Result<Identity, String> {
+        match credentials {
+            Credentials::Token(token) if self.0.contains(token) => Ok(Identity::default()),
+            _ => Err("Invalid credentials".into()),
+        }
+    }
+}
+
+struct Log(LogLevel);
+
+impl Log {
+    fn peer(ss: &Session) -> String { ss.peer_addr().map_or_else(|| "?".into(), |addr| addr.to_string()) }
+}
+
+impl SessionObserver for Log {
+    fn on_connect(&self, ss: &Session) {
+        if self.0 == LogLevel::Sessions { eprintln!("easy-rpc: {} connected", Self::peer(ss)); }
+    }
+
+    fn on_disconnect(&self, ss: &Session, error: Option<TransportError>) {
+        match error {
+            Some(error) => eprintln!("easy-rpc: {} lost: {:?}", Self::peer(ss), error),
+            None if self.0 == LogLevel::Sessions => eprintln!("easy-rpc: {} disconnected", Self::peer(ss)),
+            None => {}
+        }
+    }
+
+    fn on_error(&self, ss: &Session, error: &ProtocolError) {
+        eprintln!("easy-rpc: {} sent a rejected packet: {:?}", Self::peer(ss), error);
+    }
+}
@@ -0,0 +1,131 @@
+
+use std::cell::RefCell;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rmpv::Value;
+
+use crate::Method;
+
+/// Method of the request asking whether the peer reads the timeouts of the requests, handled by the session itself
+pub(crate) const NEGOTIATE_METHOD: &str = "$deadline";
+/// Method of the request asking whether the peer reads the metadata of the requests, handled by the session itself
+pub(crate) const METADATA_METHOD: &str = "$metadata";
+/// Key of the trace id in the metadata of a request
+pub(crate) const TRACE_KEY: &str = "trace";
+/// Key of the id of the client span in the metadata of a request, see [`SpanSink`](crate::SpanSink)
+pub(crate) const SPAN_KEY: &str = "span";
+/// Key of the idempotency key in the metadata of a request, see [`RetryPolicy`](crate::RetryPolicy)
+pub(crate) const IDEMPOTENCY_KEY: &str = "idempotency";
+
+thread_local! {
+    static CURRENT: RefCell<Option<RequestContext>> = const { RefCell::new(None) };
+}
+
+/// What identifies the request being handled on this thread, to correlate the logs of the work it starts.
+/// The session sets it around [`Service::handle`](crate::Service::handle), and it follows the work spawned with
+/// [`spawn`] or wrapped with [`RequestContext::wrap`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestContext {
+    /// The id of the request, `None` for a notify
+    pub request_id: Option<u64>,
+    pub method: String,
+    /// When the result isn't useful anymore, set by the service or a middleware
+    pub deadline: Option<Instant>,
+    /// Id of the whole operation across the services, set by the service or a middleware, or received with the
+    /// request (see [`Session::negotiate_metadata`](crate::Session::negotiate_metadata))
+    pub trace_id: Option<String>,
+    /// Id of the span of the request, when the session reports them (see
+    /// [`Session::set_span_sink`](crate::Session::set_span_sink)). The requests made while handling it are its children
+    pub span_id: Option<String>,
+}
+
+/// Restore the context replaced by a scope, even if it panics
+struct Restore(Option<RequestContext>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+impl RequestContext {
+    pub(crate) fn new(request_id: Option<u64>, method: Method) -> Self {
+        let method = method.to_string();
+        RequestContext { request_id, method, deadline: None, trace_id: None, span_id: None }
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_trace_id(mut self, trace_id: impl Into<String>) -> Self {
+        self.trace_id = Some(trace_id.into());
+        self
+    }
+
+    /// The context of this thread, `None` outside of any request
+    pub fn current() -> Option<RequestContext> {
+        CURRENT.with(|c| c.borrow().clone())
+    }
+
+    /// The time left before the deadline, zero once passed, `None` without deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Run `f` with this context as the current one, the previous one is restored after
+    pub fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        let previous = CURRENT.with(|c| c.borrow_mut().replace(self));
+        let _restore = Restore(previous);
+        f()
+    }
+
+    /// Capture the current context so `f` runs with it wherever it is called, e.g. when a job is handed to an executor
+    pub fn wrap<R>(f: impl FnOnce() -> R) -> impl FnOnce() -> R {
+        let context = Self::current();
+        move || match context {
+            Some(context) => context.scope(f),
+            None => f(),
+        }
+    }
+}
+
+/// The metadata carried by a request
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Metadata {
+    pub trace_id: Option<String>,
+    /// The client span of the request
+    pub span_id: Option<String>,
+    /// The same for all the attempts of a request
+    pub idempotency_key: Option<String>,
+}
+
+impl Metadata {
+    /// Read the metadata of a request, ignoring the unknown keys
+    pub fn read(metadata: &Value) -> Option<Metadata> {
+        let map = metadata.as_map()?;
+        let get = |name| map.iter().find(|(key, _)| key.as_str() == Some(name)).and_then(|(_, value)| value.as_str()).map(String::from);
+        Some(Metadata { trace_id: get(TRACE_KEY), span_id: get(SPAN_KEY), idempotency_key: get(IDEMPOTENCY_KEY) })
+    }
+
+    pub fn is_empty(&self) -> bool { self.trace_id.is_none() && self.span_id.is_none() && self.idempotency_key.is_none() }
+
+    pub fn write(&self, w: &mut Vec<u8>) {
+        let entries = [(TRACE_KEY, &self.trace_id), (SPAN_KEY, &self.span_id), (IDEMPOTENCY_KEY, &self.idempotency_key)];
+        rmp::encode::write_map_len(w, entries.iter().filter(|(_, value)| value.is_some()).count() as u32);
+        for (key, value) in entries.iter() {
+            if let Some(value) = value {
+                rmp::encode::write_str(w, key);
+                rmp::encode::write_str(w, value);
+            }
+        }
+    }
+}
+
+/// [`std::thread::spawn`] keeping the current context for the new thread
+pub fn spawn<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> JoinHandle<T> {
+    std::thread::spawn(RequestContext::wrap(f))
+}
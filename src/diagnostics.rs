@@ -0,0 +1,53 @@
+
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
+
+/// State of a session at some instant, to debug a hang without a debugger, see [`Session::diagnostics`](crate::Session::diagnostics).
+/// It serializes as a map whose durations are in milliseconds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub connected: bool,
+    /// The thread receiving the packets and since when it waits for one, `None` if no thread is receiving
+    pub receiver: Option<(String, Duration)>,
+    /// The requests waiting for their response, the oldest first
+    pub pending: Vec<PendingRequest>,
+    /// The count of packets in each lane of the send queue, from low to high priority, `None` without send queue
+    pub queued: Option<[usize; 3]>,
+    /// The time since the last frame was sent, `None` if none was
+    pub since_sent: Option<Duration>,
+    pub since_received: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRequest {
+    pub id: u64,
+    pub method: String,
+    pub elapsed: Duration,
+}
+
+fn millis(d: &Duration) -> u64 { d.as_millis() as u64 }
+
+impl Serialize for Diagnostics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(6))?;
+        map.serialize_entry("connected", &self.connected)?;
+        map.serialize_entry("receiver", &self.receiver.as_ref().map(|(thread, since)| (thread, millis(since))))?;
+        map.serialize_entry("pending", &self.pending)?;
+        map.serialize_entry("queued", &self.queued)?;
+        map.serialize_entry("since_sent", &self.since_sent.as_ref().map(millis))?;
+        map.serialize_entry("since_received", &self.since_received.as_ref().map(millis))?;
+        map.end()
+    }
+}
+
+impl Serialize for PendingRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("id", &self.id)?;
+        map.serialize_entry("method", &self.method)?;
+        map.serialize_entry("elapsed", &millis(&self.elapsed))?;
+        map.end()
+    }
+}
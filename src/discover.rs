@@ -0,0 +1,248 @@
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const TTL: u32 = 120;
+
+/// A server found by [`browse`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// The name it was advertised with
+    pub instance: String,
+    pub addr: SocketAddr,
+}
+
+/// A server advertised on the LAN, until it's dropped or closed
+pub struct Advertisement {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Advertisement {
+    pub fn close(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() { let _ = thread.join(); }
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) { self.close(); }
+}
+
+/// Answer the browsing of `service` (e.g. `"my-service"`, as `_my-service._tcp.local`) with `instance`, a server
+/// listening on `port` of this host. The peers get the address the answers come from
+pub fn advertise(service: &str, instance: &str, port: u16) -> io::Result<Advertisement> {
+    let service = service_name(service)?;
+    let host = format!("{}.local", label(instance)?);
+    let instance = format!("{}.{}", instance, service);
+
+    let builder = net2::UdpBuilder::new_v4()?;
+    builder.reuse_address(true)?;
+    #[cfg(unix)] net2::unix::UnixUdpBuilderExt::reuse_port(&builder, true)?;
+    let socket = builder.bind((Ipv4Addr::UNSPECIFIED, MDNS_PORT))?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+    let thread = std::thread::spawn(move || {
+        let mut buf = [0u8; 9000];
+        while !stop.load(Ordering::SeqCst) {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(_) => break,
+            };
+            let id = match parse_query(&buf[..len], &service) { Some(id) => id, None => continue };
+            let answer = encode_answer(id, &service, &instance, &host, port);
+            // Legacy queriers sending from another port only read unicast answers
+            let to = if from.port() == MDNS_PORT { SocketAddr::from((MDNS_GROUP, MDNS_PORT)) } else { from };
+            let _ = socket.send_to(&answer, to);
+        }
+    });
+    Ok(Advertisement { stopped, thread: Some(thread) })
+}
+
+/// The servers of `service` answering on the LAN within `timeout`, see [`advertise`]
+pub fn browse(service: &str, timeout: Duration) -> io::Result<Vec<Endpoint>> {
+    let service = service_name(service)?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(&encode_query(&service), (MDNS_GROUP, MDNS_PORT))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut found = Records::default();
+    let mut buf = [0u8; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline { break; }
+        socket.set_read_timeout(Some(deadline - now))?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => { let _ = found.parse(&buf[..len], from.ip()); }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(found.endpoints(&service))
+}
+
+fn invalid(message: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidInput, message) }
+
+fn label(name: &str) -> io::Result<&str> {
+    if name.is_empty() || name.len() > 63 || name.contains('.') { return Err(invalid("Names are 1 to 63 bytes without dot")); }
+    Ok(name)
+}
+
+fn service_name(service: &str) -> io::Result<String> {
+    Ok(format!("_{}._tcp.local", label(service.trim_start_matches('_'))?))
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) { buf.extend_from_slice(&n.to_be_bytes()); }
+
+fn put_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn put_record(buf: &mut Vec<u8>, name: &str, ty: u16, rdata: &[u8]) {
+    put_name(buf, name);
+    put_u16(buf, ty);
+    put_u16(buf, CLASS_IN);
+    buf.extend_from_slice(&TTL.to_be_bytes());
+    put_u16(buf, rdata.len() as u16);
+    buf.extend_from_slice(rdata);
+}
+
+fn encode_query(service: &str) -> Vec<u8> {
+    let mut buf = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    put_name(&mut buf, service);
+    put_u16(&mut buf, TYPE_PTR);
+    put_u16(&mut buf, CLASS_IN);
+    buf
+}
+
+fn encode_answer(id: u16, service: &str, instance: &str, host: &str, port: u16) -> Vec<u8> {
+    let mut buf = id.to_be_bytes().to_vec();
+    buf.extend_from_slice(&[0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0]);
+    let mut ptr = vec![];
+    put_name(&mut ptr, instance);
+    put_record(&mut buf, service, TYPE_PTR, &ptr);
+    let mut srv = vec![0, 0, 0, 0];
+    put_u16(&mut srv, port);
+    put_name(&mut srv, host);
+    put_record(&mut buf, instance, TYPE_SRV, &srv);
+    buf
+}
+
+fn get_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    packet.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// The name at `pos` and the position after it, following the compression pointers
+fn get_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let (mut end, mut jumps) = (None, 0);
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            jumps += 1;
+            if jumps > 16 { return None; }
+            end = end.or(Some(pos + 2));
+            pos = (len & 0x3f) << 8 | *packet.get(pos + 1)? as usize;
+        } else if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        } else {
+            labels.push(String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + len)?).into_owned());
+            pos += 1 + len;
+        }
+    }
+}
+
+/// The id of a query asking for `service`
+fn parse_query(packet: &[u8], service: &str) -> Option<u16> {
+    if get_u16(packet, 2)? & 0x8000 != 0 { return None; }
+    let mut pos = 12;
+    for _ in 0..get_u16(packet, 4)? {
+        let (name, next) = get_name(packet, pos)?;
+        let ty = get_u16(packet, next)?;
+        pos = next + 4;
+        if (ty == TYPE_PTR || ty == TYPE_ANY) && name.eq_ignore_ascii_case(service) { return get_u16(packet, 0); }
+    }
+    None
+}
+
+/// The records of the answers received
+#[derive(Default)]
+struct Records {
+    // Instance names by service, in the order received
+    instances: Vec<(String, String)>,
+    // Host and port by instance name
+    services: HashMap<String, (String, u16)>,
+    // Address by host, the source of the answer when missing
+    hosts: HashMap<String, IpAddr>,
+    sources: HashMap<String, IpAddr>,
+}
+
+impl Records {
+    fn parse(&mut self, packet: &[u8], from: IpAddr) -> Option<()> {
+        if get_u16(packet, 2)? & 0x8000 == 0 { return None; }
+        let mut pos = 12;
+        for _ in 0..get_u16(packet, 4)? {
+            pos = get_name(packet, pos)?.1 + 4;
+        }
+        let records = get_u16(packet, 6)? as usize + get_u16(packet, 8)? as usize + get_u16(packet, 10)? as usize;
+        for _ in 0..records {
+            let (name, next) = get_name(packet, pos)?;
+            let ty = get_u16(packet, next)?;
+            let len = get_u16(packet, next + 8)? as usize;
+            let rdata = next + 10;
+            packet.get(rdata..rdata + len)?;
+            pos = rdata + len;
+            let name = name.to_ascii_lowercase();
+            match ty {
+                TYPE_PTR => {
+                    let instance = get_name(packet, rdata)?.0;
+                    if !self.instances.iter().any(|(_, i)| i.eq_ignore_ascii_case(&instance)) {
+                        self.instances.push((name, instance));
+                    }
+                }
+                TYPE_SRV => {
+                    let host = get_name(packet, rdata + 6)?.0.to_ascii_lowercase();
+                    self.services.insert(name.clone(), (host, get_u16(packet, rdata + 4)?));
+                    self.sources.insert(name, from);
+                }
+                TYPE_A if len == 4 => {
+                    let b = &packet[rdata..rdata + 4];
+                    self.hosts.insert(name, Ipv4Addr::new(b[0], b[1], b[2], b[3]).into());
+                }
+                _ => {}
+            }
+        }
+        Some(())
+    }
+
+    fn endpoints(&self, service: &str) -> Vec<Endpoint> {
+        self.instances.iter().filter(|(s, _)| s.eq_ignore_ascii_case(service)).filter_map(|(_, instance)| {
+            let key = instance.to_ascii_lowercase();
+            let (host, port) = self.services.get(&key)?;
+            let ip = self.hosts.get(host).or_else(|| self.sources.get(&key))?;
+            let name = instance.get(..instance.len().saturating_sub(service.len() + 1)).unwrap_or(instance);
+            Some(Endpoint { instance: name.into(), addr: SocketAddr::new(*ip, *port) })
+        }).collect()
+    }
+}
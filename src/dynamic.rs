@@ -0,0 +1,101 @@
+
+use std::fmt;
+
+use rmpv::Value;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Visitor, SeqAccess, MapAccess};
+use serde::ser::{SerializeSeq, SerializeMap};
+use serde_bytes::{Bytes, ByteBuf};
+
+use crate::ext::Ext;
+
+/// A msgpack value serialized and deserialized as it is, for the calls whose types are known at runtime only
+pub(crate) struct Dynamic(pub Value);
+
+impl Serialize for Dynamic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { serialize(&self.0, serializer) }
+}
+
+struct Ref<'a>(&'a Value);
+
+impl Serialize for Ref<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { serialize(self.0, serializer) }
+}
+
+fn serialize<S: Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Value::Nil => serializer.serialize_unit(),
+        Value::Boolean(b) => serializer.serialize_bool(*b),
+        Value::Integer(n) => match n.as_u64() {
+            Some(n) => serializer.serialize_u64(n),
+            None => serializer.serialize_i64(n.as_i64().unwrap_or_default()),
+        },
+        Value::F32(f) => serializer.serialize_f32(*f),
+        Value::F64(f) => serializer.serialize_f64(*f),
+        // Not valid UTF-8, kept as the bytes
+        Value::String(s) => match s.as_str() {
+            Some(s) => serializer.serialize_str(s),
+            None => serializer.serialize_bytes(s.as_bytes()),
+        },
+        Value::Binary(b) => Bytes::new(b).serialize(serializer),
+        Value::Array(values) => {
+            let mut seq = serializer.serialize_seq(Some(values.len()))?;
+            for v in values { seq.serialize_element(&Ref(v))?; }
+            seq.end()
+        }
+        Value::Map(entries) => {
+            let mut map = serializer.serialize_map(Some(entries.len()))?;
+            for (k, v) in entries { map.serialize_entry(&Ref(k), &Ref(v))?; }
+            map.end()
+        }
+        Value::Ext(tag, data) => Ext { tag: *tag, data: data.clone() }.serialize(serializer),
+    }
+}
+
+impl<'de> Deserialize<'de> for Dynamic {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DynamicVisitor).map(Dynamic)
+    }
+}
+
+struct DynamicVisitor;
+
+impl<'de> Visitor<'de> for DynamicVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("a msgpack value") }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<Value, E> { Ok(Value::from(b)) }
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<Value, E> { Ok(Value::from(n)) }
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<Value, E> { Ok(Value::from(n)) }
+    fn visit_f32<E: de::Error>(self, f: f32) -> Result<Value, E> { Ok(Value::F32(f)) }
+    fn visit_f64<E: de::Error>(self, f: f64) -> Result<Value, E> { Ok(Value::F64(f)) }
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Value, E> { Ok(Value::from(s)) }
+    fn visit_string<E: de::Error>(self, s: String) -> Result<Value, E> { Ok(Value::from(s)) }
+    fn visit_bytes<E: de::Error>(self, b: &[u8]) -> Result<Value, E> { Ok(Value::Binary(b.into())) }
+    fn visit_byte_buf<E: de::Error>(self, b: Vec<u8>) -> Result<Value, E> { Ok(Value::Binary(b)) }
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> { Ok(Value::Nil) }
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> { Ok(Value::Nil) }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    // An extension, as told by rmp-serde
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        let (tag, data): (i8, ByteBuf) = Deserialize::deserialize(deserializer)?;
+        Ok(Value::Ext(tag, data.into_vec()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(0x100));
+        while let Some(Dynamic(v)) = seq.next_element()? { values.push(v); }
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0).min(0x100));
+        while let Some((Dynamic(k), Dynamic(v))) = map.next_entry()? { entries.push((k, v)); }
+        Ok(Value::Map(entries))
+    }
+}
@@ -0,0 +1,196 @@
+
+use std::sync::{Arc, Mutex};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::Duration;
+
+use snow::{Builder, HandshakeState, StatelessTransportState};
+pub use snow::Keypair;
+
+use crate::{Adaptor, RecvError, TransportError};
+
+/// Handshake pattern, both sides authenticate with their static keys
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PLAINTEXT: usize = MAX_MESSAGE - TAG_LEN;
+
+/// Static keys of a side
+#[derive(Debug, Clone)]
+pub struct Keys {
+    pub private: Vec<u8>,
+    /// The public key the peer must have, `None` to accept any peer (then check [`NoiseAdaptor::remote_public`])
+    pub remote_public: Option<Vec<u8>>,
+}
+
+/// Which side starts the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Initiator,
+    Responder,
+}
+
+#[derive(Debug)]
+pub enum NoiseError {
+    Noise(snow::Error),
+    /// The adaptor disconnected during the handshake
+    Disconnect,
+    /// The peer's static key is not the expected one
+    UnknownPeer,
+}
+
+impl Display for NoiseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            NoiseError::Noise(e) => write!(f, "noise: {}", e),
+            NoiseError::Disconnect => write!(f, "disconnected during the handshake"),
+            NoiseError::UnknownPeer => write!(f, "unknown peer key"),
+        }
+    }
+}
+
+impl std::error::Error for NoiseError {}
+
+impl From<snow::Error> for NoiseError {
+    fn from(e: snow::Error) -> Self { NoiseError::Noise(e) }
+}
+
+/// Generate a static key pair
+pub fn generate_keypair() -> Keypair {
+    Builder::new(PATTERN.parse().unwrap()).generate_keypair().expect("generate keypair")
+}
+
+/// Adaptor encrypting the data of another one.
+/// A packet is sent as a single frame of one or more Noise messages, all of them but the last one have the maximum length
+pub struct NoiseAdaptor {
+    inner: Arc<dyn Adaptor>,
+    transport: StatelessTransportState,
+    // Held while sending, so the nonces reach the peer in order
+    send_nonce: Mutex<u64>,
+    recv_nonce: Mutex<u64>,
+    last_error: Mutex<Option<TransportError>>,
+}
+
+impl NoiseAdaptor {
+    /// The static public key of the peer
+    pub fn remote_public(&self) -> &[u8] {
+        self.transport.get_remote_static().unwrap_or_default()
+    }
+
+    fn fail(&self, e: TransportError) -> RecvError {
+        self.last_error.lock().unwrap().get_or_insert(e);
+        self.inner.close();
+        RecvError::Disconnect
+    }
+
+    // Decrypt a frame received
+    fn open(&self, frame: Vec<u8>) -> Result<Vec<u8>, RecvError> {
+        if frame.is_empty() { return Err(self.fail(TransportError::Protocol)); }
+        let mut data = vec![0; frame.len()];
+        let mut nonce = self.recv_nonce.lock().unwrap();
+        let mut read = 0;
+        for message in frame.chunks(MAX_MESSAGE) {
+            match self.transport.read_message(*nonce, message, &mut data[read..]) {
+                Ok(len) => { read += len; *nonce += 1; }
+                // Forged or replayed data, the nonces are out of sync from now on
+                Err(_) => return Err(self.fail(TransportError::Tls)),
+            }
+        }
+        data.truncate(read);
+        Ok(data)
+    }
+}
+
+/// Do the handshake over `adaptor` and return an adaptor encrypting all the following data.
+/// This blocks until the peer takes part in the handshake on its own side
+pub fn wrap(adaptor: Arc<dyn Adaptor>, keys: &Keys, side: Side) -> Result<Arc<NoiseAdaptor>, NoiseError> {
+    let builder = Builder::new(PATTERN.parse().unwrap()).local_private_key(&keys.private);
+    let mut state = match side {
+        Side::Initiator => builder.build_initiator()?,
+        Side::Responder => builder.build_responder()?,
+    };
+    // XX takes 3 messages, the initiator writes the first one
+    let mut writing = side == Side::Initiator;
+    while !state.is_handshake_finished() {
+        if writing { write_handshake(&adaptor, &mut state)?; } else { read_handshake(&adaptor, &mut state)?; }
+        writing = !writing;
+    }
+    if let Some(expected) = &keys.remote_public {
+        if state.get_remote_static() != Some(&expected[..]) {
+            adaptor.close();
+            return Err(NoiseError::UnknownPeer);
+        }
+    }
+    Ok(Arc::new(NoiseAdaptor {
+        inner: adaptor,
+        transport: state.into_stateless_transport_mode()?,
+        send_nonce: Mutex::new(0),
+        recv_nonce: Mutex::new(0),
+        last_error: Mutex::new(None),
+    }))
+}
+
+fn write_handshake(adaptor: &Arc<dyn Adaptor>, state: &mut HandshakeState) -> Result<(), NoiseError> {
+    let mut buf = vec![0; MAX_MESSAGE];
+    let len = state.write_message(&[], &mut buf)?;
+    buf.truncate(len);
+    if adaptor.send(buf) { Ok(()) } else { Err(NoiseError::Disconnect) }
+}
+
+fn read_handshake(adaptor: &Arc<dyn Adaptor>, state: &mut HandshakeState) -> Result<(), NoiseError> {
+    let message = adaptor.recv().map_err(|_| NoiseError::Disconnect)?;
+    let mut buf = vec![0; MAX_MESSAGE];
+    state.read_message(&message, &mut buf)?;
+    Ok(())
+}
+
+impl Adaptor for NoiseAdaptor {
+    fn send(&self, mut data: Vec<u8>) -> bool {
+        self.send_buf(&mut data)
+    }
+
+    // The data is encrypted to another buffer, it stays with the session
+    fn send_buf(&self, data: &mut Vec<u8>) -> bool {
+        let count = data.len() / MAX_PLAINTEXT + 1;
+        let mut frame = vec![0; data.len() + count * TAG_LEN];
+        let mut nonce = self.send_nonce.lock().unwrap();
+        let mut written = 0;
+        // An empty packet still needs a message
+        for chunk in data.chunks(MAX_PLAINTEXT).chain(if data.is_empty() { Some(&[][..]) } else { None }) {
+            match self.transport.write_message(*nonce, chunk, &mut frame[written..]) {
+                Ok(len) => { written += len; *nonce += 1; }
+                Err(_) => return false,
+            }
+        }
+        frame.truncate(written);
+        self.inner.send(frame)
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        self.open(self.inner.recv()?)
+    }
+
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> {
+        Some(self.inner.try_recv()?.and_then(|frame| self.open(frame)))
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> { self.inner.raw_fd() }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> { self.inner.peer_addr() }
+
+    fn transport_kind(&self) -> Option<&'static str> { self.inner.transport_kind() }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> { self.inner.peer_certificate() }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> {
+        self.open(self.inner.recv_timeout(timeout)?)
+    }
+
+    fn connected(&self) -> bool { self.inner.connected() }
+
+    fn close(&self) { self.inner.close() }
+
+    fn last_error(&self) -> Option<TransportError> {
+        self.last_error.lock().unwrap().or_else(|| self.inner.last_error())
+    }
+}
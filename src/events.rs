@@ -0,0 +1,56 @@
+
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use crate::{Identity, ProtocolError, RemoteError, Session, TransportError};
+
+/// What happened to a session, see [`Session::events`](crate::Session::events)
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    /// The session started handling its connection with `loop_handle`
+    Connected,
+    /// The error which broke the connection, `None` if it was closed normally
+    Disconnected(Option<TransportError>),
+    /// The peer presented valid credentials
+    Authenticated(Identity),
+    /// A packet of the peer was rejected
+    ProtocolWarning(ProtocolError),
+    /// The peer answered a request with an error
+    RequestFailed { method: String, error: RemoteError },
+    /// A request of the peer wasn't answered within the `limit` of the [`Watchdog`](crate::Watchdog), which answered it
+    HandlerStalled { request_id: u64, method: String, limit: Duration },
+}
+
+/// Subscribers of the events of a session
+#[derive(Default)]
+pub(crate) struct EventBus(Mutex<Vec<Sender<SessionEvent>>>);
+
+impl EventBus {
+    pub fn subscribe(&self) -> Receiver<SessionEvent> {
+        let (sender, receiver) = channel();
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// The event is only made if there are subscribers, the dropped ones are forgotten
+    pub fn emit(&self, event: impl FnOnce() -> SessionEvent) {
+        let mut subscribers = self.0.lock().unwrap();
+        if subscribers.is_empty() { return; }
+        let event = event();
+        subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+/// Callbacks run on the thread where the lifecycle of a session changes, e.g. to clean up the state of a connection,
+/// see [`Session::set_observer`](crate::Session::set_observer). Unlike the [`SessionEvent`]s they see the session
+pub trait SessionObserver: Send + Sync {
+    /// The session started handling its connection with `loop_handle`
+    fn on_connect(&self, _ss: &Session) {}
+
+    /// The requests waiting for a response were failed already, `error` is `None` if the connection was closed normally
+    fn on_disconnect(&self, _ss: &Session, _error: Option<TransportError>) {}
+
+    /// A packet of the peer was rejected
+    fn on_error(&self, _ss: &Session, _error: &ProtocolError) {}
+}
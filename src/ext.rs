@@ -0,0 +1,395 @@
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rmpv::Value;
+use rmpv::decode::read_value;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Visitor};
+use serde::ser::{SerializeSeq, SerializeTuple, SerializeTupleStruct, SerializeTupleVariant, SerializeMap, SerializeStruct, SerializeStructVariant};
+use serde_bytes::{Bytes, ByteBuf};
+
+use crate::native;
+
+/// Type of the standard timestamp extension
+pub const TIMESTAMP: i8 = -1;
+
+/// Name of the newtype of an extension of a negative type, holding it encoded. The msgpack serializer refuses the
+/// types reserved by the spec, so [`append`] writes it itself
+const RAW_EXT: &str = "_EasyRpcRawExt";
+
+/// A msgpack extension value, the type of its data and the data, e.g. to pass through the ones of another library
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ext {
+    pub tag: i8,
+    pub data: Vec<u8>,
+}
+
+impl Serialize for Ext {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.tag >= 0 {
+            return serializer.serialize_newtype_struct(rmps::MSGPACK_EXT_STRUCT_NAME, &(self.tag, Bytes::new(&self.data)));
+        }
+        let mut raw = Vec::with_capacity(self.data.len() + 6);
+        write_ext(&mut raw, self.tag, &self.data);
+        serializer.serialize_newtype_struct(RAW_EXT, Bytes::new(&raw))
+    }
+}
+
+impl<'de> Deserialize<'de> for Ext {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ExtVisitor;
+
+        impl<'de> Visitor<'de> for ExtVisitor {
+            type Value = Ext;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("a msgpack extension") }
+
+            fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<Ext, D::Error> {
+                let (tag, data): (i8, ByteBuf) = Deserialize::deserialize(deserializer)?;
+                Ok(Ext { tag, data: data.into_vec() })
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(rmps::MSGPACK_EXT_STRUCT_NAME, ExtVisitor)
+    }
+}
+
+/// A time encoded as the standard timestamp extension, in its shortest form, as the msgpack libraries of the other
+/// languages read and write their dates. See [`timestamp`] for the `SystemTime` fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub SystemTime);
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self { Timestamp(time) }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (secs, nanos) = match self.0.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+            // The nanoseconds are always counted forward
+            Err(e) => match e.duration() {
+                before if before.subsec_nanos() == 0 => (-(before.as_secs() as i64), 0),
+                before => (-(before.as_secs() as i64) - 1, 1_000_000_000 - before.subsec_nanos()),
+            },
+        };
+        Ext { tag: TIMESTAMP, data: encode_timestamp(secs, nanos) }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ext = Ext::deserialize(deserializer)?;
+        if ext.tag != TIMESTAMP { return Err(de::Error::custom(format_args!("expected a timestamp, found the extension {}", ext.tag))); }
+        decode_timestamp(&ext.data).map(Timestamp).ok_or_else(|| de::Error::custom("malformed timestamp"))
+    }
+}
+
+/// `SystemTime` fields encoded as timestamps, with `#[serde(with = "easy_rpc::ext::timestamp")]`
+pub mod timestamp {
+    use std::time::SystemTime;
+
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    use super::Timestamp;
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        Timestamp(*time).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        Timestamp::deserialize(deserializer).map(|t| t.0)
+    }
+}
+
+fn encode_timestamp(secs: i64, nanos: u32) -> Vec<u8> {
+    if secs >= 0 && secs >> 34 == 0 {
+        let value = (u64::from(nanos) << 34) | secs as u64;
+        if value >> 32 == 0 { return (value as u32).to_be_bytes().to_vec(); }
+        return value.to_be_bytes().to_vec();
+    }
+    let mut data = nanos.to_be_bytes().to_vec();
+    data.extend_from_slice(&secs.to_be_bytes());
+    data
+}
+
+fn decode_timestamp(data: &[u8]) -> Option<SystemTime> {
+    let (secs, nanos) = match data.len() {
+        4 => (i64::from(u32::from_be_bytes(data.try_into().ok()?)), 0),
+        8 => {
+            let value = u64::from_be_bytes(data.try_into().ok()?);
+            ((value & ((1 << 34) - 1)) as i64, (value >> 34) as u32)
+        }
+        12 => (i64::from_be_bytes(data[4..].try_into().ok()?), u32::from_be_bytes(data[..4].try_into().ok()?)),
+        _ => return None,
+    };
+    if nanos >= 1_000_000_000 { return None; }
+    let nanos = Duration::from_nanos(u64::from(nanos));
+    if secs >= 0 { return UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64) + nanos); }
+    UNIX_EPOCH.checked_sub(Duration::from_secs(secs.wrapping_neg() as u64))?.checked_add(nanos)
+}
+
+/// Converts the data of an extension a peer sends to the value it stands for, or tells why it can't,
+/// see [`Session::set_ext_handler`](crate::Session::set_ext_handler)
+pub type ExtHandler = Arc<dyn Fn(&[u8]) -> Result<Value, String> + Send + Sync>;
+
+/// By the type of their extension
+pub(crate) type ExtHandlers = HashMap<i8, ExtHandler>;
+
+/// Replace the extensions of a msgpack payload with the values of their handlers, `None` if it has none of them
+pub(crate) fn transcode(handlers: &ExtHandlers, msgpack: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    if native::is_encoded(msgpack) { return Ok(None); }
+    let mut value = read_value(&mut &msgpack[..]).map_err(|e| e.to_string())?;
+    if !replace(handlers, &mut value)? { return Ok(None); }
+    let mut buf = Vec::with_capacity(msgpack.len());
+    write_value(&mut buf, &value);
+    Ok(Some(buf))
+}
+
+fn replace(handlers: &ExtHandlers, value: &mut Value) -> Result<bool, String> {
+    let replaced = match value {
+        Value::Ext(tag, data) => match handlers.get(tag) {
+            Some(handler) => handler(data)?,
+            None => return Ok(false),
+        },
+        Value::Array(values) => return values.iter_mut().try_fold(false, |found, v| Ok(replace(handlers, v)? || found)),
+        Value::Map(entries) => return entries.iter_mut().try_fold(false, |found, (k, v)| {
+            let key = replace(handlers, k)?;
+            Ok(replace(handlers, v)? || key || found)
+        }),
+        _ => return Ok(false),
+    };
+    *value = replaced;
+    Ok(true)
+}
+
+/// Like `rmpv::encode::write_value`, which refuses the extensions of negative types too
+pub(crate) fn write_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Ext(tag, data) => write_ext(buf, *tag, data),
+        Value::Array(values) => {
+            rmp::encode::write_array_len(buf, values.len() as u32).expect("write to Vec");
+            for v in values { write_value(buf, v); }
+        }
+        Value::Map(entries) => {
+            rmp::encode::write_map_len(buf, entries.len() as u32).expect("write to Vec");
+            for (k, v) in entries { write_value(buf, k); write_value(buf, v); }
+        }
+        _ => rmpv::encode::write_value(buf, value).expect("write to Vec"),
+    }
+}
+
+fn write_ext(buf: &mut Vec<u8>, tag: i8, data: &[u8]) {
+    match data.len() {
+        1 => buf.push(0xd4),
+        2 => buf.push(0xd5),
+        4 => buf.push(0xd6),
+        8 => buf.push(0xd7),
+        16 => buf.push(0xd8),
+        len if len < 0x100 => buf.extend_from_slice(&[0xc7, len as u8]),
+        len if len < 0x10000 => { buf.push(0xc8); buf.extend_from_slice(&(len as u16).to_be_bytes()); }
+        len => { buf.push(0xc9); buf.extend_from_slice(&(len as u32).to_be_bytes()); }
+    }
+    buf.push(tag as u8);
+    buf.extend_from_slice(data);
+}
+
+/// Serialize `val` in msgpack at the end of `buf`, the extensions of negative types included
+pub(crate) fn append<T: Serialize + ?Sized>(val: &T, buf: &mut Vec<u8>) -> Result<(), rmps::encode::Error> {
+    let out = RefCell::new(std::mem::take(buf));
+    let result = if cfg!(feature = "struct_map") {
+        val.serialize(Hook { ser: &mut rmps::Serializer::new(Shared(&out)).with_struct_map(), out: &out })
+    } else {
+        val.serialize(Hook { ser: &mut rmps::Serializer::new(Shared(&out)), out: &out })
+    };
+    *buf = out.into_inner();
+    result
+}
+
+/// Writer of the msgpack serializer, into the buffer [`Hook`] writes the extensions in
+struct Shared<'a>(&'a RefCell<Vec<u8>>);
+
+impl Write for Shared<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// The msgpack serializer `ser`, and its compounds, which writes the extensions of negative types as they are:
+/// `ser` writes their encoding as a binary, whose header is dropped from `out`
+struct Hook<'a, S> {
+    ser: S,
+    out: &'a RefCell<Vec<u8>>,
+}
+
+/// A value serialized through [`Hook`], for the elements of the compounds
+struct Hooked<'a, T: ?Sized>(&'a T, &'a RefCell<Vec<u8>>);
+
+impl<T: Serialize + ?Sized> Serialize for Hooked<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(Hook { ser: serializer, out: self.1 })
+    }
+}
+
+impl<'a, S: Serializer> Serializer for Hook<'a, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Hook<'a, S::SerializeSeq>;
+    type SerializeTuple = Hook<'a, S::SerializeTuple>;
+    type SerializeTupleStruct = Hook<'a, S::SerializeTupleStruct>;
+    type SerializeTupleVariant = Hook<'a, S::SerializeTupleVariant>;
+    type SerializeMap = Hook<'a, S::SerializeMap>;
+    type SerializeStruct = Hook<'a, S::SerializeStruct>;
+    type SerializeStructVariant = Hook<'a, S::SerializeStructVariant>;
+
+    fn serialize_bool(self, v: bool) -> Result<S::Ok, S::Error> { self.ser.serialize_bool(v) }
+    fn serialize_i8(self, v: i8) -> Result<S::Ok, S::Error> { self.ser.serialize_i8(v) }
+    fn serialize_i16(self, v: i16) -> Result<S::Ok, S::Error> { self.ser.serialize_i16(v) }
+    fn serialize_i32(self, v: i32) -> Result<S::Ok, S::Error> { self.ser.serialize_i32(v) }
+    fn serialize_i64(self, v: i64) -> Result<S::Ok, S::Error> { self.ser.serialize_i64(v) }
+    fn serialize_u8(self, v: u8) -> Result<S::Ok, S::Error> { self.ser.serialize_u8(v) }
+    fn serialize_u16(self, v: u16) -> Result<S::Ok, S::Error> { self.ser.serialize_u16(v) }
+    fn serialize_u32(self, v: u32) -> Result<S::Ok, S::Error> { self.ser.serialize_u32(v) }
+    fn serialize_u64(self, v: u64) -> Result<S::Ok, S::Error> { self.ser.serialize_u64(v) }
+    fn serialize_f32(self, v: f32) -> Result<S::Ok, S::Error> { self.ser.serialize_f32(v) }
+    fn serialize_f64(self, v: f64) -> Result<S::Ok, S::Error> { self.ser.serialize_f64(v) }
+    fn serialize_char(self, v: char) -> Result<S::Ok, S::Error> { self.ser.serialize_char(v) }
+    fn serialize_str(self, v: &str) -> Result<S::Ok, S::Error> { self.ser.serialize_str(v) }
+    fn serialize_bytes(self, v: &[u8]) -> Result<S::Ok, S::Error> { self.ser.serialize_bytes(v) }
+    fn serialize_none(self) -> Result<S::Ok, S::Error> { self.ser.serialize_none() }
+    fn serialize_unit(self) -> Result<S::Ok, S::Error> { self.ser.serialize_unit() }
+    fn serialize_unit_struct(self, name: &'static str) -> Result<S::Ok, S::Error> { self.ser.serialize_unit_struct(name) }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+        self.ser.serialize_some(&Hooked(value, self.out))
+    }
+
+    fn serialize_unit_variant(self, name: &'static str, index: u32, variant: &'static str) -> Result<S::Ok, S::Error> {
+        self.ser.serialize_unit_variant(name, index, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, name: &'static str, value: &T) -> Result<S::Ok, S::Error> {
+        if name != RAW_EXT { return self.ser.serialize_newtype_struct(name, &Hooked(value, self.out)); }
+        let start = self.out.borrow().len();
+        let ok = self.ser.serialize_newtype_struct(name, value)?;
+        let mut out = self.out.borrow_mut();
+        let header = match out.get(start) { Some(0xc4) => 2, Some(0xc5) => 3, _ => 5 };
+        out.drain(start..start + header);
+        Ok(ok)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, name: &'static str, index: u32, variant: &'static str, value: &T) -> Result<S::Ok, S::Error> {
+        self.ser.serialize_newtype_variant(name, index, variant, &Hooked(value, self.out))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        let ser = self.ser.serialize_seq(len)?;
+        Ok(Hook { ser, out: self.out })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+        let ser = self.ser.serialize_tuple(len)?;
+        Ok(Hook { ser, out: self.out })
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, S::Error> {
+        let ser = self.ser.serialize_tuple_struct(name, len)?;
+        Ok(Hook { ser, out: self.out })
+    }
+
+    fn serialize_tuple_variant(self, name: &'static str, index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeTupleVariant, S::Error> {
+        let ser = self.ser.serialize_tuple_variant(name, index, variant, len)?;
+        Ok(Hook { ser, out: self.out })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        let ser = self.ser.serialize_map(len)?;
+        Ok(Hook { ser, out: self.out })
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeStruct, S::Error> {
+        let ser = self.ser.serialize_struct(name, len)?;
+        Ok(Hook { ser, out: self.out })
+    }
+
+    fn serialize_struct_variant(self, name: &'static str, index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeStructVariant, S::Error> {
+        let ser = self.ser.serialize_struct_variant(name, index, variant, len)?;
+        Ok(Hook { ser, out: self.out })
+    }
+
+    fn is_human_readable(&self) -> bool { self.ser.is_human_readable() }
+}
+
+impl<S: SerializeSeq> SerializeSeq for Hook<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> { self.ser.serialize_element(&Hooked(value, self.out)) }
+    fn end(self) -> Result<S::Ok, S::Error> { self.ser.end() }
+}
+
+impl<S: SerializeTuple> SerializeTuple for Hook<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> { self.ser.serialize_element(&Hooked(value, self.out)) }
+    fn end(self) -> Result<S::Ok, S::Error> { self.ser.end() }
+}
+
+impl<S: SerializeTupleStruct> SerializeTupleStruct for Hook<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> { self.ser.serialize_field(&Hooked(value, self.out)) }
+    fn end(self) -> Result<S::Ok, S::Error> { self.ser.end() }
+}
+
+impl<S: SerializeTupleVariant> SerializeTupleVariant for Hook<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> { self.ser.serialize_field(&Hooked(value, self.out)) }
+    fn end(self) -> Result<S::Ok, S::Error> { self.ser.end() }
+}
+
+impl<S: SerializeMap> SerializeMap for Hook<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), S::Error> { self.ser.serialize_key(&Hooked(key, self.out)) }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> { self.ser.serialize_value(&Hooked(value, self.out)) }
+    fn end(self) -> Result<S::Ok, S::Error> { self.ser.end() }
+}
+
+impl<S: SerializeStruct> SerializeStruct for Hook<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), S::Error> {
+        self.ser.serialize_field(key, &Hooked(value, self.out))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> { self.ser.skip_field(key) }
+    fn end(self) -> Result<S::Ok, S::Error> { self.ser.end() }
+}
+
+impl<S: SerializeStructVariant> SerializeStructVariant for Hook<'_, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), S::Error> {
+        self.ser.serialize_field(key, &Hooked(value, self.out))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> { self.ser.skip_field(key) }
+    fn end(self) -> Result<S::Ok, S::Error> { self.ser.end() }
+}
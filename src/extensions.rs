@@ -0,0 +1,36 @@
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Typed storage of per-session state (authenticated user, negotiated options...),
+/// holding at most one value of each type. See [`Session::extensions`](crate::Session::extensions)
+#[derive(Default)]
+pub struct Extensions(RwLock<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>);
+
+impl Extensions {
+    /// Insert a value, return the previous value of the same type
+    pub fn insert<T: Any + Send + Sync>(&self, val: T) -> Option<Arc<T>> {
+        self.0.write().unwrap()
+            .insert(TypeId::of::<T>(), Arc::new(val))
+            .and_then(|v| v.downcast().ok())
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.0.read().unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.clone().downcast().ok())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.0.write().unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.downcast().ok())
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.0.read().unwrap().contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn clear(&self) { self.0.write().unwrap().clear(); }
+}
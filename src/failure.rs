@@ -0,0 +1,354 @@
+
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Display, Formatter};
+use std::ops::Deref;
+
+use rmpv::Value;
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, Visitor};
+
+use crate::{ext, native, DecodeError};
+
+/// Count of bytes of the value found shown in a [`DecodeFailure`]
+const SNIPPET_LEN: usize = 32;
+
+/// Arguments or a result which can't be decoded as the type asked, with where and why, see [`Arg::into`](crate::Arg::into)
+/// and [`RequestResult::decode_failure`](crate::RequestResult::decode_failure). The details are boxed to keep the
+/// results small, they're read through it
+#[derive(Debug)]
+pub struct DecodeFailure(Box<DecodeDetails>);
+
+/// Where and why a value can't be decoded, see [`DecodeFailure`]
+#[derive(Debug)]
+pub struct DecodeDetails {
+    /// The method requested or notified, empty if unknown
+    pub method: String,
+    /// It's the result of a request, not arguments
+    pub result: bool,
+    /// Where the value not decoded is, e.g. `[1].name` for the field `name` of the second argument. The fields of the
+    /// structs sent as arrays are told by their index. Empty for the whole payload
+    pub path: String,
+    /// What the type decoded expected there
+    pub expected: Option<String>,
+    /// The msgpack type found there
+    pub found: Option<&'static str>,
+    /// The first bytes of the value found, in hex
+    pub bytes: String,
+    pub error: DecodeError,
+}
+
+impl Deref for DecodeFailure {
+    type Target = DecodeDetails;
+
+    fn deref(&self) -> &DecodeDetails { &self.0 }
+}
+
+impl Display for DecodeFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Undecodable {}", if self.result { "result" } else { "arguments" })?;
+        if !self.method.is_empty() { write!(f, " of {}", self.method)?; }
+        if !self.path.is_empty() { write!(f, " at {}", self.path)?; }
+        write!(f, ": {}", self.error)?;
+        if let Some(found) = self.found { write!(f, ", found {}", found)?; }
+        write!(f, " ({})", self.bytes)
+    }
+}
+
+impl std::error::Error for DecodeFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { Some(&self.error) }
+}
+
+/// Tell where and why decoding `bytes` as `T` failed with `error`
+pub(crate) fn explain<'a, T: Deserialize<'a>>(bytes: &'a [u8], method: String, result: bool, error: DecodeError) -> DecodeFailure {
+    // Decoded again to find where, in msgpack only: the bincode payloads aren't self-describing
+    let path = if native::is_encoded(bytes) { Vec::new() } else { locate::<T>(bytes) };
+    let value = rmpv::decode::read_value(&mut &bytes[..]).ok();
+    let found = value.as_ref().and_then(|v| find(v, &path));
+    let mut snippet = Vec::new();
+    let dump = match found {
+        Some(found) => {
+            ext::write_value(&mut snippet, found);
+            &snippet[..]
+        }
+        None => bytes,
+    };
+    let message = error.to_string();
+    DecodeFailure(Box::new(DecodeDetails {
+        method,
+        result,
+        path: path.iter().map(Segment::to_string).collect(),
+        expected: message.find("expected ").map(|i| message[i + "expected ".len()..].into()),
+        found: found.map(type_name),
+        bytes: hex(dump),
+        error,
+    }))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = bytes.iter().take(SNIPPET_LEN).map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+    if bytes.len() > SNIPPET_LEN { hex.push_str(" .."); }
+    hex
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Boolean(_) => "bool",
+        Value::Integer(_) => "int",
+        Value::F32(_) => "float32",
+        Value::F64(_) => "float64",
+        Value::String(_) => "str",
+        Value::Binary(_) => "bin",
+        Value::Array(_) => "array",
+        Value::Map(_) => "map",
+        Value::Ext(..) => "ext",
+    }
+}
+
+fn find<'v>(value: &'v Value, path: &[Segment]) -> Option<&'v Value> {
+    path.iter().try_fold(value, |value, segment| match (segment, value) {
+        (Segment::Index(i), Value::Array(items)) => items.get(*i),
+        (Segment::Key(key), Value::Map(entries)) => entries.iter().find(|(k, _)| match k {
+            Value::String(k) => k.as_str() == Some(key),
+            k => k.as_u64().map(|k| k.to_string()).as_ref() == Some(key),
+        }).map(|(_, v)| v),
+        _ => None,
+    })
+}
+
+/// The path where decoding `bytes` as `T` fails
+fn locate<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Vec<Segment> {
+    let state = State::default();
+    let mut de = rmps::Deserializer::from_read_ref(bytes);
+    let _ = T::deserialize(Tracked { de: &mut de, state: &state });
+    state.failed_at.into_inner().unwrap_or_default()
+}
+
+#[derive(Clone)]
+enum Segment {
+    Index(usize),
+    Key(String),
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Segment::Index(i) => write!(f, "[{}]", i),
+            Segment::Key(key) => write!(f, ".{}", key),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    path: RefCell<Vec<Segment>>,
+    // The path of the innermost value which failed, the first one recorded
+    failed_at: RefCell<Option<Vec<Segment>>>,
+    // The key of a map being decoded
+    in_key: Cell<bool>,
+    key: RefCell<Option<String>>,
+}
+
+impl State {
+    fn record<T, E>(&self, result: Result<T, E>) -> Result<T, E> {
+        let mut failed_at = self.failed_at.borrow_mut();
+        if result.is_err() && failed_at.is_none() { *failed_at = Some(self.path.borrow().clone()); }
+        result
+    }
+
+    fn key(&self, key: impl Display) {
+        if self.in_key.get() { *self.key.borrow_mut() = Some(key.to_string()); }
+    }
+
+    fn nested<T, E>(&self, segment: Segment, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        self.path.borrow_mut().push(segment);
+        let result = f();
+        if result.is_ok() { self.path.borrow_mut().pop(); }
+        result
+    }
+}
+
+/// Deserializer keeping track of where it is
+struct Tracked<'s, D> {
+    de: D,
+    state: &'s State,
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {$(
+        fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, D::Error> {
+            let state = self.state;
+            state.record(self.de.$method($($arg,)* Wrapped { visitor, state }))
+        }
+    )*};
+}
+
+impl<'s, 'de, D: Deserializer<'de>> Deserializer<'de> for Tracked<'s, D> {
+    type Error = D::Error;
+
+    forward_deserialize! {
+        deserialize_any(); deserialize_bool(); deserialize_i8(); deserialize_i16(); deserialize_i32(); deserialize_i64();
+        deserialize_u8(); deserialize_u16(); deserialize_u32(); deserialize_u64(); deserialize_f32(); deserialize_f64();
+        deserialize_char(); deserialize_str(); deserialize_string(); deserialize_bytes(); deserialize_byte_buf();
+        deserialize_option(); deserialize_unit(); deserialize_unit_struct(name: &'static str);
+        deserialize_newtype_struct(name: &'static str); deserialize_seq(); deserialize_tuple(len: usize);
+        deserialize_tuple_struct(name: &'static str, len: usize); deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier(); deserialize_ignored_any();
+    }
+
+    fn is_human_readable(&self) -> bool { self.de.is_human_readable() }
+}
+
+/// Visitor keeping track of the nested values
+struct Wrapped<'s, V> {
+    visitor: V,
+    state: &'s State,
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty);)*) => {$(
+        fn $method<E: de::Error>(self, v: $ty) -> Result<V::Value, E> { self.visitor.$method(v) }
+    )*};
+}
+
+// The keys of the maps are strings or integers
+macro_rules! forward_visit_key {
+    ($($method:ident($ty:ty);)*) => {$(
+        fn $method<E: de::Error>(self, v: $ty) -> Result<V::Value, E> {
+            self.state.key(&v);
+            self.visitor.$method(v)
+        }
+    )*};
+}
+
+impl<'s, 'de, V: Visitor<'de>> Visitor<'de> for Wrapped<'s, V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result { self.visitor.expecting(f) }
+
+    forward_visit! {
+        visit_bool(bool); visit_f32(f32); visit_f64(f64); visit_char(char);
+        visit_bytes(&[u8]); visit_borrowed_bytes(&'de [u8]); visit_byte_buf(Vec<u8>);
+    }
+
+    forward_visit_key! {
+        visit_i8(i8); visit_i16(i16); visit_i32(i32); visit_i64(i64); visit_u8(u8); visit_u16(u16); visit_u32(u32); visit_u64(u64);
+        visit_str(&str); visit_borrowed_str(&'de str); visit_string(String);
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<V::Value, E> { self.visitor.visit_none() }
+
+    fn visit_unit<E: de::Error>(self) -> Result<V::Value, E> { self.visitor.visit_unit() }
+
+    fn visit_some<D: Deserializer<'de>>(self, de: D) -> Result<V::Value, D::Error> {
+        self.visitor.visit_some(Tracked { de, state: self.state })
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, de: D) -> Result<V::Value, D::Error> {
+        self.visitor.visit_newtype_struct(Tracked { de, state: self.state })
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<V::Value, A::Error> {
+        self.visitor.visit_seq(Seq { seq, state: self.state, index: 0 })
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<V::Value, A::Error> {
+        self.visitor.visit_map(Map { map, state: self.state })
+    }
+
+    fn visit_enum<A: de::EnumAccess<'de>>(self, data: A) -> Result<V::Value, A::Error> {
+        self.visitor.visit_enum(Enum { data, state: self.state })
+    }
+}
+
+struct Seed<'s, S> {
+    seed: S,
+    state: &'s State,
+}
+
+impl<'s, 'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for Seed<'s, S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, de: D) -> Result<S::Value, D::Error> {
+        self.seed.deserialize(Tracked { de, state: self.state })
+    }
+}
+
+struct Seq<'s, A> {
+    seq: A,
+    state: &'s State,
+    index: usize,
+}
+
+impl<'s, 'de, A: de::SeqAccess<'de>> de::SeqAccess<'de> for Seq<'s, A> {
+    type Error = A::Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, A::Error> {
+        let (seq, state) = (&mut self.seq, self.state);
+        let element = state.nested(Segment::Index(self.index), || seq.next_element_seed(Seed { seed, state }));
+        self.index += 1;
+        element
+    }
+
+    fn size_hint(&self) -> Option<usize> { self.seq.size_hint() }
+}
+
+struct Map<'s, A> {
+    map: A,
+    state: &'s State,
+}
+
+impl<'s, 'de, A: de::MapAccess<'de>> de::MapAccess<'de> for Map<'s, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, A::Error> {
+        let state = self.state;
+        *state.key.borrow_mut() = None;
+        state.in_key.set(true);
+        let key = self.map.next_key_seed(Seed { seed, state });
+        state.in_key.set(false);
+        key
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, A::Error> {
+        let (map, state) = (&mut self.map, self.state);
+        let key = state.key.borrow_mut().take().unwrap_or_else(|| "?".into());
+        state.nested(Segment::Key(key), || map.next_value_seed(Seed { seed, state }))
+    }
+
+    fn size_hint(&self) -> Option<usize> { self.map.size_hint() }
+}
+
+struct Enum<'s, A> {
+    data: A,
+    state: &'s State,
+}
+
+impl<'s, 'de, A: de::EnumAccess<'de>> de::EnumAccess<'de> for Enum<'s, A> {
+    type Error = A::Error;
+    type Variant = Enum<'s, A::Variant>;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Self::Variant), A::Error> {
+        let state = self.state;
+        self.data.variant_seed(Seed { seed, state }).map(|(value, data)| (value, Enum { data, state }))
+    }
+}
+
+impl<'s, 'de, A: de::VariantAccess<'de>> de::VariantAccess<'de> for Enum<'s, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> { self.data.unit_variant() }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, A::Error> {
+        self.data.newtype_variant_seed(Seed { seed, state: self.state })
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        self.data.tuple_variant(len, Wrapped { visitor, state: self.state })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, A::Error> {
+        self.data.struct_variant(fields, Wrapped { visitor, state: self.state })
+    }
+}
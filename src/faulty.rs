@@ -0,0 +1,148 @@
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::{Adaptor, RecvError, TransportError};
+
+/// The faults of a [`wrap`]ped adaptor, none by default. The chances are from 0 to 1, rolled for each frame
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    /// Delay of every frame
+    pub latency: Duration,
+    /// Extra delay of a frame, random up to it, so the frames may overtake each other
+    pub jitter: Duration,
+    /// Chance to lose a frame
+    pub drop: f64,
+    /// Chance to hold a frame back until the next one is received
+    pub reorder: f64,
+    /// Chance to break the connection instead of receiving a frame, it's reported as reset
+    pub disconnect: f64,
+    /// Seed of the faults to replay them, random by default
+    pub seed: Option<u64>,
+}
+
+/// Adaptor injecting the faults of a network into another one, to test how the timeouts, retries and reconnections
+/// of a service cope with them. The faults happen to the frames it receives, wrap both ends for both ways.
+/// The delayed frames are received in time only if the wrapped adaptor implements [`Adaptor::recv_timeout`]
+pub struct Faulty {
+    inner: Arc<dyn Adaptor>,
+    config: FaultConfig,
+    state: Mutex<State>,
+    broken: AtomicBool,
+    dropped: AtomicU64,
+}
+
+struct State {
+    rng: StdRng,
+    // Received and delivered once due, the earliest first
+    delayed: Vec<(Instant, Vec<u8>)>,
+    // Delivered after the next frame
+    held: Option<Vec<u8>>,
+}
+
+/// Wrap `adaptor` to inject the faults of `config`
+pub fn wrap(adaptor: Arc<dyn Adaptor>, config: FaultConfig) -> Arc<Faulty> {
+    let rng = config.seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    Arc::new(Faulty {
+        inner: adaptor,
+        config,
+        state: Mutex::new(State { rng, delayed: Vec::new(), held: None }),
+        broken: AtomicBool::new(false),
+        dropped: AtomicU64::new(0),
+    })
+}
+
+impl Faulty {
+    /// Break the connection now, like a random disconnect
+    pub fn disconnect(&self) {
+        self.broken.store(true, Ordering::SeqCst);
+        self.inner.close();
+    }
+
+    /// Count of frames lost so far
+    pub fn dropped(&self) -> u64 { self.dropped.load(Ordering::Relaxed) }
+
+    // The next frame due, waiting at most until `deadline`
+    fn receive(&self, deadline: Option<Instant>) -> Result<Vec<u8>, RecvError> {
+        loop {
+            if self.broken.load(Ordering::SeqCst) { return Err(RecvError::Disconnect); }
+            let now = Instant::now();
+            let next_due = {
+                let mut state = self.state.lock().unwrap();
+                let next = state.delayed.iter().enumerate().min_by_key(|(_, (due, _))| *due).map(|(i, (due, _))| (i, *due));
+                match next {
+                    Some((i, due)) if due <= now => return Ok(state.delayed.remove(i).1),
+                    next => next.map(|(_, due)| due),
+                }
+            };
+            let wait_until = match (next_due, deadline) {
+                (Some(due), Some(deadline)) => Some(due.min(deadline)),
+                (due, deadline) => due.or(deadline),
+            };
+            let received = match wait_until {
+                Some(until) => match until.checked_duration_since(now) {
+                    Some(wait) if wait > Duration::from_secs(0) => self.inner.recv_timeout(wait),
+                    _ => Err(RecvError::NoData),
+                },
+                None => self.inner.recv(),
+            };
+            match received {
+                Ok(frame) => self.arrived(frame)?,
+                Err(RecvError::NoData) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => return Err(RecvError::NoData),
+                Err(RecvError::NoData) => {}
+                Err(RecvError::Disconnect) => return Err(RecvError::Disconnect),
+            }
+        }
+    }
+
+    // Roll the faults of a frame received from the wrapped adaptor
+    fn arrived(&self, frame: Vec<u8>) -> Result<(), RecvError> {
+        let config = &self.config;
+        let mut state = self.state.lock().unwrap();
+        if state.rng.gen_bool(config.disconnect) {
+            drop(state);
+            self.disconnect();
+            return Err(RecvError::Disconnect);
+        }
+        if state.rng.gen_bool(config.drop) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let jitter = config.jitter.as_nanos() as u64;
+        let jitter = if jitter > 0 { Duration::from_nanos(state.rng.gen_range(0, jitter + 1)) } else { Duration::from_secs(0) };
+        let due = Instant::now() + config.latency + jitter;
+        if state.held.is_none() && state.rng.gen_bool(config.reorder) {
+            state.held = Some(frame);
+            return Ok(());
+        }
+        state.delayed.push((due, frame));
+        // Overtaken by this one
+        if let Some(held) = state.held.take() { state.delayed.push((due, held)); }
+        Ok(())
+    }
+}
+
+impl Adaptor for Faulty {
+    fn send(&self, data: Vec<u8>) -> bool { !self.broken.load(Ordering::SeqCst) && self.inner.send(data) }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> { self.receive(None) }
+
+    fn connected(&self) -> bool { !self.broken.load(Ordering::SeqCst) && self.inner.connected() }
+
+    fn close(&self) { self.inner.close() }
+
+    fn last_error(&self) -> Option<TransportError> {
+        if self.broken.load(Ordering::SeqCst) { return Some(TransportError::Reset); }
+        self.inner.last_error()
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> { self.receive(Some(Instant::now() + timeout)) }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> { self.inner.peer_addr() }
+
+    fn transport_kind(&self) -> Option<&'static str> { self.inner.transport_kind() }
+}
@@ -0,0 +1,195 @@
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::*;
+
+pub const EASY_RPC_OK: c_int = 0;
+/// The peer answered an error, the result holds its code and its message in UTF-8
+pub const EASY_RPC_REMOTE_ERROR: c_int = 1;
+pub const EASY_RPC_DISCONNECTED: c_int = -1;
+/// The method isn't a UTF-8 string
+pub const EASY_RPC_INVALID: c_int = -2;
+/// The send queue is full, see [`Session::set_send_queue`]
+pub const EASY_RPC_WOULD_BLOCK: c_int = -3;
+
+/// Transport implemented by the host application, its callbacks may be called from any thread
+#[repr(C)]
+pub struct EasyRpcAdaptor {
+    pub user: *mut c_void,
+    /// Send a whole packet, false if the connection is closed
+    pub send: extern "C" fn(user: *mut c_void, data: *const u8, len: usize) -> bool,
+    /// Wait for the next packet and point `data` to it, which stays valid until the next call. False if the connection is closed
+    pub recv: extern "C" fn(user: *mut c_void, data: *mut *const u8, len: *mut usize) -> bool,
+    /// Close the connection, so `recv` returns false. May be null
+    pub close: Option<extern "C" fn(user: *mut c_void)>,
+}
+
+struct CAdaptor {
+    adaptor: EasyRpcAdaptor,
+    connected: AtomicBool,
+}
+
+// The host application makes its callbacks thread safe
+unsafe impl Send for CAdaptor {}
+unsafe impl Sync for CAdaptor {}
+
+impl Adaptor for CAdaptor {
+    fn send(&self, data: Vec<u8>) -> bool {
+        self.connected() && (self.adaptor.send)(self.adaptor.user, data.as_ptr(), data.len())
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        let (mut data, mut len) = (ptr::null(), 0);
+        if !self.connected() || !(self.adaptor.recv)(self.adaptor.user, &mut data, &mut len) {
+            self.connected.store(false, Ordering::SeqCst);
+            return Err(RecvError::Disconnect);
+        }
+        Ok(unsafe { bytes(data, len) }.to_vec())
+    }
+
+    fn connected(&self) -> bool { self.connected.load(Ordering::SeqCst) }
+
+    fn close(&self) {
+        if self.connected.swap(false, Ordering::SeqCst) {
+            if let Some(close) = self.adaptor.close { close(self.adaptor.user); }
+        }
+    }
+}
+
+/// Handle a request of the peer, or a notify if `ret` is null. `method` is NUL-terminated, the integer methods are in decimal.
+/// A request is answered with [`easy_rpc_ret_ok`] or [`easy_rpc_ret_error`] before returning
+pub type EasyRpcHandler = extern "C" fn(user: *mut c_void, method: *const c_char, args: *const u8, len: usize, ret: *mut EasyRpcRet);
+
+/// The returner of a request being handled
+pub struct EasyRpcRet<'a, 'b>(Option<Ret<'a, 'b>>);
+
+struct CService {
+    handler: EasyRpcHandler,
+    user: *mut c_void,
+}
+
+unsafe impl Send for CService {}
+unsafe impl Sync for CService {}
+
+impl Service for CService {
+    fn handle(&self, _ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        let method = arg.method.to_string();
+        let method = CString::new(method).map_err(|_| HandleError::new(ErrorKind::MethodNotFound, "No this method"))?;
+        let mut ret = EasyRpcRet(if ret.is_valid() { Some(ret) } else { None });
+        let ret_ptr = if ret.0.is_some() { &mut ret as *mut EasyRpcRet } else { ptr::null_mut() };
+        (self.handler)(self.user, method.as_ptr(), arg.bytes.as_ptr(), arg.bytes.len(), ret_ptr);
+        Ok(())
+    }
+}
+
+/// A session made by [`easy_rpc_session_new`]
+pub struct EasyRpcSession(Arc<Session>);
+
+/// Bytes allocated by the library, freed with [`easy_rpc_buffer_free`]
+#[repr(C)]
+pub struct EasyRpcBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl EasyRpcBuffer {
+    fn new(data: Vec<u8>) -> Self {
+        let data = data.into_boxed_slice();
+        let len = data.len();
+        EasyRpcBuffer { data: Box::into_raw(data) as *mut u8, len }
+    }
+}
+
+/// The outcome of [`easy_rpc_request`], its buffer is freed with [`easy_rpc_buffer_free`]
+#[repr(C)]
+pub struct EasyRpcResult {
+    /// The msgpack value answered, or the message of the error
+    pub data: EasyRpcBuffer,
+    /// The code of the error answered, see [`RemoteError`]. 0 for a value
+    pub code: i64,
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 { &[] } else { std::slice::from_raw_parts(data, len) }
+}
+
+// An empty payload is nil, the packets always carry a value
+unsafe fn payload<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 { &[0xc0] } else { bytes(data, len) }
+}
+
+/// Make a session over `adaptor`, whose requests and notifies are handled by `handler` (may be null) with `user`
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_session_new(adaptor: EasyRpcAdaptor, handler: Option<EasyRpcHandler>, user: *mut c_void) -> *mut EasyRpcSession {
+    let service: ServiceType = match handler {
+        Some(handler) => Arc::new(CService { handler, user }),
+        None => Arc::new(EmptyService),
+    };
+    let adaptor = Arc::new(CAdaptor { adaptor, connected: AtomicBool::new(true) });
+    Box::into_raw(Box::new(EasyRpcSession(Arc::new(Session::new(adaptor, service)))))
+}
+
+/// Handle the packets of the peer until the connection is closed, see [`Session::loop_handle`]
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_session_loop(ss: *const EasyRpcSession) {
+    (*ss).0.loop_handle()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_session_close(ss: *const EasyRpcSession) {
+    let ss = &*ss;
+    ss.0.adaptor.close()
+}
+
+/// Free a session, after its loop returned
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_session_free(ss: *mut EasyRpcSession) {
+    if !ss.is_null() { drop(Box::from_raw(ss)); }
+}
+
+/// Do a request with a msgpack argument, `result` gets the msgpack value answered or the error
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_request(ss: *const EasyRpcSession, method: *const c_char, args: *const u8, len: usize, result: *mut EasyRpcResult) -> c_int {
+    let method = match CStr::from_ptr(method).to_str() { Ok(method) => method, Err(_) => return EASY_RPC_INVALID };
+    match (*ss).0.request_transfer(method, payload(args, len)) {
+        RequestResult::Data(data) | RequestResult::Decode(data) => {
+            *result = EasyRpcResult { data: EasyRpcBuffer::new(data.as_slice().to_vec()), code: 0 };
+            EASY_RPC_OK
+        }
+        RequestResult::Error(e) => {
+            *result = EasyRpcResult { data: EasyRpcBuffer::new(e.message.into_bytes()), code: e.code };
+            EASY_RPC_REMOTE_ERROR
+        }
+        RequestResult::Disconnect => EASY_RPC_DISCONNECTED,
+        RequestResult::WouldBlock => EASY_RPC_WOULD_BLOCK,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_notify(ss: *const EasyRpcSession, method: *const c_char, args: *const u8, len: usize) -> bool {
+    match CStr::from_ptr(method).to_str() {
+        Ok(method) => (*ss).0.notify_transfer(method, payload(args, len)),
+        Err(_) => false,
+    }
+}
+
+/// Answer a request with a msgpack value
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_ret_ok(ret: *mut EasyRpcRet, data: *const u8, len: usize) {
+    if let Some(ret) = ret.as_mut().and_then(|ret| ret.0.take()) { ret.ret_raw(payload(data, len)); }
+}
+
+/// Answer a request with an error message, NUL-terminated
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_ret_error(ret: *mut EasyRpcRet, message: *const c_char) {
+    if let Some(ret) = ret.as_mut().and_then(|ret| ret.0.take()) { ret.error(&CStr::from_ptr(message).to_string_lossy()); }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn easy_rpc_buffer_free(buffer: EasyRpcBuffer) {
+    if !buffer.data.is_null() { drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(buffer.data, buffer.len))); }
+}
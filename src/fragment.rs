@@ -0,0 +1,52 @@
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rmp::encode;
+
+use crate::{FRAGMENT, ProtocolError};
+
+/// Longest header of a fragment, `[FRAGMENT, ID, MORE, DATA]` without the data
+const HEADER_LEN: usize = 17;
+/// Smallest frame size, so each fragment carries some data
+pub(crate) const MIN_FRAME: usize = 64;
+/// Count of packets being reassembled at the same time
+const MAX_PARTIAL: usize = 64;
+
+/// Split `pack` into fragments no longer than `max_frame` bytes
+pub(crate) fn split(id: u64, pack: &[u8], max_frame: usize) -> Vec<Vec<u8>> {
+    let chunk_len = max_frame.max(MIN_FRAME) - HEADER_LEN;
+    let count = pack.len().div_ceil(chunk_len);
+    pack.chunks(chunk_len).enumerate().map(|(i, chunk)| {
+        let mut frame = Vec::with_capacity(chunk.len() + HEADER_LEN);
+        encode::write_array_len(&mut frame, 4);
+        encode::write_uint(&mut frame, FRAGMENT as u64);
+        encode::write_uint(&mut frame, id);
+        encode::write_bool(&mut frame, i + 1 < count);
+        encode::write_bin(&mut frame, chunk);
+        frame
+    }).collect()
+}
+
+/// Packets of the peer received in part
+#[derive(Default)]
+pub(crate) struct Reassembly(Mutex<HashMap<u64, Vec<u8>>>);
+
+impl Reassembly {
+    /// Append a fragment, return the whole packet after the last one
+    pub fn push(&self, id: u64, more: bool, data: &[u8], max_len: usize) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let mut partial = self.0.lock().unwrap();
+        if !partial.contains_key(&id) && partial.len() >= MAX_PARTIAL {
+            return Err(ProtocolError::LimitExceeded("fragmented packets"));
+        }
+        let pack = partial.entry(id).or_default();
+        if pack.len() + data.len() > max_len {
+            partial.remove(&id);
+            return Err(ProtocolError::LimitExceeded("reassembled length"));
+        }
+        pack.extend_from_slice(data);
+        Ok(if more { None } else { partial.remove(&id) })
+    }
+
+    pub fn clear(&self) { self.0.lock().unwrap().clear(); }
+}
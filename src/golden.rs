@@ -0,0 +1,114 @@
+
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{Method, ServiceType, Session, ToMethod, encode_arg, protocol};
+use crate::dynamic::Dynamic;
+use crate::mock::MockAdaptor;
+use crate::protocol::Frame;
+use crate::schema::SchemaExport;
+
+/// Environment variable which makes [`Golden::check`] rewrite the golden file instead of comparing with it
+pub const UPDATE_VAR: &str = "EASYRPC_UPDATE_GOLDEN";
+
+/// Encoded requests of sample arguments, compared with a golden file so a change of serde attributes
+/// can't change the wire format unnoticed. The file has a line `NAME HEX` per case
+pub struct Golden {
+    path: PathBuf,
+    canonical: bool,
+    cases: Vec<(String, Vec<u8>)>,
+}
+
+impl Golden {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Golden { path: path.into(), canonical: false, cases: Vec::new() }
+    }
+
+    /// Encode the arguments in canonical form, like [`Session::set_canonical`](crate::Session::set_canonical)
+    pub fn canonical(mut self, canonical: bool) -> Self {
+        self.canonical = canonical;
+        self
+    }
+
+    /// Add the request frame of `method` with `arg`, `name` must be unique and without spaces
+    pub fn case<'a>(mut self, name: &str, method: impl ToMethod<'a>, arg: impl Serialize) -> Self {
+        let mut frame = Vec::new();
+        protocol::write_request(&mut frame, 0, method.to_method(), None, None);
+        encode_arg(&arg, &mut frame, self.canonical);
+        self.cases.push((name.into(), frame));
+        self
+    }
+
+    /// Add a round trip of each method of `service` with a schema: its request of [`Schema::example`](crate::schema::Schema::example)
+    /// arguments as the case `METHOD.request`, and the response of `service` as `METHOD.response`. The golden file
+    /// is then a contract for the clients in other languages, which check they encode the requests and decode
+    /// the responses the same way
+    pub fn contract(mut self, service: ServiceType) -> Self {
+        let mock = MockAdaptor::new();
+        let mut requests = Vec::new();
+        for (info, schema) in SchemaExport::of(&*service).methods {
+            let schema = match schema { Some(schema) => schema, None => continue };
+            let id = requests.len() as u64 + 1;
+            let mut frame = Vec::new();
+            protocol::write_request(&mut frame, id, Method::Str(&info.name), None, None);
+            encode_arg(&Dynamic(schema.example()), &mut frame, self.canonical);
+            mock.receive_request(id, frame.clone());
+            requests.push((info.name, frame));
+        }
+        let session = Session::new(mock.clone(), service);
+        session.set_canonical(self.canonical);
+        session.loop_handle();
+        let sent = mock.sent();
+        for (i, (method, request)) in requests.into_iter().enumerate() {
+            let answered = |frame: &&Vec<u8>| match protocol::parse_frame(frame, |_| Ok(())) {
+                Ok(Frame::Response { id, .. }) => id == i as u64 + 1,
+                _ => false,
+            };
+            let response = sent.iter().find(answered).cloned().unwrap_or_default();
+            self.cases.push((format!("{}.request", method), request));
+            self.cases.push((format!("{}.response", method), response));
+        }
+        self
+    }
+
+    /// Compare the cases with the golden file, which is written if it doesn't exist or [`UPDATE_VAR`] is set.
+    /// The error lists the cases which changed, were added or removed
+    pub fn check(&self) -> Result<(), String> {
+        let content = std::fs::read_to_string(&self.path).ok().filter(|_| std::env::var_os(UPDATE_VAR).is_none());
+        let content = match content {
+            Some(content) => content,
+            None => return std::fs::write(&self.path, self.render()).map_err(|e| format!("{}: {}", self.path.display(), e)),
+        };
+        let golden = content.lines().filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some((parts.next()?, parts.next().unwrap_or("")))
+        }).collect::<Vec<_>>();
+
+        let mut errors = Vec::new();
+        for (name, frame) in &self.cases {
+            match golden.iter().find(|g| g.0 == name) {
+                Some((_, expected)) if *expected == hex(frame) => {}
+                Some((_, expected)) => errors.push(format!("{} changed: {} -> {}", name, expected, hex(frame))),
+                None => errors.push(format!("{} is not in the golden file", name)),
+            }
+        }
+        for (name, _) in &golden {
+            if !self.cases.iter().any(|c| c.0 == *name) { errors.push(format!("{} was removed", name)); }
+        }
+        if errors.is_empty() { Ok(()) } else {
+            Err(format!("{} doesn't match (set {} to update it):\n{}", self.path.display(), UPDATE_VAR, errors.join("\n")))
+        }
+    }
+
+    fn render(&self) -> String {
+        self.cases.iter().map(|(name, frame)| format!("{} {}\n", name, hex(frame))).collect()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes { write!(s, "{:02x}", b); }
+    s
+}
@@ -0,0 +1,60 @@
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+/// Notify of the peer releasing a handle, `ID`
+pub(crate) const RELEASE_METHOD: &str = "$release";
+
+/// Opaque reference to an object a session holds for its peer, see [`Handles`]. Encoded as an integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle(u64);
+
+impl Serialize for Handle {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { serializer.serialize_u64(self.0) }
+}
+
+impl<'de> Deserialize<'de> for Handle {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> { u64::deserialize(deserializer).map(Handle) }
+}
+
+/// Objects a service holds for the peer of a session (open files, query cursors...), which refers to them by the
+/// [`Handle`] answered in a result. They're dropped once the peer releases them with
+/// [`Session::release_handle`](crate::Session::release_handle), or when the session disconnects.
+/// See [`Session::handles`](crate::Session::handles)
+#[derive(Default)]
+pub struct Handles {
+    next_id: AtomicU64,
+    objects: RwLock<HashMap<u64, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl Handles {
+    pub fn insert<T: Any + Send + Sync>(&self, object: T) -> Handle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.objects.write().unwrap().insert(id, Arc::new(object));
+        Handle(id)
+    }
+
+    /// The object of `handle`, `None` if it was released or is of another type
+    pub fn get<T: Any + Send + Sync>(&self, handle: Handle) -> Option<Arc<T>> {
+        self.objects.read().unwrap().get(&handle.0).and_then(|object| object.clone().downcast().ok())
+    }
+
+    /// Release an object, it's dropped once the other references to it are. False if it's unknown
+    pub fn remove(&self, handle: Handle) -> bool { self.objects.write().unwrap().remove(&handle.0).is_some() }
+
+    pub fn len(&self) -> usize { self.objects.read().unwrap().len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn clear(&self) { self.objects.write().unwrap().clear(); }
+
+    pub(crate) fn handle_release(&self, args: &[u8]) -> Option<()> {
+        let handle: Handle = rmps::from_read_ref(args).ok()?;
+        self.remove(handle);
+        Some(())
+    }
+}
@@ -0,0 +1,79 @@
+
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
+
+use crate::*;
+
+pub const LIST_METHOD: &str = "rpc.list_methods";
+pub const DESCRIBE_METHOD: &str = "rpc.describe";
+
+/// Description of a method of a service, see [`Service::methods`].
+/// The types are the Rust ones, as written in the source or given by `std::any::type_name`.
+/// Sent as a map `{"name", "params", "returns"}` whose `returns` is nil for the notifies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodInfo {
+    pub name: String,
+    /// Type of the arguments, a tuple if there are several ones
+    pub params: String,
+    pub returns: Option<String>,
+}
+
+impl MethodInfo {
+    pub fn new(name: impl Into<String>, params: impl Into<String>, returns: Option<&str>) -> Self {
+        MethodInfo { name: name.into(), params: params.into(), returns: returns.map(Into::into) }
+    }
+}
+
+impl Serialize for MethodInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("name", &self.name)?;
+        map.serialize_entry("params", &self.params)?;
+        map.serialize_entry("returns", &self.returns)?;
+        map.end()
+    }
+}
+
+/// Service answering [`LIST_METHOD`] with the names of the methods of `service`,
+/// and [`DESCRIBE_METHOD`] with the [`MethodInfo`] of one of them (nil if unknown).
+/// The other methods go to `service`
+pub struct Introspect {
+    service: ServiceType,
+}
+
+impl Introspect {
+    pub fn new(service: impl Service) -> Self { Introspect { service: Arc::new(service) } }
+}
+
+impl Service for Introspect {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        match arg.method {
+            Method::Str(LIST_METHOD) => {
+                ret.ok(self.methods().into_iter().map(|m| m.name).collect::<Vec<_>>());
+                Ok(())
+            }
+            Method::Str(DESCRIBE_METHOD) => {
+                let name: String = arg.into()?;
+                ret.ok(self.methods().into_iter().find(|m| m.name == name));
+                Ok(())
+            }
+            _ => self.service.handle(ss, arg, ret),
+        }
+    }
+
+    fn supports(&self, method: Method) -> Option<bool> {
+        match method {
+            Method::Str(LIST_METHOD) | Method::Str(DESCRIBE_METHOD) => Some(true),
+            _ => self.service.supports(method),
+        }
+    }
+
+    fn schema(&self, method: Method) -> Option<&crate::schema::Schema> { self.service.schema(method) }
+
+    fn methods(&self) -> Vec<MethodInfo> {
+        let mut methods = self.service.methods();
+        methods.push(MethodInfo::new(LIST_METHOD, "()", Some("Vec<String>")));
+        methods.push(MethodInfo::new(DESCRIBE_METHOD, "String", Some("Option<MethodInfo>")));
+        methods
+    }
+}
@@ -0,0 +1,113 @@
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmpv::Value;
+use serde_json::{Value as Json, Number, Map};
+
+use crate::{Adaptor, RecvError, TransportError};
+
+/// Convert a msgpack value to JSON. Binaries become arrays of bytes, extensions `[TYPE, [BYTES...]]`,
+/// map keys which are not strings are written as JSON, and NaN or infinite floats become null
+pub fn to_json(val: &Value) -> Json {
+    match val {
+        Value::Nil => Json::Null,
+        Value::Boolean(b) => Json::Bool(*b),
+        Value::Integer(i) => i.as_u64().map(Number::from).or_else(|| i.as_i64().map(Number::from))
+            .map_or(Json::Null, Json::Number),
+        Value::F32(f) => float(*f as f64),
+        Value::F64(f) => float(*f),
+        Value::String(s) => Json::String(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        Value::Binary(b) => bytes(b),
+        Value::Array(items) => Json::Array(items.iter().map(to_json).collect()),
+        Value::Map(entries) => Json::Object(entries.iter().map(|(k, v)| {
+            let key = match k {
+                Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+                k => to_json(k).to_string(),
+            };
+            (key, to_json(v))
+        }).collect::<Map<_, _>>()),
+        Value::Ext(ty, data) => Json::Array(vec![Json::from(*ty), bytes(data)]),
+    }
+}
+
+fn float(f: f64) -> Json {
+    Number::from_f64(f).map_or(Json::Null, Json::Number)
+}
+
+fn bytes(b: &[u8]) -> Json {
+    Json::Array(b.iter().map(|&b| Json::from(b)).collect())
+}
+
+/// Convert a JSON value to msgpack, numbers are integers when they fit
+pub fn from_json(val: &Json) -> Value {
+    match val {
+        Json::Null => Value::Nil,
+        Json::Bool(b) => Value::Boolean(*b),
+        Json::Number(n) => n.as_u64().map(Value::from)
+            .or_else(|| n.as_i64().map(Value::from))
+            .unwrap_or_else(|| Value::F64(n.as_f64().unwrap_or_default())),
+        Json::String(s) => Value::from(s.as_str()),
+        Json::Array(items) => Value::Array(items.iter().map(from_json).collect()),
+        Json::Object(entries) => Value::Map(entries.iter().map(|(k, v)| (Value::from(k.as_str()), from_json(v))).collect()),
+    }
+}
+
+/// Whole packets as JSON text over `inner`, for peers without any msgpack library:
+/// a request is sent `[0, ID, METHOD, ARGS]` like its msgpack form. Wrap the adaptor when making the session,
+/// `Session::new(JsonAdaptor::new(adaptor), service)`.
+///
+/// The conversions are the ones of [`to_json`] and [`from_json`], so the binaries of compressed packets and
+/// transfers don't survive them, leave compression off. Data which isn't JSON is passed as is and dropped as malformed
+pub struct JsonAdaptor {
+    inner: Arc<dyn Adaptor>,
+}
+
+impl JsonAdaptor {
+    pub fn new(inner: Arc<dyn Adaptor>) -> Arc<JsonAdaptor> {
+        Arc::new(JsonAdaptor { inner })
+    }
+}
+
+fn decode(data: Vec<u8>) -> Vec<u8> {
+    let json = match serde_json::from_slice::<Json>(&data) { Ok(json) => json, Err(_) => return data };
+    let mut msgpack = Vec::with_capacity(data.len());
+    rmpv::encode::write_value(&mut msgpack, &from_json(&json));
+    msgpack
+}
+
+impl Adaptor for JsonAdaptor {
+    fn send(&self, data: Vec<u8>) -> bool {
+        match rmpv::decode::read_value(&mut &data[..]) {
+            Ok(val) => self.inner.send(serde_json::to_vec(&to_json(&val)).unwrap_or_default()),
+            Err(_) => false,
+        }
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        Ok(decode(self.inner.recv()?))
+    }
+
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> {
+        Some(self.inner.try_recv()?.map(decode))
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> { self.inner.raw_fd() }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> { self.inner.peer_addr() }
+
+    fn transport_kind(&self) -> Option<&'static str> { self.inner.transport_kind() }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> { self.inner.peer_certificate() }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> {
+        Ok(decode(self.inner.recv_timeout(timeout)?))
+    }
+
+    fn connected(&self) -> bool { self.inner.connected() }
+
+    fn close(&self) { self.inner.close() }
+
+    fn last_error(&self) -> Option<TransportError> { self.inner.last_error() }
+}
@@ -1,5 +1,5 @@
-#![feature(unboxed_closures, fn_traits)]
-#![allow(unused_must_use)]
+#![cfg_attr(feature = "fn_traits", feature(unboxed_closures, fn_traits))]
+#![allow(unused_must_use, clippy::missing_safety_doc)]
 
 //! `easy-rpc` is a cross-language RPC framework.
 //! # Example
@@ -29,19 +29,16 @@
 //! }
 //! 
 //! fn main() -> Result<(), Box<dyn std::error::Error>> {
-//!     std::thread::spawn(|| {
-//!         let mut ser = ws::bind("127.0.0.1:3333").unwrap();
-//!         let (adaptor, _uri) = ws::accept(&mut ser).unwrap();
-//!         Session::new(adaptor, Arc::new(ServerService)).loop_handle();
-//!     });
+//!     let listener = ws::bind_guarded("127.0.0.1:3333", Default::default())?;
+//!     let server = Server::new(listener, || Arc::new(ServerService)).start();
 //! 
-//!     std::thread::sleep_ms(100);
 //!     let session = Session::new(ws::connect("ws://127.0.0.1:3333")?, Arc::new(EmptyService));
 //!     let val: u32 = session.request("add", (1, 2)).into()?;
 //!     session.notify("print", format!("the result is {}", val));
 //!     let val: u32 = session.request(MUL, (2, 3)).into()?;
 //!     assert_eq!(val, 6);
 //! 
+//!     server.shutdown();
 //!     Ok(())
 //! }
 //! ```
@@ -56,36 +53,273 @@ pub mod ws;
 /// Adaptor of SharedMemory
 #[cfg(all(feature = "shm", not(target_os = "android")))]
 pub mod shm;
+/// Noise encryption over any adaptor
+#[cfg(feature = "noise")]
+pub mod encrypted;
+/// Conversions between msgpack and JSON values
+#[cfg(feature = "json")]
+pub mod json;
+/// CBOR wire format
+#[cfg(feature = "cbor")]
+pub mod cbor;
+/// Protobuf messages as opaque payloads
+#[cfg(feature = "protobuf")]
+pub mod proto;
+/// Forwarding of requests and notifies to another session
+pub mod relay;
+/// C API over callbacks, declared in `include/easy_rpc.h`
+#[cfg(feature = "ffi")]
+pub mod ffi;
+/// Advertisement and discovery of servers on the LAN over mDNS
+#[cfg(feature = "discover")]
+pub mod discover;
+/// Canonical msgpack encoding
+pub mod canonical;
+/// Numeric arrays in the msgpack-numpy layout
+pub mod array;
+/// Batch aggregation of high-rate notifies
+pub mod aggregate;
+/// Layers of code running around a service
+pub mod middleware;
+/// Golden files guarding the wire encoding
+pub mod golden;
+/// Byte streams tunneled through channels
+pub mod tunnel;
+/// Service dispatching to closures
+pub mod router;
+/// Services mounted under method prefixes
+pub mod namespace;
+/// Methods describing a service to its peers
+pub mod introspect;
+/// Annotated, human readable traces of frames
+pub mod trace;
+/// Context of the handled request, carried across threads
+pub mod context;
+/// Requests to several sessions at once
+pub mod multi;
+/// Recording of the frames of a session to a file, and their replay
+pub mod record;
+/// Scripted adaptor, to test services and clients without a peer
+pub mod mock;
+/// Schemas the arguments are validated against
+pub mod schema;
+/// msgpack extension types, the standard timestamps and the custom ones
+pub mod ext;
+
+/// Serialization of the values: msgpack, CBOR, JSON, bincode or your own
+pub mod codec;
+/// The protocol without its transport, to drive it from any I/O
+pub mod protocol;
+/// Adaptor injecting latency, reordering, losses and disconnects, to test under network failures
+pub mod faulty;
+mod limit;
+mod marker;
+mod queue;
+mod extensions;
+mod compress;
+mod auth;
+mod channel;
+mod fragment;
+mod attachment;
+mod pubsub;
+mod callback;
+mod handles;
+mod sessions;
+mod server;
+mod shutdown;
+mod shard;
+mod pool;
+mod metrics;
+mod events;
+mod order;
+mod native;
+mod diagnostics;
+mod clock;
+mod buffers;
+mod throttle;
+mod clients;
+mod builder;
+mod config;
+mod telemetry;
+mod tenant;
+mod cache;
+mod retry;
+mod alias;
+mod failure;
+mod listeners;
+mod dynamic;
+
+pub use limit::{DecodeLimits, SizeLimits};
+pub use extensions::Extensions;
+pub use queue::{Overflow, SendError, Priority};
+pub use compress::{CompressionCodec, Compression};
+pub use auth::{Authenticator, Credentials, Identity, NoAuthenticator};
+pub use channel::{Channel, ChannelError};
+pub use pubsub::{Broker, Event, History, MemoryHistory, Since};
+pub use callback::Callback;
+pub use handles::{Handle, Handles};
+pub use sessions::{Sessions, Rooms};
+pub use server::{Server, Listener, Accepted};
+pub use shutdown::Shutdown;
+pub use shard::{Shards, Shard};
+pub use pool::WorkerPool;
+pub use metrics::MetricsSink;
+pub use events::{SessionEvent, SessionObserver};
+pub use diagnostics::{Diagnostics, PendingRequest};
+pub use clock::{Clock, SystemClock, MockClock};
+pub use throttle::{Throttle, Watchdog};
+pub use clients::{Pool, Pooled, Failover, CanaryReport, RouteStats};
+pub use builder::SessionBuilder;
+pub use config::{ServerConfig, LogLevel};
+pub use telemetry::{Span, SpanKind, SpanSink};
+pub use tenant::{Tenant, Tenants};
+pub use cache::ResponseCache;
+pub use retry::{RetryPolicy, IdempotencyCache};
+pub use failure::{DecodeDetails, DecodeFailure};
+#[cfg(feature = "macros")]
+pub use easy_rpc_macros::rpc_service;
+#[cfg(feature = "macros")]
+#[proc_macro_hack::proc_macro_hack]
+pub use easy_rpc_macros::method_id;
 
 #[doc(no_inline)]
 pub use serde_bytes::{Bytes, ByteBuf};
+#[doc(hidden)]
+pub use paste;
 
 use std::sync::{
-    Arc, RwLock, Mutex,
-    mpsc::{channel, Sender},
-    atomic::{AtomicU32, Ordering},
+    Arc, Weak, RwLock, Mutex,
+    mpsc::{channel, Sender, Receiver, RecvTimeoutError},
+    atomic::{AtomicU64, AtomicUsize, AtomicBool, Ordering},
 };
 use std::fmt::{
     Debug, Display, Formatter,
     Result as FmtResult
 };
 use std::collections::HashMap;
+use std::io::{self, Read, Write, IoSlice};
+use std::time::{Duration, Instant, SystemTime};
+use std::panic::{self, AssertUnwindSafe};
 
 use serde::Serialize;
+use serde::Deserialize;
 use serde::de::DeserializeOwned;
-use rmps::Serializer;
 use rmps::decode::Error as DecodeError;
 use rmp::{encode, decode};
 use rmpv::{Value, decode::read_value};
 use downcast_rs::DowncastSync;
-
-const REQUEST: u32 = 0;         // Caller->Callee [REQUEST, ID: u32, METHOD: u32, ARGS: Any]
-const RESPONSE: u32 = 1;        // Callee->Caller [RESPONSE, ID: u32, ERROR: Option<String>, RESULT: Any]
-const NOTIFY: u32 = 2;          // [NOTIFY, METHOD: u32, ARGS: Any]
+use queue::SendQueue;
+use channel::Channels;
+use fragment::Reassembly;
+use attachment::Attachments;
+use alias::Aliases;
+use pubsub::Subscriptions;
+use callback::Callbacks;
+
+const REQUEST: u32 = 0;         // Caller->Callee [REQUEST, ID: u64, METHOD: u32, ARGS: Any] or [REQUEST, ID, METHOD, TIMEOUT_MS: u64, ARGS]
+                                // or [REQUEST, ID, METHOD, TIMEOUT_MS: Option<u64>, METADATA: Map, ARGS]
+const RESPONSE: u32 = 1;        // Callee->Caller [RESPONSE, ID: u64, ERROR: Option<String>, RESULT: Any]
+const NOTIFY: u32 = 2;          // [NOTIFY, METHOD: u32, ARGS: Any] or [NOTIFY, ACK_ID: u64, METHOD, ARGS], acknowledged by [RESPONSE, ACK_ID, nil, nil]
+const COMPRESSED: u32 = 3;      // [COMPRESSED, CODEC: u32, PACKET: Bin]
+const FRAGMENT: u32 = 4;        // [FRAGMENT, ID: u64, MORE: bool, DATA: Bin]
+const PING: u32 = 5;            // [PING, ID: u64], answered by [PONG, ID] without reaching the service
+const PONG: u32 = 6;
+const ATTACHMENT: u32 = 7;      // [ATTACHMENT, ID: u64, RESPONSE: bool, DATA: Bin], a blob of the request ID sent next, or of the
+                                // response to the request ID of the receiver, indexed in the order they're sent
+
+/// Control request asking whether the service handles a method, answered `Option<bool>`
+const SUPPORTS_METHOD: &str = "$supports";
+/// Notify of the progress of a request being handled, `[ID, VALUE]`, see [`Session::request_progress`]
+const PROGRESS_METHOD: &str = "$progress";
+/// Notify that the requester gave up on a request, `ID`, see [`Session::cancel_request`]
+const CANCEL_METHOD: &str = "$cancel";
+
+/// How long a request waits for the thread receiving in its place before checking it still does
+const HANDOFF: Duration = Duration::from_millis(10);
+
+/// How long the waiter of a request is kept past its deadline, its requester removes it once it gives up so the ones
+/// left were abandoned
+const EXPIRY_GRACE: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub enum RecvError {
     Disconnect,
+    /// Nothing to receive yet, only told by the receptions which don't block
+    NoData,
+}
+
+/// Classified failure of a transport, see [`Adaptor::last_error`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportError {
+    /// The peer stopped responding
+    Timeout,
+    /// The connection is reset or closed unexpectedly
+    Reset,
+    /// TLS negotiation or decryption failed
+    Tls,
+    /// A frame is larger than the transport accepts
+    TooLarge,
+    /// The peer violated the transport protocol
+    Protocol,
+    /// Other I/O error
+    Io(std::io::ErrorKind),
+}
+
+impl TransportError {
+    /// Classify an I/O error
+    pub fn from_io(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind::*;
+
+        match e.kind() {
+            TimedOut | WouldBlock => TransportError::Timeout,
+            ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof => TransportError::Reset,
+            InvalidData => TransportError::Protocol,
+            kind => TransportError::Io(kind),
+        }
+    }
+}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        use TransportError::*;
+
+        match self {
+            Timeout => write!(f, "Timeout"),
+            Reset => write!(f, "Connection reset"),
+            Tls => write!(f, "TLS error"),
+            TooLarge => write!(f, "Frame too large"),
+            Protocol => write!(f, "Protocol error"),
+            Io(kind) => write!(f, "I/O error: {:?}", kind),
+        }
+    }
+}
+
+/// Error of parsing a received packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The packet is not a valid easy-rpc frame, describes which part is broken
+    Malformed(&'static str),
+    /// Unknown packet type
+    InvalidPackType(u32),
+    /// Received a response whose request is not pending
+    UnknownResponse(u64),
+    /// A limit set by [`Session::set_decode_limits`] or [`Session::set_size_limits`] is exceeded
+    LimitExceeded(&'static str),
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        use ProtocolError::*;
+
+        match self {
+            Malformed(s) => write!(f, "Malformed packet: {}", s),
+            InvalidPackType(t) => write!(f, "Invalid packet type: {}", t),
+            UnknownResponse(id) => write!(f, "Unknown response id: {}", id),
+            LimitExceeded(s) => write!(f, "Limit exceeded: {}", s),
+        }
+    }
 }
 
 /// Adaptor of different communicated methods
@@ -101,31 +335,185 @@ pub trait Adaptor: DowncastSync {
 
     // Close the connection
     fn close(&self);
+
+    /// The error which broke the connection, `None` if it's still connected or closed normally
+    fn last_error(&self) -> Option<TransportError> { None }
+
+    /// Send `data` and leave the buffer to the session, which reuses it for the next packets.
+    /// It's taken for [`Adaptor::send`] by default, an adaptor copying the data out (e.g. to a shared memory) overrides it
+    fn send_buf(&self, data: &mut Vec<u8>) -> bool { self.send(std::mem::take(data)) }
+
+    /// Receive data into `buf`, an empty buffer of the session. It's dropped for [`Adaptor::recv`] by default
+    fn recv_buf(&self, buf: Vec<u8>) -> Result<Vec<u8>, RecvError> {
+        drop(buf);
+        self.recv()
+    }
+
+    /// Send the concatenation of `parts`, so a header and a large payload don't need to be copied together
+    /// by an adaptor able to write them one after the other. They're copied into one buffer for [`Adaptor::send`] by default
+    fn send_vectored(&self, parts: &[IoSlice]) -> bool {
+        let mut data = Vec::with_capacity(parts.iter().map(|part| part.len()).sum());
+        for part in parts { data.extend_from_slice(part); }
+        self.send(data)
+    }
+
+    /// Receive data without blocking, `Err(RecvError::NoData)` if none is available.
+    /// `None` if the adaptor can't tell (the default)
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> { None }
+
+    /// The descriptor which gets readable when data arrives, to wait for it in an event loop before [`Adaptor::try_recv`]
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> { None }
+
+    /// Receive data, waiting at most `timeout`: `Err(RecvError::NoData)` if none arrived in time.
+    /// It blocks like [`Adaptor::recv`] by default
+    fn recv_timeout(&self, _timeout: Duration) -> Result<Vec<u8>, RecvError> { self.recv() }
+
+    /// The address of the peer, `None` if the transport has none (e.g. a shared memory) or can't tell
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> { None }
+
+    /// Name of the transport, e.g. `"ws"`, for the logs
+    fn transport_kind(&self) -> Option<&'static str> { None }
+
+    /// The DER certificate the peer presented, on a TLS connection
+    fn peer_certificate(&self) -> Option<Vec<u8>> { None }
 }
 impl_downcast!(sync Adaptor);
 
 #[doc(hidden)]
-pub struct RespData(Vec<u8>, usize);
+pub struct RespData {
+    data: Vec<u8>,
+    offset: usize,
+    // The method requested, told by the decode failures
+    method: String,
+    failure: Option<DecodeFailure>,
+}
 
 impl RespData {
+    fn new(data: Vec<u8>, offset: usize) -> Self { RespData { data, offset, method: String::new(), failure: None } }
+
     #[inline]
-    pub fn into<T: DeserializeOwned>(&self) -> Result<T, DecodeError> {
-        rmps::from_read_ref(self.as_slice())
+    pub fn into<T: DeserializeOwned>(&self) -> Result<T, DecodeFailure> {
+        decode_arg(self.as_slice()).map_err(|e| failure::explain::<T>(self.as_slice(), self.method.clone(), true, e))
     }
 
+    /// Decode a value borrowing the strings and binaries from the received buffer, like [`Arg::borrow`]
     #[inline]
-    pub fn as_slice(&self) -> &[u8] { &self.0[self.1..] }
+    pub fn borrow<'a, T: Deserialize<'a>>(&'a self) -> Result<T, DecodeFailure> {
+        decode_arg(self.as_slice()).map_err(|e| failure::explain::<T>(self.as_slice(), self.method.clone(), true, e))
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] { &self.data[self.offset..] }
+}
+
+/// Error answered to a request. It's sent as a bare message when it has no code nor data, as older peers expect,
+/// otherwise as `[CODE, MESSAGE, DATA]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub code: i64,
+    pub message: String,
+    /// Encoded msgpack value, see [`RemoteError::data`]
+    pub data: Option<Vec<u8>>,
+}
+
+impl RemoteError {
+    /// Error of the application, without a code
+    pub const APPLICATION: i64 = 0;
+    pub const MALFORMED: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_ARGS: i64 = -32602;
+    pub const UNAUTHENTICATED: i64 = -32001;
+    pub const PERMISSION_DENIED: i64 = -32003;
+    pub const LIMIT_EXCEEDED: i64 = -32005;
+    /// The request is over a [`Throttle`] of the peer, the data is the delay in milliseconds before retrying if it's known
+    pub const THROTTLED: i64 = -32029;
+    /// The peer doesn't take requests anymore, e.g. it's draining before a restart (see [`Session::drain`]).
+    /// The request wasn't handled, it can be retried on another connection
+    pub const UNAVAILABLE: i64 = -32053;
+    /// The service panicked handling the request (see [`Session::set_catch_panics`]), or returned without answering it
+    /// (see [`Unanswered`]). The message tells which, it starts with "The handler panicked" for a panic
+    pub const INTERNAL: i64 = -32603;
+    /// The service didn't answer within the limit of a [`Watchdog`] of the peer, it may still be handling the request
+    pub const TIMEOUT: i64 = -32054;
+
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        RemoteError { code, message: message.into(), data: None }
+    }
+
+    /// Attach a value detailing the error
+    pub fn with_data(mut self, data: impl Serialize) -> Self {
+        let mut buf = Vec::new();
+        encode_arg(&data, &mut buf, false);
+        self.data = Some(buf);
+        self
+    }
+
+    /// Decode the attached value, `None` if there is none
+    pub fn data<T: DeserializeOwned>(&self) -> Option<Result<T, DecodeError>> {
+        self.data.as_ref().map(|data| decode_arg(data))
+    }
+
+    fn decode(val: &Value) -> Option<RemoteError> {
+        if let Some(message) = val.as_str() { return Some(RemoteError::new(Self::APPLICATION, message)); }
+        match val.as_array()?.as_slice() {
+            [code, message, data] => {
+                let mut error = RemoteError::new(code.as_i64()?, message.as_str()?);
+                if !data.is_nil() {
+                    let mut buf = Vec::new();
+                    ext::write_value(&mut buf, data);
+                    error.data = Some(buf);
+                }
+                Some(error)
+            }
+            _ => None,
+        }
+    }
+
+    fn encode(&self, w: &mut Vec<u8>) {
+        if self.code == Self::APPLICATION && self.data.is_none() {
+            encode::write_str(w, &self.message);
+            return;
+        }
+        encode::write_array_len(w, 3);
+        encode::write_sint(w, self.code);
+        encode::write_str(w, &self.message);
+        match &self.data {
+            Some(data) => w.extend_from_slice(data),
+            None => { encode::write_nil(w); }
+        }
+    }
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if self.code == Self::APPLICATION { write!(f, "{}", self.message) } else { write!(f, "{} (code {})", self.message, self.code) }
+    }
 }
 
+impl std::error::Error for RemoteError {}
+
 pub enum RequestResult {
     Data(RespData),
-    Error(String),
+    Error(RemoteError),
     Disconnect,
     Decode(RespData),
+    /// The outgoing queue is full, see [`Session::set_send_queue`]
+    WouldBlock,
 }
 
 impl std::error::Error for RequestResult {}
 
+impl From<SendError> for RequestResult {
+    fn from(e: SendError) -> Self {
+        match e {
+            SendError::WouldBlock => RequestResult::WouldBlock,
+            SendError::Disconnect => RequestResult::Disconnect,
+            SendError::TooLarge => RequestResult::Error(RemoteError::new(RemoteError::LIMIT_EXCEEDED, "Request too large")),
+        }
+    }
+}
+
 impl Debug for RequestResult {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         use RequestResult::*;
@@ -133,8 +521,10 @@ impl Debug for RequestResult {
         match self {
             Data(_) => write!(f, "<Success>"),
             Error(ref s) => write!(f, "Error: {}", s),
+            Decode(RespData { failure: Some(failure), .. }) => write!(f, "DecodeError: {}", failure),
             Decode(_) => write!(f, "DecodeError"),
             Disconnect => write!(f, "Disconnect"),
+            WouldBlock => write!(f, "WouldBlock"),
         }; Ok(())
     }
 }
@@ -148,124 +538,416 @@ impl Display for RequestResult {
 impl RequestResult {
     pub fn into<T: DeserializeOwned>(self) -> Result<T, RequestResult> {
         match self {
-            RequestResult::Data(d) => rmps::from_read_ref(d.as_slice()).map_err(|_| RequestResult::Decode(d)),
+            RequestResult::Data(mut d) => match RespData::into(&d) {
+                Ok(val) => Ok(val),
+                Err(failure) => {
+                    d.failure = Some(failure);
+                    Err(RequestResult::Decode(d))
+                }
+            },
             else_error => Err(else_error),
         }
     }
 
+    /// Why the result couldn't be decoded, after [`RequestResult::into`] failed with it
+    pub fn decode_failure(&self) -> Option<&DecodeFailure> {
+        match self { RequestResult::Decode(d) => d.failure.as_ref(), _ => None }
+    }
+
     #[inline]
     pub fn intos<T: DeserializeOwned>(self) -> Result<T, String> { self.into().map_err(|e| format!("{}", e)) }
 }
 
+/// Why a request of [`Session::call`] failed: the transport (disconnected, timed out, queue full, not connected), the peer
+/// answering an error, or the result not decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// The session disconnected before the response
+    Disconnected,
+    /// No response in time, a later one is dropped
+    Timeout,
+    /// The outgoing queue is full, see [`Session::set_send_queue`]
+    WouldBlock,
+    /// The peer answered an error
+    Remote(RemoteError),
+    /// The response can't be decoded, the message tells why like a [`DecodeFailure`]
+    Protocol(String),
+    /// No session could be connected to send it, e.g. by a [`Pool`], the message is the one of the connection error
+    Connect(String),
+}
+
+impl RequestError {
+    /// The request failed because of the connection, not of the peer nor the result, so it may be sent again
+    pub fn is_transport(&self) -> bool {
+        match self {
+            RequestError::Disconnected | RequestError::Timeout | RequestError::WouldBlock | RequestError::Connect(_) => true,
+            RequestError::Remote(_) | RequestError::Protocol(_) => false,
+        }
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            RequestError::Disconnected => write!(f, "Disconnected"),
+            RequestError::Timeout => write!(f, "Timeout"),
+            RequestError::WouldBlock => write!(f, "WouldBlock"),
+            RequestError::Remote(e) => write!(f, "Error: {}", e),
+            RequestError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            RequestError::Connect(e) => write!(f, "Can't connect: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<RequestResult> for RequestError {
+    fn from(result: RequestResult) -> Self {
+        match result {
+            RequestResult::Error(e) => RequestError::Remote(e),
+            RequestResult::Disconnect => RequestError::Disconnected,
+            RequestResult::WouldBlock => RequestError::WouldBlock,
+            RequestResult::Decode(RespData { failure: Some(failure), .. }) => RequestError::Protocol(failure.to_string()),
+            RequestResult::Data(_) | RequestResult::Decode(_) => RequestError::Protocol("undecodable result".into()),
+        }
+    }
+}
+
 /// Represent the arguments of a request/notify
 pub struct Arg<'a> {
     pub method: Method<'a>,
     pub bytes: &'a [u8],
-    pub id: u32,
+    pub id: u64,
+    /// When the caller gives up on the result, if it sent a timeout (see [`Session::negotiate_deadlines`]).
+    /// It's also the deadline of the [`RequestContext`](context::RequestContext) of the handler
+    pub deadline: Option<Instant>,
+    /// The blobs sent along a request out of its arguments, see [`Session::request_with_attachments`]
+    pub attachments: &'a [Vec<u8>],
 }
 
 impl<'a> Arg<'a> {
+    /// The `n`th attachment of the request, a `&[u8]` is also a [`Read`](std::io::Read) to stream it
+    #[inline]
+    pub fn attachment(&self, n: usize) -> Option<&'a [u8]> {
+        self.attachments.get(n).map(Vec::as_slice)
+    }
+
+    /// Decode the arguments, the failures tell where and why in the payload
+    #[inline]
+    pub fn into<T>(self) -> Result<T, DecodeFailure> where T: DeserializeOwned {
+        self.decode()
+    }
+
+    /// Decode the arguments without copying their strings and binaries out of the received packet,
+    /// e.g. as `(&str, &[u8])`. The binaries must be sent as msgpack `bin` (with `serde_bytes`) to be borrowed
     #[inline]
-    pub fn into<T>(self) -> Result<T, DecodeError> where T: DeserializeOwned {
-        rmps::from_read_ref(self.bytes)
+    pub fn borrow<T>(&self) -> Result<T, DecodeFailure> where T: Deserialize<'a> {
+        self.decode()
+    }
+
+    /// The arguments as a dynamic msgpack value, for the services whose types are known at runtime only
+    pub fn to_value(&self) -> Result<Value, DecodeFailure> {
+        self.decode::<dynamic::Dynamic>().map(|d| d.0)
+    }
+
+    fn decode<T: Deserialize<'a>>(&self) -> Result<T, DecodeFailure> {
+        decode_arg(self.bytes).map_err(|e| {
+            let method = self.method.to_string();
+            failure::explain::<T>(self.bytes, method, false, e)
+        })
     }
 }
 
-/// Returner for a request, which can response some data
+/// Returner for a request, which can response some data. With the `fn_traits` feature (nightly only) it can be called
+/// like a function, `ret(value)` is `ret.ok(value)` and `ret(result)` is `ret.forward(result)`
 pub struct Ret<'a, 'b> {
     ss: &'a Session,
-    req_id: &'b mut Option<u32>,
+    req_id: &'b mut Option<u64>,
 }
 
+#[cfg(feature = "fn_traits")]
 impl<T> std::ops::FnOnce<(T, )> for Ret<'_, '_> where T: Serialize {
     type Output = ();
 
-    extern "rust-call" fn call_once(self, arg: (T, )) -> Self::Output {
-        if let Some(req_id) = self.req_id.take() {
-            self.ss.response(req_id, arg.0);
-        }
-    }
+    extern "rust-call" fn call_once(self, arg: (T, )) -> Self::Output { self.ok(arg.0) }
 }
 
+#[cfg(feature = "fn_traits")]
 impl std::ops::FnOnce<(RequestResult, )> for Ret<'_, '_> {
     type Output = ();
 
-    extern "rust-call" fn call_once(self, arg: (RequestResult, )) -> Self::Output {
-        match arg.0 {
+    extern "rust-call" fn call_once(self, arg: (RequestResult, )) -> Self::Output { self.forward(arg.0) }
+}
+
+impl<'a, 'b> Ret<'a, 'b> {
+    pub fn ok(self, value: impl Serialize) {
+        if let Some(req_id) = self.req_id.take() {
+            self.ss.response(req_id, value);
+        }
+    }
+
+    /// Answer `value` along blobs sent out of it, see [`Session::request_with_attachments`]
+    pub fn ok_with_attachments(self, value: impl Serialize, attachments: &[&[u8]]) {
+        if let Some(req_id) = self.req_id.take() {
+            self.ss.response_with_attachments(req_id, value, attachments);
+        }
+    }
+
+    /// Answer the result of a request made to another peer, e.g. by a proxy. Nothing is answered if it failed locally
+    pub fn forward(self, result: RequestResult) {
+        match result {
             RequestResult::Data(data) => unsafe { self.ret_raw(data.as_slice()) }
-            RequestResult::Error(err) => { self.error(&err) } _ => {}
+            RequestResult::Error(err) => self.fault(&err),
+            _ => {}
         }
     }
-}
 
-impl<'a, 'b> Ret<'a, 'b> {
     pub fn error(self, s: &str) {
         if let Some(req_id) = self.req_id.take() {
             self.ss.response_error(req_id, s);
         }
     }
 
+    /// Answer an error with a code and data
+    pub fn fault(self, err: &RemoteError) {
+        if let Some(req_id) = self.req_id.take() {
+            self.ss.response_fault(req_id, err);
+        }
+    }
+
     pub unsafe fn ret_raw(self, msgpack: &[u8]) {
         if let Some(req_id) = self.req_id.take() {
             let mut resp = self.ss.prepare_response(req_id);
             encode::write_nil(&mut resp);
-            resp.extend_from_slice(msgpack);
-            self.ss.send_pack(resp);
+            self.ss.send_response(req_id, resp, msgpack);
         }
     }
 
+    /// Report the progress of the request to the requester, see [`Session::progress`]
+    pub fn progress(&self, value: impl Serialize) -> bool {
+        self.req_id.is_some_and(|req_id| self.ss.progress(req_id, value))
+    }
+
     /// Convert to AsyncRet. Be careful the session must be allocated by `Arc`
     pub unsafe fn into_async(self) -> Option<AsyncRet> {
-        self.req_id.map(|req_id| AsyncRet { ss: self.ss.arc_clone(), req_id })
+        self.req_id.take().map(|req_id| AsyncRet { ss: self.ss.arc_clone(), req_id, answered: false })
     }
 
     /// Distinguish request/notify, return true if the packet is a request
     #[inline]
-    pub fn is_valid(&self) -> bool { return self.req_id.is_some() }
+    pub fn is_valid(&self) -> bool { self.req_id.is_some() }
 }
 
-/// Asynchronous returner
+/// Asynchronous returner, like [`Ret`]. Dropped without answering, the request is left unanswered and doesn't count
+/// as in flight anymore (see [`Throttle::max_in_flight`])
 pub struct AsyncRet {
     ss: Arc<Session>,
-    req_id: u32,
+    req_id: u64,
+    answered: bool,
 }
 
 impl AsyncRet {
-    pub fn error(self, s: &str) {
-        self.ss.response_error(self.req_id, s);
+    pub fn ok(mut self, value: impl Serialize) {
+        let req_id = self.answer();
+        self.ss.response(req_id, value);
     }
 
-    pub unsafe fn ret_raw(self, msgpack: &[u8]) {
-        let mut resp = self.ss.prepare_response(self.req_id);
+    /// See [`Ret::ok_with_attachments`]
+    pub fn ok_with_attachments(mut self, value: impl Serialize, attachments: &[&[u8]]) {
+        let req_id = self.answer();
+        self.ss.response_with_attachments(req_id, value, attachments);
+    }
+
+    /// See [`Ret::forward`]
+    pub fn forward(self, result: RequestResult) {
+        match result {
+            RequestResult::Data(data) => unsafe { self.ret_raw(data.as_slice()) }
+            RequestResult::Error(err) => self.fault(&err),
+            _ => {}
+        }
+    }
+
+    pub fn error(mut self, s: &str) {
+        let req_id = self.answer();
+        self.ss.response_error(req_id, s);
+    }
+
+    /// Answer an error with a code and data
+    pub fn fault(mut self, err: &RemoteError) {
+        let req_id = self.answer();
+        self.ss.response_fault(req_id, err);
+    }
+
+    // The id of the request, which the response finishes
+    fn answer(&mut self) -> u64 {
+        self.answered = true;
+        self.req_id
+    }
+
+    /// Report the progress of the request to the requester, see [`Session::progress`]
+    pub fn progress(&self, value: impl Serialize) -> bool {
+        self.ss.progress(self.req_id, value)
+    }
+
+    pub unsafe fn ret_raw(mut self, msgpack: &[u8]) {
+        let req_id = self.answer();
+        let mut resp = self.ss.prepare_response(req_id);
         encode::write_nil(&mut resp);
-        resp.extend_from_slice(msgpack);
-        self.ss.send_pack(resp);
+        self.ss.send_response(req_id, resp, msgpack);
     }
 }
 
+impl Drop for AsyncRet {
+    fn drop(&mut self) {
+        if !self.answered { self.ss.in_flight.finish(self.req_id); }
+    }
+}
+
+#[cfg(feature = "fn_traits")]
 impl<T> std::ops::FnOnce<(T, )> for AsyncRet where T: Serialize {
     type Output = ();
 
-    extern "rust-call" fn call_once(self, arg: (T, )) -> Self::Output {
-        self.ss.response(self.req_id, &arg.0)
-    }
+    extern "rust-call" fn call_once(self, arg: (T, )) -> Self::Output { self.ok(arg.0) }
 }
 
+#[cfg(feature = "fn_traits")]
 impl std::ops::FnOnce<(RequestResult, )> for AsyncRet {
     type Output = ();
 
-    extern "rust-call" fn call_once(self, arg: (RequestResult, )) -> Self::Output {
-        match arg.0 {
-            RequestResult::Data(data) => unsafe { self.ret_raw(data.as_slice()) }
-            RequestResult::Error(err) => { self.error(&err) } _ => {}
+    extern "rust-call" fn call_once(self, arg: (RequestResult, )) -> Self::Output { self.forward(arg.0) }
+}
+
+/// Category of a [`HandleError`], which gives the code answered to the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Application,
+    Malformed,
+    MethodNotFound,
+    InvalidArgs,
+    Unauthenticated,
+    PermissionDenied,
+    LimitExceeded,
+    Throttled,
+    Unavailable,
+    Timeout,
+    Internal,
+    /// Code defined by the application
+    Custom(i64),
+}
+
+impl ErrorKind {
+    pub fn code(self) -> i64 {
+        use ErrorKind::*;
+
+        match self {
+            Application => RemoteError::APPLICATION,
+            Malformed => RemoteError::MALFORMED,
+            MethodNotFound => RemoteError::METHOD_NOT_FOUND,
+            InvalidArgs => RemoteError::INVALID_ARGS,
+            Unauthenticated => RemoteError::UNAUTHENTICATED,
+            PermissionDenied => RemoteError::PERMISSION_DENIED,
+            LimitExceeded => RemoteError::LIMIT_EXCEEDED,
+            Throttled => RemoteError::THROTTLED,
+            Unavailable => RemoteError::UNAVAILABLE,
+            Timeout => RemoteError::TIMEOUT,
+            Internal => RemoteError::INTERNAL,
+            Custom(code) => code,
         }
     }
+
+    pub fn from_code(code: i64) -> Self {
+        use ErrorKind::*;
+
+        [Application, Malformed, MethodNotFound, InvalidArgs, Unauthenticated, PermissionDenied, LimitExceeded, Throttled, Unavailable, Timeout, Internal]
+            .iter().copied().find(|k| k.code() == code).unwrap_or(Custom(code))
+    }
+}
+
+/// Error of a handler, answered to the peer as a [`RemoteError`].
+/// Implement `From<YourError>` to use `?` on your own errors
+#[derive(Debug)]
+pub struct HandleError {
+    kind: ErrorKind,
+    message: String,
+    data: Option<Vec<u8>>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl HandleError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        HandleError { kind, message: message.into(), data: None, source: None }
+    }
+
+    /// Attach a value detailing the error, sent to the peer
+    pub fn with_data(mut self, data: impl Serialize) -> Self {
+        let mut buf = Vec::new();
+        encode_arg(&data, &mut buf, false);
+        self.data = Some(buf);
+        self
+    }
+
+    /// Attach the cause of the error, it isn't sent to the peer
+    pub fn with_source(mut self, source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind { self.kind }
+
+    pub fn message(&self) -> &str { &self.message }
+
+    fn to_remote(&self) -> RemoteError {
+        RemoteError { code: self.kind.code(), message: self.message.clone(), data: self.data.clone() }
+    }
+}
+
+impl Display for HandleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult { write!(f, "{:?}: {}", self.kind, self.message) }
+}
+
+impl std::error::Error for HandleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as _)
+    }
+}
+
+impl From<&str> for HandleError {
+    fn from(message: &str) -> Self { HandleError::new(ErrorKind::Application, message) }
+}
+
+impl From<String> for HandleError {
+    fn from(message: String) -> Self { HandleError::new(ErrorKind::Application, message) }
+}
+
+/// The arguments could not be decoded
+impl From<DecodeError> for HandleError {
+    fn from(e: DecodeError) -> Self { HandleError::new(ErrorKind::InvalidArgs, e.to_string()).with_source(e) }
+}
+
+/// The arguments could not be decoded, the peer is told where and why
+impl From<DecodeFailure> for HandleError {
+    fn from(e: DecodeFailure) -> Self { HandleError::new(ErrorKind::InvalidArgs, e.to_string()).with_source(e) }
+}
+
+impl From<rmps::encode::Error> for HandleError {
+    fn from(e: rmps::encode::Error) -> Self { HandleError::new(ErrorKind::Application, e.to_string()).with_source(e) }
 }
 
-pub struct HandleError(String);
+/// Forward the error of the peer as it is
+impl From<RemoteError> for HandleError {
+    fn from(e: RemoteError) -> Self {
+        HandleError { kind: ErrorKind::from_code(e.code), message: e.message, data: e.data, source: None }
+    }
+}
 
-impl<T: std::fmt::Debug> From<T> for HandleError {
-    fn from(e: T) -> Self { HandleError(format!("{:#?}", e)) }
+/// Failure of a request made by the handler
+impl From<RequestResult> for HandleError {
+    fn from(r: RequestResult) -> Self {
+        match r {
+            RequestResult::Error(e) => e.into(),
+            r => HandleError::new(ErrorKind::Application, format!("{:?}", r)),
+        }
+    }
 }
 
 /// The method of request/notify, can be an integer or a string
@@ -289,19 +971,40 @@ impl PartialEq<str> for Method<'_> {
     }
 }
 
+/// The name of the method, an integer one by its decimal string
+impl Display for Method<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match *self {
+            Method::Int(n) => write!(f, "{}", n),
+            Method::Str(s) => f.write_str(s),
+        }
+    }
+}
+
 impl<'a> Method<'a> {
+    /// The id of the method however it's called: an integer method is its id, a string one its [`method_id`], unless
+    /// it's a decimal integer naming an integer method. The layers and the limits set by method name key their rules
+    /// by it, so calling a method by its id doesn't escape them
+    #[inline]
+    pub fn id(&self) -> u32 {
+        match *self {
+            Method::Int(n) => n,
+            Method::Str(s) => s.parse().unwrap_or_else(|_| method_id(s)),
+        }
+    }
+
     #[inline(always)]
     pub fn serialize<W: std::io::Write>(&self, w: &mut W) {
         match *self {
-            Method::Int(i) => encode::write_u32(w, i),
-            Method::Str(s) => encode::write_str(w, s),
-        };
+            Method::Int(i) => { encode::write_uint(w, i as u64); }
+            Method::Str(s) => { encode::write_str(w, s); }
+        }
     }
 
     #[inline]
     pub fn to_str(self) -> Result<&'a str, HandleError> {
         match self {
-            Method::Int(_) => Err(HandleError("Method not match".into())),
+            Method::Int(_) => Err(HandleError::new(ErrorKind::MethodNotFound, "Method not match")),
             Method::Str(s) => Ok(s),
         }
     }
@@ -310,11 +1013,19 @@ impl<'a> Method<'a> {
     pub fn to_int(self) -> Result<u32, HandleError> {
         match self {
             Method::Int(i) => Ok(i),
-            Method::Str(_) => Err(HandleError("Method not match".into())),
+            Method::Str(_) => Err(HandleError::new(ErrorKind::MethodNotFound, "Method not match")),
         }
     }
 }
 
+/// Stable integer id of the method `name`, to call it as a `Method::Int` with shorter packets: the FNV-1a hash of the
+/// name, without its top bit so it's out of the way of the method aliases (see [`Session::negotiate_aliases`]).
+/// [`router::Router`] and `#[rpc_service]` handle the ids of their methods. `method_id!("ns.name")` computes it at
+/// compile time, e.g. for the arms of [`easy_service!`]
+pub fn method_id(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5u32, |hash, b| (hash ^ b as u32).wrapping_mul(0x0100_0193)) & 0x7fff_ffff
+}
+
 /// A sugar for converting integer/string to `Method`
 pub trait ToMethod<'a> {
     fn to_method(self) -> Method<'a>;
@@ -338,8 +1049,18 @@ impl<'a> ToMethod<'a> for Method<'a> {
 /// User defined RPC service, handle the request/notify
 pub trait Service: DowncastSync {
     fn handle(&self, _ss: &Session, _arg: Arg, _ret: Ret) -> Result<(), HandleError> {
-        Err(HandleError("No this method".into()))
+        Err(HandleError::new(ErrorKind::MethodNotFound, "No this method"))
     }
+
+    /// Whether `method` is handled, told to the peers asking with [`Session::supports`]. `None` if unknown (the default)
+    fn supports(&self, _method: Method) -> Option<bool> { None }
+
+    /// The methods handled, exposed by [`introspect::Introspect`]. Empty if unknown (the default)
+    fn methods(&self) -> Vec<introspect::MethodInfo> { Vec::new() }
+
+    /// The schema the arguments of `method` must match, checked by the session before calling [`Service::handle`].
+    /// `None` to skip the check (the default)
+    fn schema(&self, _method: Method) -> Option<&schema::Schema> { None }
 }
 impl_downcast!(sync Service);
 
@@ -349,222 +1070,2125 @@ impl Service for EmptyService {}
 
 pub type ServiceType = Arc<dyn Service + Send + Sync>;
 
+// Append the encoding of an argument or a result
+pub(crate) fn encode_arg<S: Serialize>(arg: &S, w: &mut Vec<u8>, canonical: bool) {
+    if canonical {
+        canonical::append(arg, w);
+    } else {
+        ext::append(arg, w);
+    }
+}
+
+// Decode an argument or a result, in msgpack or in bincode once negotiated. The values of the other codecs
+// are converted to msgpack as they're received
+pub(crate) fn decode_arg<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, DecodeError> {
+    native::decode(bytes).unwrap_or_else(|| rmps::from_read_ref(bytes))
+}
+
+// Callback of a request with the encoded progress values, see `Session::request_progress`
+type OnProgress = Box<dyn FnMut(&[u8]) + Send>;
+
+// A request waiting for its response
+struct Waiter {
+    sender: Sender<RequestResult>,
+    since: Instant,
+    deadline: Option<Instant>,
+    method: String,
+}
+
+/// Way of a frame seen by a [`PacketTap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Observer of the raw frames of a session, see [`Session::set_packet_tap`]
+pub type PacketTap = Arc<dyn Fn(Direction, &[u8]) + Send + Sync>;
+
+/// Periodic task of a session, see [`Session::set_housekeeping`]
+pub type Housekeeping = Arc<dyn Fn(&Session) + Send + Sync>;
+
+/// Hook of [`Unanswered::Hook`], called with the method and the id of the request
+pub type UnansweredHook = Arc<dyn Fn(&Session, Method, u64) + Send + Sync>;
+
+/// What the session does with a request the service returned `Ok` from without answering it nor making an [`AsyncRet`] of it,
+/// see [`Session::set_unanswered`]
+#[derive(Clone)]
+pub enum Unanswered {
+    /// Leave the peer waiting (the default)
+    Ignore,
+    /// Answer an [`INTERNAL`](RemoteError::INTERNAL) error
+    Error,
+    /// Call the hook with the method and the id of the request, e.g. to log the bug, and leave the peer waiting
+    Hook(UnansweredHook),
+}
+
+/// Stops the loop of [`Session::spawn_loop`] by shutting the session down, without keeping the session alive
+#[derive(Clone)]
+pub struct ShutdownToken(Weak<Session>);
+
+impl ShutdownToken {
+    /// Shut the session down, its loop returns once the adaptor is closed, see [`Session::shutdown`]
+    pub fn shutdown(&self) {
+        if let Some(ss) = self.0.upgrade() { ss.shutdown(); }
+    }
+
+    /// Whether the session is shut down, disconnected or dropped
+    pub fn is_shutdown(&self) -> bool { self.0.upgrade().is_none_or(|ss| ss.is_closed()) }
+}
+
 /// Highly abstract communication endpoint
 pub struct Session {
-    sender_table: RwLock<HashMap<u32, Sender<RequestResult>>>,
+    sender_table: RwLock<HashMap<u64, Waiter>>,
+    // The thread holding `recv_mutex` and since when
+    receiver: Mutex<Option<(String, Instant)>>,
+    // When the last frame was sent and received
+    activity: Mutex<(Option<Instant>, Option<Instant>)>,
+    clock: RwLock<Arc<dyn Clock>>,
+    response_order: order::ResponseOrder,
     recv_mutex: Mutex<()>,
-    id_counter: AtomicU32,
+    // The thread running `loop_handle`, the only one receiving while it runs
+    owner: Mutex<Option<std::thread::ThreadId>>,
+    id_counter: AtomicU64,
+    decode_limits: RwLock<Option<DecodeLimits>>,
+    size_limits: RwLock<Option<SizeLimits>>,
+    throttle: RwLock<Option<throttle::Throttler>>,
+    in_flight: throttle::InFlight,
+    watchdog: RwLock<Option<Arc<Watchdog>>>,
+    draining: AtomicBool,
+    // Answer the requests asking which methods the service has
+    introspection: AtomicBool,
+    // Disconnected or shut down, it won't send nor receive anymore
+    closed: AtomicBool,
+    send_queue: RwLock<Option<SendQueue>>,
+    extensions: Extensions,
+    canonical: AtomicBool,
+    // The peer reads the timeouts of the requests
+    deadlines: AtomicBool,
+    // The peer reads the metadata of the requests
+    metadata: AtomicBool,
+    catch_panics: AtomicBool,
+    compression: RwLock<Option<Compression>>,
+    authenticator: RwLock<Option<Arc<dyn Authenticator>>>,
+    channels: Channels,
+    max_frame: AtomicUsize,
+    fragment_counter: AtomicU64,
+    reassembly: Reassembly,
+    attachments: Attachments,
+    aliases: Aliases,
+    subscriptions: Subscriptions,
+    callbacks: Callbacks,
+    listeners: listeners::Listeners,
+    handles: Handles,
+    /// Answers of the peer to `$supports`, by encoded method
+    supported: RwLock<HashMap<Vec<u8>, Option<bool>>>,
+    worker_pool: RwLock<Option<(Arc<WorkerPool>, Weak<Session>)>>,
+    notify_pool: RwLock<Option<(Arc<WorkerPool>, Weak<Session>)>>,
+    progress: Mutex<HashMap<u64, OnProgress>>,
+    // Smoothed round trip time of the pings
+    rtt: Mutex<Option<Duration>>,
+    metrics: RwLock<Option<Arc<dyn MetricsSink>>>,
+    span_sink: RwLock<Option<Arc<dyn SpanSink>>>,
+    response_cache: RwLock<Option<Arc<ResponseCache>>>,
+    retry_policy: RwLock<Option<RetryPolicy>>,
+    idempotency_cache: RwLock<Option<Arc<IdempotencyCache>>>,
+    // The idempotency keys of the requests being handled
+    idempotent: Mutex<HashMap<u64, (Arc<IdempotencyCache>, String)>>,
+    packet_tap: RwLock<Option<PacketTap>>,
+    housekeeping: RwLock<Option<(Duration, Housekeeping)>>,
+    idle_timeout: RwLock<Option<Duration>>,
+    default_timeout: RwLock<Option<Duration>>,
+    unanswered: RwLock<Unanswered>,
+    events: events::EventBus,
+    observer: RwLock<Option<Arc<dyn SessionObserver>>>,
+    // The codec of the values, msgpack or bincode once negotiated by default
+    codec: RwLock<Arc<dyn codec::Codec>>,
+    ext_handlers: RwLock<ext::ExtHandlers>,
+    buffers: buffers::BufferPool,
     pub adaptor: Arc<dyn Adaptor>,
-    pub service: ServiceType,
+    service: RwLock<ServiceType>,
 }
 
 impl Session {
     pub fn new(adaptor: Arc<dyn Adaptor>, service: ServiceType) -> Session {
         Session {
             sender_table: RwLock::new(HashMap::new()),
+            receiver: Mutex::new(None),
+            activity: Mutex::new((None, None)),
+            clock: RwLock::new(Arc::new(SystemClock)),
+            response_order: Default::default(),
             recv_mutex: Mutex::new(()),
-            id_counter: AtomicU32::new(1),
-            adaptor, service,
+            owner: Mutex::new(None),
+            id_counter: AtomicU64::new(1),
+            decode_limits: RwLock::new(None),
+            size_limits: RwLock::new(None),
+            throttle: RwLock::new(None),
+            in_flight: Default::default(),
+            watchdog: RwLock::new(None),
+            draining: AtomicBool::new(false),
+            introspection: AtomicBool::new(true),
+            closed: AtomicBool::new(false),
+            send_queue: RwLock::new(None),
+            extensions: Extensions::default(),
+            canonical: AtomicBool::new(false),
+            deadlines: AtomicBool::new(false),
+            metadata: AtomicBool::new(false),
+            catch_panics: AtomicBool::new(true),
+            compression: RwLock::new(None),
+            authenticator: RwLock::new(None),
+            channels: Channels::default(),
+            max_frame: AtomicUsize::new(0),
+            fragment_counter: AtomicU64::new(0),
+            reassembly: Reassembly::default(),
+            attachments: Attachments::default(),
+            aliases: Aliases::default(),
+            subscriptions: Subscriptions::default(),
+            callbacks: Callbacks::default(),
+            listeners: Default::default(),
+            handles: Handles::default(),
+            supported: Default::default(),
+            worker_pool: RwLock::new(None),
+            notify_pool: RwLock::new(None),
+            progress: Default::default(),
+            rtt: Mutex::new(None),
+            metrics: RwLock::new(None),
+            span_sink: RwLock::new(None),
+            response_cache: RwLock::new(None),
+            retry_policy: RwLock::new(None),
+            idempotency_cache: RwLock::new(None),
+            idempotent: Mutex::new(HashMap::new()),
+            packet_tap: RwLock::new(None),
+            housekeeping: RwLock::new(None),
+            idle_timeout: RwLock::new(None),
+            default_timeout: RwLock::new(None),
+            unanswered: RwLock::new(Unanswered::Ignore),
+            events: Default::default(),
+            observer: RwLock::new(None),
+            codec: RwLock::new(Arc::new(codec::Msgpack)),
+            ext_handlers: Default::default(),
+            buffers: buffers::BufferPool::new(),
+            adaptor, service: RwLock::new(service),
         }
     }
 
+    /// Configure a session before it starts, rather than with its setters, e.g.
+    /// `Session::builder(adaptor).service(service).default_timeout(timeout).max_payload(size).build()`
+    pub fn builder(adaptor: Arc<dyn Adaptor>) -> SessionBuilder { SessionBuilder::new(adaptor) }
+
+    /// The service handling the requests and notifies of the peer
+    pub fn service(&self) -> ServiceType { self.service.read().unwrap().clone() }
+
+    /// Handle the next requests and notifies with `service`, e.g. to reload a plugin without dropping the connection.
+    /// Those being handled finish with the previous service, which is returned
+    pub fn replace_service(&self, service: ServiceType) -> ServiceType {
+        std::mem::replace(&mut *self.service.write().unwrap(), service)
+    }
+
     /// Convert `&Session` to `Arc<Session>`. Be careful the session must be allocated by `Arc`
     pub unsafe fn arc_clone(&self) -> Arc<Session> {
         let s0 = Arc::from_raw(self as *const Session);
         let s1 = s0.clone(); std::mem::forget(s0); s1
     }
 
+    /// Per-session state shared by the handlers, e.g. `ss.extensions().insert(User(..))` and later `ss.extensions().get::<User>()`
     #[inline]
-    fn parse_method<'a>(val: &'a Value) -> Option<Method<'a>> {
-        match val {
-            Value::Integer(i) => Some(Method::Int(i.as_u64().unwrap() as u32)),
-            Value::String(s) => Some(Method::Str(s.as_str().unwrap())),
-            _ => None,
-        }
-    }
+    pub fn extensions(&self) -> &Extensions { &self.extensions }
 
-    /// Receive a packet.
-    /// This function will always block the current thread if there is no packet available.
-    pub fn recv_packet(&self) -> Option<Result<Vec<u8>, RecvError>> {
-        if let Ok(_) = self.recv_mutex.try_lock() {
-            Some(self.adaptor.recv())
-        } else { None }
-    }
+    /// Objects held for the peer, e.g. `let handle = ss.handles().insert(file)` answered by a request and
+    /// `ss.handles().get::<File>(handle)` in the next ones
+    #[inline]
+    pub fn handles(&self) -> &Handles { &self.handles }
 
-    /// Handle a packet which received by [`Session::recv_packet`]
-    pub fn handle_packet(&self, pack: Vec<u8>) {
-        let mut reader = &pack[..];
-        let start_ptr = reader.as_ptr() as usize;
-        let len = decode::read_array_len(&mut reader).unwrap();
-        let pack_type: u32 = decode::read_int(&mut reader).unwrap();
-
-        match pack_type {
-            REQUEST => {
-                assert!(len == 4);
-                let req_id: u32 = decode::read_int(&mut reader).unwrap();
-                let method_value = read_value(&mut reader).unwrap();
-                let method = Self::parse_method(&method_value).unwrap();
-
-                let mut req_wrapper = Some(req_id);
-                let ret = Ret { ss: self, req_id: &mut req_wrapper };
-                let arg = Arg { method, id: req_id, bytes: &reader };
-                if let Err(e) = self.service.handle(self, arg, ret) {
-                    self.response_error(req_id, e.0);
-                } else if req_wrapper.is_some() {
-                    // TODO: warning: not response the request
-                }
-            }
-            NOTIFY => {
-                assert!(len == 3);
-                let method_value = read_value(&mut reader).unwrap();
-                let method = Self::parse_method(&method_value).unwrap();
-                let mut req_wrapper = None;
-                let ret = Ret { ss: self, req_id: &mut req_wrapper };
-                let arg = Arg { method, id: 0, bytes: &reader };
-                self.service.handle(self, arg, ret);
-            }
-            RESPONSE => {
-                assert!(len == 4);
-                let req_id: u32 = decode::read_int(&mut reader).unwrap();
-                let error = read_value(&mut reader).unwrap();
-                if let Some(sender) = self.sender_table.write().unwrap().remove(&req_id) {
-                    sender.send(if error.is_nil() {
-                        let offset = reader.as_ptr() as usize - start_ptr;
-                        RequestResult::Data(RespData(pack, offset))
-                    } else {
-                        RequestResult::Error(error.as_str().unwrap().into())
-                    });
-                } else { panic!("sender not found"); }
-            }
-            _else => { panic!("Invalid PackType"); }
-        }
+    /// Tell the peer it can drop the object of a [`Handle`] it answered
+    pub fn release_handle(&self, handle: Handle) -> bool { self.notify(handles::RELEASE_METHOD, handle) }
+
+    /// Set the limits checked on every received packet before decoding it, `None` to disable the check (the default)
+    pub fn set_decode_limits(&self, limits: Option<DecodeLimits>) {
+        *self.decode_limits.write().unwrap() = limits;
     }
 
-    /// [`Session::recv_packet`] and then [`Session::handle_packet`] looply util the adaptor disconnect.
-    pub fn loop_handle(&self) {
-        loop {
-            match self.recv_packet() {
-                Some(Ok(pack)) => self.handle_packet(pack),
-                Some(Err(RecvError::Disconnect)) => {
-                    self.sender_table.write().unwrap().clear();
-                    break;
-                },
-                err => { panic!("unexpected error: {:?}", err); }
-            }
-        }
+    pub fn decode_limits(&self) -> Option<DecodeLimits> { *self.decode_limits.read().unwrap() }
+
+    /// Limit the size of the packets, `None` to disable the limits (the default). A larger request of the peer is
+    /// answered with a [`LIMIT_EXCEEDED`](RemoteError::LIMIT_EXCEEDED) error, as is a request of the session
+    /// (a notify fails with [`SendError::TooLarge`]). A larger response of the service is replaced by that error
+    pub fn set_size_limits(&self, limits: Option<SizeLimits>) {
+        *self.size_limits.write().unwrap() = limits;
     }
 
-    fn send_pack(&self, frame: Vec<u8>) -> bool { self.adaptor.send(frame) }
+    pub fn size_limits(&self) -> Option<SizeLimits> { *self.size_limits.read().unwrap() }
 
-    fn next_id(&self) -> u32 { self.id_counter.fetch_add(1, Ordering::SeqCst) }
+    // The length a reassembled or decompressed packet may reach
+    fn max_incoming(&self) -> usize {
+        let max_bin_len = self.decode_limits().unwrap_or_default().max_bin_len as usize;
+        self.size_limits().map_or(max_bin_len, |limits| limits.max_incoming.min(max_bin_len))
+    }
 
-    fn send_and_wait_response(&self, req_id: u32, pack: Vec<u8>) -> RequestResult {
-        use RecvError::*;
-        let (sender, recver) = channel::<RequestResult>();
-        self.sender_table.write().unwrap().insert(req_id, sender);
-        self.send_pack(pack);
-        loop {
-            if let Ok(r) = recver.try_recv() { break r; }
-            match self.recv_packet() {
-                None => break recver.recv().unwrap_or(RequestResult::Disconnect),
-                Some(Ok(pack)) => { self.handle_packet(pack); }
-                Some(Err(Disconnect)) => break RequestResult::Disconnect,
-            }
+    #[inline]
+    fn check_outgoing(&self, len: usize) -> Result<(), SendError> {
+        match self.size_limits() {
+            Some(limits) if len > limits.max_outgoing => Err(SendError::TooLarge),
+            _ => Ok(()),
         }
     }
 
-    fn prepare_request(&self, method: Method) -> (Vec<u8>, u32) {
-        let mut pack: Vec<u8> = Vec::with_capacity(0x30);
+    /// Limit the requests handled for the peer, `None` to disable the limits (the default).
+    /// The rates start over with the new limits
+    pub fn set_throttle(&self, throttle: Option<Throttle>) {
+        *self.throttle.write().unwrap() = throttle.map(throttle::Throttler::new);
+    }
+
+    /// Send packets through a queue holding at most `capacity` packets of each [`Priority`], which is drained by a writer thread.
+    /// When the queue is full, requests and notifies block or fail according to `overflow`, responses always wait.
+    /// Note the result of a send only tells whether the packet is queued
+    pub fn set_send_queue(&self, capacity: usize, overflow: Overflow) {
+        *self.send_queue.write().unwrap() = Some(SendQueue::spawn(self.adaptor.clone(), capacity, overflow));
+    }
+
+    /// Send packets directly through the adaptor again, the queued packets are still sent
+    pub fn remove_send_queue(&self) {
+        self.send_queue.write().unwrap().take();
+    }
+
+    /// Answer the peer asking which methods the service has: [`Session::supports`], and the [`introspect::LIST_METHOD`]
+    /// and [`introspect::DESCRIBE_METHOD`] of [`introspect::Introspect`]. Otherwise they get a
+    /// [`PERMISSION_DENIED`](RemoteError::PERMISSION_DENIED) error. On by default
+    pub fn set_introspection(&self, enabled: bool) {
+        self.introspection.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Serialize the arguments and results in canonical form (see [`canonical::canonicalize`]),
+    /// so equal values always produce the same bytes, e.g. for signing or caching. It costs an extra pass over the data
+    pub fn set_canonical(&self, canonical: bool) {
+        self.canonical.store(canonical, Ordering::Relaxed);
+    }
+
+    /// Catch the panics of the service (the default), answering the request with an internal error and going on with
+    /// the next packets, false to let them unwind through [`Session::loop_handle`]
+    pub fn set_catch_panics(&self, catch: bool) {
+        self.catch_panics.store(catch, Ordering::Relaxed);
+    }
+
+    /// Run a handler, its panic message if it panics and they're caught
+    fn catch_panic<T>(&self, f: impl FnOnce() -> T) -> Result<T, String> {
+        if !self.catch_panics.load(Ordering::Relaxed) { return Ok(f()); }
+        panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+            payload.downcast_ref::<&str>().map(|s| (*s).to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Box<dyn Any>".into())
+        })
+    }
+
+    /// Make the requests waiting on several threads return in the order their responses are received (the default),
+    /// false to let them return as soon as they get their response, in any order, for the highest throughput.
+    ///
+    /// When ordered, a request returns only after the requests whose responses were received before its own returned.
+    /// The thread receiving a response hands it over a channel, so what it did before is visible to the requester,
+    /// and each return happens-before the following one. Nothing is ordered between the requests and the notifies, nor
+    /// with the requests of [`multi::request_all`], whose thread waits for several responses at once
+    pub fn set_ordered_responses(&self, ordered: bool) {
+        self.response_order.set_enabled(ordered);
+    }
+
+    /// Compress the sent packets of at least `threshold` bytes, `None` to disable it (the default).
+    /// The peer must support the codec, see [`Session::negotiate_compression`]. Compressed packets are always accepted
+    pub fn set_compression(&self, compression: Option<Compression>) {
+        *self.compression.write().unwrap() = compression;
+    }
+
+    pub fn compression(&self) -> Option<Compression> { *self.compression.read().unwrap() }
+
+    /// Agree with the peer on the first of [`CompressionCodec::supported`] it also supports, then both sides compress
+    /// the packets of at least `threshold` bytes. Return `None` if there is no common codec or the peer doesn't know the negotiation
+    pub fn negotiate_compression(&self, threshold: usize) -> Option<CompressionCodec> {
+        let codecs = CompressionCodec::supported().iter().map(|&c| c as u8).collect::<Vec<_>>();
+        let codec: Option<u8> = self.request(compress::NEGOTIATE_METHOD, (codecs, threshold)).into().ok()?;
+        let codec = CompressionCodec::from_id(codec? as u64)?;
+        self.set_compression(Some(Compression { codec, threshold }));
+        Some(codec)
+    }
+
+    // Answer the negotiation request of the peer
+    fn handle_negotiation(&self, req_id: u64, args: &[u8]) {
+        let (codecs, threshold): (Vec<u8>, usize) = match rmps::from_read_ref(args) {
+            Ok(args) => args,
+            Err(_) => { self.response_error(req_id, "Malformed negotiation"); return; }
+        };
+        let codec = CompressionCodec::supported().iter().copied().find(|&c| codecs.contains(&(c as u8)));
+        self.response(req_id, codec.map(|c| c as u8));
+        // Enabled after the response, which must be readable by the peer
+        if let Some(codec) = codec {
+            self.set_compression(Some(Compression { codec, threshold }));
+        }
+    }
+
+    /// Switch both sides to bincode for the arguments and results if the peer supports it (it's built with this crate
+    /// and the `bincode` feature), return whether they did. It's faster to encode but not self-describing,
+    /// so both sides must use the same types. The packets of the session's own methods stay in msgpack. Neither side
+    /// switches if one has decode limits, and bincode arguments are refused for the methods with a schema: neither can
+    /// check them
+    pub fn negotiate_bincode(&self) -> bool {
+        if !native::supported() { return false; }
+        // Bincode values can't be checked against the decode limits
+        if self.decode_limits().is_some() { return false; }
+        let accepted = self.request(native::NEGOTIATE_METHOD, ()).into::<bool>().unwrap_or(false);
+        #[cfg(feature = "bincode")]
+        if accepted { self.set_codec(codec::Bincode); }
+        accepted
+    }
+
+    /// Send the timeout of [`Session::request_timeout`] along the request if the peer reads it (it's built with this
+    /// crate), return whether it does. Its handler gets the deadline in [`Arg::deadline`] and can stop working on it
+    pub fn negotiate_deadlines(&self) -> bool {
+        let accepted = self.request(context::NEGOTIATE_METHOD, ()).into::<bool>().unwrap_or(false);
+        self.deadlines.store(accepted, Ordering::Relaxed);
+        accepted
+    }
+
+    /// Send the trace id of the [`RequestContext`](context::RequestContext) of this thread along the requests if the
+    /// peer reads it (it's built with this crate), return whether it does. Its handler runs in a context of the same
+    /// trace id, so the requests it makes in turn carry it, on any session which negotiated it
+    pub fn negotiate_metadata(&self) -> bool {
+        let accepted = self.request(context::METADATA_METHOD, ()).into::<bool>().unwrap_or(false);
+        self.metadata.store(accepted, Ordering::Relaxed);
+        accepted
+    }
+
+    /// Call the methods of the peer by the integer aliases it gives them if it can (it's built with this crate), return
+    /// whether it does: `names` at once, the other ones after their first request or notify. The application keeps
+    /// using the names. The peer's services must not have integer methods from `0xF000_0000`, the ones of [`method_id`] never are
+    pub fn negotiate_aliases(&self, names: &[&str]) -> bool {
+        let ids = match self.request(alias::ALIAS_METHOD, (names, true)).into::<Vec<Option<u32>>>() {
+            Ok(ids) => ids,
+            Err(_) => return false,
+        };
+        for (name, id) in names.iter().zip(ids) {
+            if let Some(id) = id { self.aliases.learn(name, id); }
+        }
+        true
+    }
+
+    /// Require the peer to authenticate (see [`Session::authenticate`]) before its requests and notifies are handled,
+    /// they are rejected with an "Unauthenticated" error until then
+    pub fn set_authenticator(&self, authenticator: Option<Arc<dyn Authenticator>>) {
+        *self.authenticator.write().unwrap() = authenticator;
+    }
+
+    /// Whether the peer must authenticate, see [`Session::set_authenticator`]
+    pub fn has_authenticator(&self) -> bool { self.authenticator.read().unwrap().is_some() }
+
+    /// The identity of the authenticated peer
+    pub fn identity(&self) -> Option<Arc<Identity>> { self.extensions.get() }
+
+    /// The tenant the session serves, if a [`Server`] routed it with [`Server::with_tenants`]
+    pub fn tenant(&self) -> Option<Arc<Tenant>> { self.extensions.get() }
+
+    /// Get a fresh challenge from the peer, to be signed for [`Credentials::Signature`]
+    pub fn challenge(&self) -> Result<Vec<u8>, String> {
+        match self.request(auth::CHALLENGE_METHOD, ()) {
+            RequestResult::Error(e) => Err(e.message),
+            result => result.into::<ByteBuf>().map(ByteBuf::into_vec).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Present the credentials to the peer, return the identity it attached to the session
+    pub fn authenticate(&self, credentials: &Credentials) -> Result<Identity, String> {
+        let result = match credentials {
+            Credentials::Token(token) => self.request(auth::AUTH_METHOD, ("token", token)),
+            Credentials::Signature { public_key, signature } =>
+                self.request(auth::AUTH_METHOD, ("signature", Bytes::new(public_key), Bytes::new(signature))),
+        };
+        match result {
+            RequestResult::Error(e) => Err(e.message),
+            result => result.into::<(String, Vec<String>)>()
+                .map(|(name, roles)| Identity { name, roles }).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn authenticated(&self) -> bool {
+        self.authenticator.read().unwrap().is_none() || self.extensions.contains::<Identity>()
+    }
+
+    fn handle_auth(&self, req_id: u64, mut args: &[u8]) {
+        let authenticator = match self.authenticator.read().unwrap().clone() {
+            Some(authenticator) => authenticator,
+            None => { self.response_error(req_id, "No authentication required"); return; }
+        };
+        let credentials = match read_value(&mut args).ok().as_ref().and_then(Credentials::decode) {
+            Some(credentials) => credentials,
+            None => { self.response_error(req_id, "Malformed credentials"); return; }
+        };
+        // A challenge is only good for one attempt
+        let challenge = self.extensions.remove::<auth::Challenge>();
+        let challenge = challenge.as_ref().map(|c| &c.0[..]).unwrap_or_default();
+        match authenticator.authenticate(self, &credentials, challenge) {
+            Ok(identity) => {
+                self.response(req_id, (&identity.name, &identity.roles));
+                self.events.emit(|| SessionEvent::Authenticated(identity.clone()));
+                self.extensions.insert(identity);
+            }
+            Err(e) => self.response_fault(req_id, &RemoteError::new(RemoteError::UNAUTHENTICATED, e)),
+        }
+    }
+
+    // Answer the requests of the reserved methods, which are handled by the session itself
+    fn handle_control(&self, req_id: u64, method: &str, args: &[u8]) -> bool {
+        match method {
+            compress::NEGOTIATE_METHOD => self.handle_negotiation(req_id, args),
+            native::NEGOTIATE_METHOD => {
+                let accepted = native::supported() && self.decode_limits().is_none();
+                self.response(req_id, accepted);
+                // Enabled after the response, which must be readable by the peer
+                #[cfg(feature = "bincode")]
+                if accepted { self.set_codec(codec::Bincode); }
+            }
+            auth::CHALLENGE_METHOD => {
+                let challenge = auth::new_challenge();
+                self.response(req_id, Bytes::new(&challenge));
+                self.extensions.insert(auth::Challenge(challenge));
+            }
+            auth::AUTH_METHOD => self.handle_auth(req_id, args),
+            context::NEGOTIATE_METHOD | context::METADATA_METHOD => self.response(req_id, true),
+            alias::ALIAS_METHOD => match self.aliases.handle_alias(args) {
+                Some(ids) => self.response(req_id, ids),
+                None => self.response_error(req_id, "Malformed aliases"),
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Split the sent packets longer than `size` bytes (at least 64) into fragments, which the peer reassembles,
+    /// `None` to send them whole (the default). Fragmented packets are always accepted
+    pub fn set_max_frame(&self, size: Option<usize>) {
+        self.max_frame.store(size.map_or(0, |s| s.max(fragment::MIN_FRAME)), Ordering::Relaxed);
+    }
+
+    pub fn max_frame(&self) -> Option<usize> {
+        Some(self.max_frame.load(Ordering::Relaxed)).filter(|&s| s != 0)
+    }
+
+    /// Open a channel named `name` on the peer, which gets it with [`Session::accept_channel`]
+    pub fn open_channel<Tx: Serialize, Rx: DeserializeOwned>(self: &Arc<Self>, name: &str) -> Result<Channel<Tx, Rx>, String> {
+        Channel::open(self, name)
+    }
+
+    /// Wait for the peer to open a channel named `name`, `None` if the session disconnected
+    pub fn accept_channel<Tx: Serialize, Rx: DeserializeOwned>(self: &Arc<Self>, name: &str) -> Option<Channel<Tx, Rx>> {
+        Channel::accept(self, name)
+    }
+
+    /// Report the traffic of the session to `sink`, `None` to stop (the default)
+    pub fn set_metrics(&self, sink: Option<Arc<dyn MetricsSink>>) {
+        *self.metrics.write().unwrap() = sink;
+    }
+
+    #[inline]
+    fn metrics(&self) -> Option<Arc<dyn MetricsSink>> { self.metrics.read().unwrap().clone() }
+
+    /// Report a span for each request made or handled to `sink`, `None` to stop (the default). The trace and the
+    /// client span of a request are sent with it to the peers which negotiated it (see
+    /// [`Session::negotiate_metadata`]), so their server spans join the trace as children of the client ones
+    pub fn set_span_sink(&self, sink: Option<Arc<dyn SpanSink>>) {
+        *self.span_sink.write().unwrap() = sink;
+    }
+
+    #[inline]
+    fn span_sink(&self) -> Option<Arc<dyn SpanSink>> { self.span_sink.read().unwrap().clone() }
+
+    /// Answer the requests of the methods `cache` marks from the results it kept, `None` to stop (the default).
+    /// Keep a reference to it to invalidate them
+    pub fn set_response_cache(&self, cache: Option<Arc<ResponseCache>>) {
+        *self.response_cache.write().unwrap() = cache;
+    }
+
+    #[inline]
+    fn response_cache(&self) -> Option<Arc<ResponseCache>> { self.response_cache.read().unwrap().clone() }
+
+    /// Send the requests again when they fail as `policy` tells, `None` to stop (the default). The attempts carry an
+    /// idempotency key to the peers which negotiated the metadata (see [`Session::negotiate_metadata`]). They stop at
+    /// the deadline of the request
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+        *self.retry_policy.write().unwrap() = policy;
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> { self.retry_policy.read().unwrap().clone() }
+
+    /// Answer the requests of the peer already handled under the same idempotency key from `cache`, `None` to stop
+    /// (the default)
+    pub fn set_idempotency_cache(&self, cache: Option<Arc<IdempotencyCache>>) {
+        *self.idempotency_cache.write().unwrap() = cache;
+    }
+
+    /// Receive the events of the session from now on, until the receiver is dropped
+    pub fn events(&self) -> Receiver<SessionEvent> { self.events.subscribe() }
+
+    /// Call `observer` when the session connects, disconnects or rejects a packet, `None` to stop (the default)
+    pub fn set_observer(&self, observer: Option<Arc<dyn SessionObserver>>) {
+        *self.observer.write().unwrap() = observer;
+    }
+
+    #[inline]
+    fn observer(&self) -> Option<Arc<dyn SessionObserver>> { self.observer.read().unwrap().clone() }
+
+    /// Serialize the arguments, the results and the data of the errors sent with `codec`, [`codec::Msgpack`] by
+    /// default. The values the session receives in `codec` are converted to msgpack before they're read. A value
+    /// `codec` fails to encode is sent in msgpack, which the peer always reads
+    pub fn set_codec(&self, codec: impl codec::Codec + 'static) {
+        *self.codec.write().unwrap() = Arc::new(codec);
+    }
+
+    #[inline]
+    fn codec(&self) -> Arc<dyn codec::Codec> { self.codec.read().unwrap().clone() }
+
+    /// Convert the payload of a received packet to msgpack if it's a value of the codec of the session, and replace
+    /// its extensions with the values of their handlers. `None` if it's unchanged. The values of the other codecs
+    /// are refused, and the bincode ones while there are decode limits, they can't be checked
+    fn decode_payload(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut converted = None;
+        let mut reader = payload;
+        if let Ok(meta) = rmp::decode::read_ext_meta(&mut reader) {
+            let codec = self.codec();
+            if codec.tag() == Some(meta.typeid) {
+                if !codec::is_readable(&*codec) {
+                    if self.decode_limits().is_some() { return Err("Bincode values can't be checked against the decode limits".into()); }
+                    return Ok(None);
+                }
+                let data = reader.get(..meta.size as usize).ok_or("Truncated payload")?;
+                converted = Some(codec.to_msgpack(data)?);
+            } else if let Some(name) = codec::name(meta.typeid) {
+                return Err(format!("{} values weren't negotiated", name));
+            }
+        }
+        let handlers = self.ext_handlers.read().unwrap();
+        if handlers.is_empty() { return Ok(converted); }
+        match converted {
+            Some(msgpack) => Ok(Some(ext::transcode(&handlers, &msgpack)?.unwrap_or(msgpack))),
+            None => ext::transcode(&handlers, payload),
+        }
+    }
+
+    /// Decode the extensions of type `tag` the peer sends with `handler`, before the arguments and the results are,
+    /// e.g. the decimals of a library of another language as strings. `None` to remove it
+    pub fn set_ext_handler(&self, tag: i8, handler: Option<ext::ExtHandler>) {
+        let mut handlers = self.ext_handlers.write().unwrap();
+        match handler {
+            Some(handler) => { handlers.insert(tag, handler); }
+            None => { handlers.remove(&tag); }
+        }
+    }
+
+    /// Call `tap` with every frame as handed to the adaptor and as received from it (so compressed or fragmented),
+    /// `None` to remove it (the default)
+    pub fn set_packet_tap(&self, tap: Option<PacketTap>) {
+        *self.packet_tap.write().unwrap() = tap;
+    }
+
+    #[inline]
+    fn packet_tap(&self) -> Option<PacketTap> { self.packet_tap.read().unwrap().clone() }
+
+    /// What to do with the requests the service forgets to answer, so the peer doesn't wait for them silently
+    pub fn set_unanswered(&self, unanswered: Unanswered) {
+        *self.unanswered.write().unwrap() = unanswered;
+    }
+
+    /// Run `task` every `interval` from [`Session::loop_handle`], e.g. to send heartbeats or give up on old requests.
+    /// It runs without traffic if the adaptor implements [`Adaptor::recv_timeout`], otherwise only between the packets
+    pub fn set_housekeeping(&self, housekeeping: Option<(Duration, Housekeeping)>) {
+        *self.housekeeping.write().unwrap() = housekeeping;
+    }
+
+    fn housekeeping(&self) -> Option<(Duration, Housekeeping)> { self.housekeeping.read().unwrap().clone() }
+
+    /// Close the session from [`Session::loop_handle`] once nothing was received for `timeout`, e.g. from a client
+    /// gone without closing its connection. It's reported as disconnected by a [`TransportError::Timeout`].
+    /// The peer can keep an idle session open with [`Session::ping`]. It's only told in time if the adaptor
+    /// implements [`Adaptor::recv_timeout`], otherwise when the next packet arrives
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        *self.idle_timeout.write().unwrap() = timeout;
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> { *self.idle_timeout.read().unwrap() }
+
+    /// Give up on the requests without a timeout of their own once `timeout` passed, `None` to wait for their
+    /// responses forever (the default). [`Session::call`] fails with [`RequestError::Timeout`], and
+    /// [`Session::request`] with a [`TIMEOUT`](RemoteError::TIMEOUT) error
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        *self.default_timeout.write().unwrap() = timeout;
+    }
+
+    // A timeout too far to be told by an instant is no deadline
+    fn default_deadline(&self) -> Option<Instant> { self.default_timeout.read().unwrap().and_then(|timeout| self.now().checked_add(timeout)) }
+
+    /// Measure the time with `clock` rather than the system's, e.g. a [`MockClock`] in tests
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.write().unwrap() = clock;
+    }
+
+    #[inline]
+    fn now(&self) -> Instant { self.clock.read().unwrap().now() }
+
+    /// Report who receives, which requests wait and for how long, the depths of the send queue and the last activity,
+    /// e.g. to log it from a watchdog when the session seems stuck
+    pub fn diagnostics(&self) -> Diagnostics {
+        // Read before the times it's compared with, which may be later
+        let now = self.now();
+        let mut pending = self.sender_table.read().unwrap().iter()
+            .map(|(&id, w)| PendingRequest { id, method: w.method.clone(), elapsed: now.saturating_duration_since(w.since) })
+            .collect::<Vec<_>>();
+        pending.sort_by(|a, b| b.elapsed.cmp(&a.elapsed).then(a.id.cmp(&b.id)));
+        let (sent, received) = *self.activity.lock().unwrap();
+        Diagnostics {
+            connected: self.adaptor.connected(),
+            receiver: self.receiver.lock().unwrap().as_ref().map(|(thread, since)| (thread.clone(), now.saturating_duration_since(*since))),
+            pending,
+            queued: self.send_queue.read().unwrap().as_ref().map(SendQueue::depths),
+            since_sent: sent.map(|t| now.saturating_duration_since(t)),
+            since_received: received.map(|t| now.saturating_duration_since(t)),
+        }
+    }
+
+    /// Handle the requests on the threads of `pool` rather than on the thread receiving them, `None` to handle them inline (the default).
+    /// A slow handler doesn't delay the following packets, so the responses may be sent in a different order than the requests
+    pub fn set_worker_pool(self: &Arc<Self>, pool: Option<Arc<WorkerPool>>) {
+        *self.worker_pool.write().unwrap() = pool.map(|pool| (pool, Arc::downgrade(self)));
+    }
+
+    fn worker_pool(&self) -> Option<(Arc<WorkerPool>, Arc<Session>)> {
+        let worker_pool = self.worker_pool.read().unwrap();
+        let (pool, ss) = worker_pool.as_ref()?;
+        Some((pool.clone(), ss.upgrade()?))
+    }
+
+    /// Handle the notifies on the threads of `pool`, apart from the requests, so a burst of one doesn't delay the other.
+    /// `None` to handle them like the requests (the default). They stay in order with a pool of a single thread
+    pub fn set_notify_pool(self: &Arc<Self>, pool: Option<Arc<WorkerPool>>) {
+        *self.notify_pool.write().unwrap() = pool.map(|pool| (pool, Arc::downgrade(self)));
+    }
+
+    fn notify_pool(&self) -> Option<(Arc<WorkerPool>, Arc<Session>)> {
+        let notify_pool = self.notify_pool.read().unwrap();
+        let (pool, ss) = notify_pool.as_ref()?;
+        Some((pool.clone(), ss.upgrade()?))
+    }
+
+    // Check the arguments against the schema of the service, the bincode ones can't be so they're refused
+    fn validate(&self, method: Method, args: &[u8]) -> Result<(), RemoteError> {
+        let service = self.service();
+        let schema = match service.schema(method) { Some(schema) => schema, None => return Ok(()) };
+        if native::is_encoded(args) {
+            return Err(RemoteError::new(RemoteError::INVALID_ARGS, "Bincode arguments can't be checked against the schema"));
+        }
+        let value = read_value(&mut &args[..]).map_err(|_| RemoteError::new(RemoteError::MALFORMED, "Malformed arguments"))?;
+        schema.validate(&value).map_err(|e| {
+            RemoteError::new(RemoteError::INVALID_ARGS, e.to_string()).with_data((&e.path, &e.expected))
+        })
+    }
+
+    fn handle_notify(&self, method: Method, args: &[u8], ack: Option<u64>) {
+        let mut req_wrapper = None;
+        let ret = Ret { ss: self, req_id: &mut req_wrapper };
+        let arg = Arg { method, id: 0, bytes: args, deadline: None, attachments: &[] };
+        if let Some(cache) = self.response_cache() { cache.notified(method); }
+        if self.listeners.notified(method, args) {
+            if let Some(id) = ack { self.acknowledge(id); }
+            return;
+        }
+        self.catch_panic(|| context::RequestContext::new(None, method).scope(|| self.service().handle(self, arg, ret)));
+        if let Some(id) = ack { self.acknowledge(id); }
+    }
+
+    fn handle_request(&self, req_id: u64, method: Method, args: &[u8], deadline: Option<Instant>, metadata: context::Metadata) {
+        if let Err(e) = self.validate(method, args) { return self.response_fault(req_id, &e); }
+        let cache = self.idempotency_cache.read().unwrap().clone();
+        if let (Some(cache), Some(key)) = (cache, metadata.idempotency_key) {
+            match cache.begin(&key, self.now()) {
+                retry::Attempt::First => { self.idempotent.lock().unwrap().insert(req_id, (cache, key)); }
+                retry::Attempt::Running => {
+                    return self.response_fault(req_id, &RemoteError::new(RemoteError::UNAVAILABLE, "Duplicate request in progress"));
+                }
+                retry::Attempt::Done(response) => {
+                    let mut pack = self.prepare_response(req_id);
+                    pack.extend_from_slice(&response);
+                    self.send_response(req_id, pack, &[]);
+                    return;
+                }
+            }
+        }
+        let metrics = self.metrics();
+        let start = metrics.as_ref().map(|m| { m.request_received(method); self.now() });
+        let mut req_wrapper = Some(req_id);
+        let ret = Ret { ss: self, req_id: &mut req_wrapper };
+        let attachments = self.attachments.take(req_id, false);
+        let arg = Arg { method, id: req_id, bytes: args, deadline, attachments: &attachments };
+        let mut context = context::RequestContext::new(Some(req_id), method);
+        context.deadline = deadline;
+        context.trace_id = metadata.trace_id;
+        let sink = self.span_sink();
+        let span = sink.as_ref().map(|_| {
+            // A request from outside of any trace starts one
+            let trace_id = context.trace_id.get_or_insert_with(telemetry::new_trace_id).clone();
+            let span_id = context.span_id.get_or_insert_with(telemetry::new_span_id).clone();
+            (trace_id, span_id, SystemTime::now(), self.now())
+        });
+        let span_method = sink.as_ref().map(|_| context.method.clone());
+        let service = self.service();
+        let result = match self.catch_panic(|| context.scope(|| service.handle(self, arg, ret))) {
+            Ok(result) => result,
+            // Answered before it panicked
+            Err(_) if req_wrapper.is_none() => Ok(()),
+            Err(message) => Err(HandleError::new(ErrorKind::Internal, format!("The handler panicked: {}", message))),
+        };
+        if let (Some(metrics), Some(start)) = (metrics, start) {
+            metrics.request_handled(method, self.now() - start, result.is_err());
+        }
+        if let (Some(sink), Some((trace_id, span_id, start, instant)), Some(method)) = (sink, span, span_method) {
+            sink.span(Span {
+                kind: SpanKind::Server, method, trace_id, span_id, parent_span_id: metadata.span_id, start,
+                duration: self.now() - instant, error: result.as_ref().err().map(|e| e.to_remote().code),
+                request_size: args.len(), response_size: None,
+            });
+        }
+        if let Err(e) = result {
+            self.response_fault(req_id, &e.to_remote());
+        } else if req_wrapper.is_some() {
+            let unanswered = self.unanswered.read().unwrap().clone();
+            match unanswered {
+                Unanswered::Ignore => self.in_flight.finish(req_id),
+                Unanswered::Error => self.response_fault(req_id, &RemoteError::new(RemoteError::INTERNAL, "Not answered")),
+                Unanswered::Hook(hook) => {
+                    hook(self, method, req_id);
+                    self.in_flight.finish(req_id);
+                }
+            }
+        }
+    }
+
+    /// Subscribe to `topic` on the peer, which publishes it with a [`Broker`].
+    /// The messages are received as notifies whose method is `topic`
+    pub fn subscribe(&self, topic: &str) -> Result<(), String> {
+        self.request(pubsub::SUBSCRIBE_METHOD, topic).into::<()>().map_err(|e| match e {
+            RequestResult::Error(e) => e.message, r => format!("{:?}", r)
+        })
+    }
+
+    /// Subscribe to `topic` like [`Session::subscribe`], the events of the peer's [`History`] since `since` are
+    /// received first. Return the sequence number of the last event published to `topic`, the next ones are live
+    pub fn subscribe_since(&self, topic: &str, since: Since) -> Result<u64, String> {
+        let (kind, n) = since.encode();
+        self.request(pubsub::SUBSCRIBE_METHOD, (topic, kind, n)).into::<u64>().map_err(|e| match e {
+            RequestResult::Error(e) => e.message, r => format!("{:?}", r)
+        })
+    }
+
+    pub fn unsubscribe(&self, topic: &str) -> Result<(), String> {
+        self.request(pubsub::UNSUBSCRIBE_METHOD, topic).into::<()>().map_err(|e| match e {
+            RequestResult::Error(e) => e.message, r => format!("{:?}", r)
+        })
+    }
+
+    /// Whether the peer handles `method`, `None` if it can't tell (its service doesn't know, or it's an older peer).
+    /// The peer is asked once, its answer is kept for the session
+    pub fn supports<'a>(&self, method: impl ToMethod<'a>) -> Option<bool> {
+        let mut key = Vec::new();
+        method.to_method().serialize(&mut key);
+        if let Some(&supported) = self.supported.read().unwrap().get(&key) { return supported; }
+        let supported = match unsafe { self.request_transfer(SUPPORTS_METHOD, &key) } {
+            RequestResult::Disconnect | RequestResult::WouldBlock => return None,
+            r => r.into::<Option<bool>>().ok().flatten(),
+        };
+        self.supported.write().unwrap().insert(key, supported);
+        supported
+    }
+
+    /// Do a request, or return `default` if the peer doesn't handle `method`: it told so with [`Session::supports`]
+    /// or answered `MethodNotFound`, which is kept for the next calls
+    pub fn call_or<'a, T: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize, default: T) -> Result<T, RequestResult> {
+        let method = method.to_method();
+        if self.supports(method) == Some(false) { return Ok(default); }
+        match self.request(method, arg) {
+            RequestResult::Error(e) if e.code == RemoteError::METHOD_NOT_FOUND => {
+                let mut key = Vec::new();
+                method.serialize(&mut key);
+                self.supported.write().unwrap().insert(key, Some(false));
+                Ok(default)
+            }
+            r => r.into(),
+        }
+    }
+
+    /// Whether the peer subscribed to `topic`
+    pub fn subscribed(&self, topic: &str) -> bool { self.subscriptions.contains(topic) }
+
+    /// Limit the count of topics the peer subscribes to, `None` for no limit (the default). A subscription past it
+    /// is answered with a [`LIMIT_EXCEEDED`](RemoteError::LIMIT_EXCEEDED) error
+    pub fn set_max_subscriptions(&self, max: Option<usize>) { self.subscriptions.set_max(max); }
+
+    /// Register `f` for the peer to call with [`Session::call_back`], e.g. to observe something it holds: the [`Callback`]
+    /// goes in the arguments of a request. `f` runs on the thread receiving the packets, the arguments it can't decode
+    /// are skipped. It's revoked when the session disconnects
+    pub fn callback<T: DeserializeOwned>(&self, f: impl Fn(T) + Send + Sync + 'static) -> Callback {
+        self.callbacks.register(Arc::new(move |args| if let Ok(arg) = decode_arg(args) { f(arg) }))
+    }
+
+    /// Receive the notifies of `method` from the peer on a channel instead of the service, until it's dropped.
+    /// Each channel of the same method gets them. They're closed when the session disconnects
+    pub fn on_notify<'a>(&self, method: impl ToMethod<'a>) -> Receiver<RespData> { self.listeners.listen(method.to_method()) }
+
+    /// Forget a closure of [`Session::callback`], the later calls of the peer are ignored. False if it's unknown
+    pub fn revoke_callback(&self, callback: Callback) -> bool { self.callbacks.revoke(callback) }
+
+    /// Call a closure the peer registered with [`Session::callback`] and passed in some arguments
+    pub fn call_back(&self, callback: Callback, arg: impl Serialize) -> bool {
+        self.notify(callback::CALLBACK_METHOD, (callback, arg))
+    }
+
+    /// Stream the data of `reader` to the peer, which gets it with [`Session::receive_stream`] on the same `name`.
+    /// `progress` is called with the count of bytes sent so far, the count is returned once the peer wrote all of them
+    pub fn send_stream<R: Read>(self: &Arc<Self>, name: &str, reader: R, progress: impl FnMut(u64)) -> io::Result<u64> {
+        let channel = self.open_channel(name).map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+        tunnel::send_stream(&channel, reader, progress)
+    }
+
+    /// Wait for a stream sent with [`Session::send_stream`] on `name` and write it to `writer`,
+    /// `progress` is called with the count of bytes received so far
+    pub fn receive_stream<W: Write>(self: &Arc<Self>, name: &str, writer: W, progress: impl FnMut(u64)) -> io::Result<u64> {
+        let channel = self.accept_channel(name).ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        tunnel::receive_stream(&channel, writer, progress)
+    }
+
+    /// The error which broke the adaptor, see [`Adaptor::last_error`]
+    pub fn transport_error(&self) -> Option<TransportError> { self.adaptor.last_error() }
+
+    /// The address of the peer, see [`Adaptor::peer_addr`]
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> { self.adaptor.peer_addr() }
+
+    /// See [`Adaptor::transport_kind`]
+    pub fn transport_kind(&self) -> Option<&'static str> { self.adaptor.transport_kind() }
+
+    /// See [`Adaptor::peer_certificate`]
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> { self.adaptor.peer_certificate() }
+
+    /// Answer the following requests with an [`UNAVAILABLE`](RemoteError::UNAVAILABLE) error and wait for the ones being
+    /// handled to be answered, at most `timeout`. Return false if some still aren't. The session stays connected
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+        let clock = self.clock.read().unwrap().clone();
+        self.in_flight.wait_idle(timeout, &*clock)
+    }
+
+    pub fn is_draining(&self) -> bool { self.draining.load(Ordering::SeqCst) }
+
+    /// Close the adaptor and fail the requests waiting for their response with [`RequestResult::Disconnect`], the
+    /// following ones fail at once. The threads receiving, e.g. in [`Session::loop_handle`], return once the adaptor
+    /// unblocks them, or before receiving again if it can't. It's done when the session is dropped
+    pub fn shutdown(&self) {
+        if self.is_closed() { return; }
+        self.adaptor.close();
+        self.disconnected(None);
+    }
+
+    /// Whether the session disconnected or was shut down
+    pub fn is_closed(&self) -> bool { self.closed.load(Ordering::SeqCst) }
+
+    /// Answer the requests the service takes too long to with a [`TIMEOUT`](RemoteError::TIMEOUT) error, `None` to wait
+    /// for the service however long it takes (the default). A thread checks them until the session is disconnected, and
+    /// reports each one with a [`SessionEvent::HandlerStalled`]. The late responses of the service are dropped
+    pub fn set_watchdog(self: &Arc<Self>, watchdog: Option<Watchdog>) {
+        let watchdog = watchdog.map(Arc::new);
+        *self.watchdog.write().unwrap() = watchdog.clone();
+        let (watchdog, ss) = match watchdog { Some(watchdog) => (watchdog, Arc::downgrade(self)), None => return };
+        // The clock is taken again each tick, it may be replaced
+        std::thread::spawn(move || while let Some(clock) = ss.upgrade().map(|ss| ss.clock.read().unwrap().clone()) {
+            clock.sleep(watchdog.tick());
+            let ss = match ss.upgrade() { Some(ss) => ss, None => break };
+            let current = ss.watchdog.read().unwrap().as_ref().is_some_and(|w| Arc::ptr_eq(w, &watchdog));
+            if !current || !ss.adaptor.connected() { break; }
+            for (req_id, method, limit) in ss.in_flight.expire(&watchdog, ss.now()) {
+                let mut pack = ss.prepare_response(req_id);
+                protocol::write_fault(&mut pack, &RemoteError::new(RemoteError::TIMEOUT, "Timed out"));
+                let _ = ss.send_parts(pack, &[], Priority::Normal, true);
+                ss.events.emit(|| SessionEvent::HandlerStalled { request_id: req_id, method, limit });
+            }
+        });
+    }
+
+    /// Apply the recommended settings for a session facing untrusted peers (e.g. internet-facing servers):
+    /// * [`DecodeLimits::strict`] on every received packet
+    /// * the default [`SizeLimits`]
+    /// * a bounded send queue, so a peer not reading its data can't make the session buffer without limit
+    /// * a [`Throttle`] of the requests in flight, and of the rate of each method
+    /// * a limit of the topics the peer subscribes to
+    /// * an idle timeout, the peer keeps a quiet session open with [`Session::ping`]
+    /// * no introspection, see [`Session::set_introspection`]
+    ///
+    /// It leaves to the server what a session can't know: authenticating the peers with [`Session::set_authenticator`],
+    /// the rates of the costly methods in [`Throttle::per_method`], and the `ws::HandshakeLimits`
+    /// of a listener made by `ws::bind_guarded`. The settings are applied either way, but it fails with [`NoAuthenticator`]
+    /// if none is set yet, the peers wouldn't be authenticated
+    pub fn harden(&self) -> Result<(), NoAuthenticator> {
+        const QUEUE_CAPACITY: usize = 64;
+        const MAX_IN_FLIGHT: usize = 256;
+        const MAX_RATE: u32 = 1000;
+        const MAX_SUBSCRIPTIONS: usize = 1024;
+        const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+        self.set_decode_limits(Some(DecodeLimits::strict()));
+        self.set_size_limits(Some(SizeLimits::default()));
+        self.set_throttle(Some(Throttle { default_rate: Some(MAX_RATE), ..Throttle::default() }.max_in_flight(MAX_IN_FLIGHT)));
+        self.set_send_queue(QUEUE_CAPACITY, Overflow::Block);
+        self.set_max_subscriptions(Some(MAX_SUBSCRIPTIONS));
+        self.set_idle_timeout(Some(IDLE_TIMEOUT));
+        self.set_introspection(false);
+        if self.has_authenticator() { Ok(()) } else { Err(NoAuthenticator) }
+    }
+
+    #[inline]
+    fn check_limits(&self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        match self.decode_limits() {
+            Some(limits) => limits.check(bytes).map_err(ProtocolError::LimitExceeded),
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn parse_method(val: &Value) -> Option<Method<'_>> {
+        match val {
+            Value::Integer(i) => i.as_u64().filter(|&i| i <= u32::MAX as u64).map(|i| Method::Int(i as u32)),
+            Value::String(s) => s.as_str().map(Method::Str),
+            _ => None,
+        }
+    }
+
+    // Tell the peer the alias of a method it called by its name, to use from now on
+    fn alias_on_first_use(&self, name: &str) {
+        if let Some(id) = self.aliases.first_use(name) { self.notify(alias::ALIASED_METHOD, (name, id)); }
+    }
+
+    fn packet_type(mut pack: &[u8]) -> Option<u32> {
+        decode::read_array_len(&mut pack).ok()?;
+        decode::read_int(&mut pack).ok()
+    }
+
+    /// Receive a packet.
+    /// This function will always block the current thread if there is no packet available.
+    pub fn recv_packet(&self) -> Option<Result<Vec<u8>, RecvError>> {
+        if let Ok(_guard) = self.recv_mutex.try_lock() {
+            Some(self.recv_locked(None))
+        } else { None }
+    }
+
+    /// Receive a packet if one is available, without blocking: `Err(RecvError::NoData)` if there is none
+    /// or another thread is receiving. `None` if the adaptor can't receive without blocking, see [`Adaptor::try_recv`]
+    pub fn try_recv_packet(&self) -> Option<Result<Vec<u8>, RecvError>> {
+        let _guard = match self.recv_mutex.try_lock() { Ok(guard) => guard, Err(_) => return Some(Err(RecvError::NoData)) };
+        let frame = match self.adaptor.try_recv()? { Ok(frame) => frame, Err(e) => return Some(Err(e)) };
+        Some(Ok(self.received(frame)))
+    }
+
+    /// Handle the packets available and return their count, for an event loop driving the session instead of
+    /// [`Session::loop_handle`]. Call it when [`Adaptor::raw_fd`] gets readable, or periodically.
+    /// It's always `Ok(0)` if the adaptor can't receive without blocking
+    pub fn poll(&self) -> Result<usize, RecvError> {
+        let mut count = 0;
+        loop {
+            match self.try_recv_packet() {
+                Some(Ok(pack)) => { self.handle_packet(pack); count += 1; }
+                // Told by the next call, once the packets handled are counted
+                Some(Err(RecvError::Disconnect)) if count > 0 => return Ok(count),
+                Some(Err(RecvError::Disconnect)) => { self.disconnected(self.adaptor.last_error()); return Err(RecvError::Disconnect); }
+                Some(Err(RecvError::NoData)) | None => return Ok(count),
+            }
+        }
+    }
+
+    /// Handle a packet which received by [`Session::recv_packet`].
+    /// A malformed packet is dropped (or answered with an error if it carries a request id) and reported as `Err`,
+    /// the session is still usable after that.
+    pub fn handle_packet(&self, pack: Vec<u8>) -> Result<(), ProtocolError> {
+        let result = self.handle_frame(pack);
+        if let Err(e) = &result {
+            if let (ProtocolError::LimitExceeded(_), Some(limits)) = (e, self.size_limits()) {
+                if limits.close { self.adaptor.close(); }
+            }
+            if let Some(metrics) = self.metrics() { metrics.protocol_error(e); }
+            self.events.emit(|| SessionEvent::ProtocolWarning(e.clone()));
+            if let Some(observer) = self.observer() { observer.on_error(self, e); }
+        }
+        result
+    }
+
+    // Receive a frame while holding `recv_mutex`, waiting at most `timeout`
+    fn recv_locked(&self, timeout: Option<Duration>) -> Result<Vec<u8>, RecvError> {
+        let thread = std::thread::current();
+        let name = thread.name().map_or_else(|| format!("{:?}", thread.id()), String::from);
+        *self.receiver.lock().unwrap() = Some((name, self.now()));
+        let frame = self.recv_frame(timeout);
+        *self.receiver.lock().unwrap() = None;
+        frame
+    }
+
+    fn recv_frame(&self, timeout: Option<Duration>) -> Result<Vec<u8>, RecvError> {
+        if self.is_closed() { return Err(RecvError::Disconnect); }
+        let frame = match timeout {
+            Some(timeout) => self.adaptor.recv_timeout(timeout)?,
+            None => self.adaptor.recv_buf(self.buffers.take(0))?,
+        };
+        Ok(self.received(frame))
+    }
+
+    fn received(&self, frame: Vec<u8>) -> Vec<u8> {
+        self.activity.lock().unwrap().1 = Some(self.now());
+        if let Some(metrics) = self.metrics() { metrics.bytes_received(frame.len()); }
+        if let Some(tap) = self.packet_tap() { tap(Direction::Received, &frame); }
+        frame
+    }
+
+    fn handle_frame(&self, pack: Vec<u8>) -> Result<(), ProtocolError> {
+        use ProtocolError::*;
+        use protocol::Frame;
+
+        let start_ptr = pack.as_ptr() as usize;
+        let offset = |part: &[u8]| part.as_ptr() as usize - start_ptr;
+        if let Some(limits) = self.size_limits() {
+            if pack.len() > limits.max_incoming {
+                if let Some(req_id) = protocol::request_id(&pack) {
+                    self.response_fault(req_id, &RemoteError::new(RemoteError::LIMIT_EXCEEDED, "Packet too large"));
+                }
+                return Err(LimitExceeded("packet size"));
+            }
+        }
+
+        let frame = match protocol::parse_frame(&pack, |bytes| self.check_limits(bytes)) {
+            Ok(frame) => frame,
+            Err(protocol::FrameError { error, id: Some(id) }) => {
+                let limit = |e: &ProtocolError| RemoteError::new(RemoteError::LIMIT_EXCEEDED, e.to_string());
+                match (Self::packet_type(&pack), &error) {
+                    (Some(REQUEST), LimitExceeded(_)) => self.response_fault(id, &limit(&error)),
+                    (Some(REQUEST), _) => self.response_fault(id, &RemoteError::new(RemoteError::MALFORMED, "Malformed request")),
+                    // Wake up the requester rather than leave it waiting forever
+                    (_, LimitExceeded(_)) => { self.deliver(id, RequestResult::Error(limit(&error))); }
+                    _ => {}
+                }
+                return Err(error);
+            }
+            Err(e) => return Err(e.error),
+        };
+        match frame {
+            Frame::Request { id: req_id, method, timeout, metadata, args } => {
+                let method_offset = offset(method);
+                let args_offset = offset(args);
+                let method_value = read_value(&mut &method[..]).ok().map(|m| self.aliases.resolve(m));
+                let method = match method_value.as_ref().and_then(Self::parse_method) {
+                    Some(method) => method,
+                    None => {
+                        self.response_fault(req_id, &RemoteError::new(RemoteError::MALFORMED, "Malformed request"));
+                        return Err(Malformed("request method"));
+                    }
+                };
+                // The timeout is relative, the clocks of the peers may differ. One too far to be told by an instant
+                // is none
+                let deadline = timeout.and_then(|timeout| self.now().checked_add(Duration::from_millis(timeout)));
+                let metadata = match metadata {
+                    Some(mut metadata) => match read_value(&mut metadata).ok().as_ref().and_then(context::Metadata::read) {
+                        Some(metadata) => metadata,
+                        None => {
+                            self.response_fault(req_id, &RemoteError::new(RemoteError::MALFORMED, "Malformed request"));
+                            return Err(Malformed("request metadata"));
+                        }
+                    },
+                    None => context::Metadata::default(),
+                };
+                // Until the peer is authenticated it may only authenticate, the other control methods change the session
+                let authenticating = method == Method::Str(auth::CHALLENGE_METHOD) || method == Method::Str(auth::AUTH_METHOD);
+                if !authenticating && !self.authenticated() {
+                    self.response_fault(req_id, &RemoteError::new(RemoteError::UNAUTHENTICATED, "Unauthenticated"));
+                    return Ok(());
+                }
+                let mut reader = args;
+                let formatted = match self.decode_payload(reader) {
+                    Ok(formatted) => formatted,
+                    Err(e) => { self.response_fault(req_id, &RemoteError::new(RemoteError::INVALID_ARGS, e)); return Ok(()); }
+                };
+                if let Some(args) = &formatted {
+                    if let Err(e) = self.check_limits(args) {
+                        self.response_fault(req_id, &RemoteError::new(RemoteError::LIMIT_EXCEEDED, e.to_string()));
+                        return Err(e);
+                    }
+                    reader = args;
+                }
+
+                if let Method::Str(name) = method {
+                    if self.handle_control(req_id, name, reader) { return Ok(()); }
+                    self.alias_on_first_use(name);
+                }
+                if method == Method::Str(channel::OPEN_METHOD) {
+                    match self.channels.handle_open(reader) {
+                        Ok(()) => self.response(req_id, ()),
+                        Err(e) => self.response_error(req_id, e),
+                    }
+                    return Ok(());
+                }
+                match method {
+                    Method::Str(SUPPORTS_METHOD) | Method::Str(introspect::LIST_METHOD) | Method::Str(introspect::DESCRIBE_METHOD)
+                        if !self.introspection.load(Ordering::Relaxed) =>
+                    {
+                        self.response_fault(req_id, &RemoteError::new(RemoteError::PERMISSION_DENIED, "Introspection disabled"));
+                        return Ok(());
+                    }
+                    Method::Str(name) if name == pubsub::SUBSCRIBE_METHOD || name == pubsub::UNSUBSCRIBE_METHOD => {
+                        let replay = |topic: &str, event: &pubsub::Event| {
+                            let pack = self.prepare_notify(Method::Str(topic));
+                            self.send_parts(pack, &event.arg, Priority::Normal, true);
+                        };
+                        match self.subscriptions.handle(name, reader, replay) {
+                            Ok(Some(last)) => self.response(req_id, last),
+                            Ok(None) => self.response(req_id, ()),
+                            Err(e) => self.response_fault(req_id, &e),
+                        }
+                        return Ok(());
+                    }
+                    Method::Str(SUPPORTS_METHOD) => {
+                        match read_value(&mut reader).ok().as_ref().and_then(Self::parse_method) {
+                            Some(method) => self.response(req_id, self.service().supports(method)),
+                            None => self.response_error(req_id, "Malformed method"),
+                        }
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+                let admitted = self.in_flight.start(req_id, method, self.now(), deadline, |in_flight| {
+                    if self.is_draining() { return Err(RemoteError::new(RemoteError::UNAVAILABLE, "Draining")); }
+                    self.throttle.read().unwrap().as_ref().map_or(Ok(()), |t| t.admit(in_flight, method, self.now()))
+                });
+                if let Err(e) = admitted {
+                    self.response_fault(req_id, &e);
+                    return Ok(());
+                }
+
+                if let Some((pool, ss)) = self.worker_pool() {
+                    pool.execute(move || {
+                        let method_value = read_value(&mut &pack[method_offset..args_offset]).ok().map(|m| ss.aliases.resolve(m));
+                        match method_value.as_ref().and_then(Self::parse_method) {
+                            // Cancelled while it was queued
+                            Some(_) if ss.in_flight.dropped(req_id) => ss.in_flight.finish(req_id),
+                            Some(method) => ss.handle_request(req_id, method, formatted.as_deref().unwrap_or(&pack[args_offset..]), deadline, metadata),
+                            None => ss.in_flight.finish(req_id),
+                        }
+                    });
+                    return Ok(());
+                }
+                self.handle_request(req_id, method, reader, deadline, metadata);
+                self.buffers.give(pack);
+            }
+            Frame::Notify { ack, method, args } => {
+                let method_offset = offset(method);
+                let args_offset = offset(args);
+                let method_value = self.aliases.resolve(read_value(&mut &method[..]).map_err(|_| Malformed("notify method"))?);
+                let method = Self::parse_method(&method_value).ok_or(Malformed("notify method"))?;
+                let mut reader = args;
+                if !self.authenticated() { return Ok(()); }
+                let formatted = self.decode_payload(reader).map_err(|_| Malformed("notify arguments"))?;
+                if let Some(args) = &formatted {
+                    self.check_limits(args)?;
+                    reader = args;
+                }
+                if let Method::Str(name) = method {
+                    if self.channels.handle_notify(name, reader) {
+                        if let Some(id) = ack { self.acknowledge(id); }
+                        return Ok(());
+                    }
+                    if name == PROGRESS_METHOD {
+                        return self.progressed(reader).ok_or(Malformed("progress arguments"));
+                    }
+                    if name == CANCEL_METHOD {
+                        let req_id: u64 = decode::read_int(&mut reader).map_err(|_| Malformed("cancelled request"))?;
+                        self.in_flight.cancel(req_id);
+                        return Ok(());
+                    }
+                    if name == callback::CALLBACK_METHOD {
+                        return self.callbacks.handle(reader).ok_or(Malformed("callback arguments"));
+                    }
+                    if name == handles::RELEASE_METHOD {
+                        return self.handles.handle_release(reader).ok_or(Malformed("released handle"));
+                    }
+                    if name == alias::ALIASED_METHOD {
+                        return self.aliases.handle_aliased(reader).ok_or(Malformed("method alias"));
+                    }
+                    self.alias_on_first_use(name);
+                }
+                if let Some(metrics) = self.metrics() { metrics.notify_received(method); }
+                if self.validate(method, reader).is_err() { return Err(Malformed("notify arguments")); }
+
+                if let Some((pool, ss)) = self.notify_pool() {
+                    pool.execute(move || {
+                        let method_value = read_value(&mut &pack[method_offset..args_offset]).ok().map(|m| ss.aliases.resolve(m));
+                        if let Some(method) = method_value.as_ref().and_then(Self::parse_method) {
+                            ss.handle_notify(method, formatted.as_deref().unwrap_or(&pack[args_offset..]), ack);
+                        }
+                    });
+                    return Ok(());
+                }
+                self.handle_notify(method, reader, ack);
+                self.buffers.give(pack);
+            }
+            Frame::Response { id: req_id, error, result } => {
+                // The values converted to msgpack are checked against the limits once converted, the requester is
+                // woken up with the error rather than left waiting forever
+                let mut exceeded = None;
+                let result = if error.is_nil() {
+                    let offset = offset(result);
+                    match self.decode_payload(result) {
+                        Ok(Some(data)) => match self.check_limits(&data) {
+                            Ok(()) => RequestResult::Data(RespData::new(data, 0)),
+                            Err(e) => RequestResult::Error(RemoteError::new(RemoteError::LIMIT_EXCEEDED, exceeded.get_or_insert(e).to_string())),
+                        },
+                        Ok(None) => RequestResult::Data(RespData::new(pack, offset)),
+                        Err(e) => RequestResult::Error(RemoteError::new(RemoteError::MALFORMED, e)),
+                    }
+                } else {
+                    let mut error = RemoteError::decode(&error).ok_or(Malformed("response error"))?;
+                    if let Some(data) = &error.data {
+                        match self.decode_payload(data) {
+                            Ok(Some(data)) => match self.check_limits(&data) {
+                                Ok(()) => error.data = Some(data),
+                                Err(e) => error = RemoteError::new(RemoteError::LIMIT_EXCEEDED, exceeded.get_or_insert(e).to_string()),
+                            },
+                            Ok(None) => {}
+                            Err(e) => error = RemoteError::new(RemoteError::MALFORMED, e),
+                        }
+                    }
+                    RequestResult::Error(error)
+                };
+                if !self.deliver(req_id, result) { return Err(UnknownResponse(req_id)); }
+                if let Some(e) = exceeded { return Err(e); }
+            }
+            Frame::Compressed { codec, data } => {
+                let codec = CompressionCodec::from_id(codec).ok_or(Malformed("compression codec"))?;
+                let max_len = self.max_incoming();
+                let inner = compress::decompress(codec, data, max_len).map_err(|e| match e {
+                    "decompressed length" => LimitExceeded(e),
+                    _ => Malformed(e),
+                })?;
+                // Packets are compressed before being fragmented, and only once
+                match Self::packet_type(&inner) {
+                    Some(COMPRESSED) | Some(FRAGMENT) => return Err(Malformed("nested compression")),
+                    _ => return self.handle_frame(inner),
+                }
+            }
+            Frame::Fragment { id, more, data } => {
+                let max_len = self.max_incoming();
+                if let Some(inner) = self.reassembly.push(id, more, data, max_len)? {
+                    if Self::packet_type(&inner) == Some(FRAGMENT) { return Err(Malformed("nested fragment")); }
+                    return self.handle_frame(inner);
+                }
+            }
+            Frame::Ping(id) => {
+                let mut pack = self.buffers.take(0x10);
+                protocol::write_ping(&mut pack, PONG, id);
+                let _ = self.send_parts(pack, &[], Priority::High, true);
+            }
+            Frame::Pong(id) => {
+                if !self.deliver(id, RequestResult::Data(RespData::new(Vec::new(), 0))) { return Err(UnknownResponse(id)); }
+            }
+            Frame::Attachment { id, response, data } => {
+                self.attachments.push(id, response, data, self.max_incoming())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// [`Session::recv_packet`] and then [`Session::handle_packet`] looply util the adaptor disconnect.
+    /// Malformed packets are ignored. The housekeeping task runs in between, see [`Session::set_housekeeping`].
+    /// While it runs, the requests of the other threads wait for their response to be received by it, and the ones the
+    /// service makes while handling a packet receive on it themselves, so a service can request its peer
+    pub fn loop_handle(&self) {
+        *self.owner.lock().unwrap() = Some(std::thread::current().id());
+        self.events.emit(|| SessionEvent::Connected);
+        if let Some(observer) = self.observer() { observer.on_connect(self); }
+        let mut last_run = self.now();
+        let started = last_run;
+        let mut last_expiry = last_run;
+        loop {
+            if self.now() >= last_expiry + EXPIRY_GRACE {
+                self.expire_pending();
+                last_expiry = self.now();
+            }
+            let mut timeout = match self.housekeeping() {
+                Some((interval, task)) => {
+                    if self.now() >= last_run + interval {
+                        task(self);
+                        last_run = self.now();
+                    }
+                    Some((last_run + interval).saturating_duration_since(self.now()))
+                }
+                None => None,
+            };
+            if let Some(idle) = self.idle_timeout() {
+                let last = self.activity.lock().unwrap().1.map_or(started, |received| received.max(started));
+                let remaining = (last + idle).saturating_duration_since(self.now());
+                if remaining == Duration::from_secs(0) {
+                    self.adaptor.close();
+                    self.disconnected(Some(TransportError::Timeout));
+                    break;
+                }
+                timeout = Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+            }
+            // Wait for the lock instead of giving up, a request may be receiving on another thread
+            let packet = { let _guard = self.recv_mutex.lock().unwrap(); self.recv_locked(timeout) };
+            match packet {
+                Ok(pack) => { self.handle_packet(pack); }
+                Err(RecvError::NoData) => {}
+                Err(RecvError::Disconnect) => { self.disconnected(self.adaptor.last_error()); break; }
+            }
+        }
+        *self.owner.lock().unwrap() = None;
+    }
+
+    /// Run [`Session::loop_handle`] on a new thread, until the session disconnects or the token shuts it down.
+    /// The thread keeps the session alive until then
+    pub fn spawn_loop(self: &Arc<Self>) -> (std::thread::JoinHandle<()>, ShutdownToken) {
+        let ss = self.clone();
+        (std::thread::spawn(move || ss.loop_handle()), ShutdownToken(Arc::downgrade(self)))
+    }
+
+    fn disconnected(&self, error: Option<TransportError>) {
+        // Told once, by the first thread finding it out
+        if self.closed.swap(true, Ordering::SeqCst) { return; }
+        self.sender_table.write().unwrap().clear();
+        self.channels.close_all();
+        self.reassembly.clear();
+        self.attachments.clear();
+        self.aliases.clear();
+        self.subscriptions.clear();
+        self.callbacks.clear();
+        self.listeners.clear();
+        self.handles.clear();
+        self.events.emit(|| SessionEvent::Disconnected(error));
+        if let Some(observer) = self.observer() { observer.on_disconnect(self, error); }
+    }
+
+    #[inline]
+    fn compress(&self, frame: Vec<u8>) -> Vec<u8> {
+        match self.compression() {
+            Some(c) if frame.len() >= c.threshold => compress::compress(c.codec, &frame).unwrap_or(frame),
+            _ => frame,
+        }
+    }
+
+    fn try_send_pack(&self, frame: Vec<u8>, priority: Priority) -> Result<(), SendError> {
+        self.check_outgoing(frame.len())?;
+        self.send_frames(frame, priority, false)
+    }
+
+    fn send_frames(&self, frame: Vec<u8>, priority: Priority, mut wait: bool) -> Result<(), SendError> {
+        if self.is_closed() { return Err(SendError::Disconnect); }
+        let frame = self.compress(frame);
+        let frames = match self.max_frame() {
+            Some(max) if frame.len() > max => {
+                let id = self.fragment_counter.fetch_add(1, Ordering::Relaxed);
+                fragment::split(id, &frame, max)
+            }
+            _ => vec![frame],
+        };
+        let queue = self.send_queue.read().unwrap();
+        let (metrics, tap) = (self.metrics(), self.packet_tap());
+        self.activity.lock().unwrap().0 = Some(self.now());
+        for frame in frames {
+            if let Some(metrics) = &metrics { metrics.bytes_sent(frame.len()); }
+            if let Some(tap) = &tap { tap(Direction::Sent, &frame); }
+            match queue.as_ref() {
+                Some(queue) if wait => queue.push_wait(frame, priority)?,
+                Some(queue) => queue.push(frame, priority)?,
+                None => {
+                    let mut frame = frame;
+                    if !self.adaptor.send_buf(&mut frame) { return Err(SendError::Disconnect); }
+                    self.buffers.give(frame);
+                }
+            }
+            // Once a fragment is queued, the others must follow
+            wait = true;
+        }
+        Ok(())
+    }
+
+    // Send `header` followed by the msgpack `payload`, without copying the payload when nothing has to process
+    // the whole packet (a payload format, compression, fragmentation, queue or tap)
+    fn send_parts(&self, mut header: Vec<u8>, payload: &[u8], priority: Priority, wait: bool) -> Result<(), SendError> {
+        if self.is_closed() { return Err(SendError::Disconnect); }
+        self.check_outgoing(header.len() + payload.len())?;
+        // A request of `Session::request` is already whole
+        if payload.is_empty() { return self.send_frames(header, priority, wait); }
+        let len = header.len() + payload.len();
+        let processed = self.packet_tap().is_some()
+            || self.compression().is_some_and(|c| len >= c.threshold)
+            || self.max_frame().is_some_and(|max| len > max)
+            || self.send_queue.read().unwrap().is_some();
+        if processed {
+            header.extend_from_slice(payload);
+            return self.send_frames(header, priority, wait);
+        }
+        self.activity.lock().unwrap().0 = Some(self.now());
+        if let Some(metrics) = self.metrics() { metrics.bytes_sent(len); }
+        if !self.adaptor.send_vectored(&[IoSlice::new(&header), IoSlice::new(payload)]) { return Err(SendError::Disconnect); }
+        self.buffers.give(header);
+        Ok(())
+    }
+
+    fn next_id(&self) -> u64 { self.id_counter.fetch_add(1, Ordering::SeqCst) }
+
+    // The packet is `pack` followed by the msgpack `payload`, see `send_parts`. `None` if the response wasn't received
+    // before `deadline`, the waiter is removed on every path so a late response is dropped
+    fn send_and_wait_response(&self, req_id: u64, method: Method, pack: Vec<u8>, payload: &[u8], priority: Priority, deadline: Option<Instant>) -> Option<RequestResult> {
+        use RecvError::*;
+        let (sender, recver) = channel::<RequestResult>();
+        self.response_order.waiting(req_id);
+        let method = method.to_string();
+        self.sender_table.write().unwrap().insert(req_id, Waiter { sender, since: self.now(), deadline, method });
+        self.report_in_flight();
+        if let Err(e) = self.send_parts(pack, payload, priority, false) {
+            self.sender_table.write().unwrap().remove(&req_id);
+            self.report_in_flight();
+            self.response_order.wait_turn(req_id);
+            return Some(RequestResult::from(e));
+        }
+        let result = loop {
+            if let Ok(r) = recver.try_recv() { break Some(r); }
+            let now = self.now();
+            let remaining = match deadline {
+                Some(deadline) if now >= deadline => break None,
+                Some(deadline) => Some(deadline - now),
+                None => None,
+            };
+            // Received by the thread of `loop_handle`, unless it's the one requesting, e.g. from a service
+            let owner = *self.owner.lock().unwrap();
+            let receives = owner.is_none_or(|owner| owner == std::thread::current().id());
+            // Waiting at most until the deadline, if the adaptor can tell
+            let received = if receives { self.recv_mutex.try_lock().ok().map(|_guard| self.recv_locked(remaining)) } else { None };
+            match received {
+                // Another thread receives, until the session disconnects if it's the owner. Otherwise it may stop
+                // once it has its own response, so this one takes over
+                None => {
+                    let wait = if !receives { remaining } else { Some(remaining.map_or(HANDOFF, |r| r.min(HANDOFF))) };
+                    match wait {
+                        Some(wait) => match recver.recv_timeout(wait) {
+                            Ok(r) => break Some(r),
+                            Err(RecvTimeoutError::Timeout) => {}
+                            Err(RecvTimeoutError::Disconnected) => break Some(RequestResult::Disconnect),
+                        },
+                        None => break Some(recver.recv().unwrap_or(RequestResult::Disconnect)),
+                    }
+                }
+                Some(Ok(pack)) => { self.handle_packet(pack); }
+                Some(Err(Disconnect)) => break Some(RequestResult::Disconnect),
+                Some(Err(NoData)) => {}
+            }
+        };
+        // Not answered if the session disconnected or it timed out
+        self.sender_table.write().unwrap().remove(&req_id);
+        // Delivered while timing out
+        let result = result.or_else(|| recver.try_recv().ok());
+        self.report_in_flight();
+        self.response_order.wait_turn(req_id);
+        result
+    }
+
+    // Send a request whose response is waited by `wait_response`, so a thread can wait for several ones at once.
+    // It's not in the `response_order`, which orders the requests each waited alone by its thread
+    pub(crate) fn send_request(&self, method: Method, arg: impl Serialize) -> Result<(u64, Receiver<RequestResult>), RequestResult> {
+        let (mut pack, req_id) = self.prepare_request(method, None);
+        self.serialize_args(method, &arg, &mut pack);
+        let (sender, recver) = channel::<RequestResult>();
+        let name = method.to_string();
+        self.sender_table.write().unwrap().insert(req_id, Waiter { sender, since: self.now(), deadline: None, method: name });
+        self.report_in_flight();
+        if let Err(e) = self.send_parts(pack, &[], Priority::Normal, false) {
+            self.sender_table.write().unwrap().remove(&req_id);
+            self.report_in_flight();
+            return Err(match e {
+                SendError::WouldBlock => RequestResult::WouldBlock,
+                SendError::Disconnect => RequestResult::Disconnect,
+                SendError::TooLarge => RequestResult::Error(RemoteError::new(RemoteError::LIMIT_EXCEEDED, "Request too large")),
+            });
+        }
+        Ok((req_id, recver))
+    }
+
+    // The response of `send_request`, `None` if it wasn't received within `timeout`. It's received by another thread
+    pub(crate) fn wait_response(&self, req_id: u64, recver: &Receiver<RequestResult>, timeout: Duration) -> Option<RequestResult> {
+        let result = match recver.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(RequestResult::Disconnect),
+        };
+        self.sender_table.write().unwrap().remove(&req_id);
+        self.report_in_flight();
+        result.or_else(|| recver.try_recv().ok())
+    }
+
+    /// Hand `result` to the waiter of `req_id`, false if there is none
+    fn deliver(&self, req_id: u64, result: RequestResult) -> bool {
+        let mut table = self.sender_table.write().unwrap();
+        match table.remove(&req_id) {
+            Some(Waiter { sender, method, .. }) => {
+                // Queued while holding the table, so the waiter knows it was delivered once it removed its id
+                self.response_order.delivered(req_id);
+                let mut result = result;
+                if let RequestResult::Data(data) = &mut result { data.method = method; }
+                sender.send(result);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Answer the request `id` waiting for its response with an [`RemoteError::UNAVAILABLE`] error, e.g. once the
+    /// peer is known to have dropped it. It returns at once unless its thread receives the packets, then once it
+    /// received the next one. A late response is dropped. False if it isn't waiting
+    pub fn abort_request(&self, id: u64) -> bool {
+        let aborted = self.deliver(id, RequestResult::Error(RemoteError::new(RemoteError::UNAVAILABLE, "Request aborted")));
+        if aborted { self.report_in_flight(); }
+        aborted
+    }
+
+    /// Abort the request `id` like [`Session::abort_request`], and tell the peer it can drop it: it isn't handled if
+    /// it's still queued, and its response isn't sent. The handler isn't interrupted. Older peers ignore it
+    pub fn cancel_request(&self, id: u64) -> bool {
+        let aborted = self.abort_request(id);
+        if aborted { self.notify(CANCEL_METHOD, id); }
+        aborted
+    }
+
+    /// Abort the requests waiting for their response for `age` or more, see [`Session::abort_request`]. Return their
+    /// ids, oldest first. The waiting requests are listed by [`Session::diagnostics`]
+    pub fn abort_stale(&self, age: Duration) -> Vec<u64> {
+        let stale = self.diagnostics().pending.into_iter().filter(|p| p.elapsed >= age).map(|p| p.id).collect::<Vec<_>>();
+        stale.into_iter().filter(|&id| self.abort_request(id)).collect()
+    }
+
+    // Drop the waiters left well past their deadline, whose requester is gone
+    fn expire_pending(&self) {
+        let now = self.now();
+        let expired = |w: &Waiter| w.deadline.is_some_and(|deadline| now >= deadline + EXPIRY_GRACE);
+        if !self.sender_table.read().unwrap().values().any(expired) { return; }
+        self.sender_table.write().unwrap().retain(|_, w| !expired(w));
+        self.report_in_flight();
+    }
+
+    fn report_in_flight(&self) {
+        if let Some(metrics) = self.metrics() { metrics.in_flight(self.sender_table.read().unwrap().len()); }
+    }
+
+    fn prepare_request(&self, method: Method, timeout: Option<Duration>) -> (Vec<u8>, u64) {
+        // The trace of the request being handled follows the requests it makes
+        let trace_id = context::RequestContext::current().and_then(|c| c.trace_id);
+        self.prepare_request_with(method, timeout, context::Metadata { trace_id, ..Default::default() })
+    }
+
+    fn prepare_request_with(&self, method: Method, timeout: Option<Duration>, metadata: context::Metadata) -> (Vec<u8>, u64) {
+        let mut pack = self.buffers.take(0x30);
         let req_id = self.next_id();
-        encode::write_array_len(&mut pack, 4);
-        encode::write_u32(&mut pack, REQUEST);
-        encode::write_u32(&mut pack, req_id);
-        method.serialize(&mut pack);
+        // Sent only to the peers which understand it
+        let metadata = Some(metadata).filter(|m| !m.is_empty() && self.metadata.load(Ordering::Relaxed));
+        protocol::write_request(&mut pack, req_id, self.aliases.alias(method), timeout, metadata.as_ref());
         (pack, req_id)
     }
 
-    fn serialize<S: Serialize, W: std::io::Write>(arg: &S, w: W) {
-        if cfg!(feature = "struct_map") {
-            arg.serialize(&mut Serializer::new(w).with_struct_map());
-        } else {
-            arg.serialize(&mut Serializer::new(w));
+    fn serialize<S: Serialize>(&self, arg: &S, w: &mut Vec<u8>) {
+        self.serialize_in(&*self.codec(), arg, w)
+    }
+
+    // A value the codec fails to encode is sent in msgpack
+    fn serialize_in<S: Serialize>(&self, codec: &dyn codec::Codec, arg: &S, w: &mut Vec<u8>) {
+        let len = w.len();
+        let arg = codec::Serde::canonical(arg, self.canonical.load(Ordering::Relaxed));
+        if codec::append(codec, &arg, w).is_err() {
+            w.truncate(len);
+            codec::Encode::msgpack(&arg, w);
+        }
+    }
+
+    // The session reads the arguments of its own methods itself, they stay in msgpack with a codec it can't read
+    fn serialize_args<S: Serialize>(&self, method: Method, arg: &S, w: &mut Vec<u8>) {
+        let codec = self.codec();
+        match method {
+            Method::Str(name) if name.starts_with('$') && !codec::is_readable(&*codec) => self.serialize_in(&codec::Msgpack, arg, w),
+            _ => self.serialize_in(&*codec, arg, w),
         }
     }
 
     /// Do a request.
     /// This function will always block the current thread if the other side is not response.
     pub fn request<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> RequestResult {
-        let (mut pack, req_id) = self.prepare_request(method.to_method());
-        Self::serialize(&arg, &mut pack);
-        self.send_and_wait_response(req_id, pack)
+        self.request_with_priority(method, arg, Priority::Normal)
+    }
+
+    /// Do a request whose packet is queued with `priority`, see [`Session::set_send_queue`].
+    /// The priority is not sent to the peer, it only orders the packets waiting in the local send queue
+    pub fn request_with_priority<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize, priority: Priority) -> RequestResult {
+        self.request_until(method.to_method(), arg, priority, self.default_deadline())
+            .unwrap_or_else(|| RequestResult::Error(RemoteError::new(RemoteError::TIMEOUT, "Request timed out")))
+    }
+
+    /// Do a request and decode its result, e.g. `let sum: u32 = ss.call("add", (1, 2))?`. The error tells the failures
+    /// of the transport, the errors of the peer and the results not decoded apart
+    pub fn call<'a, R: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> Result<R, RequestError> {
+        match self.request_until(method.to_method(), arg, Priority::Normal, self.default_deadline()) {
+            Some(result) => Self::decode_result(result),
+            None => Err(RequestError::Timeout),
+        }
+    }
+
+    /// Do a request and decode its result, the same as [`Session::call`]
+    #[deprecated(note = "use Session::call")]
+    pub fn try_request<'a, T: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> Result<T, RequestError> {
+        self.call(method, arg)
+    }
+
+    /// Do a request whose argument and result are dynamic msgpack values, for the tools calling methods whose types
+    /// they only know at runtime, e.g. consoles and bridges to scripting languages
+    pub fn request_value<'a>(&self, method: impl ToMethod<'a>, arg: Value) -> Result<Value, RequestError> {
+        let result: Result<dynamic::Dynamic, _> = self.call(method, dynamic::Dynamic(arg));
+        result.map(|d| d.0)
     }
 
+    /// Like [`Session::call`], but give up with [`RequestError::Timeout`] once `timeout` passed.
+    /// A thread receiving the packets itself checks it between them, so it's only exact when another thread
+    /// receives them (e.g. with [`Session::loop_handle`]) or the adaptor implements [`Adaptor::recv_timeout`]
+    pub fn request_timeout<'a, T: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize, timeout: Duration) -> Result<T, RequestError> {
+        // A timeout too far to be told by an instant is no deadline
+        let deadline = self.now().checked_add(timeout);
+        match self.request_until(method.to_method(), arg, Priority::Normal, deadline) {
+            Some(result) => Self::decode_result(result),
+            None => Err(RequestError::Timeout),
+        }
+    }
+
+    /// Like [`Session::call`], with `on_progress` called with the values the handler reports by [`Ret::progress`]
+    /// until the response. It runs on the thread receiving the packets, the ones it can't decode are skipped
+    pub fn request_progress<'a, T: DeserializeOwned, P: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize,
+        mut on_progress: impl FnMut(P) + Send + 'static) -> Result<T, RequestError>
+    {
+        let method = method.to_method();
+        let (mut pack, req_id) = self.prepare_request(method, None);
+        self.serialize_args(method, &arg, &mut pack);
+        self.progress.lock().unwrap().insert(req_id, Box::new(move |data| if let Ok(value) = decode_arg(data) { on_progress(value) }));
+        let result = self.send_and_wait_response(req_id, method, pack, &[], Priority::Normal, None);
+        self.progress.lock().unwrap().remove(&req_id);
+        Self::decode_result(result.unwrap_or(RequestResult::Disconnect))
+    }
+
+    /// Report the progress of the request `req_id` of the peer, which gets `value` if it's waiting with
+    /// [`Session::request_progress`]. Older peers ignore it
+    pub fn progress(&self, req_id: u64, value: impl Serialize) -> bool {
+        self.notify(PROGRESS_METHOD, (req_id, value))
+    }
+
+    // Hand a progress value to the callback of its request, `None` if it's malformed
+    fn progressed(&self, mut args: &[u8]) -> Option<()> {
+        if decode::read_array_len(&mut args).ok()? != 2 { return None; }
+        let req_id: u64 = decode::read_int(&mut args).ok()?;
+        if let Some(on_progress) = self.progress.lock().unwrap().get_mut(&req_id) { on_progress(args); }
+        Some(())
+    }
+
+    fn decode_result<T: DeserializeOwned>(result: RequestResult) -> Result<T, RequestError> {
+        match result {
+            RequestResult::Data(data) => RespData::into(&data).map_err(|e| RequestError::Protocol(e.to_string())),
+            result => Err(RequestError::from(result)),
+        }
+    }
+
+    // `None` if the response wasn't received before `deadline`
+    fn request_until(&self, method: Method, arg: impl Serialize, priority: Priority, deadline: Option<Instant>) -> Option<RequestResult> {
+        let policy = match self.retry_policy() {
+            Some(policy) => policy,
+            None => return self.request_attached(method, arg, None, None, priority, deadline),
+        };
+        let key = retry::new_key();
+        let mut attempt = 1;
+        loop {
+            let result = self.request_attached(method, &arg, None, Some(&key), priority, deadline);
+            match &result {
+                Some(r) if attempt < policy.max_attempts && policy.retries(r) => {}
+                _ => return result,
+            }
+            let delay = policy.delay(attempt);
+            if deadline.is_some_and(|deadline| self.now().checked_add(delay).is_none_or(|retry| retry >= deadline)) { return result; }
+            self.clock.read().unwrap().clone().sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    // `call` sent with the idempotency key of all the attempts of a request
+    pub(crate) fn call_keyed<'a, T: DeserializeOwned>(&self, method: impl ToMethod<'a>, arg: impl Serialize, key: &str) -> Result<T, RequestError> {
+        match self.request_attached(method.to_method(), arg, None, Some(key), Priority::Normal, self.default_deadline()) {
+            Some(result) => Self::decode_result(result),
+            None => Err(RequestError::Timeout),
+        }
+    }
+
+    // `call` with the arguments already encoded in msgpack, which every session accepts, handing the id of the request
+    // to `sent` before waiting for its response, to cancel it from another thread
+    pub(crate) fn call_tracked<T: DeserializeOwned>(&self, method: Method, args: &[u8], sent: impl FnOnce(u64)) -> Result<T, RequestError> {
+        let (mut pack, req_id) = self.prepare_request(method, None);
+        pack.extend_from_slice(args);
+        sent(req_id);
+        match self.send_and_wait_response(req_id, method, pack, &[], Priority::Normal, self.default_deadline()) {
+            Some(result) => Self::decode_result(result),
+            None => Err(RequestError::Timeout),
+        }
+    }
+
+    // `request_until` sending attachments ahead of the request, and receiving the ones of the response
+    fn request_attached(&self, method: Method, arg: impl Serialize, attachments: Option<attachment::Exchange>, idempotency_key: Option<&str>,
+                        priority: Priority, deadline: Option<Instant>) -> Option<RequestResult> {
+        // Sent only to the peers which understand it
+        let timeout = deadline.filter(|_| self.deadlines.load(Ordering::Relaxed)).map(|d| d.saturating_duration_since(self.now()));
+        let sink = self.span_sink();
+        // The client span, child of the one of the request being handled
+        let span = sink.as_ref().map(|_| {
+            let context = context::RequestContext::current();
+            let trace_id = context.as_ref().and_then(|c| c.trace_id.clone()).unwrap_or_else(telemetry::new_trace_id);
+            (trace_id, telemetry::new_span_id(), context.and_then(|c| c.span_id))
+        });
+        let metadata = match &span {
+            Some((trace_id, span_id, _)) => context::Metadata { trace_id: Some(trace_id.clone()), span_id: Some(span_id.clone()), idempotency_key: None },
+            None => context::Metadata { trace_id: context::RequestContext::current().and_then(|c| c.trace_id), ..Default::default() },
+        };
+        let metadata = context::Metadata { idempotency_key: idempotency_key.map(String::from), ..metadata };
+        let (mut pack, req_id) = self.prepare_request_with(method, timeout, metadata);
+        let header_len = pack.len();
+        self.serialize_args(method, &arg, &mut pack);
+        let request_size = pack.len() - header_len;
+        let cache = self.response_cache().filter(|_| attachments.is_none());
+        let cached = cache.and_then(|cache| cache.ttl(method).map(|ttl| (cache, ttl, pack[header_len..].to_vec())));
+        if let Some((cache, _, args)) = &cached {
+            if let Some(result) = cache.get(method, args, self.now()) {
+                self.buffers.give(pack);
+                return Some(RequestResult::Data(RespData::new(result, 0)));
+            }
+        }
+        if let Some((sent, _)) = &attachments {
+            self.attachments.expect(req_id);
+            if let Err(e) = self.send_attachments(req_id, false, sent, priority) {
+                self.attachments.take(req_id, true);
+                return Some(RequestResult::from(e));
+            }
+        }
+        let metrics = self.metrics();
+        let start = metrics.as_ref().map(|m| { m.request_sent(method); self.now() });
+        let span_start = (SystemTime::now(), self.now());
+        let result = self.send_and_wait_response(req_id, method, pack, &[], priority, deadline);
+        if let Some((_, received)) = attachments { *received = self.attachments.take(req_id, true); }
+        if let (Some((cache, ttl, args)), Some(RequestResult::Data(data))) = (&cached, &result) {
+            let now = self.now();
+            cache.insert(method, args, data.as_slice(), now + *ttl, now);
+        }
+        if let (Some(sink), Some((trace_id, span_id, parent_span_id))) = (sink, span) {
+            let name = method.to_string();
+            let (error, response_size) = match &result {
+                Some(RequestResult::Data(data)) => (None, Some(data.as_slice().len())),
+                Some(RequestResult::Error(e)) => (Some(e.code), None),
+                Some(RequestResult::Decode(_)) => (Some(RemoteError::MALFORMED), None),
+                Some(RequestResult::Disconnect) | Some(RequestResult::WouldBlock) => (Some(RemoteError::UNAVAILABLE), None),
+                None => (Some(RemoteError::TIMEOUT), None),
+            };
+            sink.span(Span {
+                kind: SpanKind::Client, method: name, trace_id, span_id, parent_span_id, start: span_start.0,
+                duration: self.now() - span_start.1, error, request_size, response_size,
+            });
+        }
+        let result = result?;
+        if let (Some(metrics), Some(start)) = (metrics, start) {
+            metrics.request_finished(method, self.now() - start, &result);
+        }
+        if let RequestResult::Error(error) = &result {
+            let method = method.to_string();
+            self.events.emit(|| SessionEvent::RequestFailed { method, error: error.clone() });
+        }
+        Some(result)
+    }
+
+    /// Do a request sending `attachments` along its arguments, each blob in frames of its own rather than copied into
+    /// the packet. The handler reads them with [`Arg::attachment`]. Return the result, and the attachments of the
+    /// response if it was answered with [`Ret::ok_with_attachments`]
+    pub fn request_with_attachments<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize, attachments: &[&[u8]]) -> (RequestResult, Vec<Vec<u8>>) {
+        let mut received = Vec::new();
+        let result = self.request_attached(method.to_method(), arg, Some((attachments, &mut received)), None, Priority::Normal, self.default_deadline())
+            .unwrap_or_else(|| RequestResult::Error(RemoteError::new(RemoteError::TIMEOUT, "Request timed out")));
+        (result, received)
+    }
+
+    // Send the blobs of the packet `id` ahead of it, unless they exceed the size limit
+    fn send_attachments(&self, id: u64, response: bool, attachments: &[&[u8]], priority: Priority) -> Result<(), SendError> {
+        for data in attachments { self.check_outgoing(data.len())?; }
+        for data in attachments { self.send_parts(attachment::header(id, response, data.len()), data, priority, response)?; }
+        Ok(())
+    }
+
+    /// Measure the round trip time to the peer with a ping, answered by its session without reaching the service.
+    /// Each one updates [`Session::rtt`]
+    pub fn ping(&self) -> Result<Duration, RequestError> {
+        let mut pack = self.buffers.take(0x10);
+        let id = self.next_id();
+        protocol::write_ping(&mut pack, PING, id);
+        let start = self.now();
+        match self.send_and_wait_response(id, Method::Str("$ping"), pack, &[], Priority::High, None) {
+            Some(RequestResult::Data(_)) => {}
+            Some(result) => return Err(RequestError::from(result)),
+            None => return Err(RequestError::Timeout),
+        }
+        let sample = self.now() - start;
+        let mut rtt = self.rtt.lock().unwrap();
+        // Smoothed like the round trip time of TCP, by 1/8 of each sample
+        *rtt = Some(rtt.map_or(sample, |rtt| rtt * 7 / 8 + sample / 8));
+        Ok(sample)
+    }
+
+    /// The round trip time to the peer smoothed over the pings, `None` before the first one. See [`Session::ping`]
+    pub fn rtt(&self) -> Option<Duration> { *self.rtt.lock().unwrap() }
+
     /// Do a notify.
     pub fn notify<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> bool {
-        let mut pack = self.prepare_notify(method.to_method());
-        Self::serialize(&arg, &mut pack);
-        self.send_pack(pack)
+        self.try_notify(method, arg).is_ok()
     }
 
-    fn response(&self, req_id: u32, arg: impl Serialize) {
+    /// Do a notify, tell why it failed
+    pub fn try_notify<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> Result<(), SendError> {
+        self.notify_with_priority(method, arg, Priority::Normal)
+    }
+
+    /// Do a notify whose packet is queued with `priority`, like [`Session::request_with_priority`]
+    pub fn notify_with_priority<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize, priority: Priority) -> Result<(), SendError> {
+        let method = method.to_method();
+        let mut pack = self.prepare_notify(method);
+        self.serialize_args(method, &arg, &mut pack);
+        if let Some(metrics) = self.metrics() { metrics.notify_sent(method); }
+        self.try_send_pack(pack, priority)
+    }
+
+    /// Do a notify the peer acknowledges once its service handled it, to confirm the delivery of a critical event without
+    /// a request handler. The service can't answer anything else. Older peers reject it, so it fails with [`RequestError::Timeout`]
+    pub fn notify_ack<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize, timeout: Duration) -> Result<(), RequestError> {
+        let method = method.to_method();
+        let mut pack = self.buffers.take(0x30);
+        let ack_id = self.next_id();
+        protocol::write_notify(&mut pack, Some(ack_id), self.aliases.alias(method));
+        self.serialize_args(method, &arg, &mut pack);
+        if let Some(metrics) = self.metrics() { metrics.notify_sent(method); }
+        match self.send_and_wait_response(ack_id, method, pack, &[], Priority::Normal, self.now().checked_add(timeout)) {
+            Some(RequestResult::Data(_)) => Ok(()),
+            Some(result) => Err(RequestError::from(result)),
+            None => Err(RequestError::Timeout),
+        }
+    }
+
+    /// Do a notify of `method` for each item, the envelope is encoded once.
+    /// Return the count of items sent, which stops at the first one failing
+    pub fn notify_many<'a, T: Serialize>(&self, method: impl ToMethod<'a>, items: impl IntoIterator<Item = T>) -> Result<usize, SendError> {
+        let method = method.to_method();
+        let envelope = self.prepare_notify(method);
+        let metrics = self.metrics();
+        let mut count = 0;
+        for item in items {
+            let mut pack = Vec::with_capacity(envelope.len() + 0x20);
+            pack.extend_from_slice(&envelope);
+            self.serialize_args(method, &item, &mut pack);
+            if let Some(metrics) = &metrics { metrics.notify_sent(method); }
+            self.try_send_pack(pack, Priority::Normal)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Like [`Session::notify_many`], but the argument of each notify is an array of items,
+    /// as many as fit in `max_bytes` (at least one) in msgpack. The peer handles `Vec<T>`
+    pub fn notify_many_packed<'a, T: Serialize>(&self, method: impl ToMethod<'a>, items: impl IntoIterator<Item = T>, max_bytes: usize) -> Result<usize, SendError> {
+        let method = method.to_method();
+        let envelope = self.prepare_notify(method);
+        let metrics = self.metrics();
+        let canonical = self.canonical.load(Ordering::Relaxed);
+        // In msgpack the array is written around the items encoded already, the other codecs encode the array
+        let codec = self.codec();
+        let msgpack = codec.tag().is_none();
+        let send = |packed: &[u8], batch: &[T], len: usize| {
+            let mut pack = Vec::with_capacity(envelope.len() + 5 + packed.len());
+            pack.extend_from_slice(&envelope);
+            if msgpack {
+                encode::write_array_len(&mut pack, len as u32);
+                pack.extend_from_slice(packed);
+            } else {
+                self.serialize_in(&*codec, &batch, &mut pack);
+            }
+            if let Some(metrics) = &metrics { metrics.notify_sent(method); }
+            self.try_send_pack(pack, Priority::Normal)
+        };
+        let (mut packed, mut batch, mut size, mut len, mut count) = (Vec::new(), Vec::new(), 0, 0, 0);
+        let mut item_buf = Vec::new();
+        for item in items {
+            item_buf.clear();
+            encode_arg(&item, &mut item_buf, canonical);
+            if len > 0 && size + item_buf.len() > max_bytes {
+                send(&packed, &batch, len)?;
+                count += len;
+                packed.clear();
+                batch.clear();
+                size = 0;
+                len = 0;
+            }
+            if msgpack { packed.extend_from_slice(&item_buf); } else { batch.push(item); }
+            size += item_buf.len();
+            len += 1;
+        }
+        if len > 0 {
+            send(&packed, &batch, len)?;
+            count += len;
+        }
+        Ok(count)
+    }
+
+    /// Do a request whose argument is the JSON document `json`, the result is converted with [`json::to_json`]
+    #[cfg(feature = "json")]
+    pub fn request_json<'a>(&self, method: impl ToMethod<'a>, json: &str) -> Result<serde_json::Value, String> {
+        let arg: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        match self.request(method, &arg) {
+            RequestResult::Data(data) => read_value(&mut data.as_slice()).map(|v| json::to_json(&v)).map_err(|e| e.to_string()),
+            RequestResult::Error(e) => Err(e.message),
+            result => Err(format!("{:?}", result)),
+        }
+    }
+
+    /// Do a notify whose argument is the JSON document `json`
+    #[cfg(feature = "json")]
+    pub fn notify_json<'a>(&self, method: impl ToMethod<'a>, json: &str) -> Result<(), String> {
+        let arg: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        self.try_notify(method, &arg).map_err(|e| format!("{:?}", e))
+    }
+
+    fn response(&self, req_id: u64, arg: impl Serialize) {
         let mut pack = self.prepare_response(req_id);
         encode::write_nil(&mut pack);
-        Self::serialize(&arg, &mut pack);
-        self.send_pack(pack);
+        self.serialize(&arg, &mut pack);
+        self.send_response(req_id, pack, &[]);
+    }
+
+    fn response_with_attachments(&self, req_id: u64, arg: impl Serialize, attachments: &[&[u8]]) {
+        match self.send_attachments(req_id, true, attachments, Priority::Normal) {
+            Err(SendError::TooLarge) => self.response_fault(req_id, &RemoteError::new(RemoteError::LIMIT_EXCEEDED, "Attachment too large")),
+            _ => self.response(req_id, arg),
+        }
+    }
+
+    fn response_fault(&self, req_id: u64, err: &RemoteError) {
+        let mut pack = self.prepare_response(req_id);
+        match self.data_in_codec(err) {
+            Some(err) => protocol::write_fault(&mut pack, &err),
+            None => protocol::write_fault(&mut pack, err),
+        }
+        self.send_response(req_id, pack, &[]);
+    }
+
+    // The error with its data in the codec of the session, `None` if it stays in msgpack. The data is kept in msgpack
+    // for bincode, the peer would read it in the types of its msgpack form instead of the ones it was made of
+    fn data_in_codec(&self, err: &RemoteError) -> Option<RemoteError> {
+        let codec = self.codec();
+        if codec.tag().is_none() || !codec::is_readable(&*codec) { return None; }
+        let value = read_value(&mut &err.data.as_ref()?[..]).ok()?;
+        let mut data = Vec::new();
+        self.serialize_in(&*codec, &dynamic::Dynamic(value), &mut data);
+        Some(RemoteError { data: Some(data), ..err.clone() })
     }
 
-    fn response_error(&self, req_id: u32, err: impl AsRef<str>) {
+    fn response_error(&self, req_id: u64, err: impl AsRef<str>) {
         let mut pack = self.prepare_response(req_id);
         encode::write_str(&mut pack, err.as_ref());
         encode::write_nil(&mut pack);
-        self.send_pack(pack);
+        self.send_response(req_id, pack, &[]);
     }
 
     /// Do a request with msgpack bytes.
     pub unsafe fn request_transfer<'a>(&self, method: impl ToMethod<'a>, msgpack: &[u8]) -> RequestResult {
-        let (mut pack, req_id) = self.prepare_request(method.to_method());
-        pack.extend_from_slice(msgpack);
-        self.send_and_wait_response(req_id, pack)
+        let method = method.to_method();
+        let (pack, req_id) = self.prepare_request(method, None);
+        self.send_and_wait_response(req_id, method, pack, msgpack, Priority::Normal, None).unwrap_or(RequestResult::Disconnect)
     }
 
     /// Do a notify with msgpack bytes.
     pub unsafe fn notify_transfer<'a>(&self, method: impl ToMethod<'a>, msgpack: &[u8]) -> bool {
-        let mut pack = self.prepare_notify(method.to_method());
-        pack.extend_from_slice(msgpack);
-        self.send_pack(pack)
+        let pack = self.prepare_notify(method.to_method());
+        self.send_parts(pack, msgpack, Priority::Normal, false).is_ok()
     }
 
-    pub unsafe fn response_transfer<'a>(&self, req_id: u32, msgpack: &[u8]) -> bool {
+    pub unsafe fn response_transfer(&self, req_id: u64, msgpack: &[u8]) -> bool {
         let mut pack = self.prepare_response(req_id);
         encode::write_nil(&mut pack);
-        pack.extend_from_slice(msgpack);
-        self.send_pack(pack)
+        self.send_response(req_id, pack, msgpack)
     }
 
-    pub unsafe fn response_error_transfer<'a>(&self, req_id: u32, err: &str) -> bool {
+    pub unsafe fn response_error_transfer(&self, req_id: u64, err: &str) -> bool {
         let mut pack = self.prepare_response(req_id);
         encode::write_str(&mut pack, err);
         encode::write_nil(&mut pack);
-        self.send_pack(pack)
+        self.send_response(req_id, pack, &[])
     }
 
     fn prepare_notify(&self, method: Method) -> Vec<u8> {
-        let mut pack: Vec<u8> = Vec::new();
-        encode::write_array_len(&mut pack, 3);
-        encode::write_u32(&mut pack, NOTIFY);
-        method.serialize(&mut pack);
+        let mut pack = self.buffers.take(0x30);
+        protocol::write_notify(&mut pack, None, self.aliases.alias(method));
         pack
     }
 
-    fn prepare_response(&self, req_id: u32) -> Vec<u8> {
-        let mut pack: Vec<u8> = Vec::new();
-        encode::write_array_len(&mut pack, 4);
-        encode::write_u32(&mut pack, RESPONSE);
-        encode::write_u32(&mut pack, req_id);
+    fn acknowledge(&self, ack_id: u64) {
+        let mut pack = self.prepare_response(ack_id);
+        encode::write_nil(&mut pack);
+        encode::write_nil(&mut pack);
+        let _ = self.send_parts(pack, &[], Priority::Normal, true);
+    }
+
+    // The request isn't in flight anymore once its response is handed to the adaptor or the send queue
+    fn send_response(&self, req_id: u64, pack: Vec<u8>, payload: &[u8]) -> bool {
+        // Kept for the other attempts even if this one can't be sent
+        let idempotent = self.idempotent.lock().unwrap().remove(&req_id);
+        if let Some((cache, key)) = idempotent {
+            let mut response = pack[self.prepare_response_len(req_id)..].to_vec();
+            response.extend_from_slice(payload);
+            cache.finish(&key, response);
+        }
+        if self.in_flight.dropped(req_id) {
+            self.in_flight.finish(req_id);
+            return false;
+        }
+        let sent = match self.send_parts(pack, payload, Priority::Normal, true) {
+            Err(SendError::TooLarge) => {
+                let mut pack = self.prepare_response(req_id);
+                protocol::write_fault(&mut pack, &RemoteError::new(RemoteError::LIMIT_EXCEEDED, "Response too large"));
+                self.send_parts(pack, &[], Priority::Normal, true).is_ok()
+            }
+            result => result.is_ok(),
+        };
+        self.in_flight.finish(req_id);
+        sent
+    }
+
+    fn prepare_response_len(&self, req_id: u64) -> usize {
+        let mut header = Vec::with_capacity(0x10);
+        protocol::write_response(&mut header, req_id);
+        header.len()
+    }
+
+    fn prepare_response(&self, req_id: u64) -> Vec<u8> {
+        let mut pack = self.buffers.take(0x30);
+        protocol::write_response(&mut pack, req_id);
         pack
     }
 }
 
+/// Closes the adaptor, so the peer and the threads receiving from it see the session disconnect
+impl Drop for Session {
+    fn drop(&mut self) { self.shutdown(); }
+}
+
 unsafe impl Send for Session {}
 unsafe impl Sync for Session {}
 
@@ -576,7 +3200,7 @@ macro_rules! easy_handle {
     (@expand_args $arg:ident,) => {};
 
     (@body_option $ret:ident manual $body:block) => { $body };
-    (@body_option $ret:ident $body:block) => { $ret($body) };
+    (@body_option $ret:ident $body:block) => { $ret.ok($body) };
 
     (
         @switch $switch:expr, $arg:ident, $ret:ident,
@@ -587,7 +3211,7 @@ macro_rules! easy_handle {
                 easy_handle!(@expand_args $arg, $($argdef)*);
                 easy_handle!(@body_option $ret $($body_option)? $block)
             })*
-            _ => { return Err("Unhandled Method".into()); }
+            _ => { return Err($crate::HandleError::new($crate::ErrorKind::MethodNotFound, "Unhandled Method")); }
         }
     };
 
@@ -601,7 +3225,7 @@ macro_rules! easy_handle {
         match $arg.method {
             Int(i) => easy_handle!(@switch i, $arg, $ret, $($tts_int)*),
             $(Str($str_var) => $handle_str,)?
-            _ => { return Err("Unhandled Method".into()); }
+            _ => { return Err($crate::HandleError::new($crate::ErrorKind::MethodNotFound, "Unhandled Method")); }
         }
     };
 
@@ -615,7 +3239,7 @@ macro_rules! easy_handle {
         match $arg.method {
             Str(s) => easy_handle!(@switch s, $arg, $ret, $($tts_str)*),
             $(Int($int_var) => $handle_int,)?
-            _ => { return Err("Unhandled Method".into()); }
+            _ => { return Err($crate::HandleError::new($crate::ErrorKind::MethodNotFound, "Unhandled Method")); }
         }
     };
 
@@ -643,4 +3267,98 @@ macro_rules! easy_service {
             }
         }
     };
-}
\ No newline at end of file
+}
+/// Typed client of the methods of a service, named by their string.
+/// Methods with a return type are requests returning `Result<T, RequestError>` like [`Session::call`], the others are notifies.
+/// The arguments are sent like `easy_service!` decodes them: a single one bare, several ones as a tuple.
+///
+/// `trait Calculator` generates the client `Calculator<'a>` over a `&'a Session`, the trait `CalculatorClient`
+/// of its methods for the code using it, and `MockCalculatorClient` implementing that trait without any session.
+/// Each method of the mock answers with the closure given to its `expect_` method, and panics without one
+///
+/// ```ignore
+/// rpc_interface! {
+///     pub trait Calculator {
+///         fn add(a: u32, b: u32) -> u32;
+///         fn print(msg: String);
+///     }
+/// }
+///
+/// let sum = Calculator::new(&session).add(1, 2)?;
+///
+/// let mock = MockCalculatorClient::new();
+/// mock.expect_add(|a, b| Ok(a + b));
+/// assert_eq!(total(&mock), 3);
+/// assert_eq!(mock.calls(), ["add"]);
+/// ```
+#[macro_export]
+macro_rules! rpc_interface {
+    (@ret -> $r:ty) => { Result<$r, $crate::RequestError> };
+    (@ret) => { bool };
+
+    (@call $ss:expr, $name:ident, $arg:expr, -> $r:ty) => { $ss.call::<$r>(stringify!($name), $arg) };
+    (@call $ss:expr, $name:ident, $arg:expr,) => { $ss.notify(stringify!($name), $arg) };
+
+    (
+        $(#[$attr:meta])*
+        $vis:vis trait $client:ident {
+            $($(#[$mattr:meta])* fn $name:ident ($($a:ident: $t:ty),*) $(-> $r:ty)?;)*
+        }
+    ) => { $crate::paste::item! {
+        $(#[$attr])*
+        $vis struct $client<'a>(pub &'a $crate::Session);
+
+        impl<'a> $client<'a> {
+            pub fn new(session: &'a $crate::Session) -> Self { $client(session) }
+
+            $(
+                $(#[$mattr])*
+                pub fn $name(&self, $($a: $t),*) -> $crate::rpc_interface!(@ret $(-> $r)?) {
+                    $crate::rpc_interface!(@call self.0, $name, &($($a),*), $(-> $r)?)
+                }
+            )*
+        }
+
+        /// Methods of the client, implemented by its mock too
+        $vis trait [<$client Client>] {
+            $($(#[$mattr])* fn $name(&self, $($a: $t),*) -> $crate::rpc_interface!(@ret $(-> $r)?);)*
+        }
+
+        impl [<$client Client>] for $client<'_> {
+            $(fn $name(&self, $($a: $t),*) -> $crate::rpc_interface!(@ret $(-> $r)?) { $client::$name(self, $($a),*) })*
+        }
+
+        #[derive(Default)]
+        $vis struct [<Mock $client Client>] {
+            calls: ::std::sync::Mutex<Vec<&'static str>>,
+            $($name: ::std::sync::Mutex<Option<Box<dyn FnMut($($t),*) -> $crate::rpc_interface!(@ret $(-> $r)?) + Send>>>,)*
+        }
+
+        impl [<Mock $client Client>] {
+            pub fn new() -> Self { Self::default() }
+
+            /// Names of the methods called so far
+            pub fn calls(&self) -> Vec<&'static str> { self.calls.lock().unwrap().clone() }
+
+            $(
+                pub fn [<expect_ $name>](
+                    &self, f: impl FnMut($($t),*) -> $crate::rpc_interface!(@ret $(-> $r)?) + Send + 'static
+                ) -> &Self {
+                    *self.$name.lock().unwrap() = Some(Box::new(f));
+                    self
+                }
+            )*
+        }
+
+        impl [<$client Client>] for [<Mock $client Client>] {
+            $(
+                fn $name(&self, $($a: $t),*) -> $crate::rpc_interface!(@ret $(-> $r)?) {
+                    self.calls.lock().unwrap().push(stringify!($name));
+                    let mut expectation = self.$name.lock().unwrap();
+                    let f = expectation.as_mut().unwrap_or_else(|| panic!("unexpected call of {}", stringify!($name)));
+                    f($($a),*)
+                }
+            )*
+        }
+    } };
+}
@@ -0,0 +1,91 @@
+
+use crate::marker::{Kind, Walker};
+
+/// Maximum sizes of the packets of a session, see [`Session::set_size_limits`](crate::Session::set_size_limits)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// Maximum byte length of a received packet. A fragmented or compressed one is given up once its reassembly
+    /// or decompression goes past it, rather than buffered whole
+    pub max_incoming: usize,
+    /// Maximum byte length of a sent packet, before its compression and fragmentation
+    pub max_outgoing: usize,
+    /// Close the session once the peer sends a packet exceeding a limit, the size or a decode one
+    pub close: bool,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits { max_incoming: 0x100_0000, max_outgoing: 0x100_0000, close: false }
+    }
+}
+
+/// Limits applied to the msgpack data of received packets, checked before anything is decoded.
+/// Use [`Session::set_decode_limits`](crate::Session::set_decode_limits) to enable them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Maximum nesting depth of arrays/maps
+    pub max_depth: usize,
+    /// Maximum element count of an array
+    pub max_array_len: u32,
+    /// Maximum entry count of a map
+    pub max_map_len: u32,
+    /// Maximum byte length of a string
+    pub max_str_len: u32,
+    /// Maximum byte length of a binary or an extension value
+    pub max_bin_len: u32,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_depth: 64,
+            max_array_len: 0x10_0000,
+            max_map_len: 0x1_0000,
+            max_str_len: 0x100_0000,
+            max_bin_len: 0x1000_0000,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Tighter limits for sessions facing untrusted peers, used by [`Session::harden`](crate::Session::harden)
+    pub fn strict() -> Self {
+        DecodeLimits {
+            max_depth: 16,
+            max_array_len: 0x1_0000,
+            max_map_len: 0x1000,
+            max_str_len: 0x10_0000,
+            max_bin_len: 0x100_0000,
+        }
+    }
+
+    /// No limit at all
+    pub fn unlimited() -> Self {
+        DecodeLimits {
+            max_depth: usize::MAX,
+            max_array_len: u32::MAX,
+            max_map_len: u32::MAX,
+            max_str_len: u32::MAX,
+            max_bin_len: u32::MAX,
+        }
+    }
+
+    /// Walk through the msgpack values in `buf` without decoding them, return the name of the first exceeded limit.
+    /// Truncated or malformed data is not an error here, it's left to the real decoder.
+    pub fn check(&self, buf: &[u8]) -> Result<(), &'static str> {
+        Walker::new(u64::MAX, true).walk(buf, 0, |head, depth| {
+            let (len, max, name) = match head.kind {
+                Kind::Str => (head.len, self.max_str_len, "string length"),
+                Kind::Bin | Kind::Ext => (head.len, self.max_bin_len, "binary length"),
+                Kind::Array => (head.len, self.max_array_len, "array length"),
+                Kind::Map => (head.len, self.max_map_len, "map length"),
+                Kind::Scalar | Kind::Invalid => return Ok(()),
+            };
+            if len > u64::from(max) { return Err(name); }
+            // The arrays and maps it's in, and itself
+            if head.items().is_some() && depth >= self.max_depth { return Err("nesting depth"); }
+            Ok(())
+        })?;
+        Ok(())
+    }
+}
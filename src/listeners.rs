@@ -0,0 +1,40 @@
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use crate::{Method, RespData};
+
+fn key(method: Method) -> Vec<u8> {
+    let mut key = Vec::new();
+    method.serialize(&mut key);
+    key
+}
+
+/// Channels receiving the notifies of a method instead of the service, by the serialized method
+#[derive(Default)]
+pub(crate) struct Listeners(RwLock<HashMap<Vec<u8>, Vec<Sender<RespData>>>>);
+
+impl Listeners {
+    pub fn listen(&self, method: Method) -> Receiver<RespData> {
+        let (sender, receiver) = channel();
+        self.0.write().unwrap().entry(key(method)).or_default().push(sender);
+        receiver
+    }
+
+    /// Hand the arguments of a notify to the listeners of its method, false if none is left
+    pub fn notified(&self, method: Method, args: &[u8]) -> bool {
+        let key = key(method);
+        if !self.0.read().unwrap().contains_key(&key) { return false; }
+        let mut listeners = self.0.write().unwrap();
+        let senders = match listeners.get_mut(&key) { Some(senders) => senders, None => return false };
+        let name = method.to_string();
+        senders.retain(|sender| sender.send(RespData { method: name.clone(), ..RespData::new(args.into(), 0) }).is_ok());
+        if !senders.is_empty() { return true; }
+        listeners.remove(&key);
+        false
+    }
+
+    /// Close the channels
+    pub fn clear(&self) { self.0.write().unwrap().clear(); }
+}
@@ -0,0 +1,113 @@
+//! Walking through msgpack values by their markers and lengths, without decoding them. It finds where the packets end in
+//! a stream (see [`Protocol`](crate::protocol::Protocol)) and checks the decode limits (see [`DecodeLimits`](crate::DecodeLimits))
+
+/// What a marker starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// Nil, a boolean, an integer or a float
+    Scalar,
+    Str,
+    Bin,
+    Ext,
+    Array,
+    Map,
+    /// The marker 0xc1, never used
+    Invalid,
+}
+
+/// The head of a msgpack value: its marker, its length and the type of an extension
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Head {
+    pub kind: Kind,
+    /// Byte length of the head
+    pub size: usize,
+    /// Byte length of the data following the head, the count of elements of an array or of entries of a map
+    pub len: u64,
+}
+
+impl Head {
+    /// Count of values in an array or a map, a map entry is two
+    pub fn items(&self) -> Option<u64> {
+        match self.kind {
+            Kind::Array => Some(self.len),
+            Kind::Map => Some(2 * self.len),
+            _ => None,
+        }
+    }
+}
+
+/// Read the head of the value starting `buf`, `None` if `buf` doesn't hold all of it
+pub(crate) fn head(buf: &[u8]) -> Option<Head> {
+    let marker = *buf.first()?;
+    // Big endian length of `n` bytes after the marker
+    let read = |n: usize| buf.get(1..1 + n).map(|b| b.iter().fold(0u64, |len, &b| len << 8 | u64::from(b)));
+    let sized = |kind, n| read(n).map(|len| Head { kind, size: 1 + n, len });
+    let fixed = |kind, len| Some(Head { kind, size: 1, len });
+    match marker {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => fixed(Kind::Scalar, 0),
+        0x80..=0x8f => fixed(Kind::Map, u64::from(marker & 0x0f)),
+        0x90..=0x9f => fixed(Kind::Array, u64::from(marker & 0x0f)),
+        0xa0..=0xbf => fixed(Kind::Str, u64::from(marker & 0x1f)),
+        0xc1 => fixed(Kind::Invalid, 0),
+        0xc4 => sized(Kind::Bin, 1),
+        0xc5 => sized(Kind::Bin, 2),
+        0xc6 => sized(Kind::Bin, 4),
+        // The type of an extension follows its length
+        0xc7 => sized(Kind::Ext, 1).map(|head| Head { size: head.size + 1, ..head }),
+        0xc8 => sized(Kind::Ext, 2).map(|head| Head { size: head.size + 1, ..head }),
+        0xc9 => sized(Kind::Ext, 4).map(|head| Head { size: head.size + 1, ..head }),
+        0xca => fixed(Kind::Scalar, 4),
+        0xcb => fixed(Kind::Scalar, 8),
+        0xcc | 0xd0 => fixed(Kind::Scalar, 1),
+        0xcd | 0xd1 => fixed(Kind::Scalar, 2),
+        0xce | 0xd2 => fixed(Kind::Scalar, 4),
+        0xcf | 0xd3 => fixed(Kind::Scalar, 8),
+        0xd4..=0xd8 => Some(Head { kind: Kind::Ext, size: 2, len: 1 << (marker - 0xd4) }),
+        0xd9 => sized(Kind::Str, 1),
+        0xda => sized(Kind::Str, 2),
+        0xdb => sized(Kind::Str, 4),
+        0xdc => sized(Kind::Array, 2),
+        0xdd => sized(Kind::Array, 4),
+        0xde => sized(Kind::Map, 2),
+        0xdf => sized(Kind::Map, 4),
+    }
+}
+
+/// Walks through a msgpack value in bytes received piece by piece, showing the head of each value to a visitor with its
+/// depth. It reads only the heads, so the data doesn't need to be there yet and the bytes walked aren't read again
+#[derive(Debug, Clone)]
+pub(crate) struct Walker {
+    // Where the next value starts, from the start of the outer one
+    pub pos: usize,
+    // Count of values left to read in each opened array and map, the bottom one is the top level. Without the depths
+    // they're all counted in the bottom one
+    pending: Vec<u64>,
+    depths: bool,
+}
+
+impl Walker {
+    /// A walker through `values` values one after the other, telling the visitor their depth when `depths`, otherwise zero
+    pub fn new(values: u64, depths: bool) -> Self { Walker { pos: 0, pending: vec![values], depths } }
+
+    /// Walk `buf`, the bytes of the values from `offset`, and tell where they end once `buf` holds all of them.
+    /// The visitor gets the head of each value with the count of arrays and maps it's in
+    pub fn walk(&mut self, buf: &[u8], offset: usize, mut visit: impl FnMut(&Head, usize) -> Result<(), &'static str>)
+                -> Result<Option<usize>, &'static str> {
+        loop {
+            while self.pending.len() > 1 && self.pending.last() == Some(&0) { self.pending.pop(); }
+            if self.pending == [0] { break; }
+            let head = match buf.get(self.pos - offset..).and_then(head) { Some(head) => head, None => return Ok(None) };
+            visit(&head, if self.depths { self.pending.len() - 1 } else { 0 })?;
+            let data = if head.items().is_some() { 0 } else { head.len };
+            self.pos = self.pos.checked_add(head.size).and_then(|pos| pos.checked_add(data as usize)).ok_or("value length")?;
+            let top = self.pending.last_mut().unwrap();
+            *top -= 1;
+            match head.items() {
+                Some(items) if self.depths => self.pending.push(items),
+                Some(items) => *top = top.saturating_add(items),
+                None => {}
+            }
+        }
+        Ok(if self.pos <= offset + buf.len() { Some(self.pos) } else { None })
+    }
+}
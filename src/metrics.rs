@@ -0,0 +1,33 @@
+
+use std::time::Duration;
+
+use crate::{Method, RequestResult, ProtocolError};
+
+/// Receive the measures of a session, to feed Prometheus, statsd... See [`Session::set_metrics`](crate::Session::set_metrics).
+/// The methods are called on the sending and receiving paths, so they should be quick
+pub trait MetricsSink: Send + Sync {
+    fn request_sent(&self, _method: Method) {}
+
+    /// A request sent got its result after `elapsed`
+    fn request_finished(&self, _method: Method, _elapsed: Duration, _result: &RequestResult) {}
+
+    fn request_received(&self, _method: Method) {}
+
+    /// The service handled a request received in `elapsed`, `failed` if it returned an error
+    fn request_handled(&self, _method: Method, _elapsed: Duration, _failed: bool) {}
+
+    fn notify_sent(&self, _method: Method) {}
+
+    fn notify_received(&self, _method: Method) {}
+
+    /// Count of the requests sent waiting for their result, called when it changes
+    fn in_flight(&self, _count: usize) {}
+
+    /// A frame was handed to the adaptor or the send queue
+    fn bytes_sent(&self, _len: usize) {}
+
+    fn bytes_received(&self, _len: usize) {}
+
+    /// A received packet was dropped
+    fn protocol_error(&self, _error: &ProtocolError) {}
+}
@@ -0,0 +1,106 @@
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::*;
+
+/// Code running around the service of a session, e.g. to check the caller, log or time the requests, or rewrite the arguments.
+/// A layer calls `next.run(..)` to continue with the following layers and the service, or answers by itself
+pub trait Layer: Send + Sync {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret, next: Next) -> Result<(), HandleError>;
+}
+
+impl<F> Layer for F where F: Fn(&Session, Arg, Ret, Next) -> Result<(), HandleError> + Send + Sync {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret, next: Next) -> Result<(), HandleError> {
+        self(ss, arg, ret, next)
+    }
+}
+
+/// The rest of a [`Stack`] after a layer
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Layer>],
+    service: &'a ServiceType,
+}
+
+impl Next<'_> {
+    pub fn run(self, ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.handle(ss, arg, ret, Next { layers, service: self.service }),
+            None => self.service.handle(ss, arg, ret),
+        }
+    }
+}
+
+/// Service running the requests and notifies through layers, the first one added runs first
+pub struct Stack {
+    layers: Vec<Arc<dyn Layer>>,
+    service: ServiceType,
+}
+
+impl Stack {
+    pub fn new(service: ServiceType) -> Self {
+        Stack { layers: Vec::new(), service }
+    }
+
+    pub fn layer(mut self, layer: impl Layer + 'static) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    pub fn service(&self) -> &ServiceType { &self.service }
+}
+
+impl Service for Stack {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        Next { layers: &self.layers, service: &self.service }.run(ss, arg, ret)
+    }
+
+    fn supports(&self, method: Method) -> Option<bool> { self.service.supports(method) }
+
+    fn methods(&self) -> Vec<introspect::MethodInfo> { self.service.methods() }
+
+    fn schema(&self, method: Method) -> Option<&schema::Schema> { self.service.schema(method) }
+}
+
+/// Layer running a method only for the peers allowed to call it, by the roles of their [`Identity`] (see
+/// [`Session::identity`]). The others are answered an [`UNAUTHENTICATED`](RemoteError::UNAUTHENTICATED) error
+/// without identity, or a [`PERMISSION_DENIED`](RemoteError::PERMISSION_DENIED) one whose data is the roles it requires
+#[derive(Default)]
+pub struct Acl {
+    // By the id of the method, so a rule holds whether it's called by name or by its `method_id`
+    rules: HashMap<u32, Vec<String>>,
+    default: Option<Vec<String>>,
+}
+
+impl Acl {
+    /// Rules letting every peer call every method, until some are added
+    pub fn new() -> Self { Acl::default() }
+
+    /// Let `method` run only for the peers having one of `roles`, or for any authenticated one if it's empty. The rule
+    /// of a name holds for its [`method_id`] too
+    pub fn require<'a>(mut self, method: impl ToMethod<'a>, roles: &[&str]) -> Self {
+        self.rules.insert(method.to_method().id(), roles.iter().map(|&r| r.into()).collect());
+        self
+    }
+
+    /// The roles required by the methods without rule, instead of letting them run for anyone
+    pub fn require_by_default(mut self, roles: &[&str]) -> Self {
+        self.default = Some(roles.iter().map(|&r| r.into()).collect());
+        self
+    }
+
+    /// Whether the peer of `ss` may call `method`
+    pub fn check(&self, ss: &Session, method: Method) -> Result<(), HandleError> {
+        let roles = match self.rules.get(&method.id()).or(self.default.as_ref()) { Some(roles) => roles, None => return Ok(()) };
+        let identity = ss.identity().ok_or_else(|| HandleError::new(ErrorKind::Unauthenticated, "Unauthenticated"))?;
+        if roles.is_empty() || identity.roles.iter().any(|role| roles.contains(role)) { return Ok(()); }
+        Err(HandleError::new(ErrorKind::PermissionDenied, "Permission denied").with_data(roles))
+    }
+}
+
+impl Layer for Acl {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret, next: Next) -> Result<(), HandleError> {
+        self.check(ss, arg.method)?;
+        next.run(ss, arg, ret)
+    }
+}
@@ -0,0 +1,333 @@
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use rmp::encode;
+use rmpv::{Value, decode::read_value};
+use serde::Serialize;
+
+use crate::{Adaptor, RecvError, RemoteError, ToMethod, encode_arg};
+use crate::protocol::{self, Frame};
+
+/// How long a blocking reception waits for the session to send what the script expects next
+const WAIT: Duration = Duration::from_secs(5);
+
+enum Step {
+    // Packet received by the session once the steps before it are done
+    Incoming(Vec<u8>),
+    // Packets the session must send, a request is answered if `answer` is set
+    Request { method: Value, arg: Value, answer: Option<Result<Vec<u8>, RemoteError>> },
+    Notify { method: Value, arg: Value },
+    Response { id: u64, result: Result<Value, i64> },
+}
+
+impl Step {
+    fn describe(&self) -> String {
+        match self {
+            Step::Incoming(_) => "an incoming packet".into(),
+            Step::Request { method, arg, .. } => format!("request {} {}", method, arg),
+            Step::Notify { method, arg } => format!("notify {} {}", method, arg),
+            Step::Response { id, result: Ok(value) } => format!("response {} to request {}", value, id),
+            Step::Response { id, result: Err(code) } => format!("error {} to request {}", code, id),
+        }
+    }
+}
+
+// A packet sent by the session
+enum Packet {
+    Request(u64, Value, Value),
+    Notify(Option<u64>, Value, Value),
+    Response(u64, Result<Value, i64>),
+}
+
+impl Packet {
+    fn parse(data: &[u8]) -> Option<Packet> {
+        let value = |mut bytes: &[u8]| read_value(&mut bytes).ok();
+        match protocol::parse_frame(data, |_| Ok(())).ok()? {
+            Frame::Request { id, method, args, .. } => Some(Packet::Request(id, value(method)?, value(args)?)),
+            Frame::Notify { ack, method, args } => Some(Packet::Notify(ack, value(method)?, value(args)?)),
+            Frame::Response { id, error: Value::Nil, result } => Some(Packet::Response(id, Ok(value(result)?))),
+            Frame::Response { id, error, .. } => Some(Packet::Response(id, Err(RemoteError::decode(&error)?.code))),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Packet::Request(_, method, arg) => format!("request {} {}", method, arg),
+            Packet::Notify(_, method, arg) => format!("notify {} {}", method, arg),
+            Packet::Response(id, Ok(value)) => format!("response {} to request {}", value, id),
+            Packet::Response(id, Err(code)) => format!("error {} to request {}", code, id),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    script: VecDeque<Step>,
+    incoming: VecDeque<Vec<u8>>,
+    next_id: u64,
+    sent: Vec<Vec<u8>>,
+    // Scripted requests whose response isn't checked
+    unchecked: Vec<u64>,
+    failures: Vec<String>,
+}
+
+impl State {
+    // Hand the packets at the front of the script to the session
+    fn advance(&mut self) {
+        while let Some(Step::Incoming(_)) = self.script.front() {
+            if let Some(Step::Incoming(packet)) = self.script.pop_front() { self.incoming.push_back(packet); }
+        }
+    }
+}
+
+/// Adaptor playing a scripted peer, to test a service or the code of a client without a real one.
+/// The steps happen in order: the packets scripted to be received are once the packets expected before them were
+/// sent, the scripted requests are answered once they're sent. A session receiving after the end of the script
+/// gets disconnected, so [`Session::loop_handle`](crate::Session::loop_handle) returns
+///
+/// ```ignore
+/// let mock = MockAdaptor::new();
+/// mock.expect_request("add", (1, 2)).respond(3);
+/// mock.request("negate", 4).expect_response(-4);
+/// let session = Session::new(mock.clone(), service);
+/// // the code under test
+/// mock.verify();
+/// ```
+pub struct MockAdaptor {
+    state: Mutex<State>,
+    changed: Condvar,
+    connected: AtomicBool,
+}
+
+impl MockAdaptor {
+    pub fn new() -> Arc<MockAdaptor> {
+        Arc::new(MockAdaptor { state: Default::default(), changed: Condvar::new(), connected: AtomicBool::new(true) })
+    }
+
+    /// Expect the session to send the request `method` with `arg`. It's left unanswered unless
+    /// [`ExpectedRequest::respond`] or [`ExpectedRequest::fail`] is called
+    pub fn expect_request<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> ExpectedRequest<'_> {
+        ExpectedRequest { mock: self, method: method_value(method), arg: arg_value(&arg), answer: None }
+    }
+
+    /// Expect the session to send the notify `method` with `arg`, it's acknowledged if it asks to be
+    pub fn expect_notify<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) {
+        self.push(Step::Notify { method: method_value(method), arg: arg_value(&arg) });
+    }
+
+    /// Send the request `method` with `arg` to the session. Its response is checked if it's expected with
+    /// [`ScriptedRequest::expect_response`] or [`ScriptedRequest::expect_error`], and ignored otherwise
+    pub fn request<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> ScriptedRequest<'_> {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            state.next_id
+        };
+        let mut packet = Vec::new();
+        protocol::write_request(&mut packet, id, method.to_method(), None, None);
+        encode_arg(&arg, &mut packet, false);
+        ScriptedRequest { mock: self, id, packet: Some(packet), result: None }
+    }
+
+    /// Send the notify `method` with `arg` to the session
+    pub fn notify<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) {
+        let mut packet = Vec::new();
+        protocol::write_notify(&mut packet, None, method.to_method());
+        encode_arg(&arg, &mut packet, false);
+        self.push(Step::Incoming(packet));
+    }
+
+    // Send the request `id` encoded in `packet`, its response is left unchecked
+    pub(crate) fn receive_request(&self, id: u64, packet: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.unchecked.push(id);
+        state.script.push_back(Step::Incoming(packet));
+        state.advance();
+        self.changed.notify_all();
+    }
+
+    /// The packets sent by the session so far
+    pub fn sent(&self) -> Vec<Vec<u8>> { self.state.lock().unwrap().sent.clone() }
+
+    /// The packets the session sent out of the script, then the steps of the script not done yet
+    pub fn failures(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut failures = state.failures.clone();
+        failures.extend(state.script.iter().map(|step| format!("Expected {}", step.describe())));
+        failures
+    }
+
+    /// Panic with the [`MockAdaptor::failures`], if any
+    pub fn verify(&self) {
+        let failures = self.failures();
+        if !failures.is_empty() { panic!("The session didn't follow the script:\n{}", failures.join("\n")); }
+    }
+
+    fn push(&self, step: Step) {
+        let mut state = self.state.lock().unwrap();
+        state.script.push_back(step);
+        state.advance();
+        self.changed.notify_all();
+    }
+
+    fn wait(&self, mut state: MutexGuard<State>, timeout: Duration) -> Result<Vec<u8>, RecvError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !self.connected() { return Err(RecvError::Disconnect); }
+            if let Some(packet) = state.incoming.pop_front() { return Ok(packet); }
+            if state.script.is_empty() {
+                self.connected.store(false, Ordering::SeqCst);
+                return Err(RecvError::Disconnect);
+            }
+            let now = Instant::now();
+            if now >= deadline { return Err(RecvError::NoData); }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+fn method_value<'a>(method: impl ToMethod<'a>) -> Value {
+    let mut buf = Vec::new();
+    method.to_method().serialize(&mut buf);
+    read_value(&mut &buf[..]).unwrap()
+}
+
+fn arg_value(arg: &impl Serialize) -> Value {
+    let mut buf = Vec::new();
+    encode_arg(arg, &mut buf, false);
+    read_value(&mut &buf[..]).unwrap()
+}
+
+fn response(id: u64, answer: &Result<Vec<u8>, RemoteError>) -> Vec<u8> {
+    let mut packet = Vec::new();
+    protocol::write_response(&mut packet, id);
+    match answer {
+        Ok(result) => {
+            encode::write_nil(&mut packet);
+            packet.extend_from_slice(result);
+        }
+        Err(error) => protocol::write_fault(&mut packet, error),
+    }
+    packet
+}
+
+impl Adaptor for MockAdaptor {
+    fn send(&self, data: Vec<u8>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let packet = Packet::parse(&data);
+        state.sent.push(data);
+        let matched = match (state.script.front(), &packet) {
+            (Some(Step::Request { method, arg, answer }), Some(Packet::Request(id, m, a))) if method == m && arg == a => {
+                Some(answer.as_ref().map(|answer| response(*id, answer)))
+            }
+            (Some(Step::Notify { method, arg }), Some(Packet::Notify(ack, m, a))) if method == m && arg == a => {
+                Some(ack.map(|ack| response(ack, &Ok(vec![0xc0]))))
+            }
+            (Some(Step::Response { id, result }), Some(Packet::Response(i, r))) if id == i && result == r => Some(None),
+            (_, Some(Packet::Response(id, _))) if state.unchecked.contains(id) => return self.connected(),
+            _ => None,
+        };
+        match matched {
+            Some(answer) => {
+                state.script.pop_front();
+                state.incoming.extend(answer);
+                state.advance();
+                self.changed.notify_all();
+            }
+            None => {
+                let sent = packet.as_ref().map_or_else(|| "a malformed packet".into(), Packet::describe);
+                let expected = state.script.front().map_or_else(|| "nothing".into(), Step::describe);
+                state.failures.push(format!("Sent {} while expecting {}", sent, expected));
+            }
+        }
+        self.connected()
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        let mut state = self.state.lock().unwrap();
+        match self.wait(state, WAIT) {
+            Err(RecvError::NoData) => {
+                state = self.state.lock().unwrap();
+                let expected = state.script.front().map_or_else(String::new, Step::describe);
+                state.failures.push(format!("Timed out waiting for {}", expected));
+                self.connected.store(false, Ordering::SeqCst);
+                Err(RecvError::Disconnect)
+            }
+            result => result,
+        }
+    }
+
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> { Some(self.wait(self.state.lock().unwrap(), Duration::from_secs(0))) }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> { self.wait(self.state.lock().unwrap(), timeout) }
+
+    fn connected(&self) -> bool { self.connected.load(Ordering::SeqCst) }
+
+    fn close(&self) {
+        self.connected.store(false, Ordering::SeqCst);
+        let _state = self.state.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    fn transport_kind(&self) -> Option<&'static str> { Some("mock") }
+}
+
+/// A request expected by [`MockAdaptor::expect_request`], scripted when it's dropped
+pub struct ExpectedRequest<'m> {
+    mock: &'m MockAdaptor,
+    method: Value,
+    arg: Value,
+    answer: Option<Result<Vec<u8>, RemoteError>>,
+}
+
+impl ExpectedRequest<'_> {
+    /// Answer it with `result`
+    pub fn respond(mut self, result: impl Serialize) {
+        let mut buf = Vec::new();
+        encode_arg(&result, &mut buf, false);
+        self.answer = Some(Ok(buf));
+    }
+
+    /// Answer it with `error`
+    pub fn fail(mut self, error: RemoteError) { self.answer = Some(Err(error)); }
+}
+
+impl Drop for ExpectedRequest<'_> {
+    fn drop(&mut self) {
+        let (method, arg) = (std::mem::replace(&mut self.method, Value::Nil), std::mem::replace(&mut self.arg, Value::Nil));
+        self.mock.push(Step::Request { method, arg, answer: self.answer.take() });
+    }
+}
+
+/// A request sent by [`MockAdaptor::request`], scripted when it's dropped
+pub struct ScriptedRequest<'m> {
+    mock: &'m MockAdaptor,
+    id: u64,
+    packet: Option<Vec<u8>>,
+    result: Option<Result<Value, i64>>,
+}
+
+impl ScriptedRequest<'_> {
+    /// Expect the session to answer `result`
+    pub fn expect_response(mut self, result: impl Serialize) { self.result = Some(Ok(arg_value(&result))); }
+
+    /// Expect the session to answer an error of `code`, e.g. [`RemoteError::METHOD_NOT_FOUND`]
+    pub fn expect_error(mut self, code: i64) { self.result = Some(Err(code)); }
+}
+
+impl Drop for ScriptedRequest<'_> {
+    fn drop(&mut self) {
+        let mut state = self.mock.state.lock().unwrap();
+        if let Some(packet) = self.packet.take() { state.script.push_back(Step::Incoming(packet)); }
+        match self.result.take() {
+            Some(result) => state.script.push_back(Step::Response { id: self.id, result }),
+            None => state.unchecked.push(self.id),
+        }
+        state.advance();
+        self.mock.changed.notify_all();
+    }
+}
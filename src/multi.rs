@@ -0,0 +1,24 @@
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{RequestError, Session, ToMethod};
+
+/// Do the same request on all the `sessions` at once and gather the results in their order, waiting at most `timeout`
+/// for all of them: the ones not answered in time are [`RequestError::Timeout`]. The sessions must be received by
+/// other threads, e.g. with [`Session::loop_handle`]. The requests aren't ordered with the other ones of the sessions
+/// (see [`Session::set_ordered_responses`]): the results come back together, in the order of the sessions
+pub fn request_all<'a, T: DeserializeOwned>(sessions: &[&Session], method: impl ToMethod<'a>, arg: impl Serialize, timeout: Duration) -> Vec<Result<T, RequestError>> {
+    let method = method.to_method();
+    let deadline = Instant::now() + timeout;
+    let sent: Vec<_> = sessions.iter().map(|ss| ss.send_request(method, &arg)).collect();
+    sessions.iter().zip(sent).map(|(ss, sent)| {
+        let (req_id, recver) = sent.map_err(RequestError::from)?;
+        match ss.wait_response(req_id, &recver, deadline.saturating_duration_since(Instant::now())) {
+            Some(result) => Session::decode_result(result),
+            None => Err(RequestError::Timeout),
+        }
+    }).collect()
+}
@@ -0,0 +1,74 @@
+
+use std::collections::HashMap;
+
+use crate::*;
+
+/// Service routing `"prefix.method"` to the service mounted under `prefix`, which sees the method as `"method"`.
+/// Composites can be mounted in each others for deeper namespaces
+pub struct Composite {
+    services: HashMap<String, ServiceType>,
+    fallback: Option<ServiceType>,
+}
+
+impl Default for Composite {
+    fn default() -> Self { Composite::new() }
+}
+
+impl Composite {
+    pub fn new() -> Self { Composite { services: HashMap::new(), fallback: None } }
+
+    pub fn mount(mut self, prefix: &str, service: impl Service) -> Self {
+        self.services.insert(prefix.into(), Arc::new(service));
+        self
+    }
+
+    /// Handle the methods without a mounted prefix, including the integer ones, instead of answering `MethodNotFound`
+    pub fn fallback(mut self, service: impl Service) -> Self {
+        self.fallback = Some(Arc::new(service));
+        self
+    }
+
+    fn route<'a>(&self, method: Method<'a>) -> Option<(&ServiceType, Method<'a>)> {
+        let mounted = match method {
+            Method::Str(name) => {
+                let mut parts = name.splitn(2, '.');
+                match (parts.next(), parts.next()) {
+                    (Some(prefix), Some(rest)) => self.services.get(prefix).map(|s| (s, Method::Str(rest))),
+                    _ => None,
+                }
+            }
+            Method::Int(_) => None,
+        };
+        mounted.or_else(|| self.fallback.as_ref().map(|s| (s, method)))
+    }
+}
+
+impl Service for Composite {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        match self.route(arg.method) {
+            Some((service, method)) => service.handle(ss, Arg { method, ..arg }, ret),
+            None => Err(HandleError::new(ErrorKind::MethodNotFound, "No this method")),
+        }
+    }
+
+    fn supports(&self, method: Method) -> Option<bool> {
+        match self.route(method) {
+            Some((service, method)) => service.supports(method),
+            None => Some(false),
+        }
+    }
+
+    fn schema(&self, method: Method) -> Option<&schema::Schema> {
+        self.route(method).and_then(|(service, method)| service.schema(method))
+    }
+
+    fn methods(&self) -> Vec<introspect::MethodInfo> {
+        let mut prefixes: Vec<_> = self.services.keys().collect();
+        prefixes.sort();
+        let mut methods: Vec<_> = prefixes.into_iter().flat_map(|prefix| {
+            self.services[prefix].methods().into_iter().map(move |mut m| { m.name = format!("{}.{}", prefix, m.name); m })
+        }).collect();
+        if let Some(fallback) = &self.fallback { methods.extend(fallback.methods()); }
+        methods
+    }
+}
@@ -0,0 +1,34 @@
+
+use serde::Deserialize;
+
+use crate::DecodeError;
+
+/// Method of the request switching both sides to bincode, handled by the session itself
+pub(crate) const NEGOTIATE_METHOD: &str = "$bincode";
+
+/// Type of the msgpack extension holding a bincode payload, so it can't be mistaken for a msgpack value
+pub(crate) const EXT_TYPE: i8 = 0x42;
+
+/// Decode a payload of [`codec::Bincode`](crate::codec::Bincode), `None` if it's not one
+pub(crate) fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Option<Result<T, DecodeError>> {
+    let mut reader = bytes;
+    let meta = rmp::decode::read_ext_meta(&mut reader).ok().filter(|m| m.typeid == EXT_TYPE)?;
+    let data = reader.get(..meta.size as usize);
+    #[cfg(feature = "bincode")] {
+        let data = match data { Some(data) => data, None => return Some(Err(DecodeError::Syntax("truncated bincode payload".into()))) };
+        // The lengths in the data can't make it allocate more than the data itself
+        Some(bincode::config().limit(data.len() as u64).deserialize(data).map_err(|e| DecodeError::Syntax(e.to_string())))
+    }
+    #[cfg(not(feature = "bincode"))] {
+        let _ = data;
+        Some(Err(DecodeError::Syntax("bincode payloads need the bincode feature".into())))
+    }
+}
+
+/// Whether `bytes` is a payload of [`codec::Bincode`](crate::codec::Bincode)
+pub(crate) fn is_encoded(bytes: &[u8]) -> bool {
+    rmp::decode::read_ext_meta(&mut &bytes[..]).is_ok_and(|m| m.typeid == EXT_TYPE)
+}
+
+/// Whether this side can speak bincode
+pub(crate) fn supported() -> bool { cfg!(feature = "bincode") }
@@ -0,0 +1,73 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::ThreadId;
+
+/// Responses delivered and not yet returned by their waiters, in the order they were received
+pub(crate) struct ResponseOrder {
+    enabled: AtomicBool,
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    delivered: VecDeque<u64>,
+    /// Thread of the waiters whose return is ordered, the innermost one of each thread
+    waiters: HashMap<u64, ThreadId>,
+}
+
+impl Default for ResponseOrder {
+    fn default() -> Self {
+        ResponseOrder { enabled: AtomicBool::new(true), state: Default::default(), turn: Condvar::new() }
+    }
+}
+
+impl ResponseOrder {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+        if !enabled {
+            // Release the waiters queued before
+            let mut state = self.state.lock().unwrap();
+            state.delivered.clear();
+            state.waiters.clear();
+            self.turn.notify_all();
+        }
+    }
+
+    /// The current thread is going to wait for the response of `req_id`. A request waited lower in its stack, e.g. by
+    /// a service making a request, can't return before this one so it isn't ordered anymore
+    pub fn waiting(&self, req_id: u64) {
+        if self.enabled.load(Ordering::SeqCst) {
+            let me = std::thread::current().id();
+            let mut state = self.state.lock().unwrap();
+            state.waiters.retain(|_, thread| *thread != me);
+            state.waiters.insert(req_id, me);
+            self.turn.notify_all();
+        }
+    }
+
+    /// The response of `req_id` was handed to its waiter, called in the order of the frames
+    pub fn delivered(&self, req_id: u64) {
+        if !self.enabled.load(Ordering::SeqCst) { return; }
+        let mut state = self.state.lock().unwrap();
+        if state.waiters.contains_key(&req_id) { state.delivered.push_back(req_id); }
+    }
+
+    /// Block until the ordered responses delivered before the one of `req_id` are returned
+    pub fn wait_turn(&self, req_id: u64) {
+        if !self.enabled.load(Ordering::SeqCst) { return; }
+        let mut state = self.state.lock().unwrap();
+        let ordered = state.waiters.contains_key(&req_id);
+        while let Some(position) = state.delivered.iter().position(|&id| id == req_id) {
+            if !ordered || !state.delivered.iter().take(position).any(|id| state.waiters.contains_key(id)) {
+                state.delivered.remove(position);
+                break;
+            }
+            state = self.turn.wait(state).unwrap();
+        }
+        state.waiters.remove(&req_id);
+        self.turn.notify_all();
+    }
+}
@@ -0,0 +1,33 @@
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads handling the requests of sessions, see [`Session::set_worker_pool`](crate::Session::set_worker_pool),
+/// or their notifies, see [`Session::set_notify_pool`](crate::Session::set_notify_pool).
+/// A pool can be shared by many sessions
+pub struct WorkerPool {
+    sender: Mutex<Sender<Job>>,
+}
+
+impl WorkerPool {
+    /// Start `threads` workers (at least one), they stop once the pool is dropped and the queued requests are handled
+    pub fn new(threads: usize) -> Arc<WorkerPool> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || loop {
+                // Not holding the lock while running the job
+                let next = receiver.lock().unwrap().recv();
+                match next { Ok(job) => job(), Err(_) => break }
+            });
+        }
+        Arc::new(WorkerPool { sender: Mutex::new(sender) })
+    }
+
+    pub(crate) fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.sender.lock().unwrap().send(Box::new(job));
+    }
+}
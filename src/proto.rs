@@ -0,0 +1,41 @@
+
+use std::fmt;
+
+use prost::Message;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Visitor};
+
+/// Protobuf message carried untouched as a msgpack binary, so existing `.proto` types can be arguments and results,
+/// e.g. `"search" => (query: Proto<Query>) { Proto(reply) }`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Proto<T>(pub T);
+
+impl<T> Proto<T> {
+    pub fn into_inner(self) -> T { self.0 }
+}
+
+impl<T: Message> Serialize for Proto<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut buf = Vec::with_capacity(self.0.encoded_len());
+        self.0.encode(&mut buf).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&buf)
+    }
+}
+
+impl<'de, T: Message + Default> Deserialize<'de> for Proto<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(ProtoVisitor(std::marker::PhantomData))
+    }
+}
+
+struct ProtoVisitor<T>(std::marker::PhantomData<T>);
+
+impl<'de, T: Message + Default> Visitor<'de> for ProtoVisitor<T> {
+    type Value = Proto<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("a protobuf encoded binary") }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        T::decode(v).map(Proto).map_err(E::custom)
+    }
+}
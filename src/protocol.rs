@@ -0,0 +1,408 @@
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rmp::{encode, decode};
+use rmpv::Value;
+use rmpv::decode::read_value;
+use serde::Serialize;
+
+use crate::{encode_arg, Method, ProtocolError, RemoteError, Session, ToMethod};
+use crate::{REQUEST, RESPONSE, NOTIFY, COMPRESSED, FRAGMENT, PING, PONG, ATTACHMENT};
+use crate::context::Metadata;
+use crate::marker::{Kind, Walker};
+
+/// Size of the packets buffered by [`Protocol::feed_bytes`] by default
+const DEFAULT_MAX_PACKET: usize = 16 << 20;
+
+/// The method of a received packet
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MethodBuf {
+    Int(u32),
+    Str(String),
+}
+
+impl MethodBuf {
+    pub fn as_method(&self) -> Method<'_> {
+        match self {
+            MethodBuf::Int(i) => Method::Int(*i),
+            MethodBuf::Str(s) => Method::Str(s),
+        }
+    }
+}
+
+impl From<Method<'_>> for MethodBuf {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Int(i) => MethodBuf::Int(i),
+            Method::Str(s) => MethodBuf::Str(s.into()),
+        }
+    }
+}
+
+/// What a received packet tells
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The peer requests `method` with the msgpack `args`, answer it with [`Protocol::encode_response`]. The
+    /// `timeout` is how long the peer waits, if it tells
+    Request { id: u64, method: MethodBuf, args: Vec<u8>, timeout: Option<Duration> },
+    /// The peer notifies `method` with the msgpack `args`. If it asks for an acknowledgement, answer `ack` with
+    /// [`Protocol::encode_response`] and `Ok(())`
+    Notify { method: MethodBuf, args: Vec<u8>, ack: Option<u64> },
+    /// The answer to the request `id` of [`Protocol::encode_request`], its msgpack result or the error of the peer
+    Response { id: u64, method: MethodBuf, result: Result<Vec<u8>, RemoteError> },
+    /// The peer checks the connection, answer it with [`Protocol::encode_pong`]
+    Ping(u64),
+    Pong(u64),
+    /// The packet is dropped. If the bytes of a stream can't be split anymore, the next ones are ignored until the
+    /// protocol is [`reset`](Protocol::reset)
+    Error(ProtocolError),
+}
+
+/// The protocol of a [`Session`] without its transport, for the applications doing their I/O themselves (e.g. in an
+/// event loop of their own): it turns the calls into packets to send, and the received bytes into events. It keeps
+/// which requests are waiting for their response. The packets are read and written like the session does, but only
+/// the plain ones: the peer must not compress nor split them, the packets of the options of a session are told by
+/// [`Event::Error`]
+pub struct Protocol {
+    next_id: u64,
+    pending: HashMap<u64, MethodBuf>,
+    // The bytes of the packet being received, less the `dropped` ones of a packet too large
+    buffer: Vec<u8>,
+    scanner: Scanner,
+    dropped: usize,
+    discarding: bool,
+    broken: bool,
+    max_packet: usize,
+}
+
+impl Default for Protocol {
+    fn default() -> Self { Protocol::new() }
+}
+
+impl Protocol {
+    pub fn new() -> Self {
+        Protocol {
+            next_id: 1, pending: HashMap::new(), buffer: Vec::new(), scanner: Scanner::new(), dropped: 0,
+            discarding: false, broken: false, max_packet: DEFAULT_MAX_PACKET,
+        }
+    }
+
+    /// Receive packets of at most `max` bytes with [`Protocol::feed_bytes`], 16 MiB by default. The larger ones are
+    /// told by an [`Event::Error`] and skipped without being buffered
+    pub fn with_max_packet(mut self, max: usize) -> Self {
+        self.max_packet = max;
+        self
+    }
+
+    /// The packet of a request and its id, its response is told by an [`Event::Response`]
+    pub fn encode_request<'a>(&mut self, method: impl ToMethod<'a>, arg: impl Serialize) -> (u64, Vec<u8>) {
+        let method = method.to_method();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, method.into());
+        let mut pack = Vec::with_capacity(0x40);
+        write_request(&mut pack, id, method, None, None);
+        encode_arg(&arg, &mut pack, false);
+        (id, pack)
+    }
+
+    pub fn encode_notify<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> Vec<u8> {
+        let mut pack = Vec::with_capacity(0x40);
+        write_notify(&mut pack, None, method.to_method());
+        encode_arg(&arg, &mut pack, false);
+        pack
+    }
+
+    /// The packet answering the request `id` with a result or an error
+    pub fn encode_response<T: Serialize>(&self, id: u64, result: Result<T, &RemoteError>) -> Vec<u8> {
+        let mut pack = Vec::with_capacity(0x40);
+        write_response(&mut pack, id);
+        match result {
+            Ok(result) => {
+                encode::write_nil(&mut pack);
+                encode_arg(&result, &mut pack, false);
+            }
+            Err(error) => write_fault(&mut pack, error),
+        }
+        pack
+    }
+
+    pub fn encode_pong(&self, id: u64) -> Vec<u8> {
+        let mut pack = Vec::with_capacity(0x10);
+        write_ping(&mut pack, PONG, id);
+        pack
+    }
+
+    /// Count of requests waiting for their response
+    pub fn pending(&self) -> usize { self.pending.len() }
+
+    /// Forget the requests waiting for their response and the bytes buffered, e.g. once the transport is closed.
+    /// Return the ids of the requests
+    pub fn reset(&mut self) -> Vec<u64> {
+        self.buffer.clear();
+        self.scanner = Scanner::new();
+        self.dropped = 0;
+        self.discarding = false;
+        self.broken = false;
+        self.pending.drain().map(|(id, _)| id).collect()
+    }
+
+    /// The events of the bytes received from a stream, whose packets may be split or glued together. The bytes of
+    /// an incomplete packet are kept for the next call, and are read once
+    pub fn feed_bytes(&mut self, bytes: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+        if self.broken { return events; }
+        self.buffer.extend_from_slice(bytes);
+        loop {
+            match self.scanner.scan(&self.buffer, self.dropped) {
+                Ok(Some(end)) => {
+                    let rest = self.buffer.split_off(end - self.dropped);
+                    let packet = std::mem::replace(&mut self.buffer, rest);
+                    if end > self.max_packet && !self.discarding {
+                        events.push(Event::Error(ProtocolError::LimitExceeded("packet size")));
+                    } else if !self.discarding {
+                        events.push(self.feed_packet(&packet));
+                    }
+                    self.scanner = Scanner::new();
+                    self.dropped = 0;
+                    self.discarding = false;
+                }
+                Ok(None) => {
+                    // Too large once its lengths or its bytes tell it
+                    if !self.discarding && self.scanner.pos().max(self.dropped + self.buffer.len()) > self.max_packet {
+                        events.push(Event::Error(ProtocolError::LimitExceeded("packet size")));
+                        self.discarding = true;
+                    }
+                    // Only the bytes from the next marker on are read
+                    if self.discarding {
+                        let scanned = (self.scanner.pos() - self.dropped).min(self.buffer.len());
+                        self.buffer.drain(..scanned);
+                        self.dropped += scanned;
+                    }
+                    break;
+                }
+                // Where the next packet starts is lost
+                Err(e) => {
+                    events.push(Event::Error(ProtocolError::Malformed(e)));
+                    self.buffer.clear();
+                    self.broken = true;
+                    break;
+                }
+            }
+        }
+        events
+    }
+
+    /// The event of a whole packet, as received from a transport keeping their boundaries (like the [`Adaptor`](crate::Adaptor)s)
+    pub fn feed_packet(&mut self, packet: &[u8]) -> Event {
+        self.parse(packet).unwrap_or_else(Event::Error)
+    }
+
+    fn parse(&mut self, packet: &[u8]) -> Result<Event, ProtocolError> {
+        use ProtocolError::*;
+
+        match parse_frame(packet, |_| Ok(())).map_err(|e| e.error)? {
+            Frame::Request { id, method, timeout, args, .. } => {
+                let method = read_method(method).ok_or(Malformed("request method"))?;
+                Ok(Event::Request { id, method, args: args.into(), timeout: timeout.map(Duration::from_millis) })
+            }
+            Frame::Notify { ack, method, args } => {
+                let method = read_method(method).ok_or(Malformed("notify method"))?;
+                Ok(Event::Notify { method, args: args.into(), ack })
+            }
+            Frame::Response { id, error, result } => {
+                let result = if error.is_nil() {
+                    Ok(result.into())
+                } else {
+                    Err(RemoteError::decode(&error).ok_or(Malformed("response error"))?)
+                };
+                let method = self.pending.remove(&id).ok_or(UnknownResponse(id))?;
+                Ok(Event::Response { id, method, result })
+            }
+            Frame::Ping(id) => Ok(Event::Ping(id)),
+            Frame::Pong(id) => Ok(Event::Pong(id)),
+            Frame::Compressed { .. } => Err(InvalidPackType(COMPRESSED)),
+            Frame::Fragment { .. } => Err(InvalidPackType(FRAGMENT)),
+            Frame::Attachment { .. } => Err(InvalidPackType(ATTACHMENT)),
+        }
+    }
+}
+
+fn read_method(mut method: &[u8]) -> Option<MethodBuf> {
+    let value = read_value(&mut method).ok()?;
+    Session::parse_method(&value).map(MethodBuf::from)
+}
+
+/// The parts of a packet, read the same way by a [`Session`] and a [`Protocol`]. The methods and the metadata are
+/// their msgpack bytes, the arguments and results the bytes left
+pub(crate) enum Frame<'a> {
+    Request { id: u64, method: &'a [u8], timeout: Option<u64>, metadata: Option<&'a [u8]>, args: &'a [u8] },
+    Notify { ack: Option<u64>, method: &'a [u8], args: &'a [u8] },
+    Response { id: u64, error: Value, result: &'a [u8] },
+    Ping(u64),
+    Pong(u64),
+    Compressed { codec: u64, data: &'a [u8] },
+    Fragment { id: u64, more: bool, data: &'a [u8] },
+    Attachment { id: u64, response: bool, data: &'a [u8] },
+}
+
+/// Why a packet can't be read, and the id of its request or response once it's read
+pub(crate) struct FrameError {
+    pub error: ProtocolError,
+    pub id: Option<u64>,
+}
+
+impl From<ProtocolError> for FrameError {
+    fn from(error: ProtocolError) -> Self { FrameError { error, id: None } }
+}
+
+/// Read a packet. `check` is given the bytes following the id of a request or response, or the ack id of a notify,
+/// before they're read
+pub(crate) fn parse_frame(pack: &[u8], check: impl Fn(&[u8]) -> Result<(), ProtocolError>) -> Result<Frame<'_>, FrameError> {
+    use ProtocolError::*;
+
+    let mut reader = pack;
+    let len = decode::read_array_len(&mut reader).map_err(|_| Malformed("packet header"))?;
+    let pack_type: u32 = decode::read_int(&mut reader).map_err(|_| Malformed("packet type"))?;
+    match pack_type {
+        REQUEST => {
+            let id = decode::read_int(&mut reader).map_err(|_| Malformed("request id"))?;
+            let failed = |error| FrameError { error, id: Some(id) };
+            check(reader).map_err(failed)?;
+            if !(4..=6).contains(&len) { return Err(failed(Malformed("request length"))); }
+            let method = take_value(&mut reader).ok_or_else(|| failed(Malformed("request method")))?;
+            // Nil when followed by the metadata only
+            let timeout = if len >= 5 {
+                match read_value(&mut reader) {
+                    Ok(Value::Nil) if len == 6 => None,
+                    Ok(timeout) if timeout.as_u64().is_some() => timeout.as_u64(),
+                    _ => return Err(failed(Malformed("request timeout"))),
+                }
+            } else { None };
+            let metadata = if len == 6 {
+                Some(take_value(&mut reader).ok_or_else(|| failed(Malformed("request metadata")))?)
+            } else { None };
+            Ok(Frame::Request { id, method, timeout, metadata, args: reader })
+        }
+        NOTIFY => {
+            if len != 3 && len != 4 { return Err(Malformed("notify length").into()); }
+            let ack = if len == 4 { Some(decode::read_int(&mut reader).map_err(|_| Malformed("notify ack id"))?) } else { None };
+            check(reader)?;
+            let method = take_value(&mut reader).ok_or(Malformed("notify method"))?;
+            Ok(Frame::Notify { ack, method, args: reader })
+        }
+        RESPONSE => {
+            if len != 4 { return Err(Malformed("response length").into()); }
+            let id = decode::read_int(&mut reader).map_err(|_| Malformed("response id"))?;
+            let failed = |error| FrameError { error, id: Some(id) };
+            check(reader).map_err(failed)?;
+            let error = read_value(&mut reader).map_err(|_| failed(Malformed("response error")))?;
+            Ok(Frame::Response { id, error, result: reader })
+        }
+        COMPRESSED => {
+            if len != 3 { return Err(Malformed("compressed length").into()); }
+            let codec = decode::read_int(&mut reader).map_err(|_| Malformed("compression codec"))?;
+            Ok(Frame::Compressed { codec, data: read_bin(&mut reader).ok_or(Malformed("compressed data"))? })
+        }
+        FRAGMENT => {
+            if len != 4 { return Err(Malformed("fragment length").into()); }
+            let id = decode::read_int(&mut reader).map_err(|_| Malformed("fragment id"))?;
+            let more = decode::read_bool(&mut reader).map_err(|_| Malformed("fragment flag"))?;
+            Ok(Frame::Fragment { id, more, data: read_bin(&mut reader).ok_or(Malformed("fragment data"))? })
+        }
+        PING | PONG => {
+            if len != 2 { return Err(Malformed(if pack_type == PING { "ping length" } else { "pong length" }).into()); }
+            let id = decode::read_int(&mut reader).map_err(|_| Malformed(if pack_type == PING { "ping id" } else { "pong id" }))?;
+            Ok(if pack_type == PING { Frame::Ping(id) } else { Frame::Pong(id) })
+        }
+        ATTACHMENT => {
+            if len != 4 { return Err(Malformed("attachment length").into()); }
+            let id = decode::read_int(&mut reader).map_err(|_| Malformed("attachment id"))?;
+            let response = decode::read_bool(&mut reader).map_err(|_| Malformed("attachment flag"))?;
+            Ok(Frame::Attachment { id, response, data: read_bin(&mut reader).ok_or(Malformed("attachment data"))? })
+        }
+        _ => Err(InvalidPackType(pack_type).into()),
+    }
+}
+
+/// The id of a request, read without the rest of its packet
+pub(crate) fn request_id(mut pack: &[u8]) -> Option<u64> {
+    decode::read_array_len(&mut pack).ok()?;
+    match decode::read_int(&mut pack).ok()? {
+        REQUEST => decode::read_int(&mut pack).ok(),
+        _ => None,
+    }
+}
+
+// The bytes of the msgpack value starting `reader`, skipped
+fn take_value<'a>(reader: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = Scanner::new().scan(reader, 0).ok()??;
+    let (value, rest) = reader.split_at(len);
+    *reader = rest;
+    Some(value)
+}
+
+fn read_bin<'a>(reader: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = decode::read_bin_len(reader).ok()? as usize;
+    reader.get(..len)
+}
+
+/// Write the header of a request, followed by its arguments. The timeout is written for the peers reading it, and
+/// the metadata for the ones reading it too
+pub(crate) fn write_request(pack: &mut Vec<u8>, id: u64, method: Method, timeout: Option<Duration>, metadata: Option<&Metadata>) {
+    encode::write_array_len(pack, if metadata.is_some() { 6 } else if timeout.is_some() { 5 } else { 4 });
+    encode::write_uint(pack, REQUEST as u64);
+    // Written in the shortest form, so peers using u32 ids still understand it
+    encode::write_uint(pack, id);
+    method.serialize(pack);
+    match timeout {
+        Some(timeout) => { encode::write_uint(pack, timeout.as_millis() as u64); }
+        None if metadata.is_some() => { encode::write_nil(pack); }
+        None => {}
+    }
+    if let Some(metadata) = metadata { metadata.write(pack); }
+}
+
+/// Write the header of a notify, followed by its arguments
+pub(crate) fn write_notify(pack: &mut Vec<u8>, ack: Option<u64>, method: Method) {
+    encode::write_array_len(pack, if ack.is_some() { 4 } else { 3 });
+    encode::write_uint(pack, NOTIFY as u64);
+    if let Some(ack) = ack { encode::write_uint(pack, ack); }
+    method.serialize(pack);
+}
+
+/// Write the header of a response, followed by its error and its result
+pub(crate) fn write_response(pack: &mut Vec<u8>, id: u64) {
+    encode::write_array_len(pack, 4);
+    encode::write_uint(pack, RESPONSE as u64);
+    encode::write_uint(pack, id);
+}
+
+/// Write the error of a response, without result
+pub(crate) fn write_fault(pack: &mut Vec<u8>, error: &RemoteError) {
+    error.encode(pack);
+    encode::write_nil(pack);
+}
+
+pub(crate) fn write_ping(pack: &mut Vec<u8>, pack_type: u32, id: u64) {
+    encode::write_array_len(pack, 2);
+    encode::write_uint(pack, pack_type as u64);
+    encode::write_uint(pack, id);
+}
+
+/// Finds where a msgpack value ends in bytes received piece by piece, see [`Walker`]
+#[derive(Debug, Clone)]
+struct Scanner(Walker);
+
+impl Scanner {
+    fn new() -> Self { Scanner(Walker::new(1, false)) }
+
+    /// Scan `buf`, the bytes of the value from `offset`, and tell where the value ends once `buf` holds all of it
+    fn scan(&mut self, buf: &[u8], offset: usize) -> Result<Option<usize>, &'static str> {
+        self.0.walk(buf, offset, |head, _| if head.kind == Kind::Invalid { Err("invalid marker") } else { Ok(()) })
+    }
+
+    /// Where the next value starts, from the start of the outer one
+    fn pos(&self) -> usize { self.0.pos }
+}
@@ -0,0 +1,199 @@
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::{RemoteError, Session, Sessions, encode_arg};
+
+/// Request subscribing the peer to a topic `TOPIC`, or `[TOPIC, KIND, N]` to also replay its events since the
+/// sequence number N (KIND 0) or the time N in milliseconds since the Unix epoch (KIND 1)
+pub(crate) const SUBSCRIBE_METHOD: &str = "$subscribe";
+/// `TOPIC`
+pub(crate) const UNSUBSCRIBE_METHOD: &str = "$unsubscribe";
+
+/// Where the replay of the past events of a topic starts, see [`Session::subscribe_since`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Since {
+    /// The events after this sequence number, 0 for all of them
+    Sequence(u64),
+    /// The events published at this time or later
+    Time(SystemTime),
+}
+
+impl Since {
+    pub(crate) fn encode(self) -> (u8, u64) {
+        match self {
+            Since::Sequence(seq) => (0, seq),
+            Since::Time(time) => (1, time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)),
+        }
+    }
+
+    fn decode(kind: u8, n: u64) -> Option<Since> {
+        match kind {
+            0 => Some(Since::Sequence(n)),
+            1 => Some(Since::Time(UNIX_EPOCH + Duration::from_millis(n))),
+            _ => None,
+        }
+    }
+}
+
+/// An event published to a topic, as kept by a [`History`]
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Counted from 1 in each topic
+    pub seq: u64,
+    pub time: SystemTime,
+    /// The encoded msgpack argument of the notify
+    pub arg: Vec<u8>,
+}
+
+/// Keeper of the past events of the topics of a [`Broker`], replayed to the subscribers asking for them,
+/// see [`Broker::set_history`]
+pub trait History: Send + Sync {
+    /// Keep an event published to `topic`
+    fn record(&self, topic: &str, event: Event);
+
+    /// The events of `topic` kept since `since`, oldest first
+    fn since(&self, topic: &str, since: Since) -> Vec<Event>;
+}
+
+/// A [`History`] keeping the last events of every topic in memory
+pub struct MemoryHistory {
+    capacity: usize,
+    topics: Mutex<HashMap<String, VecDeque<Event>>>,
+}
+
+impl MemoryHistory {
+    /// Keep `capacity` events of each topic
+    pub fn new(capacity: usize) -> Self {
+        MemoryHistory { capacity, topics: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl History for MemoryHistory {
+    fn record(&self, topic: &str, event: Event) {
+        let mut topics = self.topics.lock().unwrap();
+        let events = topics.entry(topic.to_string()).or_default();
+        if events.len() >= self.capacity { events.pop_front(); }
+        if self.capacity > 0 { events.push_back(event); }
+    }
+
+    fn since(&self, topic: &str, since: Since) -> Vec<Event> {
+        let topics = self.topics.lock().unwrap();
+        let events = match topics.get(topic) { Some(events) => events, None => return Vec::new() };
+        events.iter().filter(|e| match since {
+            Since::Sequence(seq) => e.seq > seq,
+            Since::Time(time) => e.time >= time,
+        }).cloned().collect()
+    }
+}
+
+/// The history of a broker, shared with the sessions attached to it
+#[derive(Default)]
+pub(crate) struct Replay {
+    history: RwLock<Option<Arc<dyn History>>>,
+    // The last sequence number of each topic, locked while an event is published or the history is replayed,
+    // so a subscriber gets the events it asks for before the next ones
+    sequences: Mutex<HashMap<String, u64>>,
+}
+
+/// Topics the peer of a session subscribed to
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    topics: RwLock<HashSet<String>>,
+    max: RwLock<Option<usize>>,
+    replay: RwLock<Option<Arc<Replay>>>,
+}
+
+impl Subscriptions {
+    /// Subscribe or unsubscribe as asked by the peer. A subscription asking for the past events is answered
+    /// the last sequence number of the topic, once its events are handed to `send`
+    pub fn handle(&self, method: &str, args: &[u8], send: impl Fn(&str, &Event)) -> Result<Option<u64>, RemoteError> {
+        let malformed = || RemoteError::new(RemoteError::MALFORMED, "Malformed topic");
+        let (topic, since) = match rmps::from_read_ref::<_, String>(args) {
+            Ok(topic) => (topic, None),
+            Err(_) => {
+                let (topic, kind, n): (String, u8, u64) = rmps::from_read_ref(args).map_err(|_| malformed())?;
+                (topic, Some(Since::decode(kind, n).ok_or_else(malformed)?))
+            }
+        };
+        if method == UNSUBSCRIBE_METHOD {
+            self.topics.write().unwrap().remove(&topic);
+            return Ok(None);
+        }
+        let since = match since { Some(since) => since, None => return self.insert(topic).map(|_| None) };
+        let replay = self.replay.read().unwrap().clone();
+        let (replay, history) = match replay.as_ref().and_then(|r| Some((r, r.history.read().unwrap().clone()?))) {
+            Some(found) => found,
+            None => return Err(RemoteError::new(RemoteError::UNAVAILABLE, "No history")),
+        };
+        let sequences = replay.sequences.lock().unwrap();
+        let last = sequences.get(&topic).copied().unwrap_or(0);
+        for event in history.since(&topic, since) { send(&topic, &event); }
+        self.insert(topic)?;
+        Ok(Some(last))
+    }
+
+    fn insert(&self, topic: String) -> Result<(), RemoteError> {
+        let mut topics = self.topics.write().unwrap();
+        let full = self.max.read().unwrap().is_some_and(|max| topics.len() >= max);
+        if full && !topics.contains(&topic) { return Err(RemoteError::new(RemoteError::LIMIT_EXCEEDED, "Too many subscriptions")); }
+        topics.insert(topic);
+        Ok(())
+    }
+
+    pub fn contains(&self, topic: &str) -> bool { self.topics.read().unwrap().contains(topic) }
+
+    pub fn clear(&self) { self.topics.write().unwrap().clear(); }
+
+    pub fn set_max(&self, max: Option<usize>) { *self.max.write().unwrap() = max; }
+
+    fn set_replay(&self, replay: Arc<Replay>) { *self.replay.write().unwrap() = Some(replay); }
+}
+
+/// Publish notifies to the sessions whose peer subscribed to their topic, see [`Session::subscribe`]
+#[derive(Default)]
+pub struct Broker {
+    sessions: Sessions,
+    replay: Arc<Replay>,
+}
+
+impl Broker {
+    pub fn new() -> Self { Self::default() }
+
+    /// Publish to the peer of `ss` the topics it subscribes to, and replay it the events of `history` it asks for
+    /// (the ones of the last broker attached to `ss`)
+    pub fn attach(&self, ss: &Arc<Session>) {
+        ss.subscriptions.set_replay(self.replay.clone());
+        self.sessions.add(ss);
+    }
+
+    /// Keep the events published in `history`, for the subscribers asking for the past ones with
+    /// [`Session::subscribe_since`]. `None` to keep none (the default)
+    pub fn set_history(&self, history: Option<Arc<dyn History>>) {
+        *self.replay.history.write().unwrap() = history;
+    }
+
+    /// Notify the subscribers of `topic` with `arg`, the method of the notify is `topic`.
+    /// Return the count of subscribers notified
+    pub fn publish(&self, topic: &str, arg: impl Serialize) -> usize {
+        let history = match self.replay.history.read().unwrap().clone() {
+            Some(history) => history,
+            None => return self.sessions.broadcast_notify_to(topic, arg, |ss| ss.subscribed(topic)),
+        };
+        let mut sequences = self.replay.sequences.lock().unwrap();
+        let seq = sequences.entry(topic.to_string()).or_insert(0);
+        *seq += 1;
+        let mut encoded = Vec::new();
+        encode_arg(&arg, &mut encoded, false);
+        history.record(topic, Event { seq: *seq, time: SystemTime::now(), arg: encoded });
+        self.sessions.broadcast_notify_to(topic, arg, |ss| ss.subscribed(topic))
+    }
+
+    /// The sequence number of the last event published to `topic` while keeping a history, 0 if none was
+    pub fn sequence(&self, topic: &str) -> u64 {
+        self.replay.sequences.lock().unwrap().get(topic).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,122 @@
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+
+use crate::Adaptor;
+
+/// What to do when the outgoing queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Block the sending thread until there is room in the queue
+    Block,
+    /// Fail immediately with [`SendError::WouldBlock`]
+    Fail,
+}
+
+/// Error of sending a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The outgoing queue is full
+    WouldBlock,
+    /// The adaptor is disconnected
+    Disconnect,
+    /// The packet is larger than the [`SizeLimits`](crate::SizeLimits) of the session allow
+    TooLarge,
+}
+
+/// Priority of an outgoing packet. The send queue always sends the packets of a higher priority first,
+/// so control messages are not delayed by bulk transfers queued before them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Low = 0,
+    #[default]
+    Normal = 1,
+    High = 2,
+}
+
+struct Lanes {
+    // Indexed by `Priority`
+    lanes: [VecDeque<Vec<u8>>; 3],
+    closed: bool,
+}
+
+impl Lanes {
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        self.lanes.iter_mut().rev().find_map(|lane| lane.pop_front())
+    }
+}
+
+/// Bounded queue of outgoing packets with a lane per priority, drained by a writer thread
+pub(crate) struct SendQueue {
+    shared: Arc<(Mutex<Lanes>, Condvar)>,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl SendQueue {
+    /// `capacity` is the maximum count of packets in each lane
+    pub fn spawn(adaptor: Arc<dyn Adaptor>, capacity: usize, overflow: Overflow) -> Self {
+        let lanes = Lanes { lanes: Default::default(), closed: false };
+        let shared = Arc::new((Mutex::new(lanes), Condvar::new()));
+        let writer = shared.clone();
+        // The thread exits after the queue is dropped and drained, or when the adaptor disconnected
+        std::thread::spawn(move || {
+            let (lock, cond) = &*writer;
+            loop {
+                let mut lanes = lock.lock().unwrap();
+                let pack = loop {
+                    if let Some(pack) = lanes.pop() { break pack; }
+                    if lanes.closed { return; }
+                    lanes = cond.wait(lanes).unwrap();
+                };
+                cond.notify_all();
+                drop(lanes);
+
+                if !adaptor.send(pack) {
+                    let mut lanes = lock.lock().unwrap();
+                    lanes.closed = true;
+                    lanes.lanes.iter_mut().for_each(VecDeque::clear);
+                    cond.notify_all(); return;
+                }
+            }
+        });
+        SendQueue { shared, capacity: capacity.max(1), overflow }
+    }
+
+    /// Wait for room in the queue whatever the overflow policy is
+    pub fn push_wait(&self, pack: Vec<u8>, priority: Priority) -> Result<(), SendError> {
+        self.push_with(pack, priority, Overflow::Block)
+    }
+
+    pub fn push(&self, pack: Vec<u8>, priority: Priority) -> Result<(), SendError> {
+        self.push_with(pack, priority, self.overflow)
+    }
+
+    /// The count of packets in each lane
+    pub fn depths(&self) -> [usize; 3] {
+        let lanes = self.shared.0.lock().unwrap();
+        [lanes.lanes[0].len(), lanes.lanes[1].len(), lanes.lanes[2].len()]
+    }
+
+    fn push_with(&self, pack: Vec<u8>, priority: Priority, overflow: Overflow) -> Result<(), SendError> {
+        let (lock, cond) = &*self.shared;
+        let mut lanes = lock.lock().unwrap();
+        loop {
+            if lanes.closed { return Err(SendError::Disconnect); }
+            if lanes.lanes[priority as usize].len() < self.capacity { break; }
+            if overflow == Overflow::Fail { return Err(SendError::WouldBlock); }
+            lanes = cond.wait(lanes).unwrap();
+        }
+        lanes.lanes[priority as usize].push_back(pack);
+        cond.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        let (lock, cond) = &*self.shared;
+        lock.lock().unwrap().closed = true;
+        cond.notify_all();
+    }
+}
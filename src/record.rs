@@ -0,0 +1,128 @@
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{Adaptor, Direction, PacketTap, RecvError};
+
+/// Start of a recording, followed by one record per frame:
+///
+/// | bytes | field                                                      |
+/// |-------|------------------------------------------------------------|
+/// | 1     | direction, 0 if the session sent it, 1 if it received it   |
+/// | 8     | microseconds since the recording started, big endian       |
+/// | 4     | length of the frame, big endian                            |
+/// | len   | the frame, as handed to the adaptor or received from it    |
+pub const MAGIC: &[u8; 8] = b"ERPCREC1";
+
+/// A frame of a recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub direction: Direction,
+    /// When it was seen, since the recording started
+    pub time: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Tap recording every frame to `out`, for [`Session::set_packet_tap`](crate::Session::set_packet_tap).
+/// Each one is flushed, so the recording of a crashed process is complete
+pub fn tap(mut out: impl Write + Send + 'static) -> io::Result<PacketTap> {
+    out.write_all(MAGIC)?;
+    let start = Instant::now();
+    let out = Mutex::new(out);
+    Ok(Arc::new(move |direction, data: &[u8]| {
+        let mut record = Vec::with_capacity(13 + data.len());
+        record.push(match direction { Direction::Sent => 0, Direction::Received => 1 });
+        record.extend_from_slice(&(start.elapsed().as_micros() as u64).to_be_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+        let mut out = out.lock().unwrap();
+        out.write_all(&record);
+        out.flush();
+    }))
+}
+
+/// [`tap`] recording to a new file at `path`
+pub fn create(path: impl AsRef<Path>) -> io::Result<PacketTap> { tap(File::create(path)?) }
+
+/// The frames of a recording
+pub fn read(mut input: impl Read) -> io::Result<Vec<Frame>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC { return Err(invalid("Not a recording")); }
+    let mut frames = Vec::new();
+    let mut header = [0; 13];
+    loop {
+        // A record cut by a crash ends the recording
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(frames),
+            Err(e) => return Err(e),
+        }
+        let direction = match header[0] { 0 => Direction::Sent, 1 => Direction::Received, _ => return Err(invalid("Unknown direction")) };
+        let mut time = [0; 8];
+        time.copy_from_slice(&header[1..9]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[9..]);
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        match input.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(frames),
+            Err(e) => return Err(e),
+        }
+        frames.push(Frame { direction, time: Duration::from_micros(u64::from_be_bytes(time)), data });
+    }
+}
+
+/// The frames of the recording at `path`
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Frame>> { read(BufReader::new(File::open(path)?)) }
+
+/// Adaptor playing the peer of a recording: a session over it receives the frames the recorded one received, then
+/// gets disconnected. What it sends is kept, to compare with the frames the recorded one sent
+pub struct Replay {
+    received: Mutex<VecDeque<Vec<u8>>>,
+    sent: Mutex<Vec<Vec<u8>>>,
+    connected: AtomicBool,
+}
+
+impl Replay {
+    pub fn new(frames: impl IntoIterator<Item = Frame>) -> Arc<Replay> {
+        let received = frames.into_iter().filter(|f| f.direction == Direction::Received).map(|f| f.data).collect();
+        Arc::new(Replay { received: Mutex::new(received), sent: Default::default(), connected: AtomicBool::new(true) })
+    }
+
+    /// The frames sent by the session so far
+    pub fn sent(&self) -> Vec<Vec<u8>> { self.sent.lock().unwrap().clone() }
+}
+
+impl Adaptor for Replay {
+    fn send(&self, data: Vec<u8>) -> bool {
+        self.sent.lock().unwrap().push(data);
+        true
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        match self.received.lock().unwrap().pop_front() {
+            Some(frame) if self.connected() => Ok(frame),
+            _ => {
+                self.close();
+                Err(RecvError::Disconnect)
+            }
+        }
+    }
+
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> { Some(self.recv()) }
+
+    fn recv_timeout(&self, _timeout: Duration) -> Result<Vec<u8>, RecvError> { self.recv() }
+
+    fn connected(&self) -> bool { self.connected.load(Ordering::SeqCst) }
+
+    fn close(&self) { self.connected.store(false, Ordering::SeqCst); }
+
+    fn transport_kind(&self) -> Option<&'static str> { Some("replay") }
+}
@@ -0,0 +1,30 @@
+
+use crate::*;
+
+/// Service forwarding the requests and notifies of a session to another one, e.g. in a gateway between local clients
+/// and a remote server. The upstream request has its own id and its response is answered as it is, the payloads aren't
+/// decoded so both sessions must encode them the same way. A request is forwarded on the thread handling it, set a
+/// [`WorkerPool`] on the downstream session to forward several at once
+pub struct Relay {
+    upstream: Arc<Session>,
+}
+
+impl Relay {
+    pub fn new(upstream: Arc<Session>) -> Self { Relay { upstream } }
+
+    pub fn upstream(&self) -> &Arc<Session> { &self.upstream }
+}
+
+impl Service for Relay {
+    fn handle(&self, _ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        if !ret.is_valid() {
+            unsafe { self.upstream.notify_transfer(arg.method, arg.bytes); }
+            return Ok(());
+        }
+        match unsafe { self.upstream.request_transfer(arg.method, arg.bytes) } {
+            RequestResult::Disconnect => Err(HandleError::new(ErrorKind::Unavailable, "Upstream disconnected")),
+            RequestResult::WouldBlock => Err(HandleError::new(ErrorKind::Unavailable, "Upstream busy")),
+            result => { ret.forward(result); Ok(()) }
+        }
+    }
+}
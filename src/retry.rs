@@ -0,0 +1,121 @@
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{ErrorKind, RequestError, RequestResult};
+
+/// When a failed request is sent again, see [`Session::set_retry_policy`](crate::Session::set_retry_policy) and
+/// [`Failover::retry_policy`](crate::Failover::retry_policy). The attempts of a request carry the same idempotency
+/// key, so a peer keeping an [`IdempotencyCache`] handles it once
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled before each next one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Retry when the session disconnected before the response, only useful with a new session
+    pub on_disconnect: bool,
+    /// Retry when the send queue is full, see [`Session::set_send_queue`](crate::Session::set_send_queue)
+    pub on_full_queue: bool,
+    /// The kinds of errors of the peer retried
+    pub on_errors: Vec<ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            on_disconnect: true,
+            on_full_queue: true,
+            on_errors: vec![ErrorKind::Unavailable, ErrorKind::Throttled],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a request which failed with `result` can be sent again
+    pub fn retries(&self, result: &RequestResult) -> bool {
+        match result {
+            RequestResult::Disconnect => self.on_disconnect,
+            RequestResult::WouldBlock => self.on_full_queue,
+            RequestResult::Error(e) => self.on_errors.contains(&ErrorKind::from_code(e.code)),
+            RequestResult::Data(_) | RequestResult::Decode(_) => false,
+        }
+    }
+
+    pub(crate) fn retries_error(&self, error: &RequestError) -> bool {
+        match error {
+            RequestError::Disconnected => self.on_disconnect,
+            RequestError::WouldBlock => self.on_full_queue,
+            RequestError::Remote(e) => self.on_errors.contains(&ErrorKind::from_code(e.code)),
+            RequestError::Timeout | RequestError::Protocol(_) => false,
+            // It wasn't sent
+            RequestError::Connect(_) => true,
+        }
+    }
+
+    /// The delay before the attempt following the `attempt`th one
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.backoff.checked_mul(factor).unwrap_or(self.max_backoff).min(self.max_backoff)
+    }
+}
+
+pub(crate) fn new_key() -> String { format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>()) }
+
+enum State {
+    Running,
+    /// The response, after its header
+    Done(Vec<u8>),
+}
+
+/// Responses to the requests sent with an idempotency key, answered again to their other attempts instead of handling
+/// them twice, e.g. after the client reconnected. Share it among the sessions of a server, see
+/// [`Session::set_idempotency_cache`](crate::Session::set_idempotency_cache). An attempt arriving while the first
+/// one is handled is answered an [`UNAVAILABLE`](crate::RemoteError::UNAVAILABLE) error, retried by default
+pub struct IdempotencyCache {
+    entries: Mutex<HashMap<String, (State, Instant)>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+pub(crate) enum Attempt {
+    First,
+    Running,
+    Done(Vec<u8>),
+}
+
+impl IdempotencyCache {
+    /// Keep at most `capacity` responses, each one for `ttl`
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        IdempotencyCache { entries: Mutex::new(HashMap::new()), capacity, ttl }
+    }
+
+    /// Count of requests handled or being handled
+    pub fn len(&self) -> usize { self.entries.lock().unwrap().len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub(crate) fn begin(&self, key: &str, now: Instant) -> Attempt {
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, (_, since)| now.saturating_duration_since(*since) < ttl);
+        match entries.get(key) {
+            Some((State::Running, _)) => return Attempt::Running,
+            Some((State::Done(response), _)) => return Attempt::Done(response.clone()),
+            None => {}
+        }
+        // Full of requests younger than the ttl, the new one isn't protected
+        if entries.len() >= self.capacity { return Attempt::First; }
+        entries.insert(key.into(), (State::Running, now));
+        Attempt::First
+    }
+
+    pub(crate) fn finish(&self, key: &str, response: Vec<u8>) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) { entry.0 = State::Done(response); }
+    }
+}
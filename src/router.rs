@@ -0,0 +1,116 @@
+
+use std::any::type_name;
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::*;
+use crate::introspect::MethodInfo;
+use crate::schema::Schema;
+
+type Handler = Box<dyn Fn(&Session, Arg, Ret) -> Result<(), HandleError> + Send + Sync>;
+
+/// Service dispatching the string methods, and their [`method_id`], to closures, the other methods go to the fallback.
+/// The arguments are decoded as a whole, so several ones are taken as a tuple. Registering two methods of the same id panics.
+/// The layers and the limits set by method name (e.g. [`middleware::Acl`], [`Throttle`]) key their rules by
+/// [`Method::id`], so they hold for the ids too
+pub struct Router {
+    handlers: HashMap<String, (Handler, MethodInfo)>,
+    // The names of the methods by their id
+    ids: HashMap<u32, String>,
+    schemas: HashMap<String, Schema>,
+    fallback: Option<Handler>,
+}
+
+impl Default for Router {
+    fn default() -> Self { Router::new() }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            handlers: HashMap::new(),
+            ids: HashMap::new(),
+            schemas: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    // Panics if the id of `method` is the one of another method, so they can't be told apart
+    fn register(&mut self, method: &str, handler: Handler, info: MethodInfo) {
+        let id = method_id(method);
+        if let Some(other) = self.ids.get(&id).filter(|&other| other != method) {
+            panic!("The methods {:?} and {:?} have the same id {:#x}", other, method, id);
+        }
+        self.ids.insert(id, method.into());
+        self.handlers.insert(method.into(), (handler, info));
+    }
+
+    fn name<'a>(&'a self, method: Method<'a>) -> Option<&'a str> {
+        match method { Method::Str(method) => Some(method), Method::Int(id) => self.ids.get(&id).map(String::as_str) }
+    }
+
+    /// Handle `method`, answering the value returned when it's requested
+    pub fn on<A, R, F>(mut self, method: &str, handler: F) -> Self
+    where A: DeserializeOwned, R: Serialize, F: Fn(&Session, A) -> Result<R, HandleError> + Send + Sync + 'static {
+        let info = MethodInfo::new(method, type_name::<A>(), Some(type_name::<R>()));
+        self.register(method, Box::new(move |ss, arg, ret| {
+            let val = handler(ss, arg.into()?)?;
+            ret.ok(val);
+            Ok(())
+        }), info);
+        self
+    }
+
+    /// Handle the notifies of `method`, a request of it is answered nil once handled
+    pub fn on_notify<A, F>(mut self, method: &str, handler: F) -> Self
+    where A: DeserializeOwned, F: Fn(&Session, A) + Send + Sync + 'static {
+        let info = MethodInfo::new(method, type_name::<A>(), None);
+        self.register(method, Box::new(move |ss, arg, ret| {
+            handler(ss, arg.into()?);
+            ret.ok(());
+            Ok(())
+        }), info);
+        self
+    }
+
+    /// Reject the arguments of `method` which don't match `schema`, before they reach its handler
+    pub fn schema(mut self, method: &str, schema: Schema) -> Self {
+        self.schemas.insert(method.into(), schema);
+        self
+    }
+
+    /// Handle the methods without a handler, instead of answering `MethodNotFound`
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where F: Fn(&Session, Arg, Ret) -> Result<(), HandleError> + Send + Sync + 'static {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+}
+
+impl Service for Router {
+    fn handle(&self, ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        let handler = self.name(arg.method).and_then(|method| self.handlers.get(method)).map(|(handler, _)| handler);
+        match handler.or(self.fallback.as_ref()) {
+            Some(handler) => handler(ss, arg, ret),
+            None => Err(HandleError::new(ErrorKind::MethodNotFound, "No this method")),
+        }
+    }
+
+    /// Unknown for the methods going to the fallback
+    fn supports(&self, method: Method) -> Option<bool> {
+        let handled = self.name(method).is_some_and(|method| self.handlers.contains_key(method));
+        if handled { Some(true) } else if self.fallback.is_some() { None } else { Some(false) }
+    }
+
+    fn schema(&self, method: Method) -> Option<&Schema> {
+        self.name(method).and_then(|method| self.schemas.get(method))
+    }
+
+    fn methods(&self) -> Vec<MethodInfo> {
+        let mut methods: Vec<_> = self.handlers.values().map(|(_, info)| info.clone()).collect();
+        methods.sort_by(|a, b| a.name.cmp(&b.name));
+        methods
+    }
+}
@@ -0,0 +1,419 @@
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use rmpv::Value;
+
+use crate::{Method, Service};
+use crate::introspect::MethodInfo;
+
+/// Expected shape of a msgpack value, returned by [`Service::schema`](crate::Service::schema) so the session
+/// rejects the arguments which don't match with an `InvalidArgs` error before the handler runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schema {
+    Any,
+    Nil,
+    Bool,
+    /// Integer in the inclusive bounds
+    Int { min: Option<i64>, max: Option<i64> },
+    /// Float or integer
+    Float,
+    Str { max_len: Option<usize> },
+    Bin { max_len: Option<usize> },
+    /// Array of items of a schema
+    Array(Box<Schema>),
+    /// Array of a fixed count of items
+    Tuple(Vec<Schema>),
+    /// Map whose values match a schema
+    Map(Box<Schema>),
+    /// A struct, as a map with these keys or as an array in this order like rmp-serde writes it.
+    /// The `Optional` fields may be missing
+    Record(Vec<(String, Schema)>),
+    /// The schema or nil
+    Optional(Box<Schema>),
+    /// Any of the schemas
+    OneOf(Vec<Schema>),
+}
+
+/// Where and why a value doesn't match a schema. The path starts at `$`, e.g. `$.items[2]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError {
+    pub path: String,
+    pub expected: String,
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}: expected {}", self.path, self.expected)
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl Schema {
+    pub const INT: Schema = Schema::Int { min: None, max: None };
+    pub const STR: Schema = Schema::Str { max_len: None };
+    pub const BIN: Schema = Schema::Bin { max_len: None };
+
+    pub fn array(items: Schema) -> Schema { Schema::Array(Box::new(items)) }
+
+    pub fn map(values: Schema) -> Schema { Schema::Map(Box::new(values)) }
+
+    pub fn optional(schema: Schema) -> Schema { Schema::Optional(Box::new(schema)) }
+
+    pub fn record<'a>(fields: impl IntoIterator<Item = (&'a str, Schema)>) -> Schema {
+        Schema::Record(fields.into_iter().map(|(name, schema)| (name.into(), schema)).collect())
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), SchemaError> {
+        self.check(value, &mut String::from("$"))
+    }
+
+    fn check(&self, value: &Value, path: &mut String) -> Result<(), SchemaError> {
+        let valid = match (self, value) {
+            (Schema::Any, _) | (Schema::Nil, Value::Nil) | (Schema::Bool, Value::Boolean(_)) => true,
+            (Schema::Int { min, max }, Value::Integer(i)) => {
+                let above = min.is_none_or(|min| i.as_i64().is_none_or(|i| i >= min));
+                let below = max.is_none_or(|max| i.as_i64().is_some_and(|i| i <= max));
+                above && below
+            }
+            (Schema::Float, Value::F32(_)) | (Schema::Float, Value::F64(_)) | (Schema::Float, Value::Integer(_)) => true,
+            (Schema::Str { max_len }, Value::String(s)) => s.as_str().is_some() && max_len.is_none_or(|max| s.as_bytes().len() <= max),
+            (Schema::Bin { max_len }, Value::Binary(b)) => max_len.is_none_or(|max| b.len() <= max),
+            (Schema::Array(items), Value::Array(values)) => {
+                for (i, value) in values.iter().enumerate() { items.check_at(value, path, &format!("[{}]", i))?; }
+                true
+            }
+            (Schema::Tuple(items), Value::Array(values)) if items.len() == values.len() => {
+                for (i, (item, value)) in items.iter().zip(values).enumerate() { item.check_at(value, path, &format!("[{}]", i))?; }
+                true
+            }
+            (Schema::Map(schema), Value::Map(entries)) => {
+                for (key, value) in entries { schema.check_at(value, path, &format!("[{}]", key))?; }
+                true
+            }
+            (Schema::Record(fields), Value::Map(entries)) => {
+                for (name, schema) in fields {
+                    match entries.iter().find(|(k, _)| k.as_str() == Some(name)) {
+                        Some((_, value)) => schema.check_at(value, path, &format!(".{}", name))?,
+                        None if schema.is_optional() => {}
+                        None => return Err(SchemaError { path: format!("{}.{}", path, name), expected: schema.to_string() }),
+                    }
+                }
+                true
+            }
+            (Schema::Record(fields), Value::Array(values)) if values.len() <= fields.len() => {
+                for (i, (name, schema)) in fields.iter().enumerate() {
+                    match values.get(i) {
+                        Some(value) => schema.check_at(value, path, &format!(".{}", name))?,
+                        None if schema.is_optional() => {}
+                        None => return Err(SchemaError { path: format!("{}.{}", path, name), expected: schema.to_string() }),
+                    }
+                }
+                true
+            }
+            (Schema::Optional(_), Value::Nil) => true,
+            (Schema::Optional(schema), value) => return schema.check(value, path),
+            (Schema::OneOf(schemas), value) => schemas.iter().any(|s| s.validate(value).is_ok()),
+            _ => false,
+        };
+        if valid { Ok(()) } else { Err(SchemaError { path: path.clone(), expected: self.to_string() }) }
+    }
+
+    fn check_at(&self, value: &Value, path: &mut String, step: &str) -> Result<(), SchemaError> {
+        let len = path.len();
+        path.push_str(step);
+        self.check(value, path)?;
+        path.truncate(len);
+        Ok(())
+    }
+
+    /// Whether the values matching `other` all match this schema too, e.g. a new version of the arguments of a method
+    /// accepting the ones of the older clients
+    pub fn accepts_all(&self, other: &Schema) -> bool { self.narrowing(other, &mut String::from("$")).is_none() }
+
+    // The first part of `other` whose values this schema doesn't all accept: its path, then both schemas there
+    fn narrowing(&self, other: &Schema, path: &mut String) -> Option<(String, String, String)> {
+        let within = |bound: &Option<usize>, other: &Option<usize>| bound.is_none_or(|max| other.is_some_and(|o| o <= max));
+        let accepted = match (self, other) {
+            (Schema::Any, _) => true,
+            (_, Schema::OneOf(others)) => return others.iter().find_map(|other| self.narrowing(other, path)),
+            (Schema::OneOf(schemas), other) => schemas.iter().any(|schema| schema.accepts_all(other)),
+            (Schema::Optional(_), Schema::Nil) => true,
+            (Schema::Optional(schema), Schema::Optional(other)) => return schema.narrowing(other, path),
+            (Schema::Optional(schema), other) => return schema.narrowing(other, path),
+            (Schema::Nil, Schema::Nil) | (Schema::Bool, Schema::Bool) | (Schema::Float, Schema::Float) | (Schema::Float, Schema::Int { .. }) => true,
+            (Schema::Int { min, max }, Schema::Int { min: other_min, max: other_max }) => {
+                min.is_none_or(|min| other_min.is_some_and(|o| o >= min)) && max.is_none_or(|max| other_max.is_some_and(|o| o <= max))
+            }
+            (Schema::Str { max_len }, Schema::Str { max_len: other }) | (Schema::Bin { max_len }, Schema::Bin { max_len: other }) => within(max_len, other),
+            (Schema::Array(items), Schema::Array(other)) | (Schema::Map(items), Schema::Map(other)) => return items.narrowing_at(other, path, "[]"),
+            (Schema::Array(items), Schema::Tuple(others)) => {
+                return others.iter().enumerate().find_map(|(i, other)| items.narrowing_at(other, path, &format!("[{}]", i)));
+            }
+            (Schema::Tuple(items), Schema::Tuple(others)) if items.len() == others.len() => {
+                return items.iter().zip(others).enumerate().find_map(|(i, (item, other))| item.narrowing_at(other, path, &format!("[{}]", i)));
+            }
+            // The fields keep their place for the records written as arrays, the new ones come last and are optional
+            (Schema::Record(fields), Schema::Record(others)) if others.len() <= fields.len() => {
+                let renamed = fields.iter().zip(others).any(|((name, _), (other, _))| name != other);
+                if !renamed {
+                    return fields.iter().enumerate().find_map(|(i, (name, schema))| match others.get(i) {
+                        Some((_, other)) => schema.narrowing_at(other, path, &format!(".{}", name)),
+                        None if schema.is_optional() => None,
+                        None => Some((format!("{}.{}", path, name), schema.to_string(), "nothing".into())),
+                    });
+                }
+                false
+            }
+            _ => false,
+        };
+        if accepted { None } else { Some((path.clone(), self.to_string(), other.to_string())) }
+    }
+
+    fn narrowing_at(&self, other: &Schema, path: &mut String, step: &str) -> Option<(String, String, String)> {
+        let len = path.len();
+        path.push_str(step);
+        let narrowing = self.narrowing(other, path);
+        path.truncate(len);
+        narrowing
+    }
+
+    /// A value matching the schema, e.g. the arguments of the requests of a contract (see
+    /// [`Golden::contract`](crate::golden::Golden::contract)). The arrays and maps have an item, the optional values
+    /// are given and the records are written as arrays
+    pub fn example(&self) -> Value {
+        match self {
+            Schema::Any | Schema::Nil => Value::Nil,
+            Schema::Bool => Value::Boolean(false),
+            Schema::Int { min, max } => Value::from(min.unwrap_or_else(|| max.map_or(0, |max| max.min(0)))),
+            Schema::Float => Value::F64(0.0),
+            Schema::Str { .. } => Value::from(""),
+            Schema::Bin { .. } => Value::Binary(Vec::new()),
+            Schema::Array(items) => Value::Array(vec![items.example()]),
+            Schema::Tuple(items) => Value::Array(items.iter().map(Schema::example).collect()),
+            Schema::Map(values) => Value::Map(vec![(Value::from("key"), values.example())]),
+            Schema::Record(fields) => Value::Array(fields.iter().map(|(_, schema)| schema.example()).collect()),
+            Schema::Optional(schema) => schema.example(),
+            Schema::OneOf(schemas) => schemas.first().map_or(Value::Nil, Schema::example),
+        }
+    }
+
+    /// The schema as a msgpack value, read back by [`Schema::from_value`]: a string for the ones without parameters
+    /// (e.g. `"str"`), otherwise an array of the name and the parameters (e.g. `["int", 1, nil]`)
+    pub fn to_value(&self) -> Value {
+        let bound = |bound: &Option<i64>| bound.map_or(Value::Nil, Value::from);
+        let len = |len: &Option<usize>| len.map_or(Value::Nil, |len| Value::from(len as u64));
+        let list = |schemas: &[Schema]| Value::Array(schemas.iter().map(Schema::to_value).collect());
+        let tagged = |tag: &str, params: Vec<Value>| Value::Array(std::iter::once(Value::from(tag)).chain(params).collect());
+        match self {
+            Schema::Any => Value::from("any"),
+            Schema::Nil => Value::from("nil"),
+            Schema::Bool => Value::from("bool"),
+            Schema::Int { min: None, max: None } => Value::from("int"),
+            Schema::Int { min, max } => tagged("int", vec![bound(min), bound(max)]),
+            Schema::Float => Value::from("float"),
+            Schema::Str { max_len: None } => Value::from("str"),
+            Schema::Str { max_len } => tagged("str", vec![len(max_len)]),
+            Schema::Bin { max_len: None } => Value::from("bin"),
+            Schema::Bin { max_len } => tagged("bin", vec![len(max_len)]),
+            Schema::Array(items) => tagged("array", vec![items.to_value()]),
+            Schema::Tuple(items) => tagged("tuple", vec![list(items)]),
+            Schema::Map(values) => tagged("map", vec![values.to_value()]),
+            Schema::Record(fields) => {
+                let fields = fields.iter().map(|(name, schema)| Value::Array(vec![Value::from(name.as_str()), schema.to_value()]));
+                tagged("record", vec![Value::Array(fields.collect())])
+            }
+            Schema::Optional(schema) => tagged("optional", vec![schema.to_value()]),
+            Schema::OneOf(schemas) => tagged("one_of", vec![list(schemas)]),
+        }
+    }
+
+    pub fn from_value(value: &Value) -> Result<Schema, SchemaError> {
+        Self::read(value, &mut String::from("$"))
+    }
+
+    fn read(value: &Value, path: &mut String) -> Result<Schema, SchemaError> {
+        let invalid = |path: &str| SchemaError { path: path.into(), expected: "schema".into() };
+        let (tag, params) = match value {
+            Value::String(tag) => (tag.as_str(), &[][..]),
+            Value::Array(items) => match items.split_first() {
+                Some((Value::String(tag), params)) => (tag.as_str(), params),
+                _ => return Err(invalid(path)),
+            },
+            _ => return Err(invalid(path)),
+        };
+        let bound = |value: Option<&Value>| match value {
+            None | Some(Value::Nil) => Ok(None),
+            Some(value) => value.as_i64().map(Some).ok_or_else(|| invalid(path)),
+        };
+        let len = |value: Option<&Value>| match value {
+            None | Some(Value::Nil) => Ok(None),
+            Some(value) => value.as_u64().map(|len| Some(len as usize)).ok_or_else(|| invalid(path)),
+        };
+        let schema = match tag {
+            Some("any") => Schema::Any,
+            Some("nil") => Schema::Nil,
+            Some("bool") => Schema::Bool,
+            Some("int") => Schema::Int { min: bound(params.first())?, max: bound(params.get(1))? },
+            Some("float") => Schema::Float,
+            Some("str") => Schema::Str { max_len: len(params.first())? },
+            Some("bin") => Schema::Bin { max_len: len(params.first())? },
+            Some("array") | Some("map") | Some("optional") => {
+                let inner = Box::new(Self::read_at(params.first().ok_or_else(|| invalid(path))?, path, "[1]")?);
+                match tag { Some("array") => Schema::Array(inner), Some("map") => Schema::Map(inner), _ => Schema::Optional(inner) }
+            }
+            Some("tuple") | Some("one_of") => {
+                let items = params.first().and_then(Value::as_array).ok_or_else(|| invalid(path))?;
+                let items = items.iter().enumerate().map(|(i, item)| Self::read_at(item, path, &format!("[1][{}]", i))).collect::<Result<_, _>>()?;
+                if tag == Some("tuple") { Schema::Tuple(items) } else { Schema::OneOf(items) }
+            }
+            Some("record") => {
+                let fields = params.first().and_then(Value::as_array).ok_or_else(|| invalid(path))?;
+                let fields = fields.iter().enumerate().map(|(i, field)| {
+                    match field.as_array().map(Vec::as_slice) {
+                        Some([Value::String(name), schema]) if name.as_str().is_some() => {
+                            Ok((name.as_str().unwrap_or_default().to_string(), Self::read_at(schema, path, &format!("[1][{}][1]", i))?))
+                        }
+                        _ => Err(invalid(&format!("{}[1][{}]", path, i))),
+                    }
+                }).collect::<Result<_, _>>()?;
+                Schema::Record(fields)
+            }
+            _ => return Err(invalid(path)),
+        };
+        Ok(schema)
+    }
+
+    fn read_at(value: &Value, path: &mut String, step: &str) -> Result<Schema, SchemaError> {
+        let len = path.len();
+        path.push_str(step);
+        let schema = Self::read(value, path)?;
+        path.truncate(len);
+        Ok(schema)
+    }
+
+    fn is_optional(&self) -> bool {
+        matches!(self, Schema::Optional(_) | Schema::Any | Schema::Nil)
+    }
+}
+
+impl Display for Schema {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Schema::Any => write!(f, "any value"),
+            Schema::Nil => write!(f, "nil"),
+            Schema::Bool => write!(f, "boolean"),
+            Schema::Int { min: None, max: None } => write!(f, "integer"),
+            Schema::Int { min, max } => write!(f, "integer in {}..={}",
+                min.map_or(String::new(), |m| m.to_string()), max.map_or(String::new(), |m| m.to_string())),
+            Schema::Float => write!(f, "number"),
+            Schema::Str { max_len: None } => write!(f, "string"),
+            Schema::Str { max_len: Some(max) } => write!(f, "string of at most {} bytes", max),
+            Schema::Bin { max_len: None } => write!(f, "binary"),
+            Schema::Bin { max_len: Some(max) } => write!(f, "binary of at most {} bytes", max),
+            Schema::Array(items) => write!(f, "array of {}", items),
+            Schema::Tuple(items) => write!(f, "array of {} items", items.len()),
+            Schema::Map(values) => write!(f, "map of {}", values),
+            Schema::Record(fields) => write!(f, "record {{{}}}", fields.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ")),
+            Schema::Optional(schema) => write!(f, "{} or nil", schema),
+            Schema::OneOf(schemas) => write!(f, "{}", schemas.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" or ")),
+        }
+    }
+}
+
+/// The methods of a service with the schema of their arguments, see [`Service::methods`] and [`Service::schema`].
+/// Kept with [`SchemaExport::to_vec`], it tells the [`SchemaExport::breaking_changes`] of the next versions
+/// to check the wire compatibility before deploying them
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SchemaExport {
+    pub methods: Vec<(MethodInfo, Option<Schema>)>,
+}
+
+/// A change of a method breaking the clients of the previous version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakingChange {
+    pub method: String,
+    pub reason: String,
+}
+
+impl Display for BreakingChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}: {}", self.method, self.reason)
+    }
+}
+
+impl SchemaExport {
+    pub fn of(service: &dyn Service) -> Self {
+        let methods = service.methods().into_iter().map(|info| {
+            let schema = service.schema(Method::Str(&info.name)).cloned();
+            (info, schema)
+        }).collect();
+        SchemaExport { methods }
+    }
+
+    /// The export in msgpack, an array of maps `{"name", "params", "returns", "schema"}` whose `schema` is the one of
+    /// [`Schema::to_value`] or nil
+    pub fn to_vec(&self) -> Vec<u8> {
+        let methods = self.methods.iter().map(|(info, schema)| Value::Map(vec![
+            (Value::from("name"), Value::from(info.name.as_str())),
+            (Value::from("params"), Value::from(info.params.as_str())),
+            (Value::from("returns"), info.returns.as_ref().map_or(Value::Nil, |returns| Value::from(returns.as_str()))),
+            (Value::from("schema"), schema.as_ref().map_or(Value::Nil, Schema::to_value)),
+        ])).collect();
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &Value::Array(methods)).unwrap();
+        buf
+    }
+
+    pub fn from_slice(mut bytes: &[u8]) -> Result<Self, SchemaError> {
+        let invalid = |path: String| SchemaError { path, expected: "schema export".into() };
+        let value = rmpv::decode::read_value(&mut bytes).map_err(|_| invalid("$".into()))?;
+        let methods = value.as_array().ok_or_else(|| invalid("$".into()))?.iter().enumerate().map(|(i, method)| {
+            let path = format!("$[{}]", i);
+            let entries = method.as_map().ok_or_else(|| invalid(path.clone()))?;
+            let get = |key: &str| entries.iter().find(|(k, _)| k.as_str() == Some(key)).map(|(_, v)| v);
+            let text = |key: &str| get(key).and_then(Value::as_str).map(String::from);
+            let name = text("name").ok_or_else(|| invalid(format!("{}.name", path)))?;
+            let params = text("params").ok_or_else(|| invalid(format!("{}.params", path)))?;
+            let schema = match get("schema") {
+                None | Some(Value::Nil) => None,
+                Some(schema) => Some(Schema::read(schema, &mut format!("{}.schema", path))?),
+            };
+            Ok((MethodInfo { name, params, returns: text("returns") }, schema))
+        }).collect::<Result<_, _>>()?;
+        Ok(SchemaExport { methods })
+    }
+
+    /// The changes of `next` which break the clients of this version: the methods removed, the results changed, and the
+    /// arguments accepted before but not anymore. The arguments are compared by their schema, or by their Rust type
+    /// when the next version has none, so renaming such a type is reported too
+    pub fn breaking_changes(&self, next: &SchemaExport) -> Vec<BreakingChange> {
+        let mut changes = Vec::new();
+        for (old, old_schema) in &self.methods {
+            let mut change = |reason: String| changes.push(BreakingChange { method: old.name.clone(), reason });
+            let (new, new_schema) = match next.methods.iter().find(|(new, _)| new.name == old.name) {
+                Some((new, schema)) => (new, schema),
+                None => {
+                    change("removed".into());
+                    continue;
+                }
+            };
+            match (&old.returns, &new.returns) {
+                (Some(_), None) => change("is a notify now, answered nil".into()),
+                (Some(old), Some(new)) if old != new => change(format!("returns {} instead of {}", new, old)),
+                _ => {}
+            }
+            match (old_schema, new_schema) {
+                (Some(old), Some(new)) => {
+                    if let Some((path, new, old)) = new.narrowing(old, &mut String::from("$")) {
+                        change(format!("takes {} instead of {} at {}", new, old, path));
+                    }
+                }
+                (None, Some(new)) if !new.accepts_all(&Schema::Any) => change(format!("takes only {} now", new)),
+                _ if old.params != new.params && new_schema.is_none() => change(format!("takes {} instead of {}", new.params, old.params)),
+                _ => {}
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,224 @@
+
+use std::io;
+use std::net::SocketAddr;
+#[cfg(feature = "ws")]
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+#[cfg(feature = "ws")]
+use crate::{ws, ServerConfig};
+use crate::{Adaptor, Clock, SystemClock, Session, SessionBuilder, SessionObserver, Sessions, Shards, ServiceType, Tenant, Tenants};
+
+/// A connection accepted by a [`Listener`], with the uri it requested if the transport has one
+pub type Accepted = (Arc<dyn Adaptor>, Option<String>);
+
+/// Source of the connections accepted by a [`Server`], e.g. [`ws::GuardedServer`](crate::ws::GuardedServer)
+pub trait Listener: Send + Sync + 'static {
+    /// Wait for the next connection, `Ok(None)` if one failed before being established.
+    /// An error stops the server
+    fn accept(&self) -> io::Result<Option<Arc<dyn Adaptor>>>;
+
+    /// [`Listener::accept`] also returning the uri the connection requested, for the listeners which have one
+    fn accept_uri(&self) -> io::Result<Option<Accepted>> {
+        self.accept().map(|adaptor| adaptor.map(|adaptor| (adaptor, None)))
+    }
+
+    /// Stop listening, the pending and following calls of `accept` fail
+    fn close(&self);
+
+    /// The addresses listened on, if the transport has some
+    fn local_addrs(&self) -> Vec<SocketAddr> { Vec::new() }
+}
+
+type Setup = Box<dyn Fn(&Arc<Session>) + Send + Sync>;
+type Configure = Box<dyn Fn(SessionBuilder) -> SessionBuilder + Send + Sync>;
+/// The service of a session by the uri it requested, `None` to close it
+type Factory = Box<dyn Fn(Option<&str>) -> Option<(ServiceType, Option<Tenant>)> + Send + Sync>;
+
+/// Accept the connections of a listener and handle each session on its own thread
+pub struct Server {
+    listener: Box<dyn Listener>,
+    factory: Factory,
+    configure: Option<Configure>,
+    setup: Option<Setup>,
+    observer: Option<Arc<dyn SessionObserver>>,
+    shards: Option<Arc<Shards>>,
+    idle_timeout: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    sessions: Sessions,
+    stopped: AtomicBool,
+}
+
+impl Server {
+    /// `factory` makes the service of each accepted session
+    pub fn new(listener: impl Listener, factory: impl Fn() -> ServiceType + Send + Sync + 'static) -> Self {
+        Self::with_factory(listener, Box::new(move |_| Some((factory(), None))))
+    }
+
+    /// Serve many tenants from one listener: each session gets the service of the tenant of the uri it requested,
+    /// and the [`Tenant`] in its extensions (see [`Session::tenant`]). The ones of unknown tenants, or without uri,
+    /// are closed
+    pub fn with_tenants(listener: impl Listener, tenants: Tenants) -> Self {
+        Self::with_factory(listener, Box::new(move |uri| {
+            let (tenant, service) = tenants.route(uri?)?;
+            Some((service, Some(tenant)))
+        }))
+    }
+
+    /// Listen on the addresses of the config file at `path` with its limits, authentication and logging, overridden by
+    /// the environment variables, see [`ServerConfig`]. `factory` makes the service of each accepted session
+    #[cfg(feature = "ws")]
+    pub fn from_config(path: impl AsRef<Path>, factory: impl Fn() -> ServiceType + Send + Sync + 'static) -> io::Result<Self> {
+        Self::with_config(ServerConfig::load(path)?.override_from_env()?, factory)
+    }
+
+    /// Like [`Server::from_config`] with settings already read. The websocket listeners don't speak TLS, the settings
+    /// with a certificate or a key are refused: terminate TLS in front of the server
+    #[cfg(feature = "ws")]
+    pub fn with_config(config: ServerConfig, factory: impl Fn() -> ServiceType + Send + Sync + 'static) -> io::Result<Self> {
+        if config.tls_cert.is_some() || config.tls_key.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The websocket listeners don't speak TLS"));
+        }
+        let mut limits = ws::HandshakeLimits::default();
+        if let Some(timeout) = config.handshake_timeout { limits.timeout = timeout; }
+        if let Some(max) = config.max_handshakes_per_ip { limits.max_per_ip = max; }
+        let mut server = Server::new(ws::bind_guarded_all(&config.listen, limits)?, factory);
+        server.idle_timeout = config.idle_timeout;
+        server.observer = config.observer();
+        Ok(server.configure(move |builder| config.configure(builder)))
+    }
+
+    fn with_factory(listener: impl Listener, factory: Factory) -> Self {
+        Server {
+            listener: Box::new(listener),
+            factory,
+            configure: None,
+            setup: None,
+            observer: None,
+            shards: None,
+            idle_timeout: None,
+            clock: Arc::new(SystemClock),
+            sessions: Sessions::new(),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Give the options of each accepted session to its builder, e.g. the ones of a [`ServerConfig`](crate::ServerConfig).
+    /// [`Server::on_session`] runs after, once the session is built
+    pub fn configure(mut self, configure: impl Fn(SessionBuilder) -> SessionBuilder + Send + Sync + 'static) -> Self {
+        self.configure = Some(Box::new(configure));
+        self
+    }
+
+    /// Configure each accepted session before it handles any packet, e.g. to set its limits or authenticator
+    pub fn on_session(mut self, setup: impl Fn(&Arc<Session>) + Send + Sync + 'static) -> Self {
+        self.setup = Some(Box::new(setup));
+        self
+    }
+
+    /// Observe the lifecycle of each accepted session, see [`Session::set_observer`]
+    pub fn observer(mut self, observer: Arc<dyn SessionObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Partition the accepted sessions across `shards`, each one is handled on the workers of its shard
+    pub fn shards(mut self, shards: Arc<Shards>) -> Self {
+        self.shards = Some(shards);
+        self
+    }
+
+    /// Close the sessions which received nothing for `timeout`, see [`Session::set_idle_timeout`]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Measure the time of the server and of the sessions it accepts with `clock` rather than the system's,
+    /// see [`Session::set_clock`]
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Accept the connections on a new thread until [`Server::shutdown`]
+    pub fn start(self) -> Arc<Server> {
+        let server = Arc::new(self);
+        let accepting = server.clone();
+        std::thread::spawn(move || accepting.accept_loop());
+        server
+    }
+
+    fn accept_loop(&self) {
+        loop {
+            let (adaptor, uri) = match self.listener.accept_uri() {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+            if !self.is_running() {
+                adaptor.close();
+                break;
+            }
+            let (service, tenant) = match (self.factory)(uri.as_deref()) {
+                Some(routed) => routed,
+                None => {
+                    adaptor.close();
+                    continue;
+                }
+            };
+            let mut builder = Session::builder(adaptor).service(service);
+            if let Some(configure) = &self.configure { builder = configure(builder); }
+            let ss = Arc::new(builder.build());
+            if let Some(tenant) = tenant { ss.extensions().insert(tenant); }
+            ss.set_observer(self.observer.clone());
+            ss.set_idle_timeout(self.idle_timeout);
+            ss.set_clock(self.clock.clone());
+            if let Some(setup) = &self.setup { setup(&ss); }
+            self.sessions.add(&ss);
+            if let Some(shards) = &self.shards { shards.assign(&ss); }
+            std::thread::spawn(move || ss.loop_handle());
+        }
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// The addresses the listener listens on, see [`Listener::local_addrs`]
+    pub fn local_addrs(&self) -> Vec<SocketAddr> { self.listener.local_addrs() }
+
+    /// The sessions still connected, to broadcast to them
+    pub fn sessions(&self) -> &Sessions { &self.sessions }
+
+    pub fn is_running(&self) -> bool { !self.stopped.load(Ordering::SeqCst) }
+
+    /// Stop accepting connections, let the sessions answer the requests they are handling, at most `timeout`,
+    /// while the new ones are rejected (see [`Session::drain`]), then close them. Return false if some requests
+    /// were still being handled
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.stop_accepting();
+        let sessions = self.sessions.live();
+        // Reject the new requests on all of them before waiting
+        for ss in &sessions { ss.drain(Duration::from_secs(0)); }
+        // No deadline if it's too far to be told by an instant, each session waits for the whole timeout
+        let deadline = self.clock.now().checked_add(timeout);
+        // Counted rather than `all`, which would stop waiting at the first one not drained
+        let drained = sessions.iter().filter(|ss| {
+            !ss.drain(deadline.map_or(timeout, |deadline| deadline.saturating_duration_since(self.clock.now())))
+        }).count() == 0;
+        for ss in sessions { ss.adaptor.close(); }
+        drained
+    }
+
+    /// Stop accepting connections, the active sessions go on
+    pub fn stop_accepting(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.listener.close();
+    }
+
+    /// Stop accepting connections and close the active sessions
+    pub fn shutdown(&self) {
+        self.stop_accepting();
+        for ss in self.sessions.live() { ss.adaptor.close(); }
+    }
+}
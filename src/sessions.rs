@@ -0,0 +1,128 @@
+
+use std::collections::HashMap;
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use crate::{Session, ToMethod, Priority};
+
+/// Live sessions, e.g. the ones accepted by a server. The sessions dropped or disconnected are forgotten
+#[derive(Default)]
+pub struct Sessions(Mutex<Vec<Weak<Session>>>);
+
+impl Sessions {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn add(&self, ss: &Arc<Session>) {
+        let mut sessions = self.0.lock().unwrap();
+        // Forget the dropped ones, so a server accepting forever doesn't grow the list forever
+        sessions.retain(|s| s.strong_count() > 0);
+        sessions.push(Arc::downgrade(ss));
+    }
+
+    // Add `ss` unless it's in already
+    fn insert(&self, ss: &Arc<Session>) {
+        let mut sessions = self.0.lock().unwrap();
+        if !sessions.iter().any(|s| s.upgrade().is_some_and(|s| Arc::ptr_eq(&s, ss))) { sessions.push(Arc::downgrade(ss)); }
+    }
+
+    /// The sessions still connected
+    pub fn live(&self) -> Vec<Arc<Session>> {
+        let mut sessions = self.0.lock().unwrap();
+        sessions.retain(|ss| ss.upgrade().is_some_and(|ss| ss.adaptor.connected()));
+        sessions.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Forget `ss`, false if it wasn't added
+    pub fn remove(&self, ss: &Session) -> bool {
+        let mut sessions = self.0.lock().unwrap();
+        let len = sessions.len();
+        sessions.retain(|s| s.upgrade().is_some_and(|s| !std::ptr::eq(&*s, ss)));
+        sessions.len() != len
+    }
+
+    pub fn contains(&self, ss: &Session) -> bool { self.live().iter().any(|s| std::ptr::eq(&**s, ss)) }
+
+    pub fn len(&self) -> usize { self.live().len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// The count of sessions held, including the disconnected ones not forgotten yet
+    pub fn tracked(&self) -> usize { self.0.lock().unwrap().len() }
+
+    /// Notify all the sessions, the packet is serialized once. Return the count of sessions it was sent to
+    pub fn broadcast_notify<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize) -> usize {
+        self.broadcast_notify_to(method, arg, |_| true)
+    }
+
+    /// Notify the sessions for which `filter` returns true
+    pub fn broadcast_notify_to<'a>(&self, method: impl ToMethod<'a>, arg: impl Serialize, filter: impl Fn(&Session) -> bool) -> usize {
+        let method = method.to_method();
+        // Sessions serializing in canonical form need their own packet
+        let mut packs: [Option<Vec<u8>>; 2] = [None, None];
+        self.live().iter().filter(|ss| filter(ss)).filter(|ss| {
+            // Payloads in another codec, and methods aliased by the peer, are encoded for each session
+            if ss.codec().tag().is_some() || ss.aliases.is_active() {
+                return ss.try_notify(method, &arg).is_ok();
+            }
+            let pack = packs[ss.canonical.load(Ordering::Relaxed) as usize].get_or_insert_with(|| {
+                let mut pack = ss.prepare_notify(method);
+                ss.serialize(&arg, &mut pack);
+                pack
+            });
+            ss.try_send_pack(pack.clone(), Priority::Normal).is_ok()
+        }).count()
+    }
+}
+
+/// Named groups of sessions (game rooms, chat channels...) to notify together. A session leaves its rooms once dropped
+/// or disconnected, and a room is forgotten once empty
+#[derive(Default)]
+pub struct Rooms(Mutex<HashMap<String, Arc<Sessions>>>);
+
+impl Rooms {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn join(&self, room: &str, ss: &Arc<Session>) {
+        // Under the lock of the rooms, so the room isn't forgotten as empty meanwhile
+        self.0.lock().unwrap().entry(room.into()).or_default().insert(ss);
+    }
+
+    /// False if `ss` wasn't in `room`
+    pub fn leave(&self, room: &str, ss: &Session) -> bool {
+        self.room(room).is_some_and(|members| members.remove(ss))
+    }
+
+    /// Leave all the rooms `ss` is in
+    pub fn leave_all(&self, ss: &Session) {
+        for members in self.0.lock().unwrap().values() { members.remove(ss); }
+    }
+
+    /// The live sessions of `room`
+    pub fn members(&self, room: &str) -> Vec<Arc<Session>> { self.room(room).map_or_else(Vec::new, |members| members.live()) }
+
+    /// The rooms with live sessions, `ss` is in
+    pub fn rooms_of(&self, ss: &Session) -> Vec<String> {
+        self.cleanup();
+        self.0.lock().unwrap().iter().filter(|(_, members)| members.contains(ss)).map(|(room, _)| room.clone()).collect()
+    }
+
+    /// The rooms with live sessions
+    pub fn rooms(&self) -> Vec<String> {
+        self.cleanup();
+        self.0.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Notify the sessions of `room`, like [`Sessions::broadcast_notify`]. Return the count of sessions it was sent to
+    pub fn notify<'a>(&self, room: &str, method: impl ToMethod<'a>, arg: impl Serialize) -> usize {
+        self.room(room).map_or(0, |members| members.broadcast_notify(method, arg))
+    }
+
+    fn room(&self, room: &str) -> Option<Arc<Sessions>> {
+        self.cleanup();
+        self.0.lock().unwrap().get(room).cloned()
+    }
+
+    fn cleanup(&self) { self.0.lock().unwrap().retain(|_, members| !members.is_empty()); }
+}
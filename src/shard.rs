@@ -0,0 +1,103 @@
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::mpsc::{channel, Sender};
+
+use serde::Serialize;
+
+use crate::{Session, Sessions, WorkerPool};
+
+type Job = Box<dyn FnOnce(&Shard) + Send>;
+
+/// One partition of [`Shards`], with its own registry of sessions and its own workers
+pub struct Shard {
+    index: usize,
+    sessions: Sessions,
+    pool: Arc<WorkerPool>,
+    bridge: Mutex<Sender<Job>>,
+}
+
+impl Shard {
+    pub fn index(&self) -> usize { self.index }
+
+    /// The sessions assigned to this shard
+    pub fn sessions(&self) -> &Sessions { &self.sessions }
+
+    /// The workers handling the requests of the sessions of this shard
+    pub fn pool(&self) -> &Arc<WorkerPool> { &self.pool }
+
+    /// Run `job` on the thread of this shard, the jobs are run in the order they are posted
+    pub fn post(&self, job: impl FnOnce(&Shard) + Send + 'static) {
+        self.bridge.lock().unwrap().send(Box::new(job));
+    }
+}
+
+/// Sessions partitioned across independent shards by the hash of their connection, e.g. one per core.
+/// A shard only locks its own registry and queue, the shards talk through [`Shard::post`]
+pub struct Shards {
+    shards: Vec<Arc<Shard>>,
+}
+
+impl Shards {
+    /// `count` shards (at least one) each handling the requests on `threads` workers
+    pub fn new(count: usize, threads: usize) -> Arc<Shards> {
+        let shards = (0..count.max(1)).map(|index| {
+            let (sender, receiver) = channel::<Job>();
+            let shard = Arc::new(Shard { index, sessions: Sessions::new(), pool: WorkerPool::new(threads), bridge: Mutex::new(sender) });
+            // The thread stops once the shard is dropped
+            let weak: Weak<Shard> = Arc::downgrade(&shard);
+            std::thread::spawn(move || for job in receiver {
+                match weak.upgrade() { Some(shard) => job(&shard), None => break }
+            });
+            shard
+        }).collect();
+        Arc::new(Shards { shards })
+    }
+
+    pub fn len(&self) -> usize { self.shards.len() }
+
+    pub fn is_empty(&self) -> bool { self.shards.is_empty() }
+
+    pub fn get(&self, index: usize) -> Option<&Shard> { self.shards.get(index).map(|s| &**s) }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Shard> { self.shards.iter().map(|s| &**s) }
+
+    /// The shard which `key` hashes to
+    pub fn shard_of(&self, key: impl Hash) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Register `ss` in the shard of its connection and handle its requests on the workers of that shard
+    pub fn assign(&self, ss: &Arc<Session>) -> &Shard {
+        let connection = &*ss.adaptor as *const _ as *const u8 as usize;
+        let shard = self.shard_of(connection);
+        shard.sessions.add(ss);
+        ss.set_worker_pool(Some(shard.pool.clone()));
+        shard
+    }
+
+    /// The count of sessions of all the shards
+    pub fn session_count(&self) -> usize { self.iter().map(|s| s.sessions.len()).sum() }
+
+    /// Run `job` on the thread of every shard and return the sum of the results
+    pub fn broadcast(&self, job: impl Fn(&Shard) -> usize + Send + Sync + 'static) -> usize {
+        let job = Arc::new(job);
+        let (sender, receiver) = channel();
+        for shard in &self.shards {
+            let (job, sender) = (job.clone(), sender.clone());
+            shard.post(move |shard| { sender.send(job(shard)); });
+        }
+        drop(sender);
+        receiver.iter().sum()
+    }
+
+    /// Notify the sessions of all the shards, each shard serializes the packet once for its sessions.
+    /// Return the count of sessions it was sent to
+    pub fn broadcast_notify(&self, method: impl Into<String>, arg: impl Serialize + Send + Sync + 'static) -> usize {
+        let method = method.into();
+        self.broadcast(move |shard| shard.sessions.broadcast_notify(method.as_str(), &arg))
+    }
+}
@@ -1,12 +1,13 @@
 
 use std::cell::{Cell, UnsafeCell};
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::mem::{size_of, transmute};
+use std::mem::size_of;
+use std::io::IoSlice;
 use std::sync::{Arc, Mutex};
 use std::time::{Instant, Duration};
 
 use shared_memory::*;
-use crate::{Adaptor, RecvError};
+use crate::{Adaptor, RecvError, TransportError};
 
 pub use shared_memory::Timeout;
 
@@ -28,7 +29,7 @@ enum Frame {
     Ping,
     Pong,
     Timeout,
-    Data(Vec<u8>),
+    Data,
 }
 
 const SYNC_TIME_OUT: Duration = Duration::from_secs(1);
@@ -52,7 +53,7 @@ impl Channel {
         }
     }
 
-    fn send_type(&mut self, frame: &Frame) -> bool {
+    fn send_type(&mut self, frame: &Frame, len: usize) -> bool {
         let begin = Instant::now();
         while self.data_ty.load(Ordering::Relaxed) != FRAME_NONE {
             if begin.elapsed() > SYNC_TIME_OUT { return false; }
@@ -61,38 +62,44 @@ impl Channel {
         match frame {
             Frame::Ping => self.data_ty.store(FRAME_PING, Ordering::Relaxed),
             Frame::Pong => self.data_ty.store(FRAME_PONG, Ordering::Relaxed),
-            Frame::Data(ref d) => {
+            Frame::Data => {
                 self.data_ty.store(FRAME_DATA, Ordering::Relaxed);
-                self.data_len.store(d.len() as u32, Ordering::Relaxed);
+                self.data_len.store(len as u32, Ordering::Relaxed);
             }
             _ => { panic!(); }
         }
         true
     }
 
-    fn send_data(&mut self, data: &[u8]) -> bool {
-        let (mut sended, mut _recved) = (0u32, 0u32);
+    // Send the concatenation of `parts`
+    fn send_data(&mut self, parts: &[IoSlice]) -> bool {
+        let data_len: usize = parts.iter().map(|part| part.len()).sum();
+        let (mut sended, mut part, mut offset) = (0usize, 0usize, 0usize);
         loop {
-            // Copy data slice
-            {
-                let sended = sended as usize;
-                let rest_size = data.len() - sended;
-                let size = self.buf.len().min(rest_size);
-                (self.buf[..size]).copy_from_slice(&data[sended .. sended + size]);
-                // Update sended
-                let after_size = sended as usize + size;
-                self.sended.store(after_size as u32, Ordering::Relaxed);
-                if after_size >= data.len() { break; }
+            // Copy data slices, as much as the buffer holds
+            let mut size = 0;
+            while size < self.buf.len() && part < parts.len() {
+                let len = (self.buf.len() - size).min(parts[part].len() - offset);
+                (self.buf[size..size + len]).copy_from_slice(&parts[part][offset..offset + len]);
+                size += len;
+                offset += len;
+                if offset == parts[part].len() { part += 1; offset = 0; }
             }
+            // Update sended
+            sended += size;
+            self.sended.store(sended as u32, Ordering::Relaxed);
+            if sended >= data_len { break; }
             // Wait recv
-            match self.wait_recv() {
-                Some(r) => { sended = r.0; _recved = r.1; }
-                None => return false,
-            }
+            if self.wait_recv().is_none() { return false; }
         }
         true
     }
 
+    #[inline]
+    fn pending(&self) -> bool {
+        self.data_ty.load(Ordering::Relaxed) != FRAME_NONE
+    }
+
     fn wait_send(&mut self) -> Option<(u32, u32)> {
         let begin = Instant::now();
         loop {
@@ -104,7 +111,8 @@ impl Channel {
         }
     }
 
-    fn recv(&mut self) -> Frame {
+    // The data of a `Frame::Data` is written to `data`
+    fn recv(&mut self, data: &mut Vec<u8>) -> Frame {
         let result = match self.data_ty.load(Ordering::Relaxed) {
             FRAME_NONE => Frame::None,
             FRAME_PING => Frame::Ping,
@@ -113,7 +121,8 @@ impl Channel {
                 let data_size = self.data_len.load(Ordering::Relaxed) as usize;
                 if data_size == 0 { return Frame::None; }
 
-                let mut data: Vec<u8> = Vec::with_capacity(data_size);
+                data.clear();
+                data.reserve(data_size);
                 let mut timeout = false;
                 loop {
                     match self.wait_send() {
@@ -131,9 +140,9 @@ impl Channel {
                 self.sended.store(0, Ordering::Relaxed);
                 self.recved.store(0, Ordering::Relaxed);
                 self.data_len.store(0, Ordering::Relaxed);
-                if timeout { Frame::Timeout } else { Frame::Data(data) }
+                if timeout { Frame::Timeout } else { Frame::Data }
             }
-            _ => { panic!(); }
+            _ => Frame::None,
         };
         self.data_ty.store(FRAME_NONE, Ordering::Relaxed); result
     }
@@ -148,6 +157,9 @@ pub struct ShmAdaptor {
     shmem: UnsafeCell<SharedMem>,
     send_lock: Mutex<()>,
     connected: Cell<bool>,
+    // Time waited for the peer since its last pong, in ms
+    ping_time: Cell<usize>,
+    last_error: Cell<Option<TransportError>>,
     client: bool,
 }
 
@@ -156,15 +168,18 @@ impl ShmAdaptor {
     const EVT_SLAVER: usize = 1;
 
     #[inline]
-    fn shmem(&self) -> &mut SharedMem { 
+    #[allow(clippy::mut_from_ref)]
+    fn shmem(&self) -> &mut SharedMem {
         unsafe { &mut *self.shmem.get() }
     }
 
+    #[allow(clippy::mut_from_ref)]
     fn send_channel(&self) -> &mut Channel {
         let this = self.as_comm();
         if self.client { &mut this.ch2 } else { &mut this.ch1 }
     }
 
+    #[allow(clippy::mut_from_ref)]
     fn recv_channel(&self) -> &mut Channel {
         let this = self.as_comm();
         if self.client { &mut this.ch1 } else { &mut this.ch2 }
@@ -182,27 +197,63 @@ impl ShmAdaptor {
 
     #[inline]
     fn as_comm(&self) -> &'static mut Communicator {
-        unsafe { transmute(self.shmem().get_ptr()) }
+        unsafe { &mut *(self.shmem().get_ptr() as *mut Communicator) }
     }
 
-    fn send_frame(&self, frame: Frame) -> bool {
+    fn send_frame(&self, frame: Frame, parts: &[IoSlice]) -> bool {
         let _guard = self.send_lock.lock().unwrap();
         let ch = self.send_channel();
-        if !ch.send_type(&frame) { return false; }
+        if !ch.send_type(&frame, parts.iter().map(|part| part.len()).sum()) { return false; }
 
         let sid = self.send_eid();
         self.shmem().set(sid, EventState::Signaled);
-        if let Frame::Data(data) = frame {
-            return ch.send_data(&data);
+        if let Frame::Data = frame {
+            return ch.send_data(parts);
         }
         true
     }
 
+    // `Err(RecvError::NoData)` once `deadline` passed
+    fn recv_until(&self, mut buf: Vec<u8>, deadline: Option<Instant>) -> Result<Vec<u8>, RecvError> {
+        const CELL_TIMEOUT: usize = 100;
+        loop {
+            let rid = self.recv_eid();
+            let wait = deadline.map_or(CELL_TIMEOUT, |deadline| {
+                (deadline.saturating_duration_since(Instant::now()).as_millis() as usize).min(CELL_TIMEOUT)
+            });
+            let signaled = self.shmem().wait(rid, Timeout::Milli(wait)).is_ok();
+            let ping_time = self.ping_time.get();
+            // Several signals may be coalesced into one, so check the channel even if the wait timed out
+            if signaled || self.recv_channel().pending() {
+                match self.recv_channel().recv(&mut buf) {
+                    Frame::Data => { self.ping_time.set(0); return Ok(buf); }
+                    Frame::Ping => { self.send_frame(Frame::Pong, &[]); }
+                    Frame::Pong => { if ping_time > 0 { self.ping_time.set(ping_time - CELL_TIMEOUT); } }
+                    // Nothing sent, or the peer gave up in the middle of a transfer
+                    Frame::None | Frame::Timeout => {}
+                }
+            } else if wait < CELL_TIMEOUT {
+                // Not waited long enough to tell whether the peer is still there
+                return Err(RecvError::NoData);
+            } else if ping_time > 200 {
+                self.connected.set(false);
+                self.last_error.set(Some(TransportError::Timeout));
+                return Err(RecvError::Disconnect);
+            } else {
+                self.ping_time.set(ping_time + CELL_TIMEOUT);
+                self.send_frame(Frame::Ping, &[]);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) { return Err(RecvError::NoData); }
+        }
+    }
+
     fn new(shmem: SharedMem, client: bool) -> Self {
         ShmAdaptor {
             shmem: UnsafeCell::new(shmem),
             send_lock: Mutex::new(()),
             connected: Cell::new(true),
+            ping_time: Cell::new(0),
+            last_error: Cell::new(None),
             client,
         }
     }
@@ -236,33 +287,49 @@ unsafe impl Sync for ShmAdaptor {}
 
 impl Adaptor for ShmAdaptor {
     fn send(&self, data: Vec<u8>) -> bool {
-        return self.send_frame(Frame::Data(data));
+        self.send_frame(Frame::Data, &[IoSlice::new(&data)])
+    }
+
+    // The data is copied to the shared memory, the buffer stays with the session
+    fn send_buf(&self, data: &mut Vec<u8>) -> bool {
+        self.send_frame(Frame::Data, &[IoSlice::new(data)])
+    }
+
+    fn send_vectored(&self, parts: &[IoSlice]) -> bool {
+        self.send_frame(Frame::Data, parts)
     }
 
     fn recv(&self) -> Result<Vec<u8>, RecvError> {
-        const CELL_TIMEOUT: usize = 100;
-        let mut ping_time = 0usize;
-        loop {
-            let rid = self.recv_eid();
-            if let Ok(_) = self.shmem().wait(rid, Timeout::Milli(CELL_TIMEOUT)) {
-                match self.recv_channel().recv() {
-                    Frame::Data(data) => return Ok(data),
-                    Frame::Ping => { self.send_frame(Frame::Pong); }
-                    Frame::Pong => { if ping_time > 0 { ping_time -= CELL_TIMEOUT; } }
-                    Frame::None | Frame::Timeout => { panic!(""); }
-                }
-            } else if ping_time > 200 {
-                self.connected.set(false);
-                return Err(RecvError::Disconnect);
-            } else {
-                ping_time += CELL_TIMEOUT;
-                self.send_frame(Frame::Ping);
+        self.recv_buf(Vec::new())
+    }
+
+    fn recv_buf(&self, buf: Vec<u8>) -> Result<Vec<u8>, RecvError> {
+        self.recv_until(buf, None)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> {
+        self.recv_until(Vec::new(), Some(Instant::now() + timeout))
+    }
+
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> {
+        // Answer the pings on the way
+        while self.recv_channel().pending() {
+            let mut buf = Vec::new();
+            match self.recv_channel().recv(&mut buf) {
+                Frame::Data => return Some(Ok(buf)),
+                Frame::Ping => { self.send_frame(Frame::Pong, &[]); }
+                Frame::Pong | Frame::None | Frame::Timeout => {}
             }
         }
+        Some(Err(RecvError::NoData))
     }
 
     fn connected(&self) -> bool { self.connected.get() }
 
+    fn last_error(&self) -> Option<TransportError> { self.last_error.get() }
+
+    fn transport_kind(&self) -> Option<&'static str> { Some("shm") }
+
     fn close(&self) { /* TODO: */ }
 }
 
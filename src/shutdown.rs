@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Clock, Server, Session, SystemClock};
+
+enum Part {
+    Server(Arc<Server>),
+    Session(Arc<Session>),
+}
+
+struct Node {
+    name: String,
+    part: Part,
+    depends_on: Vec<String>,
+}
+
+/// Stop the servers and the sessions of a process in the order of their dependencies, e.g. a gateway and the
+/// sessions to its backends: every server stops accepting connections, then each part is drained once the parts
+/// depending on it are, so the backends still answer the requests the gateway forwards while it's drained.
+///
+/// A server is stopped with [`Server::drain`], a session with [`Session::drain`] then [`Session::shutdown`].
+/// The dependencies on unknown names are ignored, and the parts depending on each other are stopped in the order
+/// they were added
+pub struct Shutdown {
+    nodes: Vec<Node>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self { Shutdown { nodes: Vec::new(), clock: Arc::new(SystemClock) } }
+}
+
+impl Shutdown {
+    pub fn new() -> Self { Self::default() }
+
+    /// Stop `server` after the parts depending on it, and before the ones named in `depends_on`
+    pub fn server(mut self, name: &str, server: &Arc<Server>, depends_on: &[&str]) -> Self {
+        self.add(name, Part::Server(server.clone()), depends_on);
+        self
+    }
+
+    /// Stop `ss`, e.g. the session to a backend, after the parts depending on it, and before the ones named in `depends_on`
+    pub fn session(mut self, name: &str, ss: &Arc<Session>, depends_on: &[&str]) -> Self {
+        self.add(name, Part::Session(ss.clone()), depends_on);
+        self
+    }
+
+    /// Measure the timeout of [`Shutdown::run`] with `clock`, [`SystemClock`] by default
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn add(&mut self, name: &str, part: Part, depends_on: &[&str]) {
+        let depends_on = depends_on.iter().map(|&d| d.to_string()).collect();
+        self.nodes.push(Node { name: name.to_string(), part, depends_on });
+    }
+
+    /// The names of the parts in the order they are stopped
+    pub fn order(&self) -> Vec<&str> {
+        self.sequence().into_iter().map(|i| self.nodes[i].name.as_str()).collect()
+    }
+
+    fn sequence(&self) -> Vec<usize> {
+        let mut stopped = vec![false; self.nodes.len()];
+        let mut sequence = Vec::with_capacity(self.nodes.len());
+        while let Some(first) = stopped.iter().position(|s| !s) {
+            // A part waits for the ones depending on it, unless they all wait for each other
+            let ready = |i: usize| self.nodes.iter().enumerate().all(|(j, node)| {
+                stopped[j] || j == i || !node.depends_on.contains(&self.nodes[i].name)
+            });
+            let next = (first..self.nodes.len()).filter(|&i| !stopped[i]).find(|&i| ready(i)).unwrap_or(first);
+            stopped[next] = true;
+            sequence.push(next);
+        }
+        sequence
+    }
+
+    /// Stop all the parts, they have `timeout` together to answer the requests they are handling.
+    /// Return the name of each part in the order they were stopped, with false if some of its requests weren't answered
+    pub fn run(&self, timeout: Duration) -> Vec<(String, bool)> {
+        for node in &self.nodes {
+            if let Part::Server(server) = &node.part { server.stop_accepting(); }
+        }
+        // No deadline if it's too far to be told by an instant, each part waits for the whole timeout
+        let deadline = self.clock.now().checked_add(timeout);
+        let remaining = || deadline.map_or(timeout, |deadline| deadline.saturating_duration_since(self.clock.now()));
+        self.sequence().into_iter().map(|i| {
+            let node = &self.nodes[i];
+            let drained = match &node.part {
+                Part::Server(server) => server.drain(remaining()),
+                Part::Session(ss) => {
+                    let drained = ss.drain(remaining());
+                    ss.shutdown();
+                    drained
+                }
+            };
+            (node.name.clone(), drained)
+        }).collect()
+    }
+}
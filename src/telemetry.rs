@@ -0,0 +1,42 @@
+
+use std::time::{Duration, SystemTime};
+
+/// The side of a request a span covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// The session made the request, from sending it to receiving its result
+    Client,
+    /// The session handled it, while the service did
+    Server,
+}
+
+/// A request made or handled by a session, in the terms of OpenTelemetry. The ids are in lowercase hex like in a W3C
+/// `traceparent`: 32 digits for the trace, 16 for a span
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub kind: SpanKind,
+    pub method: String,
+    pub trace_id: String,
+    pub span_id: String,
+    /// For a client span, the span of the request being handled which made it; for a server span, the client span
+    /// of the peer
+    pub parent_span_id: Option<String>,
+    pub start: SystemTime,
+    pub duration: Duration,
+    /// The code of the error of the request, `None` if it succeeded
+    pub error: Option<i64>,
+    /// Length of the arguments, in bytes
+    pub request_size: usize,
+    /// Length of the result, for a client span which received one
+    pub response_size: Option<usize>,
+}
+
+/// Receive the spans of the requests of a session, to export them to a tracer (OpenTelemetry, Jaeger...).
+/// See [`Session::set_span_sink`](crate::Session::set_span_sink)
+pub trait SpanSink: Send + Sync {
+    fn span(&self, span: Span);
+}
+
+pub(crate) fn new_trace_id() -> String { format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>()) }
+
+pub(crate) fn new_span_id() -> String { format!("{:016x}", rand::random::<u64>()) }
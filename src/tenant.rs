@@ -0,0 +1,67 @@
+
+use std::collections::HashMap;
+
+use crate::ServiceType;
+
+type Factory = Box<dyn Fn(&Tenant) -> ServiceType + Send + Sync>;
+
+/// Who a session serves, parsed from the uri it was accepted on (`/acme/rpc?plan=pro` is the tenant `acme`).
+/// Attached to the session by a [`Server`](crate::Server) routing with [`Tenants`], see [`Session::tenant`](crate::Session::tenant)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Tenant {
+    /// The first segment of the path
+    pub name: String,
+    /// The whole path, without the query
+    pub path: String,
+    /// The parameters of the query, in order. They're kept as sent, not percent-decoded
+    pub query: Vec<(String, String)>,
+}
+
+impl Tenant {
+    pub fn parse(uri: &str) -> Tenant {
+        let (path, query) = match uri.find('?') { Some(i) => (&uri[..i], &uri[i + 1..]), None => (uri, "") };
+        let name = path.trim_start_matches('/').split('/').next().unwrap_or("").into();
+        let query = query.split('&').filter(|p| !p.is_empty()).map(|p| match p.find('=') {
+            Some(i) => (p[..i].into(), p[i + 1..].into()),
+            None => (p.into(), String::new()),
+        }).collect();
+        Tenant { name, path: path.into(), query }
+    }
+
+    /// The first value of the parameter `key` of the query
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+/// The services of the tenants of a [`Server`](crate::Server), see [`Server::with_tenants`](crate::Server::with_tenants).
+/// Each accepted session gets the service of its tenant, the ones of unknown tenants are closed
+#[derive(Default)]
+pub struct Tenants {
+    factories: HashMap<String, Factory>,
+    fallback: Option<Factory>,
+}
+
+impl Tenants {
+    pub fn new() -> Self { Tenants::default() }
+
+    /// Serve the tenant `name` with the services made by `factory`
+    pub fn tenant(mut self, name: &str, factory: impl Fn(&Tenant) -> ServiceType + Send + Sync + 'static) -> Self {
+        self.factories.insert(name.into(), Box::new(factory));
+        self
+    }
+
+    /// Serve the tenants without their own factory, instead of closing their sessions
+    pub fn fallback(mut self, factory: impl Fn(&Tenant) -> ServiceType + Send + Sync + 'static) -> Self {
+        self.fallback = Some(Box::new(factory));
+        self
+    }
+
+    /// The tenant of `uri` and its service, `None` if it isn't served
+    pub fn route(&self, uri: &str) -> Option<(Tenant, ServiceType)> {
+        let tenant = Tenant::parse(uri);
+        let factory = self.factories.get(&tenant.name).or(self.fallback.as_ref())?;
+        let service = factory(&tenant);
+        Some((tenant, service))
+    }
+}
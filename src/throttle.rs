@@ -0,0 +1,216 @@
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+use crate::{Clock, Method, RemoteError};
+
+/// Limits of the requests a session handles for its peer, so a single client can't saturate the server.
+/// The requests over a limit are answered with a [`RemoteError::THROTTLED`] error without running the service,
+/// see [`Session::set_throttle`](crate::Session::set_throttle)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Throttle {
+    /// Maximum count of requests being handled at once, i.e. received and not answered yet. The ones dropped unanswered
+    /// (e.g. an [`AsyncRet`](crate::AsyncRet)), or whose caller gave up by their deadline, don't count anymore
+    pub max_in_flight: Option<usize>,
+    /// Maximum requests per second of the methods, by name (integer methods by their decimal string). A method called
+    /// by its [`method_id`](crate::method_id) has the limit of its name. Bursts of one second worth of requests are allowed
+    pub per_method: HashMap<String, u32>,
+    /// Maximum requests per second of each method not in `per_method`.
+    /// Past a thousand methods requested within a second, the others share a single limit
+    pub default_rate: Option<u32>,
+}
+
+impl Throttle {
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    pub fn rate(mut self, method: impl Into<String>, per_second: u32) -> Self {
+        self.per_method.insert(method.into(), per_second);
+        self
+    }
+}
+
+/// A [`Throttle`] with what it counts
+pub(crate) struct Throttler {
+    limits: Throttle,
+    // The rates of `per_method` by the id of their method
+    rates: HashMap<u32, u32>,
+    buckets: Mutex<Buckets>,
+}
+
+/// Count of the methods with their own bucket, so the peer can't grow the map with made up names
+const MAX_BUCKETS: usize = 1024;
+
+#[derive(Default)]
+struct Buckets {
+    // Tokens left for each method, by its id, and when they were counted
+    methods: HashMap<u32, (f64, Instant)>,
+    // Shared by the methods past MAX_BUCKETS
+    overflow: Option<(f64, Instant)>,
+}
+
+impl Throttler {
+    pub(crate) fn new(limits: Throttle) -> Self {
+        let rates = limits.per_method.iter().map(|(name, &rate)| (Method::Str(name).id(), rate)).collect();
+        Throttler { limits, rates, buckets: Default::default() }
+    }
+
+    /// Whether a request can be handled while `in_flight` others are, otherwise the error to answer
+    pub(crate) fn admit(&self, in_flight: usize, method: Method, now: Instant) -> Result<(), RemoteError> {
+        if self.limits.max_in_flight.is_some_and(|max| in_flight >= max) {
+            return Err(RemoteError::new(RemoteError::THROTTLED, "Too many requests in flight"));
+        }
+        let id = method.id();
+        if let Some(rate) = self.rates.get(&id).copied().or(self.limits.default_rate) {
+            let rate = rate as f64;
+            let mut buckets = self.buckets.lock().unwrap();
+            let Buckets { methods, overflow } = &mut *buckets;
+            if methods.len() >= MAX_BUCKETS && !methods.contains_key(&id) {
+                // A bucket is full again a second after its last request at most, as good as a new one
+                methods.retain(|_, (_, last)| now.saturating_duration_since(*last) < Duration::from_secs(1));
+            }
+            let (tokens, last) = if methods.len() < MAX_BUCKETS || methods.contains_key(&id) {
+                methods.entry(id).or_insert((rate, now))
+            } else {
+                overflow.get_or_insert((rate, now))
+            };
+            *tokens = (*tokens + rate * now.saturating_duration_since(*last).as_secs_f64()).min(rate);
+            *last = now;
+            if *tokens < 1.0 {
+                let error = RemoteError::new(RemoteError::THROTTLED, "Too many requests of this method");
+                if rate == 0.0 { return Err(error); }
+                // The delay before the next token, for the client to retry
+                let retry_after = Duration::from_secs_f64((1.0 - *tokens) / rate);
+                return Err(error.with_data(retry_after.as_millis() as u64 + 1));
+            }
+            *tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+/// Limits of the time the service takes to answer the requests. Past them the session answers a [`RemoteError::TIMEOUT`]
+/// error itself, so a hung handler doesn't leave the peer waiting forever, see [`Session::set_watchdog`](crate::Session::set_watchdog)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Watchdog {
+    /// Limits of the methods, by name (integer methods by their decimal string). A method called by its
+    /// [`method_id`](crate::method_id) has the limit of its name
+    pub per_method: HashMap<String, Duration>,
+    /// Limit of each method not in `per_method`
+    pub default_timeout: Option<Duration>,
+}
+
+impl Watchdog {
+    pub fn timeout(mut self, method: impl Into<String>, timeout: Duration) -> Self {
+        self.per_method.insert(method.into(), timeout);
+        self
+    }
+
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = Some(timeout);
+        self
+    }
+
+    // The limits of `per_method` by the id of their method
+    fn limits(&self) -> HashMap<u32, Duration> {
+        self.per_method.iter().map(|(name, &limit)| (Method::Str(name).id(), limit)).collect()
+    }
+
+    /// How often the requests are checked, a tenth of the shortest limit
+    pub(crate) fn tick(&self) -> Duration {
+        let shortest = self.per_method.values().chain(&self.default_timeout).min().copied().unwrap_or(Duration::from_secs(1));
+        (shortest / 10).max(Duration::from_millis(1)).min(Duration::from_millis(100))
+    }
+}
+
+#[derive(Default)]
+struct Requests {
+    // Method, start and deadline of each request
+    started: HashMap<u64, (String, Instant, Option<Instant>)>,
+    // The requests answered by the watchdog or cancelled by the peer, whose late response is dropped
+    dropped: HashSet<u64>,
+}
+
+/// The requests received and not answered yet
+#[derive(Default)]
+pub(crate) struct InFlight {
+    requests: Mutex<Requests>,
+    finished: Condvar,
+}
+
+impl InFlight {
+    /// Count the request until [`InFlight::finish`], or until its `deadline` passed, if `admit` accepts it given the count
+    /// of the others
+    pub(crate) fn start(&self, req_id: u64, method: Method, now: Instant, deadline: Option<Instant>,
+                        admit: impl FnOnce(usize) -> Result<(), RemoteError>) -> Result<(), RemoteError> {
+        let mut requests = self.requests.lock().unwrap();
+        // Their caller gave up, the handlers which still hold them mustn't fill the slots
+        let len = requests.started.len();
+        requests.started.retain(|_, (_, _, deadline)| deadline.is_none_or(|deadline| now < deadline));
+        if requests.started.len() != len { self.finished.notify_all(); }
+        admit(requests.started.len())?;
+        let name = method.to_string();
+        requests.started.insert(req_id, (name, now, deadline));
+        Ok(())
+    }
+
+    /// The request is answered, or won't be
+    pub(crate) fn finish(&self, req_id: u64) {
+        let mut requests = self.requests.lock().unwrap();
+        requests.dropped.remove(&req_id);
+        if requests.started.remove(&req_id).is_some() { self.finished.notify_all(); }
+    }
+
+    /// Whether the watchdog answered the request already, or the peer cancelled it
+    pub(crate) fn dropped(&self, req_id: u64) -> bool {
+        self.requests.lock().unwrap().dropped.contains(&req_id)
+    }
+
+    /// The peer gave up on the request, it doesn't count as in flight anymore and its response is dropped
+    pub(crate) fn cancel(&self, req_id: u64) {
+        let mut requests = self.requests.lock().unwrap();
+        if requests.started.remove(&req_id).is_some() {
+            requests.dropped.insert(req_id);
+            self.finished.notify_all();
+        }
+    }
+
+    /// Take the requests over the limits of `watchdog`, with their method and limit, they don't count as in flight anymore
+    pub(crate) fn expire(&self, watchdog: &Watchdog, now: Instant) -> Vec<(u64, String, Duration)> {
+        let limits = watchdog.limits();
+        let mut requests = self.requests.lock().unwrap();
+        let expired: Vec<_> = requests.started.iter().filter_map(|(&req_id, (method, start, _))| {
+            // The name of an integer method is its decimal string, which is its id
+            let limit = limits.get(&Method::Str(method).id()).copied().or(watchdog.default_timeout)?;
+            if now.saturating_duration_since(*start) < limit { return None; }
+            Some((req_id, method.clone(), limit))
+        }).collect();
+        for (req_id, _, _) in &expired {
+            requests.started.remove(req_id);
+            requests.dropped.insert(*req_id);
+        }
+        if !expired.is_empty() { self.finished.notify_all(); }
+        expired
+    }
+
+    /// Wait until no request is in flight, false if some still are after `timeout`. One too far to be told by an instant
+    /// waits forever
+    pub(crate) fn wait_idle(&self, timeout: Duration, clock: &dyn Clock) -> bool {
+        let deadline = clock.now().checked_add(timeout);
+        let mut requests = self.requests.lock().unwrap();
+        while !requests.started.is_empty() {
+            requests = match deadline {
+                Some(deadline) => {
+                    let now = clock.now();
+                    if now >= deadline { return false; }
+                    self.finished.wait_timeout(requests, deadline - now).unwrap().0
+                }
+                None => self.finished.wait(requests).unwrap(),
+            };
+        }
+        true
+    }
+}
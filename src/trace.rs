@@ -0,0 +1,116 @@
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use rmpv::Value;
+
+use crate::{Direction, PacketTap, REQUEST, RESPONSE, NOTIFY, COMPRESSED, FRAGMENT, PING, PONG, ATTACHMENT};
+use crate::compress::{self, CompressionCodec};
+
+const HEX_LINE: usize = 16;
+/// Decompressed packets longer than that are not decoded
+const MAX_DECOMPRESSED: usize = 16 << 20;
+
+/// Describe a frame for the people implementing the protocol in another language:
+/// a header line with its type and fields, its decoded values, then its bytes in hex
+///
+/// ```text
+/// >> REQUEST id=1 method=3
+///    args: 5
+///    0000  94 00 01 03 05                                   .....
+/// ```
+pub fn annotate(direction: Direction, frame: &[u8]) -> String {
+    let mut s = String::new();
+    let arrow = match direction { Direction::Sent => ">>", Direction::Received => "<<" };
+    describe(&mut s, arrow, frame);
+    hex_dump(&mut s, frame);
+    s
+}
+
+/// Tap writing the annotation of every frame to `out`, for [`Session::set_packet_tap`](crate::Session::set_packet_tap)
+pub fn tap(out: impl Write + Send + 'static) -> PacketTap {
+    let out = Mutex::new(out);
+    Arc::new(move |direction, frame: &[u8]| {
+        let mut out = out.lock().unwrap();
+        out.write_all(annotate(direction, frame).as_bytes());
+        out.flush();
+    })
+}
+
+/// Annotate the frames of a recording, e.g. the ones collected by a packet tap, separated by a blank line
+pub fn render(frames: &[(Direction, Vec<u8>)]) -> String {
+    frames.iter().map(|(direction, frame)| annotate(*direction, frame)).collect::<Vec<_>>().join("\n")
+}
+
+fn describe(s: &mut String, prefix: &str, frame: &[u8]) {
+    let fields = match rmpv::decode::read_value(&mut &frame[..]) {
+        Ok(Value::Array(fields)) => fields,
+        Ok(val) => { writeln!(s, "{} MALFORMED not an array: {}", prefix, val); return; }
+        Err(e) => { writeln!(s, "{} MALFORMED {}", prefix, e); return; }
+    };
+    let ty = fields.first().and_then(Value::as_u64).unwrap_or(u64::MAX);
+    let field = |i: usize| fields.get(i).cloned().unwrap_or(Value::Nil);
+    match (ty as u32, fields.len()) {
+        (REQUEST, 4) => {
+            writeln!(s, "{} REQUEST id={} method={}", prefix, field(1), field(2));
+            writeln!(s, "   args: {}", field(3));
+        }
+        (REQUEST, 5) => {
+            writeln!(s, "{} REQUEST id={} method={} timeout={}ms", prefix, field(1), field(2), field(3));
+            writeln!(s, "   args: {}", field(4));
+        }
+        (REQUEST, 6) => {
+            writeln!(s, "{} REQUEST id={} method={} timeout={} metadata={}", prefix, field(1), field(2), field(3), field(4));
+            writeln!(s, "   args: {}", field(5));
+        }
+        (RESPONSE, 4) => {
+            writeln!(s, "{} RESPONSE id={}", prefix, field(1));
+            match field(2) {
+                Value::Nil => writeln!(s, "   result: {}", field(3)),
+                error => writeln!(s, "   error: {}", error),
+            };
+        }
+        (NOTIFY, 3) => {
+            writeln!(s, "{} NOTIFY method={}", prefix, field(1));
+            writeln!(s, "   args: {}", field(2));
+        }
+        (NOTIFY, 4) => {
+            writeln!(s, "{} NOTIFY ack={} method={}", prefix, field(1), field(2));
+            writeln!(s, "   args: {}", field(3));
+        }
+        (COMPRESSED, 3) => {
+            let codec = field(1).as_u64().and_then(CompressionCodec::from_id);
+            writeln!(s, "{} COMPRESSED codec={}", prefix, codec.map_or_else(|| field(1).to_string(), |c| format!("{:?}", c)));
+            match (codec, field(2)) {
+                (Some(codec), Value::Binary(data)) => match compress::decompress(codec, &data, MAX_DECOMPRESSED) {
+                    Ok(inner) => describe(s, "   >", &inner),
+                    Err(e) => { writeln!(s, "   can't decompress: {}", e); }
+                },
+                _ => { writeln!(s, "   codec not compiled in"); }
+            }
+        }
+        (FRAGMENT, 4) => {
+            let len = field(3).as_slice().map_or(0, <[u8]>::len);
+            writeln!(s, "{} FRAGMENT id={} more={} len={}", prefix, field(1), field(2), len);
+        }
+        (PING, 2) => { writeln!(s, "{} PING id={}", prefix, field(1)); }
+        (PONG, 2) => { writeln!(s, "{} PONG id={}", prefix, field(1)); }
+        (ATTACHMENT, 4) => {
+            let len = field(3).as_slice().map_or(0, <[u8]>::len);
+            writeln!(s, "{} ATTACHMENT id={} response={} len={}", prefix, field(1), field(2), len);
+        }
+        _ => { writeln!(s, "{} UNKNOWN {}", prefix, Value::Array(fields)); }
+    }
+}
+
+fn hex_dump(s: &mut String, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(HEX_LINE).enumerate() {
+        write!(s, "   {:04x}  ", i * HEX_LINE);
+        for b in line { write!(s, "{:02x} ", b); }
+        for _ in line.len()..HEX_LINE { s.push_str("   "); }
+        s.push(' ');
+        s.extend(line.iter().map(|&b| if b.is_ascii_graphic() { b as char } else { '.' }));
+        s.push('\n');
+    }
+}
@@ -0,0 +1,91 @@
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, Shutdown};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::{ByteBuf, Channel, ChannelError};
+
+/// Channel carrying the bytes of a tunnel, an empty message ends a direction
+pub type ByteChannel = Channel<ByteBuf, ByteBuf>;
+
+const CHUNK_LEN: usize = 0x4000;
+
+/// Pump the bytes of `reader` to the peer and the bytes of the peer to `writer`,
+/// until `reader` reached its end and the peer finished sending.
+/// `reader` is read on a new thread, which waits on the window of the channel while the peer can't keep up
+pub fn run<R: Read + Send + 'static, W: Write>(channel: ByteChannel, reader: R, mut writer: W) -> io::Result<()> {
+    let channel = Arc::new(channel);
+    let sender = spawn_sender(channel.clone(), reader);
+    let received = receive(&channel, &mut writer);
+    received.and(join(sender))
+}
+
+/// Tunnel a TCP connection, its write side is shut down once the peer finished sending
+pub fn tcp(channel: ByteChannel, stream: TcpStream) -> io::Result<()> {
+    let channel = Arc::new(channel);
+    let sender = spawn_sender(channel.clone(), stream.try_clone()?);
+    let received = receive(&channel, &mut &stream);
+    // Also unblock the sender if the tunnel broke
+    stream.shutdown(if received.is_ok() { Shutdown::Write } else { Shutdown::Both }).ok();
+    received.and(join(sender))
+}
+
+/// Send all the data of `reader`, calling `progress` with the count of bytes sent so far.
+/// Return the count once the peer wrote all of it with [`receive_stream`]
+pub fn send_stream<R: Read>(channel: &ByteChannel, mut reader: R, mut progress: impl FnMut(u64)) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_LEN];
+    let mut total = 0;
+    loop {
+        let len = reader.read(&mut buf)?;
+        channel.send(&ByteBuf::from(&buf[..len])).map_err(closed)?;
+        if len == 0 { break; }
+        total += len as u64;
+        progress(total);
+    }
+    // The receiver acknowledges the end once flushed
+    channel.recv().map_err(closed)?;
+    Ok(total)
+}
+
+/// Write the data sent with [`send_stream`] to `writer`, calling `progress` with the count of bytes received so far
+pub fn receive_stream<W: Write>(channel: &ByteChannel, mut writer: W, mut progress: impl FnMut(u64)) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let data = channel.recv().map_err(closed)?;
+        if data.is_empty() { break; }
+        writer.write_all(&data)?;
+        total += data.len() as u64;
+        progress(total);
+    }
+    writer.flush()?;
+    channel.send(&ByteBuf::new()).map_err(closed)?;
+    Ok(total)
+}
+
+fn spawn_sender<R: Read + Send + 'static>(channel: Arc<ByteChannel>, mut reader: R) -> JoinHandle<io::Result<()>> {
+    std::thread::spawn(move || {
+        let mut buf = vec![0; CHUNK_LEN];
+        loop {
+            let len = reader.read(&mut buf)?;
+            channel.send(&ByteBuf::from(&buf[..len])).map_err(closed)?;
+            if len == 0 { return Ok(()); }
+        }
+    })
+}
+
+fn receive<W: Write>(channel: &ByteChannel, writer: &mut W) -> io::Result<()> {
+    loop {
+        let data = channel.recv().map_err(closed)?;
+        if data.is_empty() { return writer.flush(); }
+        writer.write_all(&data)?;
+    }
+}
+
+fn join(sender: JoinHandle<io::Result<()>>) -> io::Result<()> {
+    sender.join().unwrap_or_else(|_| Err(io::Error::other("tunnel sender panicked")))
+}
+
+fn closed(e: ChannelError) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string())
+}
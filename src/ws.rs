@@ -2,9 +2,14 @@
 use std::io;
 use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
-use std::net::{TcpStream, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::collections::HashMap;
+use std::net::{TcpStream, TcpListener, ToSocketAddrs, SocketAddr, IpAddr, Shutdown};
+use std::time::Duration;
 
 use websocket::sync::{Server, Client};
+use websocket::sync::server::IntoWs;
 use websocket::server::NoTlsAcceptor;
 use websocket::server::WsServer;
 use websocket::{
@@ -13,43 +18,63 @@ use websocket::{
 };
 pub use websocket::WebSocketError;
 
-use crate::{Adaptor, RecvError};
+use crate::{Adaptor, RecvError, TransportError, Listener, Accepted};
 
 pub struct WsAdaptor {
     sender: Mutex<Writer<TcpStream>>,
     receiver: Mutex<Reader<TcpStream>>,
     disconnected: RwLock<bool>,
+    last_error: Mutex<Option<TransportError>>,
+    peer_addr: Option<SocketAddr>,
 }
 
 impl WsAdaptor {
     pub fn new(client: Client<TcpStream>) -> io::Result<WsAdaptor> {
+        let peer_addr = client.peer_addr().ok();
         let (receiver, sender) = client.split()?;
         Ok(WsAdaptor {
             sender: Mutex::new(sender),
             receiver: Mutex::new(receiver),
             disconnected: RwLock::new(false),
+            last_error: Mutex::new(None),
+            peer_addr,
         })
     }
+
+    fn set_error(&self, e: &WebSocketError) {
+        self.last_error.lock().unwrap().get_or_insert(classify(e));
+    }
 }
 
 impl Adaptor for WsAdaptor {
     fn send(&self, data: Vec<u8>) -> bool {
         self.sender.lock().unwrap()
-                   .send_message(&OwnedMessage::Binary(data)).is_ok()
+                   .send_message(&OwnedMessage::Binary(data))
+                   .map_err(|e| self.set_error(&e)).is_ok()
     }
 
     fn recv(&self) -> Result<Vec<u8>, RecvError> {
-        match recv_message(&mut self.receiver.lock().unwrap(), &self.sender) {
-            Err(e) => {
-                if is_disconnected(&e) {
-                    *self.disconnected.write().unwrap() = true;
-                    Err(RecvError::Disconnect)
-                } else { panic!("Invalid Error: {:?}", e); }
-            }
-            Ok(data) => { Ok(data) }
+        let message = recv_message(&mut self.receiver.lock().unwrap(), &self.sender, None);
+        self.received(message)
+    }
+
+    fn try_recv(&self) -> Option<Result<Vec<u8>, RecvError>> {
+        Some(self.recv_timeout(Duration::from_secs(0)))
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> {
+        match recv_message(&mut self.receiver.lock().unwrap(), &self.sender, Some(timeout)) {
+            Err(WebSocketError::IoError(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Err(RecvError::NoData),
+            message => self.received(message),
         }
     }
 
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+        Some(self.receiver.lock().unwrap().stream.get_ref().as_raw_fd())
+    }
+
     fn connected(&self) -> bool {
         !*self.disconnected.read().unwrap()
     }
@@ -57,25 +82,89 @@ impl Adaptor for WsAdaptor {
     fn close(&self) {
         self.sender.lock().unwrap().shutdown_all();
     }
+
+    fn last_error(&self) -> Option<TransportError> { *self.last_error.lock().unwrap() }
+
+    fn peer_addr(&self) -> Option<SocketAddr> { self.peer_addr }
+
+    fn transport_kind(&self) -> Option<&'static str> { Some("ws") }
+}
+
+impl WsAdaptor {
+    fn received(&self, message: Result<Option<Vec<u8>>, WebSocketError>) -> Result<Vec<u8>, RecvError> {
+        match message {
+            Err(e) => {
+                // The stream can't be resynchronized after a protocol error, drop the connection
+                if !is_disconnected(&e) { self.close(); }
+                self.set_error(&e);
+                *self.disconnected.write().unwrap() = true;
+                Err(RecvError::Disconnect)
+            }
+            Ok(None) => {
+                *self.disconnected.write().unwrap() = true;
+                Err(RecvError::Disconnect)
+            }
+            Ok(Some(data)) => { Ok(data) }
+        }
+    }
 }
 
-fn recv_message(r: &mut Reader<TcpStream>, s: &Mutex<Writer<TcpStream>>) -> Result<Vec<u8>, WebSocketError> {
+// Return `None` if the peer closed the connection
+// With a `timeout`, fail with `WouldBlock` if no data is available in time
+fn recv_message(r: &mut Reader<TcpStream>, s: &Mutex<Writer<TcpStream>>, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, WebSocketError> {
     loop {
+        if let Some(timeout) = timeout {
+            if !available(r, s, timeout) { return Err(io::Error::from(io::ErrorKind::WouldBlock).into()); }
+        }
         match r.recv_message()? {
             OwnedMessage::Close(_) => {
-                s.lock().unwrap()
-                 .send_message(&OwnedMessage::Close(None))?;
+                let mut s = s.lock().unwrap();
+                s.send_message(&OwnedMessage::Close(None));
+                s.shutdown_all();
+                return Ok(None);
             }
             OwnedMessage::Ping(ping) => {
                 s.lock().unwrap()
                  .send_message(&OwnedMessage::Pong(ping))?;
             }
-            OwnedMessage::Binary(msg) => { return Ok(msg); }
+            OwnedMessage::Binary(msg) => { return Ok(Some(msg)); }
             _ => {}
         }
     }
 }
 
+// Whether some data can be read within `timeout`, a message may still be incomplete
+fn available(r: &Reader<TcpStream>, s: &Mutex<Writer<TcpStream>>, timeout: Duration) -> bool {
+    if !r.stream.get_buf().is_empty() { return true; }
+    let stream = r.stream.get_ref();
+    let peeked = if timeout == Duration::from_secs(0) {
+        // The writer shares the socket, it doesn't write while it's non-blocking
+        let _writer = s.lock().unwrap();
+        stream.set_nonblocking(true);
+        let peeked = stream.peek(&mut [0]);
+        stream.set_nonblocking(false);
+        peeked
+    } else {
+        let previous = stream.read_timeout().ok().flatten();
+        stream.set_read_timeout(Some(timeout));
+        let peeked = stream.peek(&mut [0]);
+        stream.set_read_timeout(previous);
+        peeked
+    };
+    match peeked {
+        Ok(_) => true,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::TimedOut,
+    }
+}
+
+fn classify(err: &WebSocketError) -> TransportError {
+    match err {
+        WebSocketError::NoDataAvailable => TransportError::Reset,
+        WebSocketError::IoError(e) => TransportError::from_io(e),
+        _ => TransportError::Protocol,
+    }
+}
+
 fn is_disconnected(err: &WebSocketError) -> bool {
     match *err {
         WebSocketError::NoDataAvailable => true,
@@ -104,5 +193,146 @@ pub fn accept(server: &mut ServerT) -> io::Result<(Arc<WsAdaptor>, String)> {
 pub fn connect(url: &str) -> Result<Arc<WsAdaptor>, WebSocketError> {
     Ok(Arc::new(WsAdaptor::new(
         ClientBuilder::new(url).unwrap().connect_insecure()?
-    ).map_err(|e| WebSocketError::IoError(e))?))
-}
\ No newline at end of file
+    ).map_err(WebSocketError::IoError)?))
+}
+
+/// Protection of a listener against clients which never finish the handshake (slowloris)
+#[derive(Debug, Clone)]
+pub struct HandshakeLimits {
+    /// Deadline to receive the whole upgrade request and send the response, counted from the TCP accept
+    pub timeout: Duration,
+    /// Maximum count of handshakes in progress from a single IP, more connections from it are dropped
+    pub max_per_ip: usize,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        HandshakeLimits { timeout: Duration::from_secs(10), max_per_ip: 8 }
+    }
+}
+
+/// Listener which performs every handshake on its own thread within [`HandshakeLimits`],
+/// so a slow client can't stall the others
+pub struct GuardedServer {
+    receiver: Mutex<Receiver<(Arc<WsAdaptor>, String)>>,
+    local_addrs: Vec<SocketAddr>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl GuardedServer {
+    /// Wait for the next connection which completed the handshake, return the adaptor and the requested uri
+    pub fn accept(&self) -> io::Result<(Arc<WsAdaptor>, String)> {
+        self.receiver.lock().unwrap().recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "listener stopped"))
+    }
+
+    /// The first address listened on
+    pub fn local_addr(&self) -> SocketAddr { self.local_addrs[0] }
+
+    /// All the addresses listened on, see [`bind_guarded_all`]
+    pub fn local_addrs(&self) -> &[SocketAddr] { &self.local_addrs }
+
+    /// Stop listening, `accept` fails once the handshakes in progress are done
+    pub fn close(&self) {
+        // Wake the listening threads up so they see the flag
+        if !self.stopped.swap(true, Ordering::SeqCst) {
+            for addr in &self.local_addrs { TcpStream::connect(addr); }
+        }
+    }
+}
+
+impl Drop for GuardedServer {
+    fn drop(&mut self) { self.close(); }
+}
+
+impl Listener for GuardedServer {
+    fn accept(&self) -> io::Result<Option<Arc<dyn Adaptor>>> {
+        GuardedServer::accept(self).map(|(adaptor, _)| Some(adaptor as Arc<dyn Adaptor>))
+    }
+
+    fn accept_uri(&self) -> io::Result<Option<Accepted>> {
+        GuardedServer::accept(self).map(|(adaptor, uri)| Some((adaptor as Arc<dyn Adaptor>, Some(uri))))
+    }
+
+    fn close(&self) { GuardedServer::close(self) }
+
+    fn local_addrs(&self) -> Vec<SocketAddr> { self.local_addrs.clone() }
+}
+
+pub fn bind_guarded(addr: impl ToSocketAddrs, limits: HandshakeLimits) -> io::Result<GuardedServer> {
+    bind_guarded_all(&[addr], limits)
+}
+
+/// Like [`bind_guarded`], accepting the connections of all `addrs` together. The limits are shared
+pub fn bind_guarded_all<A: ToSocketAddrs>(addrs: &[A], limits: HandshakeLimits) -> io::Result<GuardedServer> {
+    if addrs.is_empty() { return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on")); }
+    let listeners = addrs.iter().map(TcpListener::bind).collect::<io::Result<Vec<_>>>()?;
+    let local_addrs = listeners.iter().map(TcpListener::local_addr).collect::<io::Result<Vec<_>>>()?;
+    let (sender, receiver) = channel();
+    let in_progress = Arc::new(Mutex::new(HashMap::<IpAddr, usize>::new()));
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    for listener in listeners {
+        listen(listener, sender.clone(), in_progress.clone(), stopped.clone(), limits.clone());
+    }
+    Ok(GuardedServer { receiver: Mutex::new(receiver), local_addrs, stopped })
+}
+
+type Handshaken = Sender<(Arc<WsAdaptor>, String)>;
+
+fn listen(listener: TcpListener, sender: Handshaken, in_progress: Arc<Mutex<HashMap<IpAddr, usize>>>, listening: Arc<AtomicBool>, limits: HandshakeLimits) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if listening.load(Ordering::SeqCst) { break; }
+            let stream = match stream { Ok(s) => s, Err(_) => continue };
+            let ip = match stream.peer_addr() { Ok(a) => a.ip(), Err(_) => continue };
+            {
+                let mut in_progress = in_progress.lock().unwrap();
+                let count = in_progress.entry(ip).or_insert(0);
+                if *count >= limits.max_per_ip { continue; }
+                *count += 1;
+            }
+
+            let slot = HandshakeSlot { in_progress: in_progress.clone(), ip };
+            let (sender, timeout) = (sender.clone(), limits.timeout);
+            std::thread::spawn(move || {
+                if let Some(r) = handshake(stream, timeout, slot) { sender.send(r); }
+            });
+        }
+    });
+}
+
+/// A handshake in progress, counted against the limit of its IP until dropped
+struct HandshakeSlot {
+    in_progress: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        let count = in_progress.get_mut(&self.ip).unwrap();
+        *count -= 1;
+        if *count == 0 { in_progress.remove(&self.ip); }
+    }
+}
+
+fn handshake(stream: TcpStream, timeout: Duration, slot: HandshakeSlot) -> Option<(Arc<WsAdaptor>, String)> {
+    // Shutdown the socket when the deadline passed, even if the client keeps trickling bytes.
+    // The slot is held until the handshake is done, and released first, so the IP may connect again
+    // once its socket is closed
+    let (done, wait) = channel::<()>();
+    let watchdog = stream.try_clone().ok()?;
+    std::thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = wait.recv_timeout(timeout) {
+            drop(slot);
+            watchdog.shutdown(Shutdown::Both);
+        }
+    });
+
+    let upgrade = stream.into_ws().ok()?;
+    let uri = upgrade.uri();
+    let client = upgrade.accept().ok()?;
+    done.send(());
+    Some((Arc::new(WsAdaptor::new(client).ok()?), uri))
+}
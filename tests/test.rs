@@ -1,12 +1,47 @@
-
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, mpsc::{channel, Sender, Receiver, RecvTimeoutError}};
+use std::time::{Duration, SystemTime};
 use easy_rpc::*;
 
+/// In-process adaptor connected to another one by channels
+struct Pipe {
+    sender: Mutex<Sender<Vec<u8>>>,
+    receiver: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl Adaptor for Pipe {
+    fn send(&self, data: Vec<u8>) -> bool { self.sender.lock().unwrap().send(data).is_ok() }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        self.receiver.lock().unwrap().recv().map_err(|_| RecvError::Disconnect)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvError> {
+        self.receiver.lock().unwrap().recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => RecvError::NoData,
+            RecvTimeoutError::Disconnected => RecvError::Disconnect,
+        })
+    }
+
+    fn connected(&self) -> bool { true }
+
+    fn close(&self) {}
+}
+
+fn pipe() -> (Arc<Pipe>, Arc<Pipe>) {
+    let (s1, r1) = channel();
+    let (s2, r2) = channel();
+    (
+        Arc::new(Pipe { sender: Mutex::new(s1), receiver: Mutex::new(r2) }),
+        Arc::new(Pipe { sender: Mutex::new(s2), receiver: Mutex::new(r1) }),
+    )
+}
+
 struct ClientService;
 struct ServerService;
 
 const RECURSIVE_ADD: u32 = 1;
 const ECHO_BIGDATA: u32 = 2;
+const ECHO: u32 = 3;
 
 easy_service! {
     ClientService(self, _ss, arg, ret)
@@ -33,6 +68,7 @@ easy_service! {
             println!("BigData Received {}", data.len());
             data
         }
+        ECHO => (val: u32) { val }
     }
 }
 
@@ -42,8 +78,8 @@ fn session_test(session: &Session) {
 
     const LEN: usize = 0x10000;
     let tail = &[1, 2, 3];
-    let mut data: Vec<u8> = Vec::with_capacity(LEN);
-    data.resize(LEN, 0); data.extend_from_slice(tail);
+    let mut data: Vec<u8> = vec![0; LEN];
+    data.extend_from_slice(tail);
 
     let data: Vec<u8> = session.request(ECHO_BIGDATA, &data).into().unwrap();
     assert_eq!(data.len(), LEN + tail.len());
@@ -59,7 +95,7 @@ fn test_ws() {
         s.loop_handle();
     });
 
-    std::thread::sleep_ms(100);
+    std::thread::sleep(Duration::from_millis(100));
     let session = Session::new(ws::connect("ws://127.0.0.1:3333").unwrap(), Arc::new(ClientService));
     session_test(&session);
 }
@@ -75,14 +111,3966 @@ fn test_js() {
 
 #[test]
 fn test_shm() {
-    std::thread::spawn(move || {
+    let server = std::thread::spawn(move || {
         let adaptor = shm::create("sharememory_test").unwrap();
         adaptor.wait(None);
         let s = Session::new(adaptor, Arc::new(ServerService));
         s.loop_handle();
     });
 
-    std::thread::sleep_ms(100);
+    std::thread::sleep(Duration::from_millis(100));
     let s = Session::new(shm::connect("sharememory_test").unwrap(), Arc::new(ClientService));
     session_test(&s);
-}
\ No newline at end of file
+    // Wait the server to release the shared memory
+    drop(s); server.join().unwrap();
+}
+
+#[test]
+fn test_shm_transport_error() {
+    let server = std::thread::spawn(move || {
+        let adaptor = shm::create("sharememory_error_test").unwrap();
+        adaptor.wait(None);
+        let s = Session::new(adaptor, Arc::new(ServerService));
+        s.loop_handle();
+        s.transport_error()
+    });
+
+    std::thread::sleep(Duration::from_millis(100));
+    let s = Session::new(shm::connect("sharememory_error_test").unwrap(), Arc::new(ClientService));
+    assert!(s.ping().is_ok());
+    // shm has no close message, the server only sees its peer stop answering pings
+    drop(s);
+    assert_eq!(server.join().unwrap(), Some(TransportError::Timeout));
+}
+#[test]
+fn test_malformed_packet() {
+    let (a1, a2) = pipe();
+    let server = Session::new(a1, Arc::new(ServerService));
+    assert!(server.handle_packet(vec![0xc1]).is_err());
+    assert!(server.handle_packet(vec![0x93, 0x07, 0x00, 0x00]).is_err());
+    // Response of a request never sent
+    assert!(server.handle_packet(vec![0x94, 0x01, 0x05, 0xc0, 0xc0]).is_err());
+
+    // A request with a broken method is answered with an error
+    assert!(server.handle_packet(vec![0x94, 0x00, 0x01, 0xc1, 0xc0]).is_err());
+    let resp = rmpv::decode::read_value(&mut &a2.recv().unwrap()[..]).unwrap();
+    assert_eq!(resp[1].as_u64(), Some(1));
+    assert_eq!(resp[2][0].as_i64(), Some(RemoteError::MALFORMED));
+    assert!(resp[2][1].is_str());
+
+    let client = Session::new(a2, Arc::new(ClientService));
+    std::thread::spawn(move || server.loop_handle());
+    let val: u32 = client.request(ECHO, 1).into().unwrap();
+    assert_eq!(val, 1);
+}
+
+#[test]
+fn test_decode_limits() {
+    let limits = DecodeLimits { max_depth: 2, max_array_len: 4, ..DecodeLimits::default() };
+    assert!(limits.check(&[0x92, 0x91, 0x01, 0x02]).is_ok());
+    assert_eq!(limits.check(&[0x91, 0x91, 0x91, 0x01]), Err("nesting depth"));
+    assert_eq!(limits.check(&[0x95, 1, 2, 3, 4, 5]), Err("array length"));
+    // Header of a 4GB array, rejected before anything is allocated
+    assert_eq!(limits.check(&[0xdd, 0xff, 0xff, 0xff, 0xff]), Err("array length"));
+
+    let (a1, a2) = pipe();
+    let server = Session::new(a1, Arc::new(ServerService));
+    server.set_decode_limits(Some(limits));
+    let client = Session::new(a2, Arc::new(ClientService));
+    std::thread::spawn(move || server.loop_handle());
+    assert!(client.request(ECHO_BIGDATA, vec![0u8; 8]).into::<Vec<u8>>().is_err());
+    let val: u32 = client.request(ECHO, 1).into().unwrap();
+    assert_eq!(val, 1);
+}
+
+/// Adaptor recording the sent packets, `send` blocks until released
+struct Recorder(Mutex<Receiver<()>>, Mutex<Sender<Vec<u8>>>);
+
+impl Adaptor for Recorder {
+    fn send(&self, data: Vec<u8>) -> bool {
+        self.0.lock().unwrap().recv().is_ok() && self.1.lock().unwrap().send(data).is_ok()
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> { Err(RecvError::Disconnect) }
+
+    fn connected(&self) -> bool { true }
+
+    fn close(&self) {}
+}
+
+#[test]
+fn test_send_queue() {
+    let (release, r) = channel();
+    let (s, _sent) = channel();
+    let session = Session::new(Arc::new(Recorder(Mutex::new(r), Mutex::new(s))), Arc::new(EmptyService));
+    session.set_send_queue(1, Overflow::Fail);
+    // The writer thread takes the first packet and blocks, the second fills the queue
+    assert_eq!(session.try_notify(ECHO, ()), Ok(()));
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(session.try_notify(ECHO, ()), Ok(()));
+    assert_eq!(session.try_notify(ECHO, ()), Err(SendError::WouldBlock));
+    match session.request(ECHO, ()) { RequestResult::WouldBlock => {}, r => panic!("{:?}", r) }
+
+    release.send(()).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(session.try_notify(ECHO, ()), Ok(()));
+    drop(release);
+}
+
+#[test]
+fn test_send_priority() {
+    let (release, r) = channel();
+    let (s, sent) = channel();
+    let session = Session::new(Arc::new(Recorder(Mutex::new(r), Mutex::new(s))), Arc::new(EmptyService));
+    session.set_send_queue(2, Overflow::Fail);
+    session.notify_with_priority("first", (), Priority::Low).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    session.notify_with_priority("bulk", (), Priority::Low).unwrap();
+    session.notify_with_priority("bulk", (), Priority::Low).unwrap();
+    assert_eq!(session.notify_with_priority("bulk", (), Priority::Low), Err(SendError::WouldBlock));
+    // A full low lane doesn't stop the high priority packets
+    session.notify_with_priority("control", (), Priority::High).unwrap();
+
+    let methods = (0..4).map(|_| {
+        release.send(()).unwrap();
+        let pack = rmpv::decode::read_value(&mut &sent.recv().unwrap()[..]).unwrap();
+        pack[1].as_str().unwrap().to_string()
+    }).collect::<Vec<_>>();
+    assert_eq!(methods, ["first", "control", "bulk", "bulk"]);
+}
+
+#[test]
+fn test_ws_handshake_limits() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let limits = ws::HandshakeLimits { timeout: Duration::from_millis(300), max_per_ip: 1 };
+    let ser = ws::bind_guarded("127.0.0.1:0", limits).unwrap();
+    let addr = ser.local_addr();
+    // A client trickling its request holds the only handshake slot of its IP until the deadline.
+    // The listener takes the connections in order, so the slot is held when the next one arrives
+    let mut slow = TcpStream::connect(addr).unwrap();
+    slow.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    assert!(ws::connect(&format!("ws://{}", addr)).is_err());
+
+    // The listener releases the slot before closing the slow socket at the deadline
+    assert_eq!(slow.read(&mut [0; 16]).unwrap_or(0), 0);
+    std::thread::spawn(move || {
+        let (adaptor, uri) = ser.accept().unwrap();
+        assert_eq!(uri, "/path");
+        Session::new(adaptor, Arc::new(ServerService)).loop_handle();
+    });
+    let session = Session::new(ws::connect(&format!("ws://{}/path", addr)).unwrap(), Arc::new(ClientService));
+    let val: u32 = session.request(ECHO, 5).into().unwrap();
+    assert_eq!(val, 5);
+}
+
+#[test]
+fn test_server() {
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let server = Server::new(listener, || Arc::new(ServerService))
+        .on_session(|ss| ss.set_decode_limits(Some(DecodeLimits::default())))
+        .start();
+
+    let clients = (0..3).map(|_| Session::new(ws::connect(&url).unwrap(), Arc::new(ClientService))).collect::<Vec<_>>();
+    for (i, client) in clients.iter().enumerate() {
+        assert_eq!(client.request(ECHO, i as u32).into::<u32>().unwrap(), i as u32);
+    }
+    assert_eq!(server.sessions().len(), 3);
+
+    server.shutdown();
+    assert!(!server.is_running());
+    for client in &clients {
+        match client.request(ECHO, 1) { RequestResult::Disconnect => {}, r => panic!("{:?}", r) }
+    }
+    assert!(ws::connect(&url).is_err());
+}
+
+struct User(String);
+struct StateService;
+
+easy_service! {
+    StateService(self, ss, arg, ret)
+
+    StringMethod {
+        "login" => (name: String) {
+            ss.extensions().insert(User(name));
+        }
+        "whoami" => () {
+            ss.extensions().get::<User>().map(|u| u.0.clone())
+        }
+    }
+}
+
+#[test]
+fn test_extensions() {
+    let (a1, a2) = pipe();
+    let server = Session::new(a1, Arc::new(StateService));
+    let client = Session::new(a2, Arc::new(EmptyService));
+    std::thread::spawn(move || server.loop_handle());
+
+    let user: Option<String> = client.request("whoami", ()).into().unwrap();
+    assert_eq!(user, None);
+    client.request("login", "alice").into::<()>().unwrap();
+    let user: Option<String> = client.request("whoami", ()).into().unwrap();
+    assert_eq!(user.as_ref().map(String::as_str), Some("alice"));
+}
+
+#[test]
+fn test_canonical() {
+    use std::collections::HashMap;
+
+    let map = (0..32).map(|i| (format!("key{}", i), i)).collect::<HashMap<_, _>>();
+    let bytes = canonical::to_vec(&map).unwrap();
+    let keys = rmpv::decode::read_value(&mut &bytes[..]).unwrap()
+        .as_map().unwrap().iter().map(|(k, _)| k.as_str().unwrap().to_string()).collect::<Vec<_>>();
+    let mut sorted = keys.clone(); sorted.sort_by_key(|k| (k.len(), k.clone()));
+    assert_eq!(keys, sorted);
+    // Non-minimal integer and unsorted map
+    assert_eq!(canonical::canonicalize(&[0x82, 0xa1, b'b', 0xce, 0, 0, 0, 1, 0xa1, b'a', 0x02]).unwrap(),
+               [0x82, 0xa1, b'a', 0x02, 0xa1, b'b', 0x01]);
+}
+
+#[test]
+fn test_aggregate() {
+    use easy_rpc::aggregate::*;
+
+    let (flushed, batches) = channel();
+    let flushed = Mutex::new(flushed);
+    let policy = BatchPolicy { max_len: 2, max_delay: Duration::from_millis(50) };
+    let service = Aggregator::new()
+        .batch("sample", policy, move |items: Vec<u32>| { flushed.lock().unwrap().send(items).unwrap(); })
+        .fallback(Arc::new(ServerService))
+        .start();
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, service));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    for i in 0..5 { assert!(client.notify("sample", i)); }
+    assert_eq!(batches.recv_timeout(Duration::from_secs(1)).unwrap(), [0, 1]);
+    assert_eq!(batches.recv_timeout(Duration::from_secs(1)).unwrap(), [2, 3]);
+    // The last one is flushed by the timer
+    assert_eq!(batches.recv_timeout(Duration::from_secs(1)).unwrap(), [4]);
+    // Requests are acknowledged, the other methods go to the fallback
+    client.request("sample", 5).into::<()>().unwrap();
+    assert_eq!(client.request(ECHO, 7).into::<u32>().unwrap(), 7);
+}
+
+#[test]
+fn test_compression() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let server2 = server.clone();
+    std::thread::spawn(move || server2.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let codec = client.negotiate_compression(0x100);
+    assert_eq!(codec, CompressionCodec::supported().first().copied());
+    assert_eq!(client.compression(), codec.map(|codec| Compression { codec, threshold: 0x100 }));
+
+    let data = vec![7u8; 0x10_0000];
+    let echo: Vec<u8> = client.request(ECHO_BIGDATA, &data).into().unwrap();
+    assert!(echo == data);
+    assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+    // The peer enables it right after answering the negotiation
+    assert_eq!(server.compression(), client.compression());
+}
+
+#[cfg(feature = "noise")]
+#[test]
+fn test_noise() {
+    use easy_rpc::encrypted::*;
+
+    let (server_key, client_key) = (generate_keypair(), generate_keypair());
+    let server_keys = Keys { private: server_key.private.clone(), remote_public: Some(client_key.public.clone()) };
+    let client_keys = Keys { private: client_key.private.clone(), remote_public: Some(server_key.public.clone()) };
+
+    let (a, b) = pipe();
+    let handshake = std::thread::spawn(move || wrap(a, &server_keys, Side::Responder).unwrap());
+    let client = wrap(b, &client_keys, Side::Initiator).unwrap();
+    let server = handshake.join().unwrap();
+    assert_eq!(client.remote_public(), &server_key.public[..]);
+
+    let server = Arc::new(Session::new(server, Arc::new(ServerService)));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(client, Arc::new(ClientService));
+    assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+    // Larger than a Noise message
+    let data = (0..200_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+    let echo: Vec<u8> = client.request(ECHO_BIGDATA, &data).into().unwrap();
+    assert!(echo == data);
+
+    // A peer with another key is refused
+    let (a, b) = pipe();
+    let other = Keys { private: generate_keypair().private, remote_public: None };
+    std::thread::spawn(move || { wrap(a, &other, Side::Responder).ok(); });
+    let client_keys = Keys { private: client_key.private, remote_public: Some(server_key.public) };
+    assert!(match wrap(b, &client_keys, Side::Initiator) { Err(NoiseError::UnknownPeer) => true, _ => false });
+}
+
+struct TestAuthenticator;
+
+impl Authenticator for TestAuthenticator {
+    fn authenticate(&self, _ss: &Session, credentials: &Credentials, challenge: &[u8]) -> Result<Identity, String> {
+        match credentials {
+            Credentials::Token(token) if token == "secret" => Ok(Identity { name: "alice".into(), roles: vec!["admin".into()] }),
+            // Stands for a real signature check
+            Credentials::Signature { public_key, signature } if !challenge.is_empty()
+                && signature.iter().rev().eq(challenge.iter()) => Ok(Identity { name: format!("{:?}", public_key), roles: vec![] }),
+            _ => Err("Bad credentials".into()),
+        }
+    }
+}
+
+#[test]
+fn test_auth() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    server.set_authenticator(Some(Arc::new(TestAuthenticator)));
+    let server2 = server.clone();
+    std::thread::spawn(move || server2.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    match client.request(ECHO, 1) { RequestResult::Error(e) => assert_eq!(e.message, "Unauthenticated"), r => panic!("{:?}", r) }
+    // Nor can it change the session before
+    for method in &["$bincode", "$compression", "$alias", "$deadline"] {
+        match client.request(*method, ()) { RequestResult::Error(e) => assert_eq!(e.code, RemoteError::UNAUTHENTICATED), r => panic!("{:?}", r) }
+    }
+    assert_eq!(client.authenticate(&Credentials::Token("guess".into())).unwrap_err(), "Bad credentials");
+    let identity = client.authenticate(&Credentials::Token("secret".into())).unwrap();
+    assert_eq!(identity, Identity { name: "alice".into(), roles: vec!["admin".into()] });
+    assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+    assert_eq!(server.identity().as_deref(), Some(&identity));
+
+    // Challenge/response, each challenge is used once
+    let challenge = client.challenge().unwrap();
+    let signature = challenge.iter().rev().copied().collect::<Vec<u8>>();
+    let credentials = Credentials::Signature { public_key: vec![1, 2], signature };
+    assert_eq!(client.authenticate(&credentials).unwrap().name, "[1, 2]");
+    assert!(client.authenticate(&credentials).is_err());
+}
+
+#[test]
+fn test_structured_error() {
+    struct Overdrawn(u32);
+    impl From<Overdrawn> for HandleError {
+        fn from(e: Overdrawn) -> Self { HandleError::new(ErrorKind::Custom(7), "overdrawn").with_data(e.0) }
+    }
+
+    struct Strict;
+    impl Service for Strict {
+        fn handle(&self, _ss: &Session, arg: Arg, _ret: Ret) -> Result<(), HandleError> {
+            match arg.method.to_str()? {
+                "plain" => Err(HandleError::new(ErrorKind::Application, "plain")),
+                "withdraw" => Err(Overdrawn(arg.into::<u32>()?).into()),
+                "coded" => Err(HandleError::new(ErrorKind::InvalidArgs, "out of range").with_data((1u32, 10u32))),
+                _ => Err(HandleError::new(ErrorKind::MethodNotFound, "No this method")),
+            }
+        }
+    }
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(Strict)));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    match client.request("plain", ()) {
+        RequestResult::Error(e) => assert_eq!(e, RemoteError::new(RemoteError::APPLICATION, "plain")),
+        r => panic!("{:?}", r),
+    }
+    match client.request("coded", ()) {
+        RequestResult::Error(e) => {
+            assert_eq!((e.code, e.message.as_str()), (RemoteError::INVALID_ARGS, "out of range"));
+            assert_eq!(e.data::<(u32, u32)>().unwrap().unwrap(), (1, 10));
+            assert_eq!(e.to_string(), "out of range (code -32602)");
+        }
+        r => panic!("{:?}", r),
+    }
+    match client.request("withdraw", 5) {
+        RequestResult::Error(e) => assert_eq!((e.code, e.data::<u32>().unwrap().unwrap()), (7, 5)),
+        r => panic!("{:?}", r),
+    }
+    match client.request("withdraw", "five") {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+    match client.request("missing", ()) {
+        RequestResult::Error(e) => assert_eq!((e.code, e.data), (RemoteError::METHOD_NOT_FOUND, None)),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn test_channel() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let (server2, client2) = (server.clone(), client.clone());
+    std::thread::spawn(move || server2.loop_handle());
+    std::thread::spawn(move || client2.loop_handle());
+
+    // Echo the lines back in upper case, more than a window of them
+    let echo = std::thread::spawn(move || {
+        let channel = server.accept_channel::<String, String>("shell").unwrap();
+        while let Ok(line) = channel.recv() { channel.send(&line.to_uppercase()).unwrap(); }
+    });
+    let channel = Arc::new(client.open_channel::<String, String>("shell").unwrap());
+    // More than a window ahead of the reader, the sender has to wait for credits
+    let sender = std::thread::spawn({
+        let channel = channel.clone();
+        move || for i in 0..200 { channel.send(&format!("line {}", i)).unwrap(); }
+    });
+    for i in 0..200 { assert_eq!(channel.recv().unwrap(), format!("LINE {}", i)); }
+    sender.join().unwrap();
+    drop(channel);
+    // Closing ends the loop of the peer
+    echo.join().unwrap();
+}
+
+#[test]
+fn test_fragmentation() {
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    server.set_max_frame(Some(0x1000));
+    std::thread::spawn(move || server.loop_handle());
+
+    // Check the size of the frames sent by the client
+    struct Sizes(Arc<Pipe>, Mutex<Vec<usize>>);
+    impl Adaptor for Sizes {
+        fn send(&self, data: Vec<u8>) -> bool {
+            self.1.lock().unwrap().push(data.len());
+            self.0.send(data)
+        }
+        fn recv(&self) -> Result<Vec<u8>, RecvError> { self.0.recv() }
+        fn connected(&self) -> bool { true }
+        fn close(&self) {}
+    }
+    let adaptor = Arc::new(Sizes(b, Mutex::new(Vec::new())));
+    let client = Session::new(adaptor.clone(), Arc::new(EmptyService));
+    client.set_max_frame(Some(0x1000));
+
+    let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+    let echo: Vec<u8> = client.request(ECHO_BIGDATA, &data).into().unwrap();
+    assert!(echo == data);
+    assert!(adaptor.1.lock().unwrap().iter().all(|&len| len <= 0x1000));
+    assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+}
+
+#[test]
+fn test_tunnel() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use easy_rpc::tunnel;
+
+    // Echo everything until the end of the connection
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        stream.write_all(&data).unwrap();
+    });
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let (server2, client2) = (server.clone(), client.clone());
+    std::thread::spawn(move || server2.loop_handle());
+    std::thread::spawn(move || client2.loop_handle());
+
+    let forward = std::thread::spawn(move || {
+        let channel = server.accept_channel("tcp").unwrap();
+        tunnel::tcp(channel, TcpStream::connect(addr).unwrap())
+    });
+    // Several windows of chunks
+    let data = (0..5_000_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let mut echo = Vec::new();
+    tunnel::run(client.open_channel("tcp").unwrap(), std::io::Cursor::new(data.clone()), &mut echo).unwrap();
+    assert!(echo == data);
+    forward.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_stream() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let (server2, client2) = (server.clone(), client.clone());
+    std::thread::spawn(move || server2.loop_handle());
+    std::thread::spawn(move || client2.loop_handle());
+
+    let data = (0..3_000_000u32).map(|i| (i % 253) as u8).collect::<Vec<u8>>();
+    let receiver = std::thread::spawn(move || {
+        let mut file = Vec::new();
+        let len = server.receive_stream("upload", &mut file, |_| {}).unwrap();
+        assert_eq!(len, file.len() as u64);
+        file
+    });
+    let mut reported = 0;
+    let len = client.send_stream("upload", &data[..], |n| { assert!(n > reported); reported = n; }).unwrap();
+    assert_eq!(len, data.len() as u64);
+    assert_eq!(reported, len);
+    assert!(receiver.join().unwrap() == data);
+}
+
+#[test]
+fn test_pubsub() {
+    struct Events(Mutex<Vec<(String, u32)>>);
+    impl Service for Events {
+        fn handle(&self, _ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+            if !ret.is_valid() {
+                let topic = arg.method.to_str()?.to_string();
+                self.0.lock().unwrap().push((topic, arg.into()?));
+            }
+            Ok(())
+        }
+    }
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let events = Arc::new(Events(Mutex::new(Vec::new())));
+    let client = Arc::new(Session::new(b, events.clone()));
+    let (server2, client2) = (server.clone(), client.clone());
+    std::thread::spawn(move || server2.loop_handle());
+    std::thread::spawn(move || client2.loop_handle());
+
+    let broker = Broker::new();
+    broker.attach(&server);
+    assert_eq!(broker.publish("ticks", 0u32), 0);
+    client.subscribe("ticks").unwrap();
+    client.subscribe("alerts").unwrap();
+    assert!(server.subscribed("ticks"));
+    assert_eq!(broker.publish("ticks", 1u32), 1);
+    assert_eq!(broker.publish("alerts", 2u32), 1);
+    client.unsubscribe("ticks").unwrap();
+    assert_eq!(broker.publish("ticks", 3u32), 0);
+    // Delivered in order, a roundtrip makes sure the notifies are handled
+    client.subscribe("ticks").unwrap();
+    assert_eq!(*events.0.lock().unwrap(), vec![("ticks".to_string(), 1), ("alerts".to_string(), 2)]);
+
+    server.set_max_subscriptions(Some(2));
+    match client.request("$subscribe", "news") {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::LIMIT_EXCEEDED),
+        r => panic!("{:?}", r),
+    }
+    assert!(!server.subscribed("news"));
+    client.subscribe("ticks").unwrap();
+    client.unsubscribe("alerts").unwrap();
+    client.subscribe("news").unwrap();
+}
+
+#[test]
+fn test_pubsub_history() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let client = Arc::new(Session::new(b, Arc::new(router::Router::new().on_notify("ticks", move |_, n: u32| log.lock().unwrap().push(n)))));
+    let (server2, client2) = (server.clone(), client.clone());
+    std::thread::spawn(move || server2.loop_handle());
+    std::thread::spawn(move || client2.loop_handle());
+
+    let broker = Broker::new();
+    broker.attach(&server);
+    // Without a history there's nothing to replay
+    assert!(client.subscribe_since("ticks", Since::Sequence(0)).is_err());
+    assert!(!server.subscribed("ticks"));
+
+    broker.set_history(Some(Arc::new(MemoryHistory::new(3))));
+    let before = SystemTime::now();
+    for n in 1..=4u32 { broker.publish("ticks", n); }
+    assert_eq!(broker.sequence("ticks"), 4);
+    // The oldest event is forgotten, the replayed ones come before the answer
+    assert_eq!(client.subscribe_since("ticks", Since::Sequence(0)), Ok(4));
+    assert_eq!(*received.lock().unwrap(), [2, 3, 4]);
+    assert_eq!(broker.publish("ticks", 5u32), 1);
+    client.subscribe("ticks").unwrap();
+    assert_eq!(*received.lock().unwrap(), [2, 3, 4, 5]);
+
+    received.lock().unwrap().clear();
+    assert_eq!(client.subscribe_since("ticks", Since::Sequence(3)), Ok(5));
+    assert_eq!(*received.lock().unwrap(), [4, 5]);
+    received.lock().unwrap().clear();
+    assert_eq!(client.subscribe_since("ticks", Since::Time(before)), Ok(5));
+    assert_eq!(*received.lock().unwrap(), [3, 4, 5]);
+    received.lock().unwrap().clear();
+    assert_eq!(client.subscribe_since("ticks", Since::Time(SystemTime::now() + Duration::from_secs(60))), Ok(5));
+    assert!(received.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_broadcast() {
+    struct Count(Mutex<u32>);
+    impl Service for Count {
+        fn handle(&self, _ss: &Session, arg: Arg, _ret: Ret) -> Result<(), HandleError> {
+            *self.0.lock().unwrap() += arg.into::<u32>()?;
+            Ok(())
+        }
+    }
+
+    let sessions = Sessions::new();
+    let mut peers = Vec::new();
+    for i in 0..3 {
+        let (a, b) = pipe();
+        let server = Arc::new(Session::new(a, Arc::new(EmptyService)));
+        // One of them serializes in canonical form
+        server.set_canonical(i == 0);
+        let count = Arc::new(Count(Mutex::new(0)));
+        let client = Arc::new(Session::new(b, count.clone()));
+        let client2 = client.clone();
+        std::thread::spawn(move || client2.loop_handle());
+        sessions.add(&server);
+        peers.push((server, client, count));
+    }
+    assert_eq!(sessions.broadcast_notify("add", 2u32), 3);
+    let first = peers[0].0.clone();
+    assert_eq!(sessions.broadcast_notify_to("add", 5u32, |ss| !std::ptr::eq(ss, &*first)), 2);
+    drop(first);
+    // A dropped session is skipped
+    peers.remove(1);
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions.broadcast_notify("add", 1u32), 2);
+
+    std::thread::sleep(Duration::from_millis(100));
+    let counts = peers.iter().map(|p| *(p.2).0.lock().unwrap()).collect::<Vec<_>>();
+    assert_eq!(counts, vec![3, 8]);
+}
+
+#[test]
+fn test_sessions_pruned() {
+    let sessions = Sessions::new();
+    let (a, _b) = pipe();
+    let kept = Arc::new(Session::new(a, Arc::new(EmptyService)));
+    sessions.add(&kept);
+    // Like a server accepting sessions which disconnect and are dropped
+    for _ in 0..1000 {
+        let (a, _b) = pipe();
+        sessions.add(&Arc::new(Session::new(a, Arc::new(EmptyService))));
+    }
+    assert!(sessions.tracked() <= 2);
+    assert_eq!(sessions.len(), 1);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json() {
+    use easy_rpc::json::{to_json, from_json};
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    assert_eq!(client.request_json(ECHO, "7").unwrap(), serde_json::json!(7));
+    // The binary result becomes an array of bytes
+    assert_eq!(client.request_json(ECHO_BIGDATA, "[1, 2, 255]").unwrap(), serde_json::json!([1, 2, 255]));
+    assert!(client.request_json(ECHO, "\"seven\"").is_err());
+    assert!(client.notify_json(ECHO, "{").is_err());
+
+    let doc = serde_json::json!({"name": "a", "values": [1, -2, 0.5, null, true]});
+    assert_eq!(to_json(&from_json(&doc)), doc);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json_format() {
+    use easy_rpc::codec::Json;
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    server.set_codec(Json);
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+    client.set_codec(Json);
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let frames2 = frames.clone();
+    client.set_packet_tap(Some(Arc::new(move |_, frame: &[u8]| frames2.lock().unwrap().push(frame.to_vec()))));
+
+    assert_eq!(client.request(ECHO, 7).into::<u32>().unwrap(), 7);
+    // The values are JSON text in the extension 0x4a
+    {
+        let frames = frames.lock().unwrap();
+        assert!(frames[0].ends_with(&[0xd4, 0x4a, b'7']) && frames[1].ends_with(&[0xd4, 0x4a, b'7']));
+    }
+
+    // The decode limits apply to the values once converted
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    server.set_codec(Json);
+    server.set_decode_limits(Some(DecodeLimits { max_array_len: 4, ..DecodeLimits::default() }));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+    client.set_codec(Json);
+    assert!(client.request(ECHO_BIGDATA, vec![0u8; 8]).into::<Vec<u8>>().is_err());
+    assert_eq!(client.request(ECHO_BIGDATA, vec![0u8; 4]).into::<Vec<u8>>().unwrap(), [0; 4]);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json_adaptor() {
+    use easy_rpc::json::JsonAdaptor;
+
+    let (a, b) = pipe();
+    let server = Session::new(JsonAdaptor::new(a), Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    // A peer writing JSON by hand
+    b.send(b"[0, 1, 3, 5]".to_vec());
+    let resp: serde_json::Value = serde_json::from_slice(&b.recv().unwrap()).unwrap();
+    assert_eq!(resp, serde_json::json!([1, 1, null, 5]));
+
+    let (a, b) = pipe();
+    let server = Session::new(JsonAdaptor::new(a), Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(JsonAdaptor::new(b), Arc::new(ClientService)));
+    let client2 = client.clone();
+    std::thread::spawn(move || client2.loop_handle());
+    session_test(&client);
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_cbor() {
+    use easy_rpc::cbor::{CborAdaptor, from_cbor, to_cbor};
+    use easy_rpc::codec::Cbor;
+
+    // The values without a form in the other are refused instead of changed
+    assert!(to_cbor(&rmpv::Value::Ext(1, vec![0])).is_err());
+    assert!(to_cbor(&rmpv::decode::read_value(&mut &[0xa1, 0xff][..]).unwrap()).is_err());
+    assert!(from_cbor(&serde_cbor::Value::Integer(-(1i128 << 64))).is_err());
+    assert_eq!(from_cbor(&serde_cbor::Value::Integer(-(1i128 << 63))).unwrap(), rmpv::Value::from(i64::min_value()));
+    let (a, _b) = pipe();
+    assert!(!CborAdaptor::new(a).send(vec![0x91, 0xd4, 0x01, 0x00]));
+
+    let (a, b) = pipe();
+    let server = Session::new(CborAdaptor::new(a), Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    // A peer writing CBOR: [0, 1, 3, 5]
+    b.send(vec![0x84, 0x00, 0x01, 0x03, 0x05]);
+    assert_eq!(b.recv().unwrap(), vec![0x84, 0x01, 0x01, 0xf6, 0x05]);
+
+    // Binaries stay byte strings
+    let (a, b) = pipe();
+    let router = router::Router::new().on("bytes", |_, data: ByteBuf| Ok(data));
+    let server = Session::new(CborAdaptor::new(a), Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    b.send(vec![0x84, 0x00, 0x02, 0x65, b'b', b'y', b't', b'e', b's', 0x42, 0xca, 0xfe]);
+    assert_eq!(b.recv().unwrap(), vec![0x84, 0x01, 0x02, 0xf6, 0x42, 0xca, 0xfe]);
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    server.set_codec(Cbor);
+    let server2 = server.clone();
+    std::thread::spawn(move || server2.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+    client.set_codec(Cbor);
+    assert_eq!(client.request(ECHO, 300).into::<u32>().unwrap(), 300);
+    // A session accepts msgpack and the values of its own codec only
+    server.set_codec(easy_rpc::codec::Msgpack);
+    match client.request(ECHO, 300) {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn test_bincode() {
+    let router = router::Router::new().on("sample", |_, (id, values): (u64, Vec<f32>)| Ok((id + 1, values)))
+        .on("checked", |_, n: u32| Ok(n))
+        .schema("checked", easy_rpc::schema::Schema::INT);
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    let server2 = server.clone();
+    std::thread::spawn(move || server2.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let client2 = client.clone();
+    std::thread::spawn(move || client2.loop_handle());
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let frames2 = frames.clone();
+    client.set_packet_tap(Some(Arc::new(move |_, frame: &[u8]| frames2.lock().unwrap().push(frame.to_vec()))));
+
+    assert!(client.negotiate_bincode());
+    frames.lock().unwrap().clear();
+    let sample: (u64, Vec<f32>) = client.request("sample", (1u64, vec![0.5f32])).into().unwrap();
+    assert_eq!(sample, (2, vec![0.5]));
+    // The request and its response carry bincode in the extension 0x42
+    let seen = std::mem::replace(&mut *frames.lock().unwrap(), Vec::new());
+    assert!(seen.len() == 2 && seen.iter().all(|f| f.windows(3).any(|w| w == [0xc7, 20, 0x42])));
+    // The bincode arguments can't be checked against a schema
+    match client.request("checked", 1u32) {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+
+    // The session's own methods still work
+    let accepted = std::thread::spawn(move || server.accept_channel::<u32, u32>("numbers").unwrap());
+    let channel = client.open_channel::<u32, u32>("numbers").unwrap();
+    channel.send(&7).unwrap();
+    assert_eq!(accepted.join().unwrap().recv().unwrap(), 7);
+
+    // Bincode values can't be checked against decode limits, a session with some doesn't switch
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    server.set_decode_limits(Some(DecodeLimits::default()));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+    assert!(!client.negotiate_bincode());
+    // Nor does it accept bincode values it didn't negotiate
+    match unsafe { client.request_transfer(ECHO, &[0xd4, 0x42, 0x05]) } {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[cfg(feature = "protobuf")]
+#[test]
+fn test_protobuf() {
+    use easy_rpc::proto::Proto;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Query {
+        #[prost(string, tag = "1")]
+        text: String,
+        #[prost(uint32, tag = "2")]
+        limit: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Reply {
+        #[prost(string, repeated, tag = "1")]
+        results: Vec<String>,
+    }
+
+    struct SearchService;
+    easy_service! {
+        SearchService(self, _ss, arg, ret)
+
+        StringMethod {
+            "search" => (query: Proto<Query>) {
+                let query = query.into_inner();
+                Proto(Reply { results: (0..query.limit).map(|i| format!("{} {}", query.text, i)).collect() })
+            }
+        }
+    }
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(SearchService));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let reply: Proto<Reply> = client.request("search", Proto(Query { text: "rust".into(), limit: 2 })).into().unwrap();
+    assert_eq!(reply.0.results, vec!["rust 0", "rust 1"]);
+    // Not a protobuf binary
+    assert!(client.request("search", 1u32).into::<Proto<Reply>>().is_err());
+}
+
+#[test]
+fn test_worker_pool() {
+    struct SlowService;
+    easy_service! {
+        SlowService(self, _ss, arg, ret)
+
+        StringMethod {
+            "sleep" => (ms: u64) {
+                std::thread::sleep(Duration::from_millis(ms));
+                ms
+            }
+        }
+    }
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(SlowService)));
+    server.set_worker_pool(Some(WorkerPool::new(4)));
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let client2 = client.clone();
+    std::thread::spawn(move || server.loop_handle());
+    std::thread::spawn(move || client2.loop_handle());
+
+    // Handled at the same time, a quick request isn't stuck behind the slow ones
+    let start = std::time::Instant::now();
+    let slow = (0..3).map(|_| {
+        let client = client.clone();
+        std::thread::spawn(move || client.request("sleep", 300u64).into::<u64>().unwrap())
+    }).collect::<Vec<_>>();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(client.request("sleep", 0u64).into::<u64>().unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_millis(250));
+    for t in slow { assert_eq!(t.join().unwrap(), 300); }
+    assert!(start.elapsed() < Duration::from_millis(600));
+}
+
+#[test]
+fn test_middleware() {
+    use easy_rpc::middleware::{Stack, Layer, Next};
+
+    struct Log(Arc<Mutex<Vec<u32>>>);
+    impl Layer for Log {
+        fn handle(&self, ss: &Session, arg: Arg, ret: Ret, next: Next) -> Result<(), HandleError> {
+            self.0.lock().unwrap().push(arg.method.to_int()?);
+            next.run(ss, arg, ret)
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let service = Stack::new(Arc::new(ServerService))
+        .layer(Log(log.clone()))
+        .layer(|ss: &Session, arg: Arg, ret: Ret, next: Next| -> Result<(), HandleError> {
+            match arg.method.to_int()? {
+                RECURSIVE_ADD => { ret.error("Forbidden"); Ok(()) }
+                // Rewrite the argument
+                ECHO => {
+                    let val: u32 = rmp_serde::from_read_ref(arg.bytes)?;
+                    let bytes = rmp_serde::to_vec(&(val * 10))?;
+                    next.run(ss, Arg { bytes: &bytes, ..arg }, ret)
+                }
+                _ => next.run(ss, arg, ret),
+            }
+        });
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(service));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+
+    assert_eq!(client.request(ECHO, 4).into::<u32>().unwrap(), 40);
+    match client.request(RECURSIVE_ADD, 0) { RequestResult::Error(e) => assert_eq!(e.message, "Forbidden"), r => panic!("{:?}", r) }
+    assert_eq!(*log.lock().unwrap(), vec![ECHO, RECURSIVE_ADD]);
+}
+
+#[test]
+fn test_ndarray() {
+    use easy_rpc::array::NdArray;
+
+    struct Transpose;
+    easy_service! {
+        Transpose(self, _ss, arg, ret)
+
+        StringMethod {
+            "transpose" => (m: NdArray) {
+                let v = m.to_vec::<f32>().ok_or("not f32")?;
+                let (rows, cols) = (m.shape[0], m.shape[1]);
+                let t = (0..rows * cols).map(|i| v[(i % rows) * cols + i / rows]).collect::<Vec<_>>();
+                NdArray::new(vec![cols, rows], &t)
+            }
+        }
+    }
+
+    let m = NdArray::new(vec![2, 3], &[1f32, 2., 3., 4., 5., 6.]).unwrap();
+    assert!(NdArray::new(vec![2, 2], &[1f32]).is_none());
+    // The layout msgpack-numpy decodes
+    let value = rmpv::decode::read_value(&mut &rmp_serde::to_vec(&m).unwrap()[..]).unwrap();
+    let map = value.as_map().unwrap();
+    assert_eq!(map[0].0.as_slice(), Some(&b"nd"[..]));
+    assert_eq!(map[1].1.as_str(), Some("<f4"));
+    assert_eq!(map[4].1.as_slice().map(<[u8]>::len), Some(24));
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(Transpose));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    let t: Option<NdArray> = client.request("transpose", &m).into().unwrap();
+    let t = t.unwrap();
+    assert_eq!(t.shape, vec![3, 2]);
+    assert_eq!(t.to_vec::<f32>().unwrap(), vec![1., 4., 2., 5., 3., 6.]);
+    assert!(t.to_vec::<f64>().is_none());
+
+    #[cfg(feature = "ndarray")]
+    {
+        let a = ndarray::arr2(&[[1i32, 2], [3, 4]]);
+        let back = NdArray::from(&a).to_ndarray::<i32>().unwrap();
+        assert_eq!(back, a.into_dyn());
+    }
+}
+
+#[test]
+fn test_metrics() {
+    #[derive(Default)]
+    struct Counters {
+        sent: Mutex<Vec<(u32, bool)>>,
+        handled: Mutex<Vec<(u32, bool)>>,
+        in_flight: Mutex<Vec<usize>>,
+        bytes: Mutex<(usize, usize)>,
+    }
+    impl MetricsSink for Counters {
+        fn request_finished(&self, method: Method, _elapsed: Duration, result: &RequestResult) {
+            let ok = if let RequestResult::Data(_) = result { true } else { false };
+            self.sent.lock().unwrap().push((method.to_int().unwrap_or(0), ok));
+        }
+        fn request_handled(&self, method: Method, _elapsed: Duration, failed: bool) {
+            self.handled.lock().unwrap().push((method.to_int().unwrap_or(0), failed));
+        }
+        fn in_flight(&self, count: usize) {
+            self.in_flight.lock().unwrap().push(count);
+        }
+        fn bytes_sent(&self, len: usize) { self.bytes.lock().unwrap().0 += len; }
+        fn bytes_received(&self, len: usize) { self.bytes.lock().unwrap().1 += len; }
+    }
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    let server_metrics = Arc::new(Counters::default());
+    server.set_metrics(Some(server_metrics.clone()));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+    let client_metrics = Arc::new(Counters::default());
+    client.set_metrics(Some(client_metrics.clone()));
+
+    assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+    // Malformed argument
+    assert!(client.request(ECHO, "one").into::<u32>().is_err());
+    assert_eq!(*client_metrics.sent.lock().unwrap(), vec![(ECHO, true), (ECHO, false)]);
+    assert_eq!(*server_metrics.handled.lock().unwrap(), vec![(ECHO, false), (ECHO, true)]);
+    assert_eq!(*client_metrics.in_flight.lock().unwrap(), vec![1, 0, 1, 0]);
+    let (sent, received) = *client_metrics.bytes.lock().unwrap();
+    let (server_sent, server_received) = *server_metrics.bytes.lock().unwrap();
+    assert_eq!((sent, received), (server_received, server_sent));
+    assert!(sent > 0 && received > 0);
+}
+
+#[test]
+fn test_packet_tap() {
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let tapped = frames.clone();
+    client.set_packet_tap(Some(Arc::new(move |direction: Direction, frame: &[u8]| {
+        tapped.lock().unwrap().push((direction, frame.to_vec()));
+    })));
+
+    assert_eq!(client.request(ECHO, 7).into::<u32>().unwrap(), 7);
+    client.set_packet_tap(None);
+    client.request(ECHO, 8);
+    let frames = frames.lock().unwrap();
+    assert_eq!(frames.len(), 2);
+    // [REQUEST, 1, ECHO, 7] and [RESPONSE, 1, nil, 7]
+    assert_eq!(frames[0], (Direction::Sent, vec![0x94, 0, 1, 3, 7]));
+    assert_eq!(frames[1], (Direction::Received, vec![0x94, 1, 1, 0xc0, 7]));
+}
+
+#[test]
+fn test_golden() {
+    use easy_rpc::golden::Golden;
+    use easy_rpc::schema::Schema;
+
+    let path = std::env::temp_dir().join(format!("easy-rpc-golden-{}.txt", std::process::id()));
+    let golden = |y: i32| Golden::new(&path).case("add", "add", (1, 2)).case("point", ECHO, (1, y));
+    // Written the first time, then compared
+    golden(2).check().unwrap();
+    assert!(std::fs::read_to_string(&path).unwrap().starts_with("add 94000"));
+    golden(2).check().unwrap();
+    let err = golden(300).check().unwrap_err();
+    assert!(err.contains("point changed") && !err.contains("add"));
+    assert!(Golden::new(&path).case("add", "add", (1, 2)).check().unwrap_err().contains("point was removed"));
+    std::fs::remove_file(&path).unwrap();
+
+    // A contract of the methods with a schema, with the answers of the service
+    let router = router::Router::new()
+        .on("add", |_, (a, b): (i32, i32)| Ok(a + b))
+        .on("untyped", |_, _: ()| Ok(()))
+        .schema("add", Schema::Tuple(vec![Schema::Int { min: Some(1), max: None }, Schema::INT]));
+    Golden::new(&path).contract(Arc::new(router)).check().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "add.request 940001a3616464920100\nadd.response 940101c001\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+rpc_interface! {
+    trait Calculator {
+        fn add(a: u32, b: u32) -> u32;
+        fn negate(a: i32) -> i32;
+        fn zero() -> u32;
+        fn sqrt(a: f64) -> f64;
+        fn print(msg: String);
+    }
+}
+
+struct CalculatorService(Mutex<Vec<String>>);
+
+easy_service! {
+    CalculatorService(self, _ss, arg, ret)
+
+    StringMethod {
+        "add" => (a: u32, b: u32) { a + b }
+        "negate" => (a: i32) { -a }
+        "zero" => () { 0u32 }
+        "print" => (msg: String) { self.0.lock().unwrap().push(msg) }
+    }
+}
+
+#[test]
+fn test_rpc_interface() {
+    let (a, b) = pipe();
+    let service = Arc::new(CalculatorService(Mutex::new(Vec::new())));
+    let server = Session::new(a, service.clone());
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let calculator = Calculator::new(&client);
+    assert_eq!(calculator.add(1, 2).unwrap(), 3);
+    assert_eq!(calculator.negate(4).unwrap(), -4);
+    assert_eq!(calculator.zero().unwrap(), 0);
+    match calculator.sqrt(4.0) { Err(RequestError::Remote(e)) => assert_eq!(e.code, RemoteError::METHOD_NOT_FOUND), r => panic!("{:?}", r) }
+    assert!(calculator.print("hello".into()));
+    // The notify was handled once a later request is answered
+    assert_eq!(calculator.zero().unwrap(), 0);
+    assert_eq!(*service.0.lock().unwrap(), vec!["hello".to_string()]);
+}
+
+#[test]
+fn test_rpc_interface_mock() {
+    fn sum_and_report(calculator: &impl CalculatorClient, values: &[u32]) -> u32 {
+        let sum = values.iter().fold(calculator.zero().unwrap(), |acc, &v| calculator.add(acc, v).unwrap());
+        calculator.print(format!("sum {}", sum));
+        sum
+    }
+
+    let mock = MockCalculatorClient::new();
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let printed2 = printed.clone();
+    mock.expect_zero(|| Ok(0))
+        .expect_add(|a, b| Ok(a + b))
+        .expect_print(move |msg| { printed2.lock().unwrap().push(msg); true });
+    assert_eq!(sum_and_report(&mock, &[1, 2, 3]), 6);
+    assert_eq!(mock.calls(), ["zero", "add", "add", "add", "print"]);
+    assert_eq!(*printed.lock().unwrap(), ["sum 6"]);
+
+    // The real client is usable in place of the mock
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(CalculatorService(Mutex::new(Vec::new()))));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert_eq!(sum_and_report(&Calculator::new(&client), &[4, 5]), 9);
+}
+
+#[cfg(feature = "macros")]
+#[test]
+fn test_rpc_service() {
+    struct Calculator(Mutex<Vec<String>>);
+
+    #[rpc_service]
+    impl Calculator {
+        fn new() -> Self { Calculator(Mutex::new(Vec::new())) }
+
+        fn add(&self, a: u32, b: u32) -> u32 { a + b }
+
+        fn div(&self, a: u32, b: u32) -> Result<u32, HandleError> {
+            a.checked_div(b).ok_or_else(|| HandleError::new(ErrorKind::InvalidArgs, "division by zero"))
+        }
+
+        fn zero(&self) -> u32 { 0 }
+
+        fn print(&self, msg: String) { self.0.lock().unwrap().push(msg) }
+
+        fn peer_echo(&self, ss: &Session, val: u32) -> Result<u32, RequestResult> { ss.request(ECHO, val).into() }
+    }
+
+    use easy_rpc::introspect::MethodInfo;
+    let methods = Calculator::new().methods();
+    assert_eq!(methods[0], MethodInfo::new("add", "(u32, u32)", Some("u32")));
+    assert_eq!(methods[1], MethodInfo::new("div", "(u32, u32)", Some("u32")));
+    assert_eq!(methods[3], MethodInfo::new("print", "String", Some("()")));
+    assert_eq!(methods.len(), 5);
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(Calculator::new()));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(ServerService)));
+    let client2 = client.clone();
+    std::thread::spawn(move || client2.loop_handle());
+
+    assert_eq!(client.request("add", (1, 2)).into::<u32>().unwrap(), 3);
+    assert_eq!(client.request(method_id!("add"), (1, 2)).into::<u32>().unwrap(), 3);
+    assert_eq!(client.request("zero", ()).into::<u32>().unwrap(), 0);
+    assert_eq!(client.request("peer_echo", 5).into::<u32>().unwrap(), 5);
+    assert!(client.request("print", "hi").into::<()>().is_ok());
+    match client.request("div", (1, 0)) {
+        RequestResult::Error(e) => assert_eq!((e.code, e.message.as_str()), (RemoteError::INVALID_ARGS, "division by zero")),
+        r => panic!("{:?}", r),
+    }
+    assert_eq!((client.supports("add"), client.supports("new")), (Some(true), Some(false)));
+    for method in &["new", "missing"] {
+        match client.request(*method, ()) {
+            RequestResult::Error(e) => assert_eq!(e.code, RemoteError::METHOD_NOT_FOUND),
+            r => panic!("{:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_router() {
+    use easy_rpc::router::Router;
+
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let printed2 = printed.clone();
+    let router = Router::new()
+        .on("add", |_, (a, b): (u32, u32)| Ok(a + b))
+        .on("echo", |ss, val: u32| ss.request(ECHO, val).into::<u32>().map_err(HandleError::from))
+        .on_notify("print", move |_, msg: String| printed2.lock().unwrap().push(msg))
+        .fallback(|_, arg, ret| { ret.ok(format!("fallback {}", arg.method.to_int()?)); Ok(()) });
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(ServerService)));
+    let client2 = client.clone();
+    std::thread::spawn(move || client2.loop_handle());
+
+    assert_eq!(client.request("add", (1, 2)).into::<u32>().unwrap(), 3);
+    assert_eq!(client.request("echo", 7).into::<u32>().unwrap(), 7);
+    assert!(client.notify("print", "one"));
+    assert!(client.request("print", "two").into::<()>().is_ok());
+    assert_eq!(*printed.lock().unwrap(), ["one", "two"]);
+    assert_eq!(client.request(9, ()).into::<String>().unwrap(), "fallback 9");
+    match client.request("add", "bad") {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn test_events() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    server.set_authenticator(Some(Arc::new(TestAuthenticator)));
+    let server_events = server.events();
+    let server2 = server.clone();
+    let handle = std::thread::spawn(move || server2.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    let client_events = client.events();
+
+    assert_eq!(server_events.recv().unwrap(), SessionEvent::Connected);
+    client.authenticate(&Credentials::Token("secret".into())).unwrap();
+    match server_events.recv().unwrap() {
+        SessionEvent::Authenticated(identity) => assert_eq!(identity.name, "alice"),
+        e => panic!("{:?}", e),
+    }
+    assert!(server.handle_packet(vec![0xc1]).is_err());
+    match server_events.recv().unwrap() {
+        SessionEvent::ProtocolWarning(ProtocolError::Malformed(_)) => {}
+        e => panic!("{:?}", e),
+    }
+
+    assert!(client.request("missing", ()).into::<()>().is_err());
+    match client_events.try_recv().unwrap() {
+        SessionEvent::RequestFailed { method, error } => assert_eq!((method.as_str(), error.message.as_str()), ("missing", "Unhandled Method")),
+        e => panic!("{:?}", e),
+    }
+
+    drop(client);
+    handle.join().unwrap();
+    assert_eq!(server_events.recv().unwrap(), SessionEvent::Disconnected(None));
+}
+
+#[test]
+fn test_observer() {
+    /// Record the callbacks, with the state of the session they see
+    struct Log(Mutex<Vec<String>>);
+
+    impl SessionObserver for Log {
+        fn on_connect(&self, ss: &Session) { self.0.lock().unwrap().push(format!("connect {}", ss.subscribed("news"))); }
+
+        fn on_disconnect(&self, ss: &Session, error: Option<TransportError>) {
+            self.0.lock().unwrap().push(format!("disconnect {:?} {}", error, ss.subscribed("news")));
+        }
+
+        fn on_error(&self, _ss: &Session, error: &ProtocolError) { self.0.lock().unwrap().push(format!("error {}", error)); }
+    }
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let log = Arc::new(Log(Mutex::new(Vec::new())));
+    server.set_observer(Some(log.clone()));
+    let server2 = server.clone();
+    let handle = std::thread::spawn(move || server2.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    client.subscribe("news").unwrap();
+    assert!(server.subscribed("news"));
+    assert!(server.handle_packet(vec![0xc1]).is_err());
+
+    drop(client);
+    handle.join().unwrap();
+    let log = log.0.lock().unwrap();
+    assert_eq!(log.len(), 3);
+    assert_eq!((log[0].as_str(), log[2].as_str()), ("connect false", "disconnect None false"));
+    assert!(log[1].starts_with("error "));
+}
+
+#[test]
+fn test_supports() {
+    use easy_rpc::router::Router;
+
+    let (a, b) = pipe();
+    let router = Router::new().on("add", |_, (a, b): (u32, u32)| Ok(a + b));
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert_eq!(client.supports("add"), Some(true));
+    assert_eq!(client.supports("sub"), Some(false));
+    assert_eq!(client.supports(1), Some(false));
+    assert_eq!(client.call_or("add", (1, 2), 0).unwrap(), 3);
+    assert_eq!(client.call_or("sub", (1, 2), 0).unwrap(), 0);
+
+    // A service which can't tell, the answer is learnt from the first call
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert_eq!(client.supports(ECHO), None);
+    assert_eq!(client.call_or(ECHO, 7, 0).unwrap(), 7);
+    assert_eq!(client.call_or(100, 7, 0).unwrap(), 0);
+    assert_eq!(client.supports(100), Some(false));
+}
+
+#[test]
+fn test_namespace() {
+    use easy_rpc::namespace::Composite;
+    use easy_rpc::router::Router;
+
+    let fs = Router::new().on("read", |_, path: String| Ok(format!("content of {}", path)));
+    let kv = Router::new().on("get", |_, key: String| Ok(key.len() as u32));
+    let composite = Composite::new()
+        .mount("fs", fs)
+        .mount("db", Composite::new().mount("kv", kv))
+        .fallback(ServerService);
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(composite));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    assert_eq!(client.request("fs.read", "a.txt").into::<String>().unwrap(), "content of a.txt");
+    assert_eq!(client.request("db.kv.get", "key").into::<u32>().unwrap(), 3);
+    assert_eq!(client.request(ECHO, 5).into::<u32>().unwrap(), 5);
+    for method in &["fs.write", "read", "net.get"] {
+        match client.request(*method, "x") {
+            RequestResult::Error(e) => assert_eq!(e.code, RemoteError::METHOD_NOT_FOUND, "{}", method),
+            r => panic!("{:?}", r),
+        }
+    }
+    assert_eq!((client.supports("fs.read"), client.supports("fs.write"), client.supports("db.kv.get")), (Some(true), Some(false), Some(true)));
+}
+
+#[test]
+fn test_introspect() {
+    use std::collections::HashMap;
+    use easy_rpc::introspect::{Introspect, MethodInfo};
+    use easy_rpc::namespace::Composite;
+    use easy_rpc::router::Router;
+
+    let math = Router::new()
+        .on("add", |_, (a, b): (u32, u32)| Ok(a + b))
+        .on_notify("log", |_, _: String| {});
+    let service = Introspect::new(Composite::new().mount("math", math));
+    assert_eq!(service.methods()[1], MethodInfo::new("math.log", std::any::type_name::<String>(), None));
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(service));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let names: Vec<String> = client.request("rpc.list_methods", ()).into().unwrap();
+    assert_eq!(names, ["math.add", "math.log", "rpc.list_methods", "rpc.describe"]);
+    let info: HashMap<String, Option<String>> = client.request("rpc.describe", "math.add").into().unwrap();
+    assert_eq!(info["params"].as_deref(), Some("(u32, u32)"));
+    assert_eq!(info["returns"].as_deref(), Some("u32"));
+    assert!(client.request("rpc.describe", "math.sub").into::<Option<()>>().unwrap().is_none());
+    assert_eq!(client.supports("rpc.describe"), Some(true));
+}
+
+#[test]
+fn test_notify_many() {
+    use easy_rpc::router::Router;
+
+    let single = Arc::new(Mutex::new(Vec::new()));
+    let packed = Arc::new(Mutex::new(Vec::new()));
+    let (single2, packed2) = (single.clone(), packed.clone());
+    let router = Router::new()
+        .on_notify("sample", move |_, v: u32| single2.lock().unwrap().push(v))
+        .on_notify("samples", move |_, v: Vec<u32>| packed2.lock().unwrap().push(v))
+        .on("sync", |_, ()| Ok(()));
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    assert_eq!(client.notify_many("sample", 0..5u32).unwrap(), 5);
+    // Each u32 below 128 is a single byte
+    assert_eq!(client.notify_many_packed("samples", 0..10u32, 4).unwrap(), 10);
+    assert_eq!(client.notify_many_packed("samples", vec![1000u32], 1).unwrap(), 1);
+    client.request("sync", ()).into::<()>().unwrap();
+    assert_eq!(*single.lock().unwrap(), [0, 1, 2, 3, 4]);
+    assert_eq!(*packed.lock().unwrap(), [vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9], vec![1000]]);
+}
+
+#[test]
+fn test_codec() {
+    use easy_rpc::codec::{self, Codec, Encode, Msgpack, Serde};
+
+    // msgpack with its bytes reversed, in the extension 0x20
+    struct Reversed;
+    impl Codec for Reversed {
+        fn tag(&self) -> Option<i8> { Some(0x20) }
+
+        fn encode(&self, val: &dyn Encode, w: &mut Vec<u8>) -> Result<(), String> {
+            let mut msgpack = Vec::new();
+            val.msgpack(&mut msgpack);
+            w.extend(msgpack.iter().rev());
+            Ok(())
+        }
+
+        fn to_msgpack(&self, data: &[u8]) -> Result<Vec<u8>, String> { Ok(data.iter().rev().copied().collect()) }
+    }
+
+    let mut buf = Vec::new();
+    codec::append(&Msgpack, &Serde::new(&(1u32, "a")), &mut buf).unwrap();
+    assert_eq!(buf, [0x92, 0x01, 0xa1, b'a']);
+    buf.clear();
+    codec::append(&Reversed, &Serde::new(&(1u32, "a")), &mut buf).unwrap();
+    assert_eq!(buf, [0xd6, 0x20, b'a', 0xa1, 0x01, 0x92]);
+
+    let router = router::Router::new()
+        .on("echo", |_, n: u32| Ok(n))
+        .on("fail", |_, n: u32| Err::<(), _>(HandleError::new(ErrorKind::Application, "failed").with_data(n)));
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    server.set_codec(Reversed);
+    server.set_worker_pool(Some(WorkerPool::new(2)));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    client.set_codec(Reversed);
+    let client2 = client.clone();
+    std::thread::spawn(move || client2.loop_handle());
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let frames2 = frames.clone();
+    client.set_packet_tap(Some(Arc::new(move |_, frame: &[u8]| frames2.lock().unwrap().push(frame.to_vec()))));
+
+    assert_eq!(client.request("echo", 300).into::<u32>().unwrap(), 300);
+    match client.request("fail", 7) {
+        RequestResult::Error(e) => assert_eq!(e.data::<u32>().unwrap().unwrap(), 7),
+        r => panic!("{:?}", r),
+    }
+    // The arguments, the results and the data of the errors are all in the codec
+    let seen = std::mem::replace(&mut *frames.lock().unwrap(), Vec::new());
+    assert!(seen[0].ends_with(&[0xc7, 3, 0x20, 0x2c, 0x01, 0xcd]) && seen[1].ends_with(&[0xc7, 3, 0x20, 0x2c, 0x01, 0xcd]));
+    assert!(seen[2].ends_with(&[0xd4, 0x20, 0x07]) && seen[3].ends_with(&[0xd4, 0x20, 0x07, 0xc0]));
+    // msgpack is read all the same
+    assert_eq!(unsafe { client.request_transfer("echo", &[0x05]) }.into::<u32>().unwrap(), 5);
+
+    // The values of another codec are refused, whichever this side is built with
+    match unsafe { client.request_transfer("echo", &[0xd4, 0x43, 0x05]) } {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn test_shards() {
+    let shards = Shards::new(3, 1);
+    let mut peers = Vec::new();
+    for _ in 0..8 {
+        let (a, b) = pipe();
+        let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+        let index = shards.assign(&server).index();
+        assert!(shards.get(index).unwrap().sessions().live().iter().any(|ss| Arc::ptr_eq(ss, &server)));
+        let server2 = server.clone();
+        std::thread::spawn(move || server2.loop_handle());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let client = Arc::new(Session::new(b, Arc::new(router::Router::new().on_notify("tick", move |_, n: u32| log.lock().unwrap().push(n)))));
+        let client2 = client.clone();
+        std::thread::spawn(move || client2.loop_handle());
+        // Handled on the workers of the shard
+        assert_eq!(client.request(ECHO, 7).into::<u32>().unwrap(), 7);
+        peers.push((server, client, received));
+    }
+    assert_eq!(shards.len(), 3);
+    assert_eq!(shards.session_count(), 8);
+    assert_eq!(shards.broadcast(|shard| shard.sessions().len()), 8);
+    assert_eq!(shards.broadcast_notify("tick", 1u32), 8);
+
+    // A shard can hand work to another one
+    let (sender, receiver) = channel();
+    shards.get(1).unwrap().post(move |shard| sender.send(shard.index()).unwrap());
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Ok(1));
+
+    std::thread::sleep(Duration::from_millis(100));
+    for (_, _, received) in &peers {
+        assert_eq!(*received.lock().unwrap(), vec![1]);
+    }
+}
+
+#[test]
+fn test_trace() {
+    use easy_rpc::trace;
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(ClientService));
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let frames2 = frames.clone();
+    client.set_packet_tap(Some(Arc::new(move |direction, frame: &[u8]| frames2.lock().unwrap().push((direction, frame.to_vec())))));
+    assert_eq!(client.request(ECHO, 5).into::<u32>().unwrap(), 5);
+
+    let text = trace::render(&frames.lock().unwrap());
+    assert_eq!(text, concat!(
+        ">> REQUEST id=1 method=3\n",
+        "   args: 5\n",
+        "   0000  94 00 01 03 05                                   .....\n",
+        "\n",
+        "<< RESPONSE id=1\n",
+        "   result: 5\n",
+        "   0000  94 01 01 c0 05                                   .....\n",
+    ));
+    assert!(trace::annotate(Direction::Received, &[0xc1]).starts_with("<< MALFORMED"));
+}
+
+#[test]
+fn test_request_context() {
+    use easy_rpc::context::{self, RequestContext};
+
+    let router = router::Router::new()
+        .on("where", |_, ()| {
+            let here = RequestContext::current().unwrap();
+            // Work spawned from the handler keeps the context, even through a middleware-like scope
+            let traced = here.clone().with_trace_id("t1").scope(|| context::spawn(RequestContext::current).join().unwrap());
+            Ok((here.request_id, here.method, traced.and_then(|c| c.trace_id)))
+        })
+        .on("pooled", |_, ()| {
+            let (sender, receiver) = channel();
+            let job = RequestContext::wrap(move || sender.send(RequestContext::current().map(|c| c.method)).unwrap());
+            std::thread::spawn(job);
+            Ok(receiver.recv().unwrap())
+        });
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let (id, method, trace): (Option<u64>, String, Option<String>) = client.request("where", ()).into().unwrap();
+    assert_eq!((id, method.as_str(), trace.as_deref()), (Some(1), "where", Some("t1")));
+    assert_eq!(client.request("pooled", ()).into::<Option<String>>().unwrap().as_deref(), Some("pooled"));
+    assert_eq!(RequestContext::current(), None);
+}
+
+#[test]
+fn test_ordered_responses() {
+    for &ordered in &[true, false] {
+        let (a, b) = pipe();
+        let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+        server.set_worker_pool(Some(WorkerPool::new(4)));
+        std::thread::spawn(move || server.loop_handle());
+        let client = Arc::new(Session::new(b, Arc::new(ClientService)));
+        client.set_ordered_responses(ordered);
+        let receiving = client.clone();
+        std::thread::spawn(move || receiving.loop_handle());
+
+        let threads = (0..8u32).map(|i| {
+            let client = client.clone();
+            std::thread::spawn(move || (0..50).all(|j| client.request(ECHO, i * 100 + j).into::<u32>().unwrap() == i * 100 + j))
+        }).collect::<Vec<_>>();
+        assert!(threads.into_iter().all(|t| t.join().unwrap()));
+        // The requests of a service waiting lower in the stack don't block the nested ones
+        assert_eq!(client.request(RECURSIVE_ADD, 0).into::<u32>().unwrap(), 2);
+    }
+}
+
+#[test]
+fn test_diagnostics() {
+    let (a, b) = pipe();
+    let client = Arc::new(Session::new(a, Arc::new(EmptyService)));
+    client.set_send_queue(8, Overflow::Block);
+    let report = client.diagnostics();
+    assert!(report.connected && report.receiver.is_none() && report.pending.is_empty());
+    assert_eq!((report.queued, report.since_sent), (Some([0, 0, 0]), None));
+
+    // A request the peer never answers
+    let requester = client.clone();
+    std::thread::Builder::new().name("requester".into()).spawn(move || requester.request("stuck", ())).unwrap();
+    b.recv().unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    let report = client.diagnostics();
+    assert_eq!(report.receiver.as_ref().map(|r| r.0.as_str()), Some("requester"));
+    assert_eq!(report.pending.len(), 1);
+    assert_eq!((report.pending[0].id, report.pending[0].method.as_str()), (1, "stuck"));
+    assert!(report.pending[0].elapsed >= Duration::from_millis(50));
+    assert!(report.since_sent.is_some() && report.since_received.is_none());
+
+    let encoded = rmpv::decode::read_value(&mut &rmp_serde::to_vec(&report).unwrap()[..]).unwrap();
+    let field = |map: &rmpv::Value, key| map.as_map().unwrap().iter().find(|(k, _)| k.as_str() == Some(key)).unwrap().1.clone();
+    assert_eq!(field(&field(&encoded, "pending")[0], "method").as_str(), Some("stuck"));
+}
+
+#[test]
+fn test_mock_clock() {
+    use easy_rpc::aggregate::*;
+
+    let clock = MockClock::new();
+    let (flushed, batches) = channel();
+    let flushed = Mutex::new(flushed);
+    let policy = BatchPolicy { max_len: 10, max_delay: Duration::from_secs(60) };
+    let service = Aggregator::new()
+        .batch("sample", policy, move |items: Vec<u32>| { flushed.lock().unwrap().send(items).unwrap(); })
+        .clock(clock.clone())
+        .start();
+    let (a, b) = pipe();
+    let server = Session::new(a, service);
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    client.set_clock(clock.clone());
+
+    client.request("sample", 1).into::<()>().unwrap();
+    // Only the time of the clock counts
+    assert!(batches.recv_timeout(Duration::from_millis(100)).is_err());
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(batches.recv_timeout(Duration::from_secs(1)).unwrap(), [1]);
+
+    let (c, d) = pipe();
+    let stuck = Arc::new(Session::new(c, Arc::new(EmptyService)));
+    stuck.set_clock(clock.clone());
+    let requester = stuck.clone();
+    std::thread::spawn(move || requester.request("stuck", ()));
+    d.recv().unwrap();
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(stuck.diagnostics().pending[0].elapsed, Duration::from_secs(5));
+    assert_eq!(clock.elapsed(), Duration::from_secs(65));
+}
+
+#[test]
+fn test_schema() {
+    use easy_rpc::schema::Schema;
+
+    let schema = Schema::record(vec![("name", Schema::STR), ("tags", Schema::array(Schema::STR)), ("limit", Schema::optional(Schema::Int { min: Some(1), max: Some(100) }))]);
+    let router = router::Router::new()
+        .on("search", |_, (name, tags, limit): (String, Vec<String>, Option<u32>)| Ok(format!("{} {} {:?}", name, tags.len(), limit)))
+        .schema("search", schema);
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    assert_eq!(client.request("search", ("x", ["a"], 5)).into::<String>().unwrap(), "x 1 Some(5)");
+    match client.request("search", ("x", ("a", 1), 5)) {
+        RequestResult::Error(e) => {
+            assert_eq!((e.code, e.message.as_str()), (RemoteError::INVALID_ARGS, "$.tags[1]: expected string"));
+            assert_eq!(e.data::<(String, String)>().unwrap().unwrap(), ("$.tags[1]".to_string(), "string".to_string()));
+        }
+        r => panic!("{:?}", r),
+    }
+    match client.request("search", ("x", ["a"], 500)) {
+        RequestResult::Error(e) => assert_eq!(e.message, "$.limit: expected integer in 1..=100"),
+        r => panic!("{:?}", r),
+    }
+    match client.request("search", ["x"]) {
+        RequestResult::Error(e) => assert_eq!(e.message, "$.tags: expected array of string"),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn test_schema_export() {
+    use easy_rpc::schema::{BreakingChange, Schema, SchemaExport};
+
+    let limit = Schema::Int { min: Some(1), max: Some(100) };
+    let v1 = router::Router::new()
+        .on("search", |_, (_, limit): (String, u32)| Ok(limit))
+        .on("count", |_, _: ()| Ok(0u32))
+        .on("legacy", |_, _: ()| Ok(()))
+        .schema("search", Schema::record(vec![("name", Schema::STR), ("limit", limit.clone())]));
+    let v1 = SchemaExport::of(&v1);
+    assert_eq!(SchemaExport::from_slice(&v1.to_vec()), Ok(v1.clone()));
+    assert!(v1.breaking_changes(&v1).is_empty());
+
+    // Widening the arguments is compatible
+    let v2 = router::Router::new()
+        .on("search", |_, (_, limit, _): (String, u32, Option<bool>)| Ok(limit))
+        .on("count", |_, _: ()| Ok(0u32))
+        .on("legacy", |_, _: ()| Ok(()))
+        .schema("search", Schema::record(vec![("name", Schema::STR), ("limit", Schema::INT), ("exact", Schema::optional(Schema::Bool))]));
+    assert!(v1.breaking_changes(&SchemaExport::of(&v2)).is_empty());
+
+    let v3 = router::Router::new()
+        .on("search", |_, (_, limit): (String, u32)| Ok(limit))
+        .on("count", |_, _: ()| Ok(0u64))
+        .schema("search", Schema::record(vec![("name", Schema::Str { max_len: Some(10) }), ("limit", limit)]));
+    let changes: Vec<_> = v1.breaking_changes(&SchemaExport::of(&v3)).iter().map(BreakingChange::to_string).collect();
+    assert_eq!(changes, vec![
+        "count: returns u64 instead of u32",
+        "legacy: removed",
+        "search: takes string of at most 10 bytes instead of string at $.name",
+    ]);
+    assert!(SchemaExport::from_slice(b"\x91\x80").is_err());
+}
+
+#[test]
+fn test_borrowed_args() {
+    struct Borrowing;
+    impl Service for Borrowing {
+        fn handle(&self, _ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+            let (name, data): (&str, &[u8]) = arg.borrow()?;
+            // Views into the received packet
+            let packet = arg.bytes.as_ptr() as usize..arg.bytes.as_ptr() as usize + arg.bytes.len();
+            let borrowed = packet.contains(&(name.as_ptr() as usize)) && packet.contains(&(data.as_ptr() as usize));
+            ret.ok((name.len(), data.len(), borrowed));
+            Ok(())
+        }
+    }
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(Borrowing));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    match client.request("sizes", ("hello", Bytes::new(&[1, 2, 3]))) {
+        RequestResult::Data(data) => assert_eq!(data.borrow::<(usize, usize, bool)>().unwrap(), (5, 3, true)),
+        r => panic!("{:?}", r),
+    }
+    match client.request(ECHO, 1) {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+}
+
+/// Adaptor copying the data out of the buffers of the session, recording the buffers it's given
+struct Copying {
+    pipe: Arc<Pipe>,
+    sent: Mutex<Vec<usize>>,
+    received: Mutex<Vec<usize>>,
+    vectored: Mutex<Vec<Vec<usize>>>,
+}
+
+impl Copying {
+    fn new(pipe: Arc<Pipe>) -> Arc<Copying> {
+        Arc::new(Copying { pipe, sent: Mutex::new(Vec::new()), received: Mutex::new(Vec::new()), vectored: Mutex::new(Vec::new()) })
+    }
+}
+
+impl Adaptor for Copying {
+    fn send(&self, data: Vec<u8>) -> bool { self.pipe.send(data) }
+
+    fn send_buf(&self, data: &mut Vec<u8>) -> bool {
+        self.sent.lock().unwrap().push(data.as_ptr() as usize);
+        self.pipe.send(data.clone())
+    }
+
+    fn send_vectored(&self, parts: &[std::io::IoSlice]) -> bool {
+        self.vectored.lock().unwrap().push(parts.iter().map(|part| part.as_ptr() as usize).collect());
+        self.pipe.send(parts.iter().flat_map(|part| part.iter().copied()).collect())
+    }
+
+    fn recv(&self) -> Result<Vec<u8>, RecvError> { self.pipe.recv() }
+
+    fn recv_buf(&self, buf: Vec<u8>) -> Result<Vec<u8>, RecvError> {
+        self.received.lock().unwrap().push(buf.capacity());
+        self.pipe.recv()
+    }
+
+    fn connected(&self) -> bool { true }
+
+    fn close(&self) {}
+}
+
+#[test]
+fn test_buffer_pool() {
+    let (a, b) = pipe();
+    let adaptor = Copying::new(a);
+    let session = Session::new(adaptor.clone(), Arc::new(ServerService));
+    let peer = Session::new(b, Arc::new(EmptyService));
+    for val in 0..3u32 { assert!(session.notify(ECHO, val)); }
+    for val in 0..3u32 {
+        assert!(peer.notify(ECHO, val));
+        let pack = session.recv_packet().unwrap().unwrap();
+        assert_eq!(session.handle_packet(pack), Ok(()));
+    }
+    // The buffer of a packet is reused for the next one
+    let sent = adaptor.sent.lock().unwrap();
+    assert_eq!(sent.len(), 3);
+    assert!(sent.iter().all(|&ptr| ptr == sent[0]));
+    let received = adaptor.received.lock().unwrap();
+    assert_eq!(received.len(), 3);
+    assert!(received.iter().all(|&capacity| capacity > 0));
+}
+
+#[test]
+fn test_vectored_send() {
+    let (a, b) = pipe();
+    let adaptor = Copying::new(a);
+    let session = Session::new(adaptor.clone(), Arc::new(EmptyService));
+    let peer = Session::new(b, Arc::new(ServerService));
+    std::thread::spawn(move || peer.loop_handle());
+
+    // The payload is handed to the adaptor as is, after the header
+    let payload = rmp_serde::to_vec(&5u32).unwrap();
+    let val: u32 = unsafe { session.request_transfer(ECHO, &payload) }.into().unwrap();
+    assert_eq!(val, 5);
+    assert!(unsafe { session.notify_transfer(ECHO, &payload) });
+    {
+        let vectored = adaptor.vectored.lock().unwrap();
+        assert_eq!(vectored.len(), 2);
+        assert!(vectored.iter().all(|parts| parts.len() == 2 && parts[1] == payload.as_ptr() as usize));
+    }
+
+    // A packet processed as a whole is sent in one buffer
+    session.set_packet_tap(Some(Arc::new(|_, _: &[u8]| {})));
+    let val: u32 = unsafe { session.request_transfer(ECHO, &payload) }.into().unwrap();
+    assert_eq!(val, 5);
+    assert_eq!(adaptor.vectored.lock().unwrap().len(), 2);
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_try_request() {
+    let (a, b) = pipe();
+    let client = Arc::new(Session::new(a, Arc::new(EmptyService)));
+    let server = Session::new(b, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let val: Result<u32, _> = client.try_request(ECHO, 5);
+    assert_eq!(val, Ok(5));
+    let missing: Result<(), _> = client.try_request("missing", ());
+    match missing {
+        Err(RequestError::Remote(e)) => assert_eq!(e.code, RemoteError::METHOD_NOT_FOUND),
+        r => panic!("{:?}", r),
+    }
+    let mistyped: Result<String, _> = client.try_request(ECHO, 5);
+    match mistyped {
+        Err(RequestError::Protocol(_)) => {}
+        r => panic!("{:?}", r),
+    }
+
+    // With another thread receiving, a request the peer never answers times out and isn't pending anymore
+    let (a, b) = pipe();
+    let client = Arc::new(Session::new(a, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    std::thread::sleep(Duration::from_millis(20));
+    let val: Result<u32, _> = client.request_timeout(ECHO, 5, Duration::from_millis(50));
+    assert_eq!(val, Err(RequestError::Timeout));
+    assert!(client.diagnostics().pending.is_empty());
+    drop(b);
+    let val: Result<u32, _> = client.try_request(ECHO, 5);
+    assert_eq!(val, Err(RequestError::Disconnected));
+}
+
+#[test]
+fn test_poll() {
+    let server = std::thread::spawn(|| {
+        let mut ser = ws::bind("127.0.0.1:3335").unwrap();
+        let (adaptor, _uri) = ws::accept(&mut ser).unwrap();
+        let s = Session::new(adaptor, Arc::new(ServerService));
+        let readable = s.adaptor.raw_fd().is_some();
+        // An event loop of the application, which isn't blocked by the session
+        let mut handled = 0;
+        while let Ok(count) = s.poll() {
+            handled += count;
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        (readable, handled)
+    });
+
+    std::thread::sleep(Duration::from_millis(100));
+    let session = Session::new(ws::connect("ws://127.0.0.1:3335").unwrap(), Arc::new(ClientService));
+    match session.try_recv_packet() { Some(Err(RecvError::NoData)) => {}, r => panic!("{:?}", r) }
+    assert_eq!(session.request(ECHO, 5).into::<u32>().unwrap(), 5);
+    assert_eq!(session.request(ECHO, 6).into::<u32>().unwrap(), 6);
+    drop(session);
+    assert_eq!(server.join().unwrap(), (true, 2));
+}
+
+#[test]
+fn test_housekeeping() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    let runs = Arc::new(AtomicUsize::new(0));
+    let runs2 = runs.clone();
+    server.set_housekeeping(Some((Duration::from_millis(20), Arc::new(move |_: &Session| { runs2.fetch_add(1, Ordering::SeqCst); }))));
+    let handle = std::thread::spawn(move || server.loop_handle());
+
+    // It runs without any traffic, and the packets are still handled
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(runs.load(Ordering::SeqCst) >= 3);
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert_eq!(client.request(ECHO, 5).into::<u32>().unwrap(), 5);
+    drop(client);
+    handle.join().unwrap();
+}
+
+struct DeadlineService;
+
+easy_service! {
+    DeadlineService(self, _ss, arg, ret)
+
+    StringMethod {
+        "remaining" => () {
+            let remaining = context::RequestContext::current().and_then(|c| c.remaining());
+            (arg.deadline.is_some(), remaining.map(|r| r.as_millis() as u64))
+        }
+    }
+}
+
+#[test]
+fn test_deadline() {
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(DeadlineService));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    let timeout = Duration::from_secs(5);
+
+    // The timeout is only sent once the peer told it reads it
+    let remaining: Result<(bool, Option<u64>), _> = client.request_timeout("remaining", (), timeout);
+    assert_eq!(remaining, Ok((false, None)));
+    assert!(client.negotiate_deadlines());
+    let remaining: Result<(bool, Option<u64>), _> = client.request_timeout("remaining", (), timeout);
+    let (known, remaining) = remaining.unwrap();
+    assert!(known && remaining.map_or(false, |ms| ms > 4000 && ms <= 5000));
+    assert_eq!(client.request("remaining", ()).into::<(bool, Option<u64>)>().unwrap(), (false, None));
+}
+
+#[test]
+fn test_deadline_overflow() {
+    use rmpv::Value;
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(DeadlineService));
+    std::thread::spawn(move || server.loop_handle());
+    let request = |id: u64, timeout: Value| {
+        let mut pack = Vec::new();
+        rmpv::encode::write_value(&mut pack, &Value::Array(vec![Value::from(0), Value::from(id), Value::from("remaining"), timeout, Value::Nil])).unwrap();
+        b.send(pack);
+        match rmpv::decode::read_value(&mut &b.recv().unwrap()[..]).unwrap() {
+            Value::Array(items) => {
+                assert_eq!(&items[..3], &[Value::from(1), Value::from(id), Value::Nil]);
+                items[3].as_array().unwrap()[0].as_bool().unwrap()
+            }
+            v => panic!("{}", v),
+        }
+    };
+    // A timeout too far for an instant is dropped or kept, the session goes on
+    request(1, Value::from(u64::max_value()));
+    assert!(request(2, Value::from(60_000)));
+
+    // Nor do the timeouts given locally
+    let forever = Duration::from_secs(u64::max_value());
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let (server2, client2) = (server.clone(), client.clone());
+    std::thread::spawn(move || server2.loop_handle());
+    std::thread::spawn(move || client2.loop_handle());
+    let echo: Result<u32, _> = client.request_timeout(ECHO, 1, forever);
+    assert_eq!(echo, Ok(1));
+    client.set_default_timeout(Some(forever));
+    let echo: Result<u32, _> = client.call(ECHO, 2);
+    assert_eq!(echo, Ok(2));
+    assert!(client.notify_ack("event", (), forever).is_ok());
+    assert!(server.drain(forever));
+}
+
+/// Keep the "hold" requests unanswered until released, answer the others
+struct Holding(Mutex<Vec<AsyncRet>>);
+
+impl Service for Holding {
+    fn handle(&self, _ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+        match arg.method.to_str()? {
+            "hold" => self.0.lock().unwrap().extend(unsafe { ret.into_async() }),
+            _ => ret.ok(()),
+        }
+        Ok(())
+    }
+}
+
+impl Holding {
+    fn wait(&self, count: usize) {
+        while self.0.lock().unwrap().len() < count { std::thread::sleep(Duration::from_millis(10)); }
+    }
+
+    fn release(&self) {
+        let held: Vec<_> = self.0.lock().unwrap().drain(..).collect();
+        for ret in held { ret.ok(()); }
+    }
+}
+
+#[test]
+fn test_throttle() {
+    let (a, b) = pipe();
+    let holding = Arc::new(Holding(Mutex::new(Vec::new())));
+    let server = Arc::new(Session::new(a, holding.clone()));
+    let clock = MockClock::new();
+    server.set_clock(clock.clone());
+    server.set_throttle(Some(Throttle::default().max_in_flight(2).rate("ping", 2)));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    let throttled = |r: RequestResult| match r {
+        RequestResult::Error(e) if e.code == RemoteError::THROTTLED => e.data::<u64>().map(Result::unwrap),
+        r => panic!("{:?}", r),
+    };
+    // A burst of one second, then a call each half second
+    assert!(client.request("ping", ()).into::<()>().is_ok());
+    assert!(client.request("ping", ()).into::<()>().is_ok());
+    assert_eq!(throttled(client.request("ping", ())), Some(501));
+    clock.advance(Duration::from_millis(500));
+    assert!(client.request("ping", ()).into::<()>().is_ok());
+    assert!(throttled(client.request("ping", ())).is_some());
+    // Called by its id, it has the limit of its name
+    assert!(throttled(client.request(method_id("ping"), ())).is_some());
+
+    let waiting: Vec<_> = (0..2).map(|_| {
+        let client = client.clone();
+        std::thread::spawn(move || client.request("hold", ()).into::<()>().is_ok())
+    }).collect();
+    holding.wait(2);
+    assert_eq!(throttled(client.request("other", ())), None);
+    holding.release();
+    for waiting in waiting { assert!(waiting.join().unwrap()); }
+    assert!(client.request("other", ()).into::<()>().is_ok());
+}
+
+#[test]
+fn test_throttle_unanswered() {
+    let (a, b) = pipe();
+    let holding = Arc::new(Holding(Mutex::new(Vec::new())));
+    let server = Arc::new(Session::new(a, holding.clone()));
+    let clock = MockClock::new();
+    server.set_clock(clock.clone());
+    server.set_throttle(Some(Throttle::default().max_in_flight(1)));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let other = || match client.request("other", ()) {
+        RequestResult::Error(e) => e.code,
+        _ => 0,
+    };
+    let hold = |timeout| {
+        let client = client.clone();
+        std::thread::spawn(move || -> Result<(), _> { client.request_timeout("hold", (), timeout) })
+    };
+
+    // An AsyncRet dropped unanswered frees its slot
+    let waiting = hold(Duration::from_millis(200));
+    holding.wait(1);
+    assert_eq!(other(), RemoteError::THROTTLED);
+    holding.0.lock().unwrap().clear();
+    assert_eq!(other(), 0);
+    assert_eq!(waiting.join().unwrap(), Err(RequestError::Timeout));
+
+    // So does a request past its deadline
+    assert!(client.negotiate_deadlines());
+    let waiting = hold(Duration::from_secs(1));
+    holding.wait(1);
+    assert_eq!(other(), RemoteError::THROTTLED);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(other(), 0);
+    assert_eq!(waiting.join().unwrap(), Err(RequestError::Timeout));
+    holding.release();
+}
+
+#[test]
+fn test_throttle_methods() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(Holding(Mutex::new(Vec::new())))));
+    let clock = MockClock::new();
+    server.set_clock(clock.clone());
+    server.set_throttle(Some(Throttle { default_rate: Some(1), ..Throttle::default() }));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    let admitted = |method: String| match client.request(method.as_str(), ()) {
+        RequestResult::Error(e) if e.code == RemoteError::THROTTLED => false,
+        r => r.into::<()>().is_ok(),
+    };
+    // Each name has its own limit, then the made up ones share one
+    assert!((0..1024).all(|i| admitted(format!("m{}", i))));
+    assert!(!admitted("m0".into()));
+    assert!(admitted("m1024".into()));
+    assert!(!admitted("m1025".into()));
+    // The idle limits are forgotten
+    clock.advance(Duration::from_secs(1));
+    assert!(admitted("m2000".into()));
+    assert!(admitted("m2001".into()));
+}
+
+#[test]
+fn test_harden() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::builder(a).service(Arc::new(Holding(Mutex::new(Vec::new())))).harden().build());
+    // The time stands still, the burst of a second is never refilled
+    server.set_clock(MockClock::new());
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    assert!((0..1000).all(|_| client.request("ping", ()).into::<()>().is_ok()));
+    match client.request("ping", ()) {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::THROTTLED),
+        r => panic!("{:?}", r),
+    }
+    assert!((0..1024).all(|i| client.subscribe(&format!("topic{}", i)).is_ok()));
+    assert!(client.subscribe("topic1024").is_err());
+}
+
+#[test]
+fn test_harden_introspection() {
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(introspect::Introspect::new(ServerService))));
+    // It tells the peers aren't authenticated
+    assert_eq!(server.harden(), Err(NoAuthenticator));
+    assert!(!server.has_authenticator());
+    server.set_authenticator(Some(Arc::new(TestAuthenticator)));
+    assert_eq!(server.harden(), Ok(()));
+    assert!(server.has_authenticator());
+    server.set_authenticator(None);
+    assert!(!server.has_authenticator());
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let denied = |r: RequestResult| match r {
+        RequestResult::Error(e) => e.code == RemoteError::PERMISSION_DENIED,
+        _ => false,
+    };
+    assert!(denied(client.request("$supports", "ping")));
+    assert!(denied(client.request(introspect::LIST_METHOD, ())));
+    assert!(denied(client.request(introspect::DESCRIBE_METHOD, "ping")));
+    assert_eq!(client.supports(ECHO), None);
+    assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+}
+
+#[test]
+fn test_drain() {
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let holding = Arc::new(Holding(Mutex::new(Vec::new())));
+    let service = holding.clone();
+    let server = Server::new(listener, move || service.clone()).start();
+    let client = Arc::new(Session::new(ws::connect(&url).unwrap(), Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    let requester = client.clone();
+    let held = std::thread::spawn(move || requester.request("hold", ()).into::<()>().is_ok());
+    holding.wait(1);
+    let draining = server.clone();
+    let drained = std::thread::spawn(move || draining.drain(Duration::from_secs(5)));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!server.is_running());
+    match client.request("ping", ()) {
+        RequestResult::Error(e) => assert_eq!(ErrorKind::from_code(e.code), ErrorKind::Unavailable),
+        r => panic!("{:?}", r),
+    }
+    assert!(ws::connect(&url).is_err());
+
+    // The request being handled is answered before the session is closed
+    holding.release();
+    assert!(held.join().unwrap());
+    assert!(drained.join().unwrap());
+    match client.request("ping", ()) { RequestResult::Disconnect => {}, r => panic!("{:?}", r) }
+}
+
+#[test]
+fn test_shutdown_order() {
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let holding = Arc::new(Holding(Mutex::new(Vec::new())));
+    let service = holding.clone();
+    let gateway = Server::new(listener, move || service.clone()).start();
+    let client = Arc::new(Session::new(ws::connect(&url).unwrap(), Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    // The session of the gateway to its backend
+    let (a, b) = pipe();
+    let backend = Session::new(a, Arc::new(ServerService));
+    std::thread::spawn(move || backend.loop_handle());
+    let outbound = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let outbound2 = outbound.clone();
+    std::thread::spawn(move || outbound2.loop_handle());
+
+    let shutdown = Shutdown::new().session("backend", &outbound, &[]).server("gateway", &gateway, &["backend"]);
+    assert_eq!(shutdown.order(), ["gateway", "backend"]);
+    let requester = client;
+    let held = std::thread::spawn(move || requester.request("hold", ()).into::<()>().is_ok());
+    holding.wait(1);
+    let stopping = std::thread::spawn(move || shutdown.run(Duration::from_secs(5)));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!gateway.is_running());
+    assert!(ws::connect(&url).is_err());
+    // The backend still answers while the gateway is drained
+    assert_eq!(outbound.request(ECHO, 1).into::<u32>().unwrap(), 1);
+    holding.release();
+    assert!(held.join().unwrap());
+    assert_eq!(stopping.join().unwrap(), [("gateway".to_string(), true), ("backend".to_string(), true)]);
+    assert!(outbound.is_closed());
+
+    // Parts waiting for each other go in the order they were added
+    let shutdown = Shutdown::new().session("a", &outbound, &["b"]).session("b", &outbound, &["a", "c"]).session("c", &outbound, &[]);
+    assert_eq!(shutdown.order(), ["a", "b", "c"]);
+}
+
+#[test]
+fn test_server_config() {
+    let config = ServerConfig::parse("# two listeners\nlisten = 127.0.0.1:0, 127.0.0.1:0\nmax_msg_size = 1024\nauth_token = \"s3cret\"\nlog = errors\n").unwrap();
+    assert_eq!(config.listen, ["127.0.0.1:0", "127.0.0.1:0"]);
+    assert_eq!((config.max_msg_size, config.log), (Some(1024), LogLevel::Errors));
+    assert_eq!(ServerConfig::parse("listen = 127.0.0.1:0\nports = 2").unwrap_err().to_string(), "line 2: unknown key `ports`");
+    assert!(ServerConfig::parse("max_in_flight = many").is_err());
+
+    let path = std::env::temp_dir().join(format!("easy-rpc-config-{}.conf", std::process::id()));
+    std::fs::write(&path, "listen = 127.0.0.1:0\nlisten = 127.0.0.1:0\nmax_msg_size = 1024\nauth_token = \"s3cret\"\n").unwrap();
+    let server = Server::from_config(&path, || Arc::new(ServerService)).unwrap().start();
+    std::fs::remove_file(&path).unwrap();
+    let addrs = server.local_addrs();
+    assert_eq!(addrs.len(), 2);
+    for addr in addrs {
+        let client = Arc::new(Session::new(ws::connect(&format!("ws://{}", addr)).unwrap(), Arc::new(EmptyService)));
+        let receiver = client.clone();
+        std::thread::spawn(move || receiver.loop_handle());
+        match client.request(ECHO, 1) { RequestResult::Error(e) => assert_eq!(e.message, "Unauthenticated"), r => panic!("{:?}", r) }
+        client.authenticate(&Credentials::Token("s3cret".into())).unwrap();
+        assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+        assert!(client.request(ECHO, ByteBuf::from(vec![0; 2048])).into::<ByteBuf>().is_err());
+    }
+    server.shutdown();
+
+    let config = ServerConfig::parse("listen = 127.0.0.1:0\ntls_cert = server.pem\ntls_key = server.key").unwrap();
+    assert!(Server::with_config(config, || Arc::new(ServerService)).is_err());
+}
+
+#[test]
+fn test_server_config_env() {
+    let config = ServerConfig::parse("listen = 127.0.0.1:9000\nmax_msg_size = 1024\nauth_token = a\nlog = errors").unwrap();
+    let vars = vec![
+        ("EASYRPC_LISTEN".to_string(), "0.0.0.0:9000, [::]:9000".to_string()),
+        ("EASYRPC_MAX_MSG_SIZE".to_string(), "4096".to_string()),
+        ("EASYRPC_AUTH_TOKEN".to_string(), "b".to_string()),
+        ("PATH".to_string(), "/bin".to_string()),
+    ];
+    let config = config.override_from(vars).unwrap();
+    assert_eq!(config.listen, ["0.0.0.0:9000", "[::]:9000"]);
+    assert_eq!((config.max_msg_size, config.auth_tokens, config.log), (Some(4096), vec!["b".to_string()], LogLevel::Errors));
+
+    let bad = ServerConfig::default().override_from(vec![("EASYRPC_MAX_IN_FLIGHT".to_string(), "many".to_string())]);
+    assert_eq!(bad.unwrap_err().to_string(), "EASYRPC_MAX_IN_FLIGHT: `many` is not a number");
+    assert!(ServerConfig::default().override_from(vec![("EASYRPC_PORTS".to_string(), "2".to_string())]).is_err());
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn test_ffi() {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_void};
+    use easy_rpc::ffi::*;
+
+    // The callbacks of a C application, over a pipe
+    struct CPipe(Arc<Pipe>, Mutex<Vec<u8>>);
+
+    extern "C" fn send(user: *mut c_void, data: *const u8, len: usize) -> bool {
+        let pipe = unsafe { &*(user as *const CPipe) };
+        pipe.0.send(unsafe { std::slice::from_raw_parts(data, len) }.to_vec())
+    }
+
+    extern "C" fn recv(user: *mut c_void, data: *mut *const u8, len: *mut usize) -> bool {
+        let pipe = unsafe { &*(user as *const CPipe) };
+        let packet = match pipe.0.recv() { Ok(packet) => packet, Err(_) => return false };
+        let mut last = pipe.1.lock().unwrap();
+        *last = packet;
+        unsafe { *data = last.as_ptr(); *len = last.len(); }
+        true
+    }
+
+    extern "C" fn handler(_user: *mut c_void, method: *const c_char, args: *const u8, len: usize, ret: *mut EasyRpcRet) {
+        unsafe {
+            match CStr::from_ptr(method).to_str().unwrap() {
+                "double" => {
+                    let val: u32 = rmp_serde::from_read_ref(std::slice::from_raw_parts(args, len)).unwrap();
+                    let result = rmp_serde::to_vec(&(val * 2)).unwrap();
+                    easy_rpc_ret_ok(ret, result.as_ptr(), result.len());
+                }
+                _ => easy_rpc_ret_error(ret, "No this method\0".as_ptr() as *const c_char),
+            }
+        }
+    }
+
+    let (a, b) = pipe();
+    let user = Box::into_raw(Box::new(CPipe(a, Mutex::new(Vec::new())))) as *mut c_void;
+    let adaptor = EasyRpcAdaptor { user, send, recv, close: None };
+    let ss = unsafe { easy_rpc_session_new(adaptor, Some(handler), std::ptr::null_mut()) } as usize;
+    std::thread::spawn(move || unsafe { easy_rpc_session_loop(ss as *const EasyRpcSession) });
+    let client = Arc::new(Session::new(b, Arc::new(ServerService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    assert_eq!(client.request("double", 21).into::<u32>().unwrap(), 42);
+    match client.request("triple", 1) { RequestResult::Error(e) => assert_eq!(e.message, "No this method"), r => panic!("{:?}", r) }
+
+    let ss = ss as *const EasyRpcSession;
+    let args = rmp_serde::to_vec(&7u32).unwrap();
+    let mut result = EasyRpcResult { data: EasyRpcBuffer { data: std::ptr::null_mut(), len: 0 }, code: 0 };
+    unsafe {
+        assert_eq!(easy_rpc_request(ss, "3\0".as_ptr() as *const c_char, args.as_ptr(), args.len(), &mut result), EASY_RPC_REMOTE_ERROR);
+        assert_eq!(std::slice::from_raw_parts(result.data.data, result.data.len), b"Unhandled Method");
+        assert_eq!(result.code, RemoteError::METHOD_NOT_FOUND);
+        easy_rpc_buffer_free(result.data);
+        assert!(easy_rpc_notify(ss, "print\0".as_ptr() as *const c_char, std::ptr::null(), 0));
+    }
+}
+
+#[test]
+fn test_client_pool() {
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let server = Server::new(listener, || Arc::new(ServerService)).start();
+    let pool = Arc::new(Pool::new(2, move || ws::connect(&url), || Arc::new(EmptyService)).max_in_flight(1));
+
+    let (first, second) = (pool.get().unwrap(), pool.get().unwrap());
+    assert!(!Arc::ptr_eq(&first, &second));
+    assert_eq!(pool.connected(), 2);
+    // Both sessions are busy until one is given back
+    let waiting = pool.clone();
+    let third = std::thread::spawn(move || -> Result<u32, _> { waiting.request(ECHO, 3) });
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(first.request(ECHO, 1).into::<u32>().unwrap(), 1);
+    drop(first);
+    assert_eq!(third.join().unwrap(), Ok(3));
+
+    // A dead session is replaced
+    let dead = &**second as *const Session;
+    second.adaptor.close();
+    drop(second);
+    while pool.connected() > 1 { std::thread::sleep(Duration::from_millis(10)); }
+    let sessions: Vec<_> = (0..2).map(|_| pool.get().unwrap()).collect();
+    assert!(sessions.iter().all(|ss| &***ss as *const Session != dead));
+    drop(sessions);
+    let four: Result<u32, _> = pool.request(ECHO, 4);
+    assert_eq!(four, Ok(4));
+    server.shutdown();
+}
+
+#[test]
+fn test_client_pool_connect() {
+    let (started, connecting) = channel();
+    let (finish, finished) = channel::<()>();
+    let (started, finished) = (Mutex::new(started), Mutex::new(finished));
+    let pool = Arc::new(Pool::new(2, move || {
+        started.lock().unwrap().send(()).unwrap();
+        finished.lock().unwrap().recv().unwrap();
+        let (a, b) = pipe();
+        std::thread::spawn(move || Session::new(a, Arc::new(ServerService)).loop_handle());
+        Ok::<_, std::io::Error>(b)
+    }, || Arc::new(EmptyService)));
+
+    // The pool isn't locked while a session connects
+    let getting = pool.clone();
+    let first = std::thread::spawn(move || getting.get().is_ok());
+    connecting.recv().unwrap();
+    let (counted, count) = channel();
+    let counting = pool.clone();
+    std::thread::spawn(move || counted.send(counting.connected()));
+    assert_eq!(count.recv_timeout(Duration::from_secs(1)), Ok(0));
+    finish.send(()).unwrap();
+    assert!(first.join().unwrap());
+    assert_eq!(pool.connected(), 1);
+
+    // Nor is the error of the connection lost
+    let refused = Pool::new(1, || Err::<Arc<Pipe>, _>(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused")),
+                            || Arc::new(EmptyService));
+    let echo: Result<u32, _> = refused.request(ECHO, 1);
+    assert_eq!(echo, Err(RequestError::Connect("refused".into())));
+}
+
+#[test]
+fn test_hedged_request() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let (stall, slow, fail) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+    let handlers = (stall.clone(), slow.clone(), fail.clone());
+    let server = Server::new(listener, move || {
+        let (stalled, slow, fail) = handlers.clone();
+        Arc::new(router::Router::new().on("read", move |_, n: u32| {
+            if fail.swap(false, Ordering::SeqCst) { return Err("failed".into()); }
+            if stalled.swap(false, Ordering::SeqCst) { std::thread::sleep(Duration::from_secs(2)); }
+            // The hedge of this one fails
+            if slow.swap(false, Ordering::SeqCst) {
+                fail.store(true, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            Ok(n)
+        }))
+    }).start();
+    let pool = Pool::new(2, move || ws::connect(&url), || Arc::new(EmptyService));
+
+    // Timed first, then a stalled request is sent again on the other session
+    for n in 0..10 { assert_eq!(pool.hedged_request("read", n), Ok(n)); }
+    // An error doesn't win over the response of the other request
+    slow.store(true, Ordering::SeqCst);
+    assert_eq!(pool.hedged_request("read", 11), Ok(11));
+    assert!(!fail.load(Ordering::SeqCst));
+    stall.store(true, Ordering::SeqCst);
+    let start = std::time::Instant::now();
+    assert_eq!(pool.hedged_request("read", 10), Ok(10));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(pool.connected(), 2);
+    // The stalled request was aborted, its session takes the next ones
+    let sessions: Vec<_> = (0..2).map(|_| pool.get().unwrap()).collect();
+    assert!(sessions.iter().all(|ss| ss.diagnostics().pending.is_empty()));
+    drop(sessions);
+    server.shutdown();
+}
+
+#[test]
+fn test_failover() {
+    let servers: Vec<_> = (0..3).map(|_| {
+        let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+        let url = format!("ws://{}", listener.local_addr());
+        (url, Server::new(listener, || Arc::new(ServerService)).start())
+    }).collect();
+    let urls: Vec<_> = servers.iter().map(|(url, _)| url.clone()).collect();
+
+    let client = Failover::new(urls[..2].to_vec(), |url| ws::connect(url), || Arc::new(EmptyService));
+    let one: Result<u32, _> = client.request(ECHO, 1);
+    assert_eq!((one, client.endpoint()), (Ok(1), Some(urls[0].clone())));
+    servers[0].1.shutdown();
+    // The request sent as the connection drops fails, the next ones go to the other endpoint
+    let two = (0..10).map(|_| -> Result<u32, _> { client.request(ECHO, 2) }).find(Result::is_ok);
+    assert_eq!((two, client.endpoint()), (Some(Ok(2)), Some(urls[1].clone())));
+
+    let balanced = Failover::new(urls.clone(), |url| ws::connect(url), || Arc::new(EmptyService)).balance();
+    for i in 0..4 {
+        let echo: Result<u32, _> = balanced.request(ECHO, i);
+        assert_eq!(echo, Ok(i));
+    }
+    assert_eq!(balanced.connected(), &urls[1..]);
+    assert_eq!(servers[2].1.sessions().len(), 1);
+}
+
+#[test]
+fn test_failover_clock() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    let (up, attempts) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+    let (up2, attempts2) = (up.clone(), attempts.clone());
+    let clock = MockClock::new();
+    let client = Failover::new(vec!["pipe"], move |_| {
+        attempts2.fetch_add(1, Ordering::SeqCst);
+        if !up2.load(Ordering::SeqCst) { return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "down")); }
+        let (a, b) = pipe();
+        std::thread::spawn(move || Session::new(a, Arc::new(ServerService)).loop_handle());
+        Ok(b)
+    }, || Arc::new(EmptyService)).retry_delay(Duration::from_secs(10)).clock(clock.clone());
+
+    let echo = || -> Result<u32, _> { client.request(ECHO, 1) };
+    assert!(echo().is_err());
+    up.store(true, Ordering::SeqCst);
+    // The endpoint is skipped until the delay passed on the clock
+    assert!(echo().is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(echo(), Ok(1));
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_failover_connect() {
+    let (started, connecting) = channel();
+    let (finish, finished) = channel::<()>();
+    let (started, finished) = (Mutex::new(started), Mutex::new(finished));
+    let client = Arc::new(Failover::new(vec!["slow", "down"], move |address| {
+        if address == "down" { return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused")); }
+        started.lock().unwrap().send(()).unwrap();
+        finished.lock().unwrap().recv().unwrap();
+        let (a, b) = pipe();
+        std::thread::spawn(move || Session::new(a, Arc::new(ServerService)).loop_handle());
+        Ok(b)
+    }, || Arc::new(EmptyService)));
+
+    // The endpoints aren't locked while one connects
+    let requesting = client.clone();
+    let first = std::thread::spawn(move || -> Result<u32, _> { requesting.request(ECHO, 1) });
+    connecting.recv().unwrap();
+    let (listed, list) = channel();
+    let listing = client.clone();
+    std::thread::spawn(move || listed.send(listing.connected()));
+    assert_eq!(list.recv_timeout(Duration::from_secs(1)), Ok(vec![]));
+    finish.send(()).unwrap();
+    assert_eq!(first.join().unwrap(), Ok(1));
+    assert_eq!(client.connected(), vec!["slow".to_string()]);
+
+    // The error of the last connection tried is kept
+    let down = Failover::new(vec!["down"], |_| Err::<Arc<Pipe>, _>(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused")),
+                             || Arc::new(EmptyService));
+    let echo: Result<u32, _> = down.request(ECHO, 1);
+    assert_eq!(echo, Err(RequestError::Connect("refused".into())));
+}
+
+#[test]
+fn test_canary() {
+    let servers: Vec<_> = (0..2).map(|_| {
+        let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+        let url = format!("ws://{}", listener.local_addr());
+        (url, Server::new(listener, || Arc::new(ServerService)).start())
+    }).collect();
+
+    let client = Failover::new(vec![servers[0].0.clone()], |url| ws::connect(url), || Arc::new(EmptyService)).canary(servers[1].0.clone(), 100.0);
+    let echo: Result<u32, _> = client.request(ECHO, 1);
+    assert_eq!(echo, Ok(1));
+    let missing: Result<(), _> = client.request("missing", ());
+    assert!(missing.is_err());
+    assert_eq!((servers[0].1.sessions().len(), servers[1].1.sessions().len()), (0, 1));
+    let report = client.canary_report().unwrap();
+    assert_eq!((report.canary.requests, report.canary.errors, report.stable.requests), (2, 1, 0));
+    assert!((report.error_delta() - 0.5).abs() < 1e-9);
+
+    // The canary down, the requests go to the other endpoints
+    servers[1].1.shutdown();
+    let echo = (0..10).map(|_| -> Result<u32, _> { client.request(ECHO, 2) }).find(Result::is_ok);
+    assert_eq!(echo, Some(Ok(2)));
+    assert!(client.canary_report().unwrap().stable.requests >= 1);
+
+    let client = Failover::new(vec![servers[0].0.clone()], |url| ws::connect(url), || Arc::new(EmptyService)).canary(servers[1].0.clone(), 0.0);
+    let echo: Result<u32, _> = client.request(ECHO, 3);
+    assert_eq!(echo, Ok(3));
+    assert_eq!(client.canary_report().unwrap().canary, RouteStats::default());
+}
+
+#[test]
+fn test_relay() {
+    use easy_rpc::relay::Relay;
+
+    let (a, b) = pipe();
+    let calculator = Arc::new(CalculatorService(Mutex::new(Vec::new())));
+    let server = Session::new(a, calculator.clone());
+    std::thread::spawn(move || server.loop_handle());
+    let upstream = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = upstream.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    let (c, d) = pipe();
+    let gateway = Session::new(c, Arc::new(Relay::new(upstream)));
+    std::thread::spawn(move || gateway.loop_handle());
+    let client = Session::new(d, Arc::new(EmptyService));
+
+    assert_eq!(client.request("add", (1, 2)).into::<u32>().unwrap(), 3);
+    match client.request("missing", ()) { RequestResult::Error(e) => assert_eq!(e.message, "Unhandled Method"), r => panic!("{:?}", r) }
+    assert!(client.notify("print", "relayed"));
+    assert_eq!(client.request("zero", ()).into::<u32>().unwrap(), 0);
+    assert_eq!(*calculator.0.lock().unwrap(), ["relayed"]);
+
+    // Nothing at the other end of the upstream session
+    let ((e, f), (g, h)) = (pipe(), pipe());
+    drop(e);
+    let gateway = Session::new(g, Arc::new(Relay::new(Arc::new(Session::new(f, Arc::new(EmptyService))))));
+    std::thread::spawn(move || gateway.loop_handle());
+    let client = Session::new(h, Arc::new(EmptyService));
+    match client.request("add", (1, 2)) {
+        RequestResult::Error(e) => assert_eq!(ErrorKind::from_code(e.code), ErrorKind::Unavailable),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn test_replace_service() {
+    use easy_rpc::router::Router;
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(Router::new().on("version", |_, ()| Ok(1)))));
+    let receiver = server.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert_eq!(client.request("version", ()).into::<u32>().unwrap(), 1);
+
+    let previous = server.replace_service(Arc::new(Router::new().on("version", |_, ()| Ok(2)).on("new", |_, ()| Ok(()))));
+    assert_eq!(previous.supports(Method::Str("version")), Some(true));
+    assert_eq!(client.request("version", ()).into::<u32>().unwrap(), 2);
+    assert!(client.request("new", ()).into::<()>().is_ok());
+}
+
+#[test]
+fn test_watchdog() {
+    use easy_rpc::router::Router;
+
+    let (a, b) = pipe();
+    let router = Router::new()
+        .on("hang", |_, ()| { std::thread::sleep(Duration::from_millis(300)); Ok(1) })
+        .on("fast", |_, ()| Ok(2));
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    server.set_watchdog(Some(Watchdog::default().timeout("hang", Duration::from_millis(50))));
+    let events = server.events();
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let start = std::time::Instant::now();
+    match client.request("hang", ()) {
+        RequestResult::Error(e) => assert_eq!(ErrorKind::from_code(e.code), ErrorKind::Timeout),
+        r => panic!("{:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_millis(300));
+    assert_eq!(events.recv().unwrap(), SessionEvent::Connected);
+    match events.recv_timeout(Duration::from_secs(1)).unwrap() {
+        SessionEvent::HandlerStalled { method, limit, .. } => assert_eq!((method.as_str(), limit), ("hang", Duration::from_millis(50))),
+        e => panic!("{:?}", e),
+    }
+    // The late response of the handler is dropped
+    assert_eq!(client.request("fast", ()).into::<u32>().unwrap(), 2);
+    // Called by its id, it has the limit of its name
+    let start = std::time::Instant::now();
+    match client.request(method_id("hang"), ()) {
+        RequestResult::Error(e) => assert_eq!(ErrorKind::from_code(e.code), ErrorKind::Timeout),
+        r => panic!("{:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_millis(300));
+}
+
+#[test]
+fn test_peer_addr() {
+    use easy_rpc::router::Router;
+
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let local = listener.local_addr();
+    let router = Arc::new(Router::new().on("whoami", |ss: &Session, ()| Ok((ss.peer_addr().map(|a| a.ip().to_string()), ss.transport_kind()))));
+    let _server = Server::new(listener, move || router.clone()).start();
+    let client = Session::new(ws::connect(&format!("ws://{}", local)).unwrap(), Arc::new(EmptyService));
+
+    assert_eq!(client.peer_addr(), Some(local));
+    assert_eq!(client.transport_kind(), Some("ws"));
+    assert_eq!(client.peer_certificate(), None);
+    let (ip, kind): (Option<String>, Option<String>) = client.request("whoami", ()).into().unwrap();
+    assert_eq!((ip.as_deref(), kind.as_deref()), (Some("127.0.0.1"), Some("ws")));
+
+    let (a, _b) = pipe();
+    let ss = Session::new(a, Arc::new(EmptyService));
+    assert_eq!((ss.peer_addr(), ss.transport_kind()), (None, None));
+}
+
+#[test]
+fn test_unanswered() {
+    struct Forgetful;
+
+    impl Service for Forgetful {
+        fn handle(&self, _ss: &Session, _arg: Arg, _ret: Ret) -> Result<(), HandleError> { Ok(()) }
+    }
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(Forgetful)));
+    server.set_unanswered(Unanswered::Error);
+    let receiver = server.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    match client.request("forgotten", ()) {
+        RequestResult::Error(e) => assert_eq!(ErrorKind::from_code(e.code), ErrorKind::Internal),
+        r => panic!("{:?}", r),
+    }
+
+    let forgotten = Arc::new(Mutex::new(Vec::new()));
+    let log = forgotten.clone();
+    server.set_unanswered(Unanswered::Hook(Arc::new(move |_, method, id| log.lock().unwrap().push((method == Method::Str("forgotten"), id)))));
+    let result: Result<(), _> = client.request_timeout("forgotten", (), Duration::from_millis(200));
+    assert_eq!(result, Err(RequestError::Timeout));
+    assert_eq!(forgotten.lock().unwrap().len(), 1);
+    assert!(forgotten.lock().unwrap()[0].0);
+}
+
+#[test]
+fn test_notify_ack() {
+    let (a, b) = pipe();
+    let calculator = Arc::new(CalculatorService(Mutex::new(Vec::new())));
+    let server = Session::new(a, calculator.clone());
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    assert_eq!(client.notify_ack("print", "critical", Duration::from_secs(1)), Ok(()));
+    assert_eq!(*calculator.0.lock().unwrap(), ["critical"]);
+
+    // Nobody to acknowledge it
+    let (c, d) = pipe();
+    drop(d);
+    let client = Session::new(c, Arc::new(EmptyService));
+    assert_eq!(client.notify_ack("print", "lost", Duration::from_secs(1)), Err(RequestError::Disconnected));
+}
+
+#[test]
+fn test_notify_pool() {
+    use easy_rpc::router::Router;
+
+    let (a, b) = pipe();
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let log = progress.clone();
+    let router = Router::new()
+        .on_notify("progress", move |_, n: u32| { std::thread::sleep(Duration::from_millis(100)); log.lock().unwrap().push(n) })
+        .on("ping", |_, ()| Ok(1));
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    server.set_notify_pool(Some(WorkerPool::new(1)));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    for n in 0..3 { assert!(client.notify("progress", n)); }
+    let start = std::time::Instant::now();
+    assert_eq!(client.request("ping", ()).into::<u32>().unwrap(), 1);
+    assert!(start.elapsed() < Duration::from_millis(100));
+    while progress.lock().unwrap().len() < 3 { std::thread::sleep(Duration::from_millis(10)); }
+    assert_eq!(*progress.lock().unwrap(), [0, 1, 2]);
+}
+
+#[test]
+fn test_progress() {
+    struct Installer;
+
+    impl Service for Installer {
+        fn handle(&self, _ss: &Session, _arg: Arg, ret: Ret) -> Result<(), HandleError> {
+            for percent in &[0, 50, 100] { assert!(ret.progress(percent)); }
+            ret.ok("installed");
+            Ok(())
+        }
+    }
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(Installer));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let log = progress.clone();
+    let result: Result<String, _> = client.request_progress("install", (), move |percent: u32| log.lock().unwrap().push(percent));
+    assert_eq!(result.unwrap(), "installed");
+    assert_eq!(*progress.lock().unwrap(), [0, 50, 100]);
+    // Without callback the values are dropped
+    assert_eq!(client.request("install", ()).into::<String>().unwrap(), "installed");
+}
+
+#[test]
+fn test_callback() {
+    use easy_rpc::router::Router;
+
+    // The server keeps the observers of its clients to call them back later
+    let (a, b) = pipe();
+    let observers = Arc::new(Mutex::new(Vec::new()));
+    let registry = observers.clone();
+    let router = Router::new()
+        .on("observe", move |_, callback: Callback| { registry.lock().unwrap().push(callback); Ok(()) });
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    let receiver = server.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    let (sender, changes) = channel();
+    let sender = Mutex::new(sender);
+    let callback = client.callback(move |value: u32| { let _ = sender.lock().unwrap().send(value); });
+    assert!(client.request("observe", callback).into::<()>().is_ok());
+    let observer = observers.lock().unwrap()[0];
+    assert!(server.call_back(observer, 1));
+    assert!(server.call_back(observer, 2));
+    assert_eq!(changes.recv_timeout(Duration::from_secs(1)), Ok(1));
+    assert_eq!(changes.recv_timeout(Duration::from_secs(1)), Ok(2));
+
+    assert!(client.revoke_callback(callback));
+    assert!(!client.revoke_callback(callback));
+    assert!(server.call_back(observer, 3));
+    assert!(changes.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn test_handles() {
+    use easy_rpc::router::Router;
+
+    struct Cursor(Mutex<std::ops::Range<u32>>);
+
+    let router = Router::new()
+        .on("query", |ss, n: u32| Ok(ss.handles().insert(Cursor(Mutex::new(0..n)))))
+        .on("next", |ss, cursor: Handle| {
+            let cursor = ss.handles().get::<Cursor>(cursor).ok_or("Unknown cursor")?;
+            let next = cursor.0.lock().unwrap().next();
+            Ok(next)
+        });
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    let receiver = server.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    let cursor: Handle = client.request("query", 2).into().unwrap();
+    assert_eq!(client.request("next", cursor).into::<Option<u32>>().unwrap(), Some(0));
+    assert_eq!(client.request("next", cursor).into::<Option<u32>>().unwrap(), Some(1));
+    assert_eq!(client.request("next", cursor).into::<Option<u32>>().unwrap(), None);
+    assert_eq!(server.handles().len(), 1);
+    assert!(server.handles().get::<String>(cursor).is_none());
+
+    assert!(client.release_handle(cursor));
+    match client.request("next", cursor) { RequestResult::Error(e) => assert_eq!(e.message, "Unknown cursor"), r => panic!("{:?}", r) }
+    assert!(server.handles().is_empty());
+}
+
+#[test]
+fn test_request_all() {
+    use easy_rpc::router::Router;
+
+    let clients: Vec<_> = (0..3u32).map(|n| {
+        let (a, b) = pipe();
+        let router = Router::new().on("id", move |_, ()| {
+            if n == 2 { std::thread::sleep(Duration::from_millis(300)); }
+            Ok(n)
+        });
+        let server = Session::new(a, Arc::new(router));
+        std::thread::spawn(move || server.loop_handle());
+        let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+        let receiver = client.clone();
+        std::thread::spawn(move || receiver.loop_handle());
+        client
+    }).collect();
+    let (c, d) = pipe();
+    drop(d);
+    let gone = Session::new(c, Arc::new(EmptyService));
+
+    let sessions: Vec<&Session> = clients.iter().map(|ss| &**ss).chain(Some(&gone)).collect();
+    let start = std::time::Instant::now();
+    let results: Vec<Result<u32, _>> = multi::request_all(&sessions, "id", (), Duration::from_millis(100));
+    assert!(start.elapsed() < Duration::from_millis(300));
+    assert_eq!(results, [Ok(0), Ok(1), Err(RequestError::Timeout), Err(RequestError::Disconnected)]);
+
+    // Its requests aren't ordered with the others of the session, which don't wait for them nor hold them back
+    let threads = (0..4).map(|_| {
+        let client = clients[0].clone();
+        std::thread::spawn(move || (0..50).all(|_| client.call::<u32>("id", ()) == Ok(0)))
+    }).collect::<Vec<_>>();
+    for _ in 0..50 {
+        let results: Vec<Result<u32, _>> = multi::request_all(&[&*clients[0], &*clients[1]], "id", (), Duration::from_secs(5));
+        assert_eq!(results, [Ok(0), Ok(1)]);
+    }
+    assert!(threads.into_iter().all(|t| t.join().unwrap()));
+}
+
+#[test]
+fn test_record_replay() {
+    use easy_rpc::record::{self, Replay};
+
+    let path = std::env::temp_dir().join(format!("easy-rpc-{}.rec", std::process::id()));
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(CalculatorService(Mutex::new(Vec::new()))));
+    server.set_packet_tap(Some(record::create(&path).unwrap()));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert_eq!(client.request("add", (1, 2)).into::<u32>().unwrap(), 3);
+    assert!(client.notify("print", "recorded"));
+    assert_eq!(client.request("zero", ()).into::<u32>().unwrap(), 0);
+
+    let frames = record::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let directions: Vec<_> = frames.iter().map(|f| f.direction).collect();
+    assert_eq!(directions, [Direction::Received, Direction::Sent, Direction::Received, Direction::Received, Direction::Sent]);
+    assert!(frames.windows(2).all(|w| w[0].time <= w[1].time));
+
+    // The same traffic replayed to a new service gets the same responses
+    let calculator = Arc::new(CalculatorService(Mutex::new(Vec::new())));
+    let replay = Replay::new(frames.clone());
+    Session::new(replay.clone(), calculator.clone()).loop_handle();
+    let expected: Vec<_> = frames.into_iter().filter(|f| f.direction == Direction::Sent).map(|f| f.data).collect();
+    assert_eq!(replay.sent(), expected);
+    assert_eq!(*calculator.0.lock().unwrap(), ["recorded"]);
+    assert!(record::read(&b"not a recording"[..]).is_err());
+}
+
+#[test]
+fn test_mock_adaptor() {
+    use easy_rpc::mock::MockAdaptor;
+
+    // A client gets the scripted answers
+    let mock = MockAdaptor::new();
+    mock.expect_request("add", (1, 2)).respond(3);
+    mock.expect_request("negate", 1).fail(RemoteError::new(RemoteError::INVALID_ARGS, "Positive"));
+    mock.expect_notify("print", "hello");
+    let client = Session::new(mock.clone(), Arc::new(EmptyService));
+    let sum: u32 = client.call("add", (1, 2)).unwrap();
+    assert_eq!(sum, 3);
+    let negated: Result<i32, _> = client.call("negate", 1);
+    match negated {
+        Err(RequestError::Remote(e)) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        _ => panic!("expected the scripted error"),
+    }
+    assert!(client.notify("print", "hello"));
+    mock.verify();
+    assert_eq!(mock.sent().len(), 3);
+
+    // A service answers the scripted requests
+    let mock = MockAdaptor::new();
+    let calculator = Arc::new(CalculatorService(Mutex::new(Vec::new())));
+    mock.request("add", (1, 2)).expect_response(3);
+    mock.notify("print", "scripted");
+    mock.request("missing", ()).expect_error(RemoteError::METHOD_NOT_FOUND);
+    mock.request("zero", ());
+    Session::new(mock.clone(), calculator.clone()).loop_handle();
+    mock.verify();
+    assert_eq!(*calculator.0.lock().unwrap(), ["scripted"]);
+
+    // What doesn't follow the script is reported
+    let mock = MockAdaptor::new();
+    mock.expect_notify("print", "hello");
+    mock.expect_notify("print", "never");
+    let client = Session::new(mock.clone(), Arc::new(EmptyService));
+    client.notify("print", "bye");
+    assert_eq!(mock.failures().len(), 3);
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mock.verify())).is_err());
+}
+
+#[test]
+fn test_ping() {
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(CalculatorService(Mutex::new(Vec::new()))));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert_eq!(client.rtt(), None);
+    let first = client.ping().unwrap();
+    assert_eq!(client.rtt(), Some(first));
+    let second = client.ping().unwrap();
+    assert_eq!(client.rtt(), Some(first * 7 / 8 + second / 8));
+    // The requests still get their responses
+    assert_eq!(client.request("add", (1, 2)).into::<u32>().unwrap(), 3);
+
+    let (a, b) = pipe();
+    let client = Session::new(a, Arc::new(EmptyService));
+    drop(b);
+    assert_eq!(client.ping(), Err(RequestError::Disconnected));
+}
+
+#[test]
+fn test_idle_timeout() {
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(EmptyService));
+    server.set_idle_timeout(Some(Duration::from_millis(150)));
+    let events = server.events();
+    let handle = std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    // The pings keep it open
+    for _ in 0..5 {
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(client.ping().is_ok());
+    }
+    handle.join().unwrap();
+    assert_eq!(events.recv().unwrap(), SessionEvent::Connected);
+    assert_eq!(events.recv().unwrap(), SessionEvent::Disconnected(Some(TransportError::Timeout)));
+}
+
+#[test]
+fn test_size_limits() {
+    use easy_rpc::mock::MockAdaptor;
+    use easy_rpc::router::Router;
+
+    let limits = SizeLimits { max_incoming: 64, max_outgoing: 64, close: false };
+    let (a, b) = pipe();
+    let router = Router::new().on("repeat", |_, n: usize| Ok("x".repeat(n)));
+    let server = Session::new(a, Arc::new(router));
+    server.set_size_limits(Some(limits));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+
+    // A response too large is replaced by an error
+    let small: String = client.call("repeat", 10).unwrap();
+    assert_eq!(small.len(), 10);
+    let large: Result<String, _> = client.call("repeat", 100);
+    match large {
+        Err(RequestError::Remote(e)) => assert_eq!(e.code, RemoteError::LIMIT_EXCEEDED),
+        _ => panic!("expected a limit error"),
+    }
+    // So is a request too large for the peer
+    let sent: Result<String, _> = client.call("repeat", "y".repeat(100));
+    match sent {
+        Err(RequestError::Remote(e)) => assert_eq!(e.message, "Packet too large"),
+        _ => panic!("expected a limit error"),
+    }
+    // Or for the session itself
+    client.set_size_limits(Some(limits));
+    let sent: Result<String, _> = client.call("repeat", "y".repeat(100));
+    match sent {
+        Err(RequestError::Remote(e)) => assert_eq!(e.message, "Request too large"),
+        _ => panic!("expected a limit error"),
+    }
+    assert_eq!(client.try_notify("repeat", "y".repeat(100)), Err(SendError::TooLarge));
+
+    // The session can be closed by the peer exceeding a limit
+    let mock = MockAdaptor::new();
+    mock.request("print", "z".repeat(100)).expect_error(RemoteError::LIMIT_EXCEEDED);
+    mock.notify("print", "never handled");
+    let calculator = Arc::new(CalculatorService(Mutex::new(Vec::new())));
+    let server = Session::new(mock.clone(), calculator.clone());
+    server.set_size_limits(Some(SizeLimits { close: true, ..limits }));
+    server.loop_handle();
+    mock.verify();
+    assert!(calculator.0.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_session_builder() {
+    use easy_rpc::router::Router;
+
+    let (a, b) = pipe();
+    let router = Router::new()
+        .on("add", |_, (a, b): (u32, u32)| Ok(a + b))
+        .on("slow", |_, ()| { std::thread::sleep(Duration::from_millis(300)); Ok(()) });
+    let server = Session::builder(a).service(Arc::new(router)).max_payload(1024).build();
+    assert_eq!(server.size_limits().map(|limits| limits.max_incoming), Some(1024));
+    std::thread::spawn(move || server.loop_handle());
+
+    let client = Arc::new(Session::builder(b).default_timeout(Duration::from_millis(50)).build());
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let sum: u32 = client.call("add", (1, 2)).unwrap();
+    assert_eq!(sum, 3);
+    let slow: Result<(), _> = client.call("slow", ());
+    assert_eq!(slow, Err(RequestError::Timeout));
+    match client.request("slow", ()) {
+        RequestResult::Error(e) => assert_eq!(e.code, RemoteError::TIMEOUT),
+        _ => panic!("expected a timeout"),
+    }
+}
+
+#[test]
+fn test_trace_propagation() {
+    use easy_rpc::context::RequestContext;
+    use easy_rpc::router::Router;
+
+    let trace = || Router::new().on("trace", |_, ()| Ok(RequestContext::current().and_then(|c| c.trace_id)));
+    let (a, b) = pipe();
+    let backend = Session::new(a, Arc::new(trace()));
+    std::thread::spawn(move || backend.loop_handle());
+    let to_backend = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = to_backend.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    assert!(to_backend.negotiate_metadata());
+
+    // The frontend forwards to the backend from its handler
+    let (a, b) = pipe();
+    let router = Router::new().on("forward", move |_, ()| to_backend.request("trace", ()).into::<Option<String>>().map_err(HandleError::from));
+    let frontend = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || frontend.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    let traced = || client.request("forward", ()).into::<Option<String>>().unwrap();
+
+    // Not sent before the negotiation, nor without a trace
+    assert_eq!(RequestContext::default().with_trace_id("t-1").scope(traced), None);
+    assert!(client.negotiate_metadata());
+    assert_eq!(traced(), None);
+    assert_eq!(RequestContext::default().with_trace_id("t-1").scope(traced), Some("t-1".into()));
+}
+
+#[test]
+fn test_spans() {
+    use easy_rpc::{Span, SpanKind, SpanSink};
+    use easy_rpc::router::Router;
+    use std::sync::mpsc::{channel, Sender};
+
+    struct Collect(Mutex<Sender<Span>>);
+    impl SpanSink for Collect {
+        fn span(&self, span: Span) { self.0.lock().unwrap().send(span).unwrap(); }
+    }
+
+    let (tx, spans) = channel();
+    let sink = Arc::new(Collect(Mutex::new(tx)));
+    let router = Router::new().on("half", |_, n: u32| if n % 2 == 0 { Ok(n / 2) } else { Err(HandleError::from("odd")) });
+    let (a, b) = pipe();
+    let server = Session::builder(a).service(Arc::new(router)).span_sink(sink.clone()).build();
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::builder(b).span_sink(sink).build());
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    assert!(client.negotiate_metadata());
+    // The client and the server span of a call, in any order
+    let next = || {
+        let mut call: Vec<Span> = spans.iter().filter(|s| s.method == "half").take(2).collect();
+        call.sort_by_key(|s| s.kind == SpanKind::Server);
+        (call.remove(0), call.remove(0))
+    };
+
+    assert_eq!(client.request("half", 8u32).into::<u32>().unwrap(), 4);
+    let (called, handled) = next();
+    assert_eq!(called.trace_id.len(), 32);
+    assert_eq!(called.span_id.len(), 16);
+    assert_eq!(called.parent_span_id, None);
+    assert_eq!((called.error, called.request_size, called.response_size), (None, 1, Some(1)));
+    // The server span joins the trace of the client one
+    assert_eq!(handled.trace_id, called.trace_id);
+    assert_eq!(handled.parent_span_id, Some(called.span_id.clone()));
+    assert_ne!(handled.span_id, called.span_id);
+    assert_eq!((handled.error, handled.request_size), (None, 1));
+
+    assert!(client.request("half", 3u32).into::<u32>().is_err());
+    let (called, handled) = next();
+    assert!(called.error.is_some());
+    assert_eq!(handled.error, called.error);
+    assert_eq!(called.response_size, None);
+}
+
+#[test]
+fn test_acl() {
+    use easy_rpc::middleware::{Acl, Stack};
+
+    let acl = Acl::new().require(ECHO_BIGDATA, &["admin", "ops"]).require(ECHO, &[]);
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(Stack::new(Arc::new(ServerService)).layer(acl))));
+    let receiver = server.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(ClientService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let code = |result| match result { RequestResult::Error(e) => (e.code, e.data.map(|d| rmp_serde::from_read_ref::<_, Vec<String>>(&d).unwrap())), r => panic!("{:?}", r) };
+
+    // The methods without rule run for anyone
+    assert_eq!(client.request(RECURSIVE_ADD, 0).into::<u32>().unwrap(), 2);
+    assert_eq!(code(client.request(ECHO, 1)), (RemoteError::UNAUTHENTICATED, None));
+
+    server.extensions().insert(Identity { name: "bob".into(), roles: vec!["dev".into()] });
+    assert_eq!(client.request(ECHO, 1).into::<u32>().unwrap(), 1);
+    assert_eq!(code(client.request(ECHO_BIGDATA, vec![1u8])), (RemoteError::PERMISSION_DENIED, Some(vec!["admin".into(), "ops".into()])));
+
+    server.extensions().insert(Identity { name: "carol".into(), roles: vec!["dev".into(), "ops".into()] });
+    assert_eq!(client.request(ECHO_BIGDATA, vec![1u8]).into::<Vec<u8>>().unwrap(), vec![1]);
+
+    // A rule holds whether the method is called by name or by its id
+    let acl = Acl::new().require("admin.delete", &["admin"]).require(method_id("reports.read"), &["ops"]);
+    let router = router::Router::new().on("admin.delete", |_, ()| Ok("deleted")).on("reports.read", |_, ()| Ok("read"));
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(Stack::new(Arc::new(router)).layer(acl))));
+    let receiver = server.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    assert_eq!(code(client.request("admin.delete", ())), (RemoteError::UNAUTHENTICATED, None));
+    assert_eq!(code(client.request(method_id("admin.delete"), ())), (RemoteError::UNAUTHENTICATED, None));
+    server.extensions().insert(Identity { name: "bob".into(), roles: vec!["dev".into()] });
+    assert_eq!(code(client.request(method_id("reports.read"), ())), (RemoteError::PERMISSION_DENIED, Some(vec!["ops".into()])));
+    assert_eq!(code(client.request("reports.read", ())), (RemoteError::PERMISSION_DENIED, Some(vec!["ops".into()])));
+    server.extensions().insert(Identity { name: "carol".into(), roles: vec!["admin".into(), "ops".into()] });
+    assert_eq!(client.request(method_id("admin.delete"), ()).into::<String>().unwrap(), "deleted");
+    assert_eq!(client.request("reports.read", ()).into::<String>().unwrap(), "read");
+}
+
+#[test]
+fn test_tenants() {
+    use easy_rpc::router::Router;
+
+    let plan = |ss: &Session| ss.tenant().map(|t| (t.name.clone(), t.param("plan").map(String::from)));
+    let tenants = Tenants::new()
+        .tenant("acme", move |_| Arc::new(Router::new().on("plan", move |ss, ()| Ok(plan(ss)))))
+        .tenant("globex", |tenant| {
+            let name = tenant.name.clone();
+            Arc::new(Router::new().on("plan", move |_, ()| Ok(Some((name.clone(), None::<String>)))))
+        });
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let server = Server::with_tenants(listener, tenants).start();
+
+    let request = |path: &str| {
+        let client = Session::new(ws::connect(&format!("{}{}", url, path)).unwrap(), Arc::new(EmptyService));
+        client.request("plan", ()).into::<Option<(String, Option<String>)>>()
+    };
+    assert_eq!(request("/acme/rpc?region=eu&plan=pro").unwrap(), Some(("acme".into(), Some("pro".into()))));
+    assert_eq!(request("/globex").unwrap(), Some(("globex".into(), None)));
+    // The sessions of unknown tenants are closed
+    match request("/initech") { Err(RequestResult::Disconnect) => {}, r => panic!("{:?}", r) }
+    server.shutdown();
+
+    let tenant = Tenant::parse("/acme/v2?flag&plan=free");
+    assert_eq!(tenant.path, "/acme/v2");
+    assert_eq!(tenant.query, vec![("flag".into(), String::new()), ("plan".into(), "free".into())]);
+}
+
+#[test]
+fn test_rooms() {
+    struct Count(Mutex<u32>);
+    impl Service for Count {
+        fn handle(&self, _ss: &Session, _arg: Arg, _ret: Ret) -> Result<(), HandleError> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    let rooms = Rooms::new();
+    let (mut servers, mut counts) = (Vec::new(), Vec::new());
+    for _ in 0..3 {
+        let (a, b) = pipe();
+        servers.push(Arc::new(Session::new(a, Arc::new(EmptyService))));
+        let count = Arc::new(Count(Mutex::new(0)));
+        let client = Session::new(b, count.clone());
+        counts.push(count);
+        std::thread::spawn(move || client.loop_handle());
+    }
+    rooms.join("lobby-3", &servers[0]);
+    rooms.join("lobby-3", &servers[1]);
+    rooms.join("lobby-3", &servers[1]);
+    rooms.join("game-1", &servers[1]);
+    rooms.join("game-1", &servers[2]);
+
+    assert_eq!(rooms.notify("lobby-3", "chat", "hi"), 2);
+    assert_eq!(rooms.notify("nowhere", "chat", "hi"), 0);
+    let mut joined = rooms.rooms_of(&servers[1]);
+    joined.sort();
+    assert_eq!(joined, vec!["game-1", "lobby-3"]);
+
+    assert!(rooms.leave("lobby-3", &servers[0]));
+    assert!(!rooms.leave("lobby-3", &servers[0]));
+    rooms.leave_all(&servers[1]);
+    // The empty room is forgotten
+    assert_eq!(rooms.rooms(), vec!["game-1"]);
+    // So is a dropped session
+    servers.remove(2);
+    assert_eq!(rooms.notify("game-1", "chat", "bye"), 0);
+    assert!(rooms.rooms().is_empty());
+
+    std::thread::sleep(Duration::from_millis(100));
+    let received = counts.iter().map(|c| *c.0.lock().unwrap()).collect::<Vec<_>>();
+    assert_eq!(received, vec![1, 1, 0]);
+
+    // Joined once by the threads racing to join
+    let rooms = Arc::new(rooms);
+    let server = servers[0].clone();
+    let joining: Vec<_> = (0..8).map(|_| {
+        let (rooms, server) = (rooms.clone(), server.clone());
+        std::thread::spawn(move || rooms.join("race", &server))
+    }).collect();
+    for joining in joining { joining.join().unwrap(); }
+    assert_eq!(rooms.members("race").len(), 1);
+}
+
+#[test]
+fn test_attachments() {
+    struct Thumbnail;
+    impl Service for Thumbnail {
+        fn handle(&self, _ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+            let step: usize = arg.borrow()?;
+            let image = arg.attachment(0).ok_or("no image")?;
+            let thumbnail = image.iter().step_by(step).copied().collect::<Vec<u8>>();
+            ret.ok_with_attachments(arg.attachments.len(), &[&thumbnail, b"meta"]);
+            Ok(())
+        }
+    }
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(Thumbnail));
+    // The attachments of requests which never come don't fill the session, the oldest ones are dropped
+    for id in 0x80..=0xffu8 { assert!(server.handle_packet(vec![0x94, 0x07, 0xcc, id, 0xc2, 0xc4, 0x01, id]).is_ok()); }
+    std::thread::spawn(move || server.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    let image = (0..0x40000u32).map(|i| i as u8).collect::<Vec<u8>>();
+    let (result, attachments) = client.request_with_attachments("thumbnail", 4, &[&image, &[1, 2]]);
+    assert_eq!(result.into::<usize>().unwrap(), 2);
+    assert_eq!(attachments.len(), 2);
+    assert_eq!(attachments[0], image.iter().step_by(4).copied().collect::<Vec<u8>>());
+    assert_eq!(attachments[1], b"meta");
+
+    let (result, attachments) = client.request_with_attachments("thumbnail", 4, &[]);
+    match result { RequestResult::Error(e) => assert_eq!(e.message, "no image"), r => panic!("{:?}", r) }
+    assert!(attachments.is_empty());
+    // Bounded like the packets
+    client.set_size_limits(Some(SizeLimits { max_outgoing: 0x1000, ..SizeLimits::default() }));
+    match client.request_with_attachments("thumbnail", 4, &[&image]).0 { RequestResult::Error(e) => assert_eq!(e.code, RemoteError::LIMIT_EXCEEDED), r => panic!("{:?}", r) }
+}
+
+#[test]
+fn test_response_cache() {
+    use easy_rpc::router::Router;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let calls = Arc::new(AtomicU32::new(0));
+    let counted = calls.clone();
+    let router = Router::new()
+        .on("config", move |_, key: String| Ok(format!("{}={}", key, counted.fetch_add(1, Ordering::SeqCst))))
+        .on("now", |_, ()| Ok(0));
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    let receiver = server.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    let clock = MockClock::new();
+    let cache = Arc::new(ResponseCache::new().cache("config", Duration::from_secs(60)).invalidate_on("config-changed", "config"));
+    let client = Arc::new(Session::builder(b).clock(clock.clone()).response_cache(cache.clone()).build());
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let config = |key: &str| client.request("config", key).into::<String>().unwrap();
+
+    assert_eq!(config("a"), "a=0");
+    assert_eq!(config("a"), "a=0");
+    // Keyed by the arguments
+    assert_eq!(config("b"), "b=1");
+    assert_eq!(cache.len(), 2);
+    // The other methods aren't cached
+    client.request("now", ()).into::<u32>().unwrap();
+    assert_eq!(cache.len(), 2);
+
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(config("a"), "a=2");
+    cache.invalidate("config");
+    assert_eq!(config("a"), "a=3");
+    server.notify("config-changed", ());
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(cache.is_empty());
+    assert_eq!(config("a"), "a=4");
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+}
+
+#[test]
+fn test_retry() {
+    use easy_rpc::router::Router;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let policy = RetryPolicy { backoff: Duration::from_millis(10), ..RetryPolicy::default() };
+    let attempts = Arc::new(AtomicU32::new(0));
+    let counted = attempts.clone();
+    let router = Router::new().on("flaky", move |_, ()| match counted.fetch_add(1, Ordering::SeqCst) {
+        0 | 1 => Err(HandleError::new(ErrorKind::Unavailable, "busy")),
+        n => Ok(n),
+    });
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    client.set_retry_policy(Some(RetryPolicy { max_attempts: 2, ..policy.clone() }));
+    assert!(client.request("flaky", ()).into::<u32>().is_err());
+    attempts.store(0, Ordering::SeqCst);
+    client.set_retry_policy(Some(policy.clone()));
+    assert_eq!(client.request("flaky", ()).into::<u32>().unwrap(), 2);
+
+    // The server crashes after charging, the client charges again on a new connection under the same key
+    let charges = Arc::new(AtomicU32::new(0));
+    let cache = Arc::new(IdempotencyCache::new(100, Duration::from_secs(60)));
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let counted = charges.clone();
+    let service: ServiceType = Arc::new(Router::new().on("charge", move |ss, amount: u32| {
+        if counted.fetch_add(1, Ordering::SeqCst) == 0 { ss.adaptor.close(); }
+        Ok(amount)
+    }));
+    let _server = Server::new(listener, move || service.clone())
+        .on_session(move |ss| ss.set_idempotency_cache(Some(cache.clone())))
+        .start();
+    let client = Failover::new(vec![url], |url| ws::connect(url), || Arc::new(EmptyService)).retry_policy(policy);
+    let charged: u32 = client.request("charge", 42).unwrap();
+    assert_eq!(charged, 42);
+    assert_eq!(charges.load(Ordering::SeqCst), 1);
+    // A new request is handled
+    let charged: u32 = client.request("charge", 7).unwrap();
+    assert_eq!(charged, 7);
+    assert_eq!(charges.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_method_aliases() {
+    use easy_rpc::router::Router;
+
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let router = Router::new()
+        .on("echo", |_, s: String| Ok(s))
+        .on("other", |_, n: u32| Ok(n + 1))
+        .on_notify("event", move |_, n: u32| { tx.lock().unwrap().send(n).unwrap(); });
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(router)));
+    std::thread::spawn(move || server.loop_handle());
+
+    let client = Arc::new(Session::new(b, Arc::new(Router::new())));
+    let sent = Arc::new(Mutex::new(Vec::<Vec<u8>>::new()));
+    let tapped = sent.clone();
+    client.set_packet_tap(Some(Arc::new(move |direction, frame: &[u8]| {
+        if direction == Direction::Sent { tapped.lock().unwrap().push(frame.into()); }
+    })));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+    let last_names = |name: &str| sent.lock().unwrap().last().unwrap().windows(name.len()).any(|w| w == name.as_bytes());
+
+    assert!(client.negotiate_aliases(&["echo", "event"]));
+    assert_eq!(client.request("echo", "hi").into::<String>().unwrap(), "hi");
+    assert!(!last_names("echo"));
+    assert!(client.notify("event", 7));
+    assert!(!last_names("event"));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), 7);
+
+    // Aliased after its first use
+    assert_eq!(client.request("other", 1).into::<u32>().unwrap(), 2);
+    assert!(last_names("other"));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.request("other", 2).into::<u32>().unwrap(), 3);
+    assert!(!last_names("other"));
+
+}
+
+#[test]
+fn test_method_id() {
+    use easy_rpc::router::Router;
+
+    // Stable across builds and peers
+    assert_eq!(method_id("calc.add"), 1_138_637_037);
+    #[cfg(feature = "macros")]
+    {
+        const ADD: u32 = method_id!("calc.add");
+        assert_eq!(ADD, method_id("calc.add"));
+    }
+
+    let (a, b) = pipe();
+    let router = Router::new().on("calc.add", |_, (a, b): (u32, u32)| Ok(a + b));
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(Router::new()));
+    assert_eq!(client.request(method_id("calc.add"), (1, 2)).into::<u32>().unwrap(), 3);
+    assert_eq!(client.request("calc.add", (2, 3)).into::<u32>().unwrap(), 5);
+    assert_eq!(client.supports(method_id("calc.add")), Some(true));
+    assert_eq!(client.supports(method_id("calc.sub")), Some(false));
+
+    // The layers and the limits set by name hold for the ids
+    let (a, b) = pipe();
+    let router = Router::new().on("admin.delete", |_, ()| Ok("deleted")).on("calc.add", |_, (a, b): (u32, u32)| Ok(a + b));
+    let stack = middleware::Stack::new(Arc::new(router)).layer(middleware::Acl::new().require("admin.delete", &["admin"]));
+    let server = Session::new(a, Arc::new(stack));
+    server.set_throttle(Some(Throttle::default().rate("calc.add", 0)));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(Router::new()));
+    let code = |result| match result { RequestResult::Error(e) => e.code, r => panic!("{:?}", r) };
+    assert_eq!(code(client.request(method_id("admin.delete"), ())), RemoteError::UNAUTHENTICATED);
+    assert_eq!(code(client.request(method_id("calc.add"), (1, 2))), RemoteError::THROTTLED);
+
+    assert_eq!(method_id("m329698"), method_id("m901416"));
+    let collision = std::panic::catch_unwind(|| Router::new().on("m329698", |_, ()| Ok(())).on("m901416", |_, ()| Ok(())));
+    assert!(collision.is_err());
+}
+
+#[test]
+fn test_decode_failure() {
+    use easy_rpc::router::Router;
+    use std::collections::HashMap;
+
+    let (a, b) = pipe();
+    let router = Router::new()
+        .on("add", |_, (a, b): (u32, u32)| Ok(a + b))
+        .on("total", |_, prices: HashMap<String, u32>| Ok(prices.values().sum::<u32>()));
+    let server = Session::new(a, Arc::new(router));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(Router::new()));
+
+    match client.request("add", (1, "x")) {
+        RequestResult::Error(e) => {
+            assert_eq!(e.code, RemoteError::INVALID_ARGS);
+            assert!(e.message.starts_with("Undecodable arguments of add at [1]: "), "{}", e.message);
+            assert!(e.message.ends_with(", found str (a1 78)"), "{}", e.message);
+        }
+        r => panic!("{:?}", r),
+    }
+    let mut prices = HashMap::new();
+    prices.insert("cake", "free");
+    match client.request("total", prices) {
+        RequestResult::Error(e) => assert!(e.message.starts_with("Undecodable arguments of total at .cake: "), "{}", e.message),
+        r => panic!("{:?}", r),
+    }
+
+    let result = client.request("add", (1, 2)).into::<String>().err().unwrap();
+    let failure = result.decode_failure().unwrap();
+    assert_eq!((failure.method.as_str(), failure.result, failure.path.as_str()), ("add", true, ""));
+    assert_eq!((failure.expected.as_deref(), failure.found, failure.bytes.as_str()), (Some("a string"), Some("int"), "03"));
+    assert!(format!("{:?}", result).starts_with("DecodeError: Undecodable result of add: "));
+}
+
+#[test]
+fn test_call() {
+    let (a, b) = pipe();
+    let client = Session::new(a, Arc::new(EmptyService));
+    let server = Session::new(b, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+
+    let echo: Result<u32, _> = client.call(ECHO, 5);
+    assert_eq!(echo, Ok(5));
+    let missing: Result<(), _> = client.call("missing", ());
+    match missing {
+        Err(e @ RequestError::Remote(_)) => assert!(!e.is_transport()),
+        r => panic!("{:?}", r),
+    }
+    let mistyped: Result<String, _> = client.call(ECHO, 5);
+    match mistyped {
+        Err(RequestError::Protocol(e)) => assert!(e.starts_with("Undecodable result of 3: "), "{}", e),
+        r => panic!("{:?}", r),
+    }
+    let (a, b) = pipe();
+    let client = Session::new(a, Arc::new(EmptyService));
+    drop(b);
+    let disconnected: Result<u32, _> = client.call(ECHO, 5);
+    assert!(disconnected.unwrap_err().is_transport());
+}
+
+#[test]
+fn test_shutdown() {
+    use easy_rpc::router::Router;
+
+    let (release, released) = channel::<()>();
+    let released = Mutex::new(released);
+    let router: ServiceType = Arc::new(Router::new()
+        .on("echo", |_, n: u32| Ok(n))
+        .on("wait", move |_, ()| { released.lock().unwrap().recv_timeout(Duration::from_secs(5)).ok(); Ok(()) }));
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let server = Server::new(listener, move || router.clone()).start();
+
+    // The requests waiting and the thread receiving are woken up
+    let client = Arc::new(Session::new(ws::connect(&url).unwrap(), Arc::new(EmptyService)));
+    let receiver = client.clone();
+    let (stopped, loop_stopped) = channel();
+    std::thread::spawn(move || { receiver.loop_handle(); stopped.send(()).unwrap(); });
+    let requester = client.clone();
+    let (done, waited) = channel();
+    std::thread::spawn(move || {
+        let waited: Result<(), _> = requester.call("wait", ());
+        done.send(waited).unwrap();
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    client.shutdown();
+    assert_eq!(waited.recv_timeout(Duration::from_secs(1)).unwrap(), Err(RequestError::Disconnected));
+    loop_stopped.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(client.is_closed());
+    assert!(client.request("echo", 1).into::<u32>().is_err());
+    release.send(()).unwrap();
+
+    // Dropping a session disconnects it from the peer
+    let client = Session::new(ws::connect(&url).unwrap(), Arc::new(EmptyService));
+    assert_eq!(client.call("echo", 2), Ok(2));
+    drop(client);
+    let mut live = server.sessions().len();
+    for _ in 0..50 {
+        if live == 0 { break; }
+        std::thread::sleep(Duration::from_millis(20));
+        live = server.sessions().len();
+    }
+    assert_eq!(live, 0);
+    server.shutdown();
+}
+
+#[test]
+fn test_spawn_loop() {
+    let listener = ws::bind_guarded("127.0.0.1:0", ws::HandshakeLimits::default()).unwrap();
+    let url = format!("ws://{}", listener.local_addr());
+    let server = Server::new(listener, || Arc::new(ServerService)).start();
+
+    let client = Arc::new(Session::new(ws::connect(&url).unwrap(), Arc::new(EmptyService)));
+    let (thread, token) = client.spawn_loop();
+    assert_eq!(client.call(ECHO, 7), Ok(7));
+    assert!(!token.is_shutdown());
+    drop(client);
+    // The loop keeps the session alive until the token stops it
+    token.shutdown();
+    let (joined, stopped) = channel();
+    std::thread::spawn(move || joined.send(thread.join().is_ok()).unwrap());
+    assert_eq!(stopped.recv_timeout(Duration::from_secs(1)), Ok(true));
+    assert!(token.is_shutdown());
+    server.shutdown();
+}
+
+#[test]
+fn test_reentrant_requests() {
+    use easy_rpc::router::Router;
+    let (a, b) = pipe();
+    let (entered, wait_entered) = channel();
+    let (go, wait_go) = channel::<()>();
+    let (entered, wait_go) = (Mutex::new(entered), Mutex::new(wait_go));
+    let server = Arc::new(Session::new(a, Arc::new(Router::new().on("nested", move |ss, n: u32| {
+        entered.lock().unwrap().send(()).unwrap();
+        wait_go.lock().unwrap().recv().unwrap();
+        let n: u32 = ss.request("echo", n).into()?;
+        Ok(n + 1)
+    }))));
+    let looping = server.clone();
+    std::thread::spawn(move || looping.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(Router::new()
+        .on("echo", |_, n: u32| Ok(n))
+        .on("slow", |_, n: u32| { std::thread::sleep(Duration::from_millis(200)); Ok(n) }))));
+    let looping = client.clone();
+    std::thread::spawn(move || looping.loop_handle());
+
+    let (done, finished) = channel();
+    let nested = done.clone();
+    std::thread::spawn(move || nested.send(client.request("nested", 1).into::<u32>().ok()).unwrap());
+    wait_entered.recv().unwrap();
+    // Another thread of the server requests while its service handles "nested", and is answered before the nested request
+    std::thread::spawn(move || done.send(server.request("slow", 5).into::<u32>().ok()).unwrap());
+    std::thread::sleep(Duration::from_millis(50));
+    go.send(()).unwrap();
+    let mut results = (0..2).map(|_| finished.recv_timeout(Duration::from_secs(5)).unwrap()).collect::<Vec<_>>();
+    results.sort();
+    assert_eq!(results, vec![Some(2), Some(5)]);
+
+    // The services of both sessions request the other one from their receiving thread, while other threads request too
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let looping = server.clone();
+    std::thread::spawn(move || looping.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(ClientService)));
+    let looping = client.clone();
+    std::thread::spawn(move || looping.loop_handle());
+    let (done, finished) = channel();
+    // Answered by the server asking the client, or by the client
+    for (session, added) in vec![(client, 2), (server.clone(), 1), (server, 1)] {
+        let done = done.clone();
+        std::thread::spawn(move || done.send((0..200).all(|i| session.request(RECURSIVE_ADD, i).into::<u32>().ok() == Some(i + added))).unwrap());
+    }
+    for _ in 0..3 { assert_eq!(finished.recv_timeout(Duration::from_secs(10)), Ok(true)); }
+}
+
+#[test]
+fn test_on_notify() {
+    use easy_rpc::router::Router;
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(EmptyService)));
+    let (handled, to_service) = channel();
+    let handled = Mutex::new(handled);
+    let client = Arc::new(Session::new(b, Arc::new(Router::new()
+        .on("event", move |_, n: u32| { handled.lock().unwrap().send(n).unwrap(); Ok(()) }))));
+    let looping = client.clone();
+    std::thread::spawn(move || looping.loop_handle());
+
+    let (events, others) = (client.on_notify("event"), client.on_notify("event"));
+    assert!(server.notify("event", 1));
+    assert_eq!(events.recv_timeout(Duration::from_secs(1)).unwrap().borrow::<u32>().unwrap(), 1);
+    assert_eq!(others.recv_timeout(Duration::from_secs(1)).unwrap().borrow::<u32>().unwrap(), 1);
+    // Back to the service once the channels are dropped
+    drop((events, others));
+    assert!(server.notify("event", 2));
+    assert_eq!(to_service.recv_timeout(Duration::from_secs(1)), Ok(2));
+    assert!(to_service.try_recv().is_err());
+
+    let events = client.on_notify("event");
+    drop(server);
+    assert!(events.recv_timeout(Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn test_ext_types() {
+    use std::time::UNIX_EPOCH;
+    use easy_rpc::ext::{Ext, Timestamp};
+    use easy_rpc::router::Router;
+
+    // The shortest of the three forms
+    let encoded = |time: SystemTime| canonical::to_vec(&Timestamp(time)).unwrap();
+    assert_eq!(encoded(UNIX_EPOCH + Duration::from_secs(1)), vec![0xd6, 0xff, 0, 0, 0, 1]);
+    assert_eq!(encoded(UNIX_EPOCH + Duration::new(1, 1)), vec![0xd7, 0xff, 0, 0, 0, 4, 0, 0, 0, 1]);
+    assert_eq!(encoded(UNIX_EPOCH - Duration::from_millis(500)),
+        vec![0xc7, 12, 0xff, 0x1d, 0xcd, 0x65, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    // Nested in the compounds too
+    let second = UNIX_EPOCH + Duration::from_secs(1);
+    let mut nested = std::collections::BTreeMap::new();
+    nested.insert("at", vec![Some(Timestamp(second)), None]);
+    assert_eq!(canonical::to_vec(&nested).unwrap(), vec![0x81, 0xa2, b'a', b't', 0x92, 0xd6, 0xff, 0, 0, 0, 1, 0xc0]);
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(Router::new()
+        .on("later", |_, t: Timestamp| Ok(Timestamp(t.0 + Duration::from_secs(60))))
+        .on("text", |_, s: Vec<String>| Ok(s.concat()))
+        .on("latest", |_, times: Vec<(u32, Timestamp)>| Ok(times.into_iter().max_by_key(|t| t.1)))
+        .on("decimal", |_, ()| Ok(Ext { tag: 5, data: b"2.50".to_vec() })))));
+    // As a library of another language would send its decimals
+    let as_string: ext::ExtHandler = Arc::new(|data| String::from_utf8(data.into()).map(rmpv::Value::from).map_err(|e| e.to_string()));
+    server.set_ext_handler(5, Some(as_string.clone()));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::builder(b).ext_handler(5, as_string).build();
+
+    let now = SystemTime::now();
+    assert_eq!(client.call("later", Timestamp(now)), Ok(Timestamp(now + Duration::from_secs(60))));
+    let before = UNIX_EPOCH - Duration::new(3, 7);
+    assert_eq!(client.call("later", Timestamp(before)), Ok(Timestamp(before + Duration::from_secs(60))));
+    assert_eq!(client.call("latest", vec![(1, Timestamp(now)), (2, Timestamp(before))]), Ok(Some((1, Timestamp(now)))));
+    // For the `SystemTime` fields
+    let bytes = canonical::to_vec(&Timestamp(before)).unwrap();
+    assert_eq!(ext::timestamp::deserialize(&mut rmp_serde::Deserializer::new(&bytes[..])).unwrap(), before);
+    assert_eq!(client.call("text", vec![Ext { tag: 5, data: b"1.25".to_vec() }]), Ok("1.25".to_string()));
+    assert_eq!(client.call("decimal", ()), Ok("2.50".to_string()));
+    // Without a handler, the extension is kept
+    client.set_ext_handler(5, None);
+    assert_eq!(client.call("decimal", ()), Ok(Ext { tag: 5, data: b"2.50".to_vec() }));
+}
+
+#[test]
+fn test_dynamic_values() {
+    use rmpv::Value;
+    use easy_rpc::router::Router;
+
+    struct Inspect;
+    impl Service for Inspect {
+        fn handle(&self, _ss: &Session, arg: Arg, ret: Ret) -> Result<(), HandleError> {
+            ret.ok(arg.to_value()?.to_string());
+            Ok(())
+        }
+    }
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(Inspect));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    let arg = Value::Array(vec![Value::from(1), Value::from("a"), Value::Nil, Value::Map(vec![(Value::from("k"), Value::F64(1.5))])]);
+    assert_eq!(client.call("inspect", (1, "a", (), Bytes::new(b"\x01"))), Ok("[1, \"a\", nil, [1]]".to_string()));
+    assert_eq!(client.request_value("inspect", arg), Ok(Value::from("[1, \"a\", nil, {\"k\": 1.5}]")));
+
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(Router::new()
+        .on("swap", |_, (s, n): (String, f32)| Ok((n, s)))
+        .on("stamp", |_, t: ext::Timestamp| Ok(t))));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    let swapped = client.request_value("swap", Value::Array(vec![Value::from("a"), Value::F32(1.5)]));
+    assert_eq!(swapped, Ok(Value::Array(vec![Value::F32(1.5), Value::from("a")])));
+    // The extensions are kept
+    let stamp = Value::Ext(ext::TIMESTAMP, vec![0, 0, 0, 1]);
+    assert_eq!(client.request_value("stamp", stamp.clone()), Ok(stamp));
+    match client.request_value("swap", Value::from(1)) {
+        Err(RequestError::Remote(e)) => assert_eq!(e.code, RemoteError::INVALID_ARGS),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn test_protocol() {
+    use easy_rpc::protocol::{Protocol, Event, MethodBuf};
+
+    let (a, b) = pipe();
+    let server = Arc::new(Session::new(a, Arc::new(ServerService)));
+    let looping = server.clone();
+    std::thread::spawn(move || looping.loop_handle());
+    let mut proto = Protocol::new();
+
+    let (id, pack) = proto.encode_request(ECHO, 5u32);
+    b.send(pack);
+    match proto.feed_packet(&b.recv().unwrap()) {
+        Event::Response { id: got, method, result: Ok(result) } => {
+            assert_eq!((got, method), (id, MethodBuf::Int(ECHO)));
+            assert_eq!(rmp_serde::from_slice::<u32>(&result).unwrap(), 5);
+        }
+        event => panic!("unexpected {:?}", event),
+    }
+    assert_eq!(proto.pending(), 0);
+
+    // The session requests back, answered by the protocol side
+    let (id, pack) = proto.encode_request(RECURSIVE_ADD, 0u32);
+    b.send(pack);
+    let event = proto.feed_packet(&b.recv().unwrap());
+    match event {
+        Event::Request { id: back, method: MethodBuf::Int(RECURSIVE_ADD), args, .. } => {
+            let val: u32 = rmp_serde::from_slice(&args).unwrap();
+            b.send(proto.encode_response(back, Ok(val + 1)));
+        }
+        event => panic!("unexpected {:?}", event),
+    }
+    match proto.feed_packet(&b.recv().unwrap()) {
+        Event::Response { id: got, result: Ok(result), .. } if got == id => assert_eq!(rmp_serde::from_slice::<u32>(&result).unwrap(), 2),
+        event => panic!("unexpected {:?}", event),
+    }
+
+    // The packets of a stream, split or glued together
+    let pinging = server.clone();
+    let ping = std::thread::spawn(move || pinging.ping().is_ok());
+    assert!(server.notify("event", 7u32));
+    let mut stream = b.recv().unwrap();
+    stream.extend(b.recv().unwrap());
+    let (first, rest) = stream.split_at(stream.len() / 2 + 1);
+    let mut events = proto.feed_bytes(&first[..1]);
+    events.extend(proto.feed_bytes(&first[1..]));
+    events.extend(proto.feed_bytes(rest));
+    assert_eq!(events.len(), 2);
+    for event in events {
+        match event {
+            Event::Ping(id) => { b.send(proto.encode_pong(id)); }
+            Event::Notify { method, args, ack: None } => {
+                assert_eq!(method, MethodBuf::Str("event".into()));
+                assert_eq!(rmp_serde::from_slice::<u32>(&args).unwrap(), 7);
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+    assert!(ping.join().unwrap());
+
+    assert_eq!(proto.feed_packet(&proto.encode_response(42, Ok(()))), Event::Error(ProtocolError::UnknownResponse(42)));
+    assert_eq!(proto.feed_bytes(b"\x94\xc1"), vec![Event::Error(ProtocolError::Malformed("invalid marker"))]);
+    assert_eq!(proto.feed_packet(b"\x93\x05\x01\x02"), Event::Error(ProtocolError::Malformed("ping length")));
+    // The stream can't be split anymore after the invalid marker, ignored until reset
+    assert!(proto.feed_bytes(&proto.encode_pong(1)).is_empty());
+    let (id, _) = proto.encode_request("lost", ());
+    assert_eq!(proto.reset(), vec![id]);
+
+    // The packets too large are skipped, read piece by piece
+    let mut proto = Protocol::new().with_max_packet(0x100);
+    let mut stream = proto.encode_notify("big", Bytes::new(&[7; 0x1000]));
+    stream.extend(proto.encode_notify("small", 1u32));
+    stream.extend(proto.encode_notify("nested", vec![vec![0u8; 0x100]; 4]));
+    stream.extend(proto.encode_notify("small", 2u32));
+    let events: Vec<_> = stream.chunks(7).flat_map(|chunk| proto.feed_bytes(chunk)).collect();
+    let small = |n: u32| Event::Notify { method: MethodBuf::Str("small".into()), args: rmp_serde::to_vec(&n).unwrap(), ack: None };
+    let too_large = Event::Error(ProtocolError::LimitExceeded("packet size"));
+    assert_eq!(events, vec![too_large.clone(), small(1), too_large, small(2)]);
+}
+
+#[test]
+fn test_catch_panics() {
+    use easy_rpc::router::Router;
+
+    let router = || Router::new()
+        .on("boom", |_, n: u32| -> Result<u32, HandleError> { panic!("boom {}", n) })
+        .on("echo", |_, n: u32| Ok(n));
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(router()));
+    std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    let result: Result<u32, RequestError> = client.call("boom", 1);
+    match result {
+        Err(RequestError::Remote(e)) => {
+            assert_eq!(e.code, RemoteError::INTERNAL);
+            assert!(e.message.contains("boom 1"), "{}", e.message);
+        }
+        r => panic!("{:?}", r),
+    }
+    // The session goes on, after a panicking notify too
+    assert!(client.notify("boom", 2));
+    assert_eq!(client.call("echo", 3), Ok(3u32));
+
+    let (a, b) = pipe();
+    let server = Session::builder(a).service(Arc::new(router())).propagate_panics().build();
+    let looping = std::thread::spawn(move || server.loop_handle());
+    let client = Session::new(b, Arc::new(EmptyService));
+    assert!(client.notify("boom", 4));
+    assert!(looping.join().is_err());
+}
+
+#[test]
+fn test_abort_requests() {
+    let (a, b) = pipe();
+    let client = Arc::new(Session::new(a, Arc::new(EmptyService)));
+    let looping = client.clone();
+    std::thread::spawn(move || looping.loop_handle());
+    // Received by the loop, so the request waits on its own
+    while client.diagnostics().receiver.is_none() { std::thread::sleep(Duration::from_millis(1)); }
+    let requesting = client.clone();
+    let request = std::thread::spawn(move || -> Result<u32, RequestError> { requesting.call("never", ()) });
+    // The peer receives it and never answers
+    b.recv().unwrap();
+    let pending = client.diagnostics().pending;
+    assert_eq!(pending.len(), 1);
+    assert_eq!(client.abort_stale(Duration::from_secs(60)), Vec::<u64>::new());
+    assert_eq!(client.abort_stale(Duration::from_secs(0)), vec![pending[0].id]);
+    match request.join().unwrap() {
+        Err(RequestError::Remote(e)) => assert_eq!(e.code, RemoteError::UNAVAILABLE),
+        r => panic!("{:?}", r),
+    }
+    assert!(client.diagnostics().pending.is_empty());
+    assert!(!client.abort_request(pending[0].id));
+}
+
+#[test]
+fn test_cancel_request() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    let (a, b) = pipe();
+    let (held, handled) = (Arc::new(AtomicBool::new(true)), Arc::new(AtomicUsize::new(0)));
+    let handler = (held.clone(), handled.clone());
+    let server = Arc::new(Session::new(a, Arc::new(router::Router::new().on("hold", move |_, ()| {
+        while handler.0.load(Ordering::SeqCst) { std::thread::sleep(Duration::from_millis(1)); }
+        handler.1.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }))));
+    server.set_worker_pool(Some(WorkerPool::new(1)));
+    let looping = server.clone();
+    std::thread::spawn(move || looping.loop_handle());
+    let client = Arc::new(Session::new(b, Arc::new(EmptyService)));
+    let receiver = client.clone();
+    std::thread::spawn(move || receiver.loop_handle());
+
+    // The second one waits in the queue of the server behind the first
+    let requests: Vec<_> = (1..=2).map(|sent| {
+        let requesting = client.clone();
+        let request = std::thread::spawn(move || -> Result<(), RequestError> { requesting.call("hold", ()) });
+        while client.diagnostics().pending.len() < sent { std::thread::sleep(Duration::from_millis(1)); }
+        request
+    }).collect();
+    let mut pending: Vec<_> = client.diagnostics().pending.into_iter().map(|p| p.id).collect();
+    pending.sort();
+    assert!(client.cancel_request(pending[1]));
+    assert!(!client.cancel_request(pending[1]));
+    // Received by the server after the cancel
+    client.ping().unwrap();
+    held.store(false, Ordering::SeqCst);
+    let mut results = requests.into_iter().map(|r| r.join().unwrap());
+    assert_eq!(results.next(), Some(Ok(())));
+    match results.next() {
+        Some(Err(RequestError::Remote(e))) => assert_eq!(e.code, RemoteError::UNAVAILABLE),
+        r => panic!("{:?}", r),
+    }
+    // The cancelled one was never handled
+    assert!(server.drain(Duration::from_secs(5)));
+    assert_eq!(handled.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_faulty() {
+    use easy_rpc::faulty::{self, FaultConfig};
+
+    // Delayed responses
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let config = FaultConfig { latency: Duration::from_millis(30), seed: Some(1), ..Default::default() };
+    let client = Session::new(faulty::wrap(b, config), Arc::new(EmptyService));
+    let start = std::time::Instant::now();
+    assert_eq!(client.call(ECHO, 5), Ok(5u32));
+    assert!(start.elapsed() >= Duration::from_millis(30));
+
+    // Lost responses time out
+    let (a, b) = pipe();
+    let server = Session::new(a, Arc::new(ServerService));
+    std::thread::spawn(move || server.loop_handle());
+    let lossy = faulty::wrap(b, FaultConfig { drop: 1.0, ..Default::default() });
+    let client = Session::builder(lossy.clone()).default_timeout(Duration::from_millis(50)).build();
+    let result: Result<u32, RequestError> = client.call(ECHO, 5);
+    assert_eq!(result, Err(RequestError::Timeout));
+    assert_eq!(lossy.dropped(), 1);
+
+    // Each frame held back is overtaken by the next one
+    let (a, b) = pipe();
+    let reordering = faulty::wrap(b, FaultConfig { reorder: 1.0, ..Default::default() });
+    for frame in 1..=4u8 { a.send(vec![frame]); }
+    let frames: Vec<_> = (0..4).map(|_| reordering.recv().unwrap()).collect();
+    assert_eq!(frames, vec![vec![2], vec![1], vec![4], vec![3]]);
+    match reordering.recv_timeout(Duration::from_millis(10)) { Err(RecvError::NoData) => {} r => panic!("{:?}", r) }
+
+    // Broken connections
+    let (a, b) = pipe();
+    let broken = faulty::wrap(b, FaultConfig { disconnect: 1.0, ..Default::default() });
+    a.send(vec![1]);
+    match broken.recv() { Err(RecvError::Disconnect) => {} r => panic!("{:?}", r) }
+    assert!(!broken.connected() && !broken.send(vec![2]));
+    assert_eq!(broken.last_error(), Some(TransportError::Reset));
+}
+
+#[cfg(feature = "discover")]
+#[test]
+fn test_discover() {
+    use easy_rpc::discover;
+
+    let mut advertised = discover::advertise("easy-rpc-test", "device-1", 40001).unwrap();
+    let _other = discover::advertise("easy-rpc-other", "device-2", 40002).unwrap();
+    let found = discover::browse("easy-rpc-test", Duration::from_millis(500)).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].instance, "device-1");
+    assert_eq!(found[0].addr.port(), 40001);
+
+    advertised.close();
+    assert!(discover::browse("easy-rpc-test", Duration::from_millis(300)).unwrap().is_empty());
+    assert!(discover::advertise("bad.name", "device", 1).is_err());
+}